@@ -0,0 +1,25 @@
+use std::error::Error;
+use std::path::PathBuf;
+
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let proto_dir = PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap())
+        .join("src")
+        .join("plugins")
+        .join("coprocessor")
+        .join("proto");
+    let coprocessor_src = proto_dir.join("coprocessor.proto");
+
+    println!(
+        "cargo:rerun-if-changed={}",
+        coprocessor_src.to_str().unwrap()
+    );
+
+    // The router implements the gRPC framing over its own HTTP client, only the messages are needed
+    tonic_build::configure()
+        .build_client(false)
+        .build_server(false)
+        .emit_rerun_if_changed(false)
+        .compile(&[coprocessor_src], &[proto_dir])?;
+
+    Ok(())
+}
@@ -1,6 +1,7 @@
 use std::fs;
 use std::path::PathBuf;
 
+mod coprocessor;
 mod studio;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -33,5 +34,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("cargo:rustc-env=FEDERATION_VERSION={fed_version}");
 
-    studio::main()
+    studio::main()?;
+    coprocessor::main()
 }
@@ -1,4 +1,5 @@
 //! Axum http server factory. Axum provides routing capability on top of Hyper HTTP.
+use std::collections::BTreeMap;
 use std::pin::Pin;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::AtomicU64;
@@ -50,11 +51,15 @@ use crate::axum_factory::listeners::get_extra_listeners;
 use crate::axum_factory::listeners::serve_router_on_listen_addr;
 use crate::configuration::Configuration;
 use crate::configuration::ListenAddr;
+use crate::configuration::SupergraphWebSocket;
 use crate::http_server_factory::HttpServerFactory;
 use crate::http_server_factory::HttpServerHandle;
 use crate::http_server_factory::Listener;
 use crate::plugins::telemetry::SpanMode;
+use crate::plugins::traffic_shaping::circuit_breaker_states;
+use crate::plugins::traffic_shaping::CircuitState;
 use crate::plugins::traffic_shaping::Elapsed;
+use crate::plugins::traffic_shaping::Overloaded;
 use crate::plugins::traffic_shaping::RateLimited;
 use crate::router::ApolloRouterError;
 use crate::router_factory::Endpoint;
@@ -111,6 +116,9 @@ enum HealthStatus {
 #[derive(Debug, Serialize)]
 struct Health {
     status: HealthStatus,
+    /// State of the subgraph circuit breakers
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    circuit_breakers: BTreeMap<String, CircuitState>,
 }
 
 pub(crate) fn make_axum_router<RF>(
@@ -138,11 +146,11 @@ where
                 configuration.health_check.path.clone(),
                 service_fn(move |req: router::Request| {
                     let mut status_code = StatusCode::OK;
-                    let health = if let Some(query) = req.router_request.uri().query() {
+                    let status = if let Some(query) = req.router_request.uri().query() {
                         let query_upper = query.to_ascii_uppercase();
                         // Could be more precise, but sloppy match is fine for this use case
                         if query_upper.starts_with("READY") {
-                            let status = if ready.load(Ordering::SeqCst) {
+                            if ready.load(Ordering::SeqCst) {
                                 HealthStatus::Up
                             } else {
                                 // It's hard to get k8s to parse payloads. Especially since we
@@ -150,10 +158,9 @@ where
                                 // So, compromise, k8s will interpret this as probe fail.
                                 status_code = StatusCode::SERVICE_UNAVAILABLE;
                                 HealthStatus::Down
-                            };
-                            Health { status }
+                            }
                         } else if query_upper.starts_with("LIVE") {
-                            let status = if live.load(Ordering::SeqCst) {
+                            if live.load(Ordering::SeqCst) {
                                 HealthStatus::Up
                             } else {
                                 // It's hard to get k8s to parse payloads. Especially since we
@@ -161,17 +168,16 @@ where
                                 // So, compromise, k8s will interpret this as probe fail.
                                 status_code = StatusCode::SERVICE_UNAVAILABLE;
                                 HealthStatus::Down
-                            };
-                            Health { status }
-                        } else {
-                            Health {
-                                status: HealthStatus::Up,
                             }
+                        } else {
+                            HealthStatus::Up
                         }
                     } else {
-                        Health {
-                            status: HealthStatus::Up,
-                        }
+                        HealthStatus::Up
+                    };
+                    let health = Health {
+                        status,
+                        circuit_breakers: circuit_breaker_states(),
                     };
                     tracing::trace!(?health, request = ?req.router_request, "health check");
                     async move {
@@ -549,11 +555,13 @@ pub(super) fn main_router<RF>(
 where
     RF: RouterFactory,
 {
+    let websocket = configuration.supergraph.experimental_websocket.clone();
     let mut router = Router::new().route(
         &configuration.supergraph.sanitized_path(),
         get({
+            let websocket = websocket.clone();
             move |Extension(service): Extension<RF>, request: Request<DecompressionBody<Body>>| {
-                handle_graphql(service.create().boxed(), request)
+                handle_graphql_get(service, websocket.clone(), request)
             }
         })
         .post({
@@ -569,7 +577,7 @@ where
             get({
                 move |Extension(service): Extension<RF>,
                       request: Request<DecompressionBody<Body>>| {
-                    handle_graphql(service.create().boxed(), request)
+                    handle_graphql_get(service, websocket.clone(), request)
                 }
             })
             .post({
@@ -584,6 +592,23 @@ where
     router
 }
 
+async fn handle_graphql_get<RF>(
+    router_factory: RF,
+    websocket: SupergraphWebSocket,
+    http_request: Request<DecompressionBody<Body>>,
+) -> Response
+where
+    RF: RouterFactory,
+{
+    if websocket.enabled && super::websocket::is_upgrade_request(&http_request) {
+        return super::websocket::handle_upgrade(router_factory, websocket, http_request).await;
+    }
+
+    handle_graphql(router_factory.create().boxed(), http_request)
+        .await
+        .into_response()
+}
+
 async fn handle_graphql(
     service: router::BoxService,
     http_request: Request<DecompressionBody<Body>>,
@@ -617,6 +642,9 @@ async fn handle_graphql(
                 if source_err.is::<Elapsed>() {
                     return Elapsed::new().into_response();
                 }
+                if source_err.is::<Overloaded>() {
+                    return Overloaded::new().into_response();
+                }
             }
             if e.is::<RateLimited>() {
                 return RateLimited::new().into_response();
@@ -624,6 +652,9 @@ async fn handle_graphql(
             if e.is::<Elapsed>() {
                 return Elapsed::new().into_response();
             }
+            if e.is::<Overloaded>() {
+                return Overloaded::new().into_response();
+            }
 
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -262,7 +262,8 @@ pub(super) fn serve_router_on_listen_addr(
                                             let connection = Http::new()
                                             .http1_keep_alive(true)
                                             .http1_header_read_timeout(Duration::from_secs(10))
-                                            .serve_connection(stream, app);
+                                            .serve_connection(stream, app)
+                                            .with_upgrades();
 
                                         tokio::pin!(connection);
                                         tokio::select! {
@@ -291,7 +292,8 @@ pub(super) fn serve_router_on_listen_addr(
                                         let app = IdleConnectionChecker::new(received_first_request.clone(), app);
                                         let connection = Http::new()
                                         .http1_keep_alive(true)
-                                        .serve_connection(stream, app);
+                                        .serve_connection(stream, app)
+                                        .with_upgrades();
 
                                         tokio::pin!(connection);
                                         tokio::select! {
@@ -331,7 +333,8 @@ pub(super) fn serve_router_on_listen_addr(
                                             .http1_keep_alive(true)
                                             .http1_header_read_timeout(Duration::from_secs(10))
                                             .http2_only(http2)
-                                            .serve_connection(stream, app);
+                                            .serve_connection(stream, app)
+                                            .with_upgrades();
 
                                         tokio::pin!(connection);
                                         tokio::select! {
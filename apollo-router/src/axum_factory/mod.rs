@@ -5,6 +5,7 @@ mod listeners;
 #[cfg(test)]
 pub(crate) mod tests;
 pub(crate) mod utils;
+pub(crate) mod websocket;
 
 use std::sync::Arc;
 use std::sync::OnceLock;
@@ -1165,7 +1165,7 @@ async fn it_errors_on_bad_accept_header() -> Result<(), ApolloRouterError> {
     );
     assert_eq!(
         response.text().await.unwrap(),
-        r#"{"errors":[{"message":"'accept' header must be one of: \\\"*/*\\\", \"application/json\", \"application/graphql-response+json\", \"multipart/mixed;subscriptionSpec=1.0\" or \"multipart/mixed;deferSpec=20220824\"","extensions":{"code":"INVALID_ACCEPT_HEADER"}}]}"#
+        r#"{"errors":[{"message":"'accept' header must be one of: \\\"*/*\\\", \"application/json\", \"application/graphql-response+json\", \"multipart/mixed;subscriptionSpec=1.0\", \"multipart/mixed;deferSpec=20220824\" or \"text/event-stream\"","extensions":{"code":"INVALID_ACCEPT_HEADER"}}]}"#
     );
 
     server.shutdown().await
@@ -2349,3 +2349,92 @@ async fn test_supergraph_timeout() {
     let body = response.bytes().await.unwrap();
     assert_eq!(std::str::from_utf8(&body).unwrap(), "request timed out");
 }
+
+#[tokio::test]
+async fn websocket_query() {
+    use futures::SinkExt;
+    use tokio_tungstenite::connect_async;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use crate::axum_factory::websocket::WEBSOCKET_CONNECTION_INIT_PAYLOAD;
+    use crate::configuration::SupergraphWebSocket;
+
+    let conf = Arc::new(
+        Configuration::fake_builder()
+            .supergraph(
+                Supergraph::fake_builder()
+                    .experimental_websocket(SupergraphWebSocket {
+                        enabled: true,
+                        ..Default::default()
+                    })
+                    .build(),
+            )
+            .build()
+            .unwrap(),
+    );
+    let router_service = router::service::from_supergraph_mock_callback_and_configuration(
+        move |req| {
+            assert_eq!(
+                req.context
+                    .get::<_, serde_json::Value>(WEBSOCKET_CONNECTION_INIT_PAYLOAD)
+                    .unwrap(),
+                Some(json!({"token": "XXX"}))
+            );
+            Ok(SupergraphResponse::new_from_graphql_response(
+                graphql::Response::builder()
+                    .data(json!({"response": "yay"}))
+                    .build(),
+                req.context,
+            ))
+        },
+        conf.clone(),
+    )
+    .await;
+    let (server, _client) = init_with_config(router_service, conf, MultiMap::new())
+        .await
+        .unwrap();
+
+    let url =
+        format!("{}/", server.graphql_listen_address().as_ref().unwrap()).replacen("http", "ws", 1);
+    let mut request = url.into_client_request().unwrap();
+    request.headers_mut().insert(
+        header::SEC_WEBSOCKET_PROTOCOL,
+        HeaderValue::from_static("graphql-transport-ws"),
+    );
+    let (mut socket, response) = connect_async(request).await.unwrap();
+    assert_header!(
+        &response,
+        header::SEC_WEBSOCKET_PROTOCOL,
+        vec!["graphql-transport-ws".to_string()]
+    );
+
+    socket
+        .send(Message::Text(
+            json!({"type": "connection_init", "payload": {"token": "XXX"}}).to_string(),
+        ))
+        .await
+        .unwrap();
+    let message = socket.next().await.unwrap().unwrap().into_text().unwrap();
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&message).unwrap(),
+        json!({"type": "connection_ack"})
+    );
+
+    socket
+        .send(Message::Text(
+            json!({"type": "subscribe", "id": "1", "payload": {"query": "query"}}).to_string(),
+        ))
+        .await
+        .unwrap();
+    let message = socket.next().await.unwrap().unwrap().into_text().unwrap();
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&message).unwrap(),
+        json!({"type": "next", "id": "1", "payload": {"data": {"response": "yay"}}})
+    );
+    let message = socket.next().await.unwrap().unwrap().into_text().unwrap();
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&message).unwrap(),
+        json!({"type": "complete", "id": "1"})
+    );
+}
@@ -0,0 +1,391 @@
+//! GraphQL over WebSocket for clients of the router.
+//!
+//! Each operation received on a connection is executed as a separate router request, and its
+//! responses are sent back as messages of the sub-protocol negotiated during the upgrade.
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use axum::extract::ws::CloseFrame;
+use axum::extract::ws::Message;
+use axum::extract::ws::WebSocket;
+use axum::extract::FromRequestParts;
+use axum::extract::WebSocketUpgrade;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use bytes::BytesMut;
+use futures::SinkExt;
+use futures::StreamExt;
+use http::header::ACCEPT;
+use http::header::CONNECTION;
+use http::header::CONTENT_LENGTH;
+use http::header::CONTENT_TYPE;
+use http::header::SEC_WEBSOCKET_EXTENSIONS;
+use http::header::SEC_WEBSOCKET_KEY;
+use http::header::SEC_WEBSOCKET_PROTOCOL;
+use http::header::SEC_WEBSOCKET_VERSION;
+use http::header::UPGRADE;
+use http::request::Parts;
+use http::Method;
+use http::Request;
+use http::StatusCode;
+use http_body::Body as _;
+use hyper::Body;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tower::BoxError;
+use tower::ServiceExt;
+use tower_http::decompression::DecompressionBody;
+
+use crate::configuration::SupergraphWebSocket;
+use crate::graphql;
+use crate::protocols::websocket::ClientMessage;
+use crate::protocols::websocket::ServerError;
+use crate::protocols::websocket::ServerMessage;
+use crate::protocols::websocket::WebSocketProtocol;
+use crate::router_factory::RouterFactory;
+use crate::services::router;
+use crate::services::router::ClientRequestAccepts;
+use crate::services::APPLICATION_JSON_HEADER_VALUE;
+use crate::Context;
+
+/// Context key holding the payload of the `connection_init` message sent by the client
+pub(crate) const WEBSOCKET_CONNECTION_INIT_PAYLOAD: &str =
+    "apollo_websocket::connection_init_payload";
+
+const OUTGOING_MESSAGES_BUFFER: usize = 32;
+
+// Close codes defined by the graphql-transport-ws protocol
+const CLOSE_INVALID_MESSAGE: u16 = 4400;
+const CLOSE_UNAUTHORIZED: u16 = 4401;
+const CLOSE_INIT_TIMEOUT: u16 = 4408;
+const CLOSE_SUBSCRIBER_ALREADY_EXISTS: u16 = 4409;
+const CLOSE_TOO_MANY_INIT_REQUESTS: u16 = 4429;
+
+/// Returns true if the request asks to upgrade the connection to a WebSocket
+pub(super) fn is_upgrade_request<B>(request: &Request<B>) -> bool {
+    request
+        .headers()
+        .get(UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.eq_ignore_ascii_case("websocket"))
+        .unwrap_or_default()
+}
+
+pub(super) async fn handle_upgrade<RF>(
+    router_factory: RF,
+    configuration: SupergraphWebSocket,
+    request: Request<DecompressionBody<Body>>,
+) -> Response
+where
+    RF: RouterFactory,
+{
+    let (mut parts, _body) = request.into_parts();
+    let upgrade = match WebSocketUpgrade::from_request_parts(&mut parts, &()).await {
+        Ok(upgrade) => upgrade,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let protocol = match WebSocketProtocol::negotiate(&parts.headers) {
+        Some(protocol) => protocol,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "'{}' header must be one of: {:?} or {:?}",
+                    SEC_WEBSOCKET_PROTOCOL,
+                    WebSocketProtocol::GraphqlWs.sub_protocol(),
+                    WebSocketProtocol::SubscriptionsTransportWs.sub_protocol(),
+                ),
+            )
+                .into_response()
+        }
+    };
+
+    upgrade
+        .protocols([protocol.sub_protocol()])
+        .on_upgrade(move |socket| {
+            Session::new(router_factory, protocol, configuration, parts).run(socket)
+        })
+}
+
+struct Session<RF> {
+    router_factory: RF,
+    protocol: WebSocketProtocol,
+    configuration: SupergraphWebSocket,
+    /// Parts of the upgrade request, reused to build the request of each operation
+    parts: Parts,
+    /// Set once the client sent `connection_init`
+    init_payload: Option<Option<serde_json_bytes::Value>>,
+    operations: HashMap<String, AbortHandle>,
+}
+
+impl<RF> Session<RF>
+where
+    RF: RouterFactory,
+{
+    fn new(
+        router_factory: RF,
+        protocol: WebSocketProtocol,
+        configuration: SupergraphWebSocket,
+        mut parts: Parts,
+    ) -> Self {
+        for name in [
+            CONNECTION,
+            UPGRADE,
+            CONTENT_LENGTH,
+            SEC_WEBSOCKET_KEY,
+            SEC_WEBSOCKET_VERSION,
+            SEC_WEBSOCKET_PROTOCOL,
+            SEC_WEBSOCKET_EXTENSIONS,
+        ] {
+            parts.headers.remove(name);
+        }
+        parts.method = Method::POST;
+
+        Self {
+            router_factory,
+            protocol,
+            configuration,
+            parts,
+            init_payload: None,
+            operations: HashMap::new(),
+        }
+    }
+
+    async fn run(mut self, socket: WebSocket) {
+        let (mut sink, mut stream) = socket.split();
+        let (tx, mut rx) = mpsc::channel::<ServerMessage>(OUTGOING_MESSAGES_BUFFER);
+        let init_timeout = tokio::time::sleep(self.configuration.connection_init_timeout);
+        tokio::pin!(init_timeout);
+
+        let close = loop {
+            tokio::select! {
+                _ = &mut init_timeout, if self.init_payload.is_none() => {
+                    break Some((CLOSE_INIT_TIMEOUT, "Connection initialisation timeout"));
+                }
+                message = stream.next() => {
+                    let message = match message {
+                        Some(Ok(Message::Text(text))) => serde_json::from_str(&text),
+                        Some(Ok(Message::Binary(bin))) => serde_json::from_slice(&bin),
+                        Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break None,
+                    };
+                    let message = match message {
+                        Ok(message) => message,
+                        Err(_) => break Some((CLOSE_INVALID_MESSAGE, "Invalid message received")),
+                    };
+                    match self.handle_client_message(message, &tx) {
+                        Ok(Some(reply)) => {
+                            if self.send(&mut sink, &reply).await.is_err() {
+                                break None;
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(close) => break close,
+                    }
+                }
+                Some(message) = rx.recv() => {
+                    let id = message.id();
+                    // operations completed by the client do not get any more messages
+                    if let Some(id) = &id {
+                        if !self.operations.contains_key(id) {
+                            continue;
+                        }
+                    }
+                    if let ServerMessage::Complete { .. } | ServerMessage::Error { .. } = message {
+                        if let Some(id) = &id {
+                            self.operations.remove(id);
+                        }
+                    }
+                    if self.send(&mut sink, &message).await.is_err() {
+                        break None;
+                    }
+                }
+            }
+        };
+
+        for (_, operation) in self.operations.drain() {
+            operation.abort();
+        }
+        if let Some((code, reason)) = close {
+            let _ = sink
+                .send(Message::Close(Some(CloseFrame {
+                    code,
+                    reason: Cow::Borrowed(reason),
+                })))
+                .await;
+        }
+        let _ = sink.close().await;
+    }
+
+    /// Returns the message to reply with, or the reason to close the connection
+    fn handle_client_message(
+        &mut self,
+        message: ClientMessage,
+        tx: &mpsc::Sender<ServerMessage>,
+    ) -> Result<Option<ServerMessage>, Option<(u16, &'static str)>> {
+        match message {
+            ClientMessage::ConnectionInit { payload } => {
+                if self.init_payload.is_some() {
+                    return Err(Some((
+                        CLOSE_TOO_MANY_INIT_REQUESTS,
+                        "Too many initialisation requests",
+                    )));
+                }
+                self.init_payload = Some(payload);
+                Ok(Some(ServerMessage::ConnectionAck))
+            }
+            ClientMessage::Subscribe { id, payload } | ClientMessage::OldStart { id, payload } => {
+                if self.init_payload.is_none() {
+                    return Err(Some((CLOSE_UNAUTHORIZED, "Unauthorized")));
+                }
+                if self.operations.contains_key(&id) {
+                    return Err(Some((
+                        CLOSE_SUBSCRIBER_ALREADY_EXISTS,
+                        "Subscriber for this id already exists",
+                    )));
+                }
+                match self.operation_request(payload) {
+                    Ok(request) => {
+                        let service = self.router_factory.create().boxed();
+                        let operation = tokio::spawn(execute_operation(
+                            service,
+                            request,
+                            id.clone(),
+                            tx.clone(),
+                        ));
+                        self.operations.insert(id, operation.abort_handle());
+                        Ok(None)
+                    }
+                    Err(err) => Ok(Some(ServerMessage::Error {
+                        id,
+                        payload: ServerError::Errors(vec![graphql::Error::builder()
+                            .message(format!("cannot create the operation request: {err}"))
+                            .extension_code("WEBSOCKET_INVALID_OPERATION")
+                            .build()]),
+                    })),
+                }
+            }
+            ClientMessage::Complete { id } | ClientMessage::OldStop { id } => {
+                if let Some(operation) = self.operations.remove(&id) {
+                    operation.abort();
+                }
+                Ok(None)
+            }
+            ClientMessage::ConnectionTerminate => Err(None),
+            ClientMessage::Ping { payload } => Ok(Some(ServerMessage::Pong {
+                payload: payload.map(|payload| payload.into()),
+            })),
+            ClientMessage::Pong { .. } => Ok(None),
+        }
+    }
+
+    fn operation_request(&self, payload: graphql::Request) -> Result<router::Request, BoxError> {
+        let mut request = http::Request::builder()
+            .method(self.parts.method.clone())
+            .uri(self.parts.uri.clone())
+            .version(self.parts.version)
+            .body(Body::from(serde_json::to_vec(&payload)?))?;
+        *request.headers_mut() = self.parts.headers.clone();
+        request
+            .headers_mut()
+            .insert(CONTENT_TYPE, APPLICATION_JSON_HEADER_VALUE.clone());
+        request
+            .headers_mut()
+            .insert(ACCEPT, APPLICATION_JSON_HEADER_VALUE.clone());
+
+        let context = Context::new();
+        context.extensions().lock().insert(ClientRequestAccepts {
+            websocket: true,
+            ..Default::default()
+        });
+        if let Some(Some(init_payload)) = &self.init_payload {
+            context.insert_json_value(WEBSOCKET_CONNECTION_INIT_PAYLOAD, init_payload.clone());
+        }
+
+        Ok(router::Request {
+            router_request: request,
+            context,
+        })
+    }
+
+    async fn send<S>(&self, sink: &mut S, message: &ServerMessage) -> Result<(), BoxError>
+    where
+        S: futures::Sink<Message, Error = axum::Error> + Unpin,
+    {
+        let text = self.protocol.encode(message)?;
+        sink.send(Message::Text(text)).await?;
+        Ok(())
+    }
+}
+
+/// Executes one operation and forwards its responses to the connection
+async fn execute_operation(
+    service: router::BoxService,
+    request: router::Request,
+    id: String,
+    tx: mpsc::Sender<ServerMessage>,
+) {
+    let response = match service.oneshot(request).await {
+        Ok(response) => response,
+        Err(err) => {
+            let _ = tx
+                .send(ServerMessage::Error {
+                    id,
+                    payload: ServerError::Errors(vec![graphql::Error::builder()
+                        .message(format!("cannot execute the operation: {err}"))
+                        .extension_code("WEBSOCKET_OPERATION_ERROR")
+                        .build()]),
+                })
+                .await;
+            return;
+        }
+    };
+
+    let (parts, mut body) = response.response.into_parts();
+    let mut buffer = BytesMut::new();
+    let mut first = true;
+    let mut end_of_body = false;
+    while !end_of_body {
+        match body.data().await {
+            Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+            Some(Err(_)) | None => end_of_body = true,
+        }
+        loop {
+            // responses are delimited by new lines, except for errors created before
+            // the request reaches the supergraph, which are sent as a single JSON body
+            let line = match buffer.iter().position(|byte| *byte == b'\n') {
+                Some(position) => buffer.split_to(position + 1).freeze(),
+                None if end_of_body && !buffer.is_empty() => buffer.split().freeze(),
+                None => break,
+            };
+            let message = match graphql::Response::from_bytes("router", line) {
+                Ok(response) if first && !parts.status.is_success() && response.data.is_none() => {
+                    ServerMessage::Error {
+                        id: id.clone(),
+                        payload: ServerError::Errors(response.errors),
+                    }
+                }
+                Ok(response) => ServerMessage::Next {
+                    id: id.clone(),
+                    payload: response,
+                },
+                Err(err) => ServerMessage::Error {
+                    id: id.clone(),
+                    payload: ServerError::Error(
+                        graphql::Error::builder()
+                            .message(format!("cannot deserialize the operation response: {err}"))
+                            .extension_code("WEBSOCKET_INVALID_RESPONSE")
+                            .build(),
+                    ),
+                },
+            };
+            first = false;
+            let is_error = matches!(message, ServerMessage::Error { .. });
+            if tx.send(message).await.is_err() || is_error {
+                return;
+            }
+        }
+    }
+
+    let _ = tx.send(ServerMessage::Complete { id }).await;
+}
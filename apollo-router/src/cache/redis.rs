@@ -15,8 +15,10 @@ use fred::types::FromRedis;
 use fred::types::PerformanceConfig;
 use fred::types::ReconnectPolicy;
 use fred::types::RedisConfig;
+use fred::types::Scanner;
 use fred::types::TlsConfig;
 use fred::types::TlsHostMapping;
+use futures::StreamExt;
 use tower::BoxError;
 use url::Url;
 
@@ -34,6 +36,9 @@ const SUPPORTED_REDIS_SCHEMES: [&str; 6] = [
     "rediss-sentinel",
 ];
 
+/// Number of keys requested on each iteration of a SCAN
+const SCAN_COUNT: u32 = 100;
+
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub(crate) struct RedisKey<K>(pub(crate) K)
 where
@@ -255,6 +260,27 @@ impl RedisCacheStorage {
         self.ttl
     }
 
+    /// The underlying client, for commands that are not about caching
+    pub(crate) fn client(&self) -> &RedisClient {
+        &self.inner
+    }
+
+    /// Open a new connection with the same configuration. A connection subscribed to
+    /// pub/sub channels cannot send other commands.
+    pub(crate) async fn subscriber(&self) -> Result<RedisClient, RedisError> {
+        let client = self.inner.clone_new();
+        let _handle = client.connect();
+
+        // a TLS connection to a TCP Redis could hang, so we add a timeout
+        tokio::time::timeout(Duration::from_secs(5), client.wait_for_connect())
+            .await
+            .map_err(|_| {
+                RedisError::new(RedisErrorKind::Timeout, "timeout connecting to Redis")
+            })??;
+
+        Ok(client)
+    }
+
     fn preprocess_urls(urls: Vec<Url>) -> Result<Url, RedisError> {
         let url_len = urls.len();
         let mut urls_iter = urls.into_iter();
@@ -348,7 +374,7 @@ impl RedisCacheStorage {
         self.ttl = ttl;
     }
 
-    fn make_key<K: KeyType>(&self, key: RedisKey<K>) -> String {
+    pub(crate) fn make_key<K: KeyType>(&self, key: RedisKey<K>) -> String {
         match &self.namespace {
             Some(namespace) => format!("{namespace}:{key}"),
             None => key.to_string(),
@@ -479,6 +505,32 @@ impl RedisCacheStorage {
         tracing::trace!("insert result {:?}", r);
     }
 
+    /// Deletes the keys matching a glob style pattern, which is prefixed with the namespace.
+    /// Returns the number of deleted keys
+    pub(crate) async fn delete_matching(&self, pattern: &str) -> Result<u64, RedisError> {
+        let pattern = self.make_key(RedisKey(pattern.to_string()));
+        let mut scan = if self.inner.is_clustered() {
+            self.inner
+                .scan_cluster(pattern, Some(SCAN_COUNT), None)
+                .boxed()
+        } else {
+            self.inner.scan(pattern, Some(SCAN_COUNT), None).boxed()
+        };
+
+        let mut count = 0;
+        while let Some(page) = scan.next().await {
+            let mut page = page?;
+            let keys = page.take_results().unwrap_or_default();
+            page.next()?;
+            // keys are deleted one by one because they can be on different cluster slots
+            for key in keys {
+                count += self.inner.del::<u64, _>(key).await?;
+            }
+        }
+
+        Ok(count)
+    }
+
     pub(crate) async fn insert_multiple<K: KeyType, V: ValueType>(
         &self,
         data: &[(RedisKey<K>, RedisValue<V>)],
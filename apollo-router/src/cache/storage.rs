@@ -3,6 +3,7 @@ use std::fmt::{self};
 use std::hash::Hash;
 use std::num::NonZeroUsize;
 use std::sync::Arc;
+use std::time::Duration;
 
 use lru::LruCache;
 use serde::de::DeserializeOwned;
@@ -162,9 +163,14 @@ where
     }
 
     pub(crate) async fn insert(&self, key: K, value: V) {
+        self.insert_with_ttl(key, value, None).await
+    }
+
+    /// Inserts with an expiration for the Redis entry, instead of the one from the Redis configuration
+    pub(crate) async fn insert_with_ttl(&self, key: K, value: V, ttl: Option<Duration>) {
         if let Some(redis) = self.redis.as_ref() {
             redis
-                .insert(RedisKey(key.clone()), RedisValue(value.clone()), None)
+                .insert(RedisKey(key.clone()), RedisValue(value.clone()), ttl)
                 .await;
         }
 
@@ -615,6 +615,9 @@ pub(crate) struct Supergraph {
 
     /// Query planning options
     pub(crate) query_planning: QueryPlanning,
+
+    /// GraphQL over WebSocket options for clients
+    pub(crate) experimental_websocket: SupergraphWebSocket,
 }
 
 fn default_defer_support() -> bool {
@@ -631,6 +634,7 @@ impl Supergraph {
         defer_support: Option<bool>,
         query_planning: Option<QueryPlanning>,
         reuse_query_fragments: Option<bool>,
+        experimental_websocket: Option<SupergraphWebSocket>,
     ) -> Self {
         Self {
             listen: listen.unwrap_or_else(default_graphql_listen),
@@ -639,6 +643,7 @@ impl Supergraph {
             defer_support: defer_support.unwrap_or_else(default_defer_support),
             query_planning: query_planning.unwrap_or_default(),
             reuse_query_fragments,
+            experimental_websocket: experimental_websocket.unwrap_or_default(),
         }
     }
 }
@@ -654,6 +659,7 @@ impl Supergraph {
         defer_support: Option<bool>,
         query_planning: Option<QueryPlanning>,
         reuse_query_fragments: Option<bool>,
+        experimental_websocket: Option<SupergraphWebSocket>,
     ) -> Self {
         Self {
             listen: listen.unwrap_or_else(test_listen),
@@ -662,6 +668,7 @@ impl Supergraph {
             defer_support: defer_support.unwrap_or_else(default_defer_support),
             query_planning: query_planning.unwrap_or_default(),
             reuse_query_fragments,
+            experimental_websocket: experimental_websocket.unwrap_or_default(),
         }
     }
 }
@@ -689,6 +696,31 @@ impl Supergraph {
     }
 }
 
+/// GraphQL over WebSocket configuration for clients.
+///
+/// When enabled, the GraphQL endpoint accepts WebSocket upgrades using either the
+/// `graphql-transport-ws` or the `graphql-ws` sub-protocol.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct SupergraphWebSocket {
+    /// Set to true to accept WebSocket connections on the GraphQL path (default: false)
+    pub(crate) enabled: bool,
+
+    /// Time allowed to the client to send the `connection_init` message after the upgrade (default: 10s)
+    #[serde(with = "humantime_serde")]
+    #[schemars(with = "String")]
+    pub(crate) connection_init_timeout: Duration,
+}
+
+impl Default for SupergraphWebSocket {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            connection_init_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
 /// Configuration for operation limits, parser limits, HTTP limits, etc.
 #[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 #[serde(deny_unknown_fields, default)]
@@ -906,7 +938,7 @@ impl Default for InMemoryCache {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 /// Redis cache configuration
 pub(crate) struct RedisCache {
@@ -1079,7 +1111,7 @@ pub(crate) fn load_key(data: &str) -> io::Result<PrivateKey> {
 }
 
 /// Configuration options pertaining to the subgraph server component.
-#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 #[serde(default)]
 pub(crate) struct TlsClient {
@@ -1110,7 +1142,7 @@ impl Default for TlsClient {
 }
 
 /// TLS client authentication
-#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct TlsClientAuth {
     /// list of certificates in PEM format
@@ -1399,4 +1431,45 @@ pub(crate) struct Batching {
 
     /// Batching mode
     pub(crate) mode: BatchingMode,
+
+    /// Batching of the requests sent to subgraphs
+    #[serde(default)]
+    pub(crate) subgraph: SubgraphConfiguration<SubgraphBatching>,
+}
+
+/// Subgraph level batching configuration
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct SubgraphBatching {
+    /// Coalesce the fetches made to this subgraph by the operations of a client batch into a single batch request (default: false)
+    pub(crate) enabled: bool,
+
+    /// Also coalesce the fetches made concurrently by operations outside of client batches (default: false)
+    pub(crate) coalesce_concurrent: bool,
+
+    /// Maximum time a fetch waits for other fetches to join its batch request (default: 5ms)
+    #[serde(with = "humantime_serde")]
+    #[schemars(with = "String")]
+    pub(crate) max_wait: Duration,
+
+    /// Maximum number of operations in a batch request (default: unlimited)
+    pub(crate) max_size: Option<usize>,
+}
+
+impl Default for SubgraphBatching {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            coalesce_concurrent: false,
+            max_wait: Duration::from_millis(5),
+            max_size: None,
+        }
+    }
+}
+
+impl Batching {
+    /// Batching configuration for the requests sent to a subgraph, if enabled
+    pub(crate) fn subgraph_batching(&self, subgraph_name: &str) -> Option<&SubgraphBatching> {
+        Some(self.subgraph.get(subgraph_name)).filter(|config| config.enabled)
+    }
 }
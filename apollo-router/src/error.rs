@@ -96,6 +96,21 @@ pub(crate) enum FetchError {
         reason: String,
     },
 
+    /// Batch request to '{service}' failed: {reason}
+    SubrequestBatchingError {
+        /// The service failed.
+        service: String,
+
+        /// The reason the batch request failed.
+        reason: String,
+    },
+
+    /// circuit breaker of service '{service}' is open, the request was not sent
+    SubrequestCircuitOpen {
+        /// The service that was not called.
+        service: String,
+    },
+
     /// could not find path: {reason}
     ExecutionPathNotFound { reason: String },
 }
@@ -126,7 +141,9 @@ impl FetchError {
                 }
                 FetchError::SubrequestMalformedResponse { service, .. }
                 | FetchError::SubrequestUnexpectedPatchResponse { service }
-                | FetchError::SubrequestWsError { service, .. } => {
+                | FetchError::SubrequestWsError { service, .. }
+                | FetchError::SubrequestBatchingError { service, .. }
+                | FetchError::SubrequestCircuitOpen { service } => {
                     extensions
                         .entry("service")
                         .or_insert_with(|| service.clone().into());
@@ -168,6 +185,8 @@ impl ErrorExtension for FetchError {
             }
             FetchError::SubrequestHttpError { .. } => "SUBREQUEST_HTTP_ERROR",
             FetchError::SubrequestWsError { .. } => "SUBREQUEST_WEBSOCKET_ERROR",
+            FetchError::SubrequestBatchingError { .. } => "SUBREQUEST_BATCHING_ERROR",
+            FetchError::SubrequestCircuitOpen { .. } => "SUBREQUEST_CIRCUIT_OPEN",
             FetchError::ExecutionPathNotFound { .. } => "EXECUTION_PATH_NOT_FOUND",
             FetchError::MalformedRequest { .. } => "MALFORMED_REQUEST",
             FetchError::MalformedResponse { .. } => "MALFORMED_RESPONSE",
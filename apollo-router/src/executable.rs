@@ -36,6 +36,8 @@ use crate::router::ConfigurationSource;
 use crate::router::RouterHttpServer;
 use crate::router::SchemaSource;
 use crate::router::ShutdownSource;
+use crate::services::http::proxy::set_outbound_proxy;
+use crate::services::http::proxy::OutboundProxy;
 use crate::uplink::Endpoints;
 use crate::uplink::UplinkConfig;
 use crate::LicenseSource;
@@ -246,6 +248,18 @@ pub struct Opt {
     #[clap(long = "listen", env = "APOLLO_ROUTER_LISTEN_ADDRESS")]
     listen_address: Option<SocketAddr>,
 
+    /// HTTP proxy for the requests to subgraphs, coprocessors, Apollo Uplink and JWKS endpoints. Proxy credentials can be set in the URL.
+    #[clap(long = "outbound-proxy", env = "APOLLO_ROUTER_OUTBOUND_PROXY")]
+    outbound_proxy: Option<Url>,
+
+    /// Hosts (comma separated) reached without the outbound proxy: host names, domains, IP addresses, networks in CIDR notation, or `*`.
+    #[clap(
+        long = "no-proxy",
+        env = "APOLLO_ROUTER_NO_PROXY",
+        value_delimiter = ','
+    )]
+    no_proxy: Option<Vec<String>>,
+
     /// Display version and exit.
     #[clap(action = ArgAction::SetTrue, long, short = 'V')]
     pub(crate) version: bool,
@@ -508,6 +522,11 @@ impl Executable {
         if opt.apollo_uplink_poll_interval < Duration::from_secs(10) {
             return Err(anyhow!("apollo-uplink-poll-interval must be at least 10s"));
         }
+        if let Some(url) = opt.outbound_proxy.as_ref() {
+            let proxy = OutboundProxy::new(url, opt.no_proxy.as_deref().unwrap_or_default())
+                .map_err(|e| anyhow!("invalid outbound proxy configuration: {e}"))?;
+            set_outbound_proxy(proxy);
+        }
         let current_directory = std::env::current_dir()?;
         // Enable hot reload when dev mode is enabled
         opt.hot_reload = opt.hot_reload || opt.dev;
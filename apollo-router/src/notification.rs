@@ -30,6 +30,8 @@ use crate::graphql;
 use crate::spec::Schema;
 use crate::Configuration;
 
+pub(crate) mod redis;
+
 static NOTIFY_CHANNEL_SIZE: usize = 1024;
 static DEFAULT_MSG_CHANNEL_SIZE: usize = 128;
 
@@ -81,6 +83,26 @@ type ResponseSenderWithCreated<V> = oneshot::Sender<(
     bool,
 )>;
 
+/// Shares topics between router instances, so that only one of them owns the source of a topic
+#[async_trait::async_trait]
+pub(crate) trait DistributedTopics<K, V>: Send + Sync {
+    /// Claim a topic that was just created on this instance.
+    ///
+    /// Returns `true` if this instance owns the topic: it must feed `msg_sender` from the source,
+    /// and its messages are shared with the other instances. Otherwise the messages published by the
+    /// owner are relayed to `msg_sender`. `close` deletes the local topic once nobody listens to it anymore.
+    async fn claim(
+        &self,
+        topic: K,
+        msg_sender: broadcast::Sender<Option<V>>,
+        close: Box<dyn FnOnce() + Send>,
+    ) -> bool;
+
+    /// Resolves to `true` once this instance took over a topic from an owner that went away.
+    /// The caller then has to feed the topic from the source.
+    async fn takeover(&self, topic: K) -> bool;
+}
+
 enum Notification<K, V> {
     CreateOrSubscribe {
         topic: K,
@@ -117,6 +139,12 @@ enum Notification<K, V> {
     UpdateHeartbeat {
         new_ttl: Option<Duration>,
     },
+    UpdateDistributed {
+        distributed: Option<Arc<dyn DistributedTopics<K, V>>>,
+    },
+    Distributed {
+        response_sender: oneshot::Sender<Option<Arc<dyn DistributedTopics<K, V>>>>,
+    },
     #[cfg(test)]
     TryDelete {
         topic: K,
@@ -156,7 +184,12 @@ where
     ) -> Notify<K, V> {
         let (sender, receiver) = mpsc::channel(NOTIFY_CHANNEL_SIZE);
         let receiver_stream = ReceiverStream::new(receiver);
-        tokio::task::spawn(task(receiver_stream, ttl, heartbeat_error_message));
+        tokio::task::spawn(task(
+            receiver_stream,
+            sender.downgrade(),
+            ttl,
+            heartbeat_error_message,
+        ));
         Notify {
             sender,
             queue_size,
@@ -215,6 +248,34 @@ where
         Ok(())
     }
 
+    /// Share the topics created without heartbeat with other router instances
+    pub(crate) async fn set_distributed(
+        &self,
+        distributed: Option<Arc<dyn DistributedTopics<K, V>>>,
+    ) -> Result<(), NotifyError<V>> {
+        self.sender
+            .send(Notification::UpdateDistributed { distributed })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Wait until this instance owns a topic that was created by another router instance.
+    /// Returns `false` if the topic is not shared or if it was closed before
+    pub(crate) async fn takeover(&mut self, topic: K) -> Result<bool, NotifyError<V>> {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.sender
+            .send(Notification::Distributed {
+                response_sender: response_tx,
+            })
+            .await?;
+
+        match response_rx.await? {
+            Some(distributed) => Ok(distributed.takeover(topic).await),
+            None => Ok(false),
+        }
+    }
+
     // boolean in the tuple means `created`
     pub(crate) async fn create_or_subscribe(
         &mut self,
@@ -596,6 +657,7 @@ impl<K, V> Handle<K, V> where K: Clone {}
 
 async fn task<K, V>(
     mut receiver: ReceiverStream<Notification<K, V>>,
+    pubsub_sender: mpsc::WeakSender<Notification<K, V>>,
     mut ttl: Option<Duration>,
     heartbeat_error_message: Option<V>,
 ) where
@@ -603,6 +665,7 @@ async fn task<K, V>(
     V: Send + Clone + 'static,
 {
     let mut pubsub: PubSub<K, V> = PubSub::new(ttl);
+    let mut distributed: Option<Arc<dyn DistributedTopics<K, V>>> = None;
 
     let mut ttl_fut: Box<dyn Stream<Item = tokio::time::Instant> + Send + Unpin> = match ttl {
         Some(ttl) => Box::new(IntervalStream::new(tokio::time::interval(ttl))),
@@ -627,7 +690,26 @@ async fn task<K, V>(
                             Notification::Unsubscribe { topic } => pubsub.unsubscribe(topic),
                             Notification::ForceDelete { topic } => pubsub.force_delete(topic),
                             Notification::CreateOrSubscribe { topic,  msg_sender, response_sender, heartbeat_enabled } => {
-                                pubsub.subscribe_or_create(topic, msg_sender, response_sender, heartbeat_enabled);
+                                match &distributed {
+                                    // Topics with heartbeat are fed by callbacks sent to this instance, they can't be shared
+                                    Some(distributed) if !heartbeat_enabled && !pubsub.exist(&topic) => {
+                                        pubsub.create_topic(topic.clone(), msg_sender.clone(), heartbeat_enabled);
+                                        let msg_receiver = msg_sender.subscribe();
+                                        let distributed = distributed.clone();
+                                        let pubsub_sender = pubsub_sender.clone();
+                                        let closed_topic = topic.clone();
+                                        let close = Box::new(move || {
+                                            if let Some(pubsub_sender) = pubsub_sender.upgrade() {
+                                                let _ = pubsub_sender.try_send(Notification::ForceDelete { topic: closed_topic });
+                                            }
+                                        });
+                                        tokio::task::spawn(async move {
+                                            let created = distributed.claim(topic, msg_sender.clone(), close).await;
+                                            let _ = response_sender.send((msg_sender, msg_receiver, created));
+                                        });
+                                    }
+                                    _ => pubsub.subscribe_or_create(topic, msg_sender, response_sender, heartbeat_enabled),
+                                }
                             }
                             Notification::Subscribe {
                                 topic,
@@ -672,6 +754,12 @@ async fn task<K, V>(
                                 }
 
                             }
+                            Notification::UpdateDistributed { distributed: new_distributed } => {
+                                distributed = new_distributed;
+                            }
+                            Notification::Distributed { response_sender } => {
+                                let _ = response_sender.send(distributed.clone());
+                            }
                             Notification::Exist {
                                 topic,
                                 response_sender,
@@ -1116,4 +1204,51 @@ mod tests {
         let subscriptions_nb = notify.debug().await.unwrap();
         assert_eq!(subscriptions_nb, 0);
     }
+
+    /// Behaves as if another router instance owned every topic
+    struct RemoteTopics;
+
+    #[async_trait::async_trait]
+    impl DistributedTopics<Uuid, serde_json_bytes::Value> for RemoteTopics {
+        async fn claim(
+            &self,
+            _topic: Uuid,
+            msg_sender: broadcast::Sender<Option<serde_json_bytes::Value>>,
+            _close: Box<dyn FnOnce() + Send>,
+        ) -> bool {
+            tokio::task::spawn(async move {
+                let _ = msg_sender.send(Some(serde_json_bytes::json!({"test": "remote"})));
+            });
+            false
+        }
+
+        async fn takeover(&self, _topic: Uuid) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn it_shares_topics_without_heartbeat() {
+        let mut notify: Notify<Uuid, serde_json_bytes::Value> = Notify::builder().build();
+        notify
+            .set_distributed(Some(Arc::new(RemoteTopics)))
+            .await
+            .unwrap();
+        let topic_1 = Uuid::new_v4();
+        let topic_2 = Uuid::new_v4();
+
+        let (handle1, created) = notify.create_or_subscribe(topic_1, false).await.unwrap();
+        assert!(!created);
+        let mut handle1 = handle1.into_stream();
+        let new_msg = handle1.next().await.unwrap();
+        assert_eq!(new_msg, serde_json_bytes::json!({"test": "remote"}));
+        assert!(notify.takeover(topic_1).await.unwrap());
+
+        // Topics with heartbeat stay local
+        let (_handle2, created) = notify.create_or_subscribe(topic_2, true).await.unwrap();
+        assert!(created);
+
+        notify.set_distributed(None).await.unwrap();
+        assert!(!notify.takeover(topic_1).await.unwrap());
+    }
 }
@@ -0,0 +1,694 @@
+//! Redis backed sharing of subscription topics between router instances
+//!
+//! The first instance to claim a topic owns it: it holds a lease on the topic in Redis, keeps
+//! the upstream subscription open and publishes its events on a channel dedicated to the topic.
+//! The other instances subscribe to that channel and relay its events to their local subscribers.
+//! The owner publishes a ping whenever it renews its lease, so if an instance does not receive
+//! anything on the channel for a whole lease, it tries to take the topic over.
+//!
+//! The Redis commands are issued through [`TopicStore`], so that the lease handling can run
+//! against an in memory store in tests.
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use fred::interfaces::EventInterface;
+use fred::interfaces::LuaInterface;
+use fred::interfaces::PubsubInterface;
+use fred::prelude::KeysInterface;
+use fred::prelude::RedisClient;
+use fred::types::Expiration;
+use fred::types::SetOptions;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tokio_stream::wrappers::BroadcastStream;
+use tower::BoxError;
+use uuid::Uuid;
+
+use super::DistributedTopics;
+use crate::cache::redis::RedisCacheStorage;
+use crate::cache::redis::RedisKey;
+use crate::configuration::RedisCache;
+
+/// Extends the lease if this instance still owns it
+const RENEW_LEASE_SCRIPT: &str = r#"
+if redis.call("get", KEYS[1]) == ARGV[1] then
+    return redis.call("pexpire", KEYS[1], ARGV[2])
+else
+    return 0
+end
+"#;
+
+/// Releases the lease if this instance still owns it
+const RELEASE_LEASE_SCRIPT: &str = r#"
+if redis.call("get", KEYS[1]) == ARGV[1] then
+    return redis.call("del", KEYS[1])
+else
+    return 0
+end
+"#;
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", content = "payload", rename_all = "snake_case")]
+enum Event<V> {
+    Ping,
+    Next(V),
+    Complete,
+}
+
+/// Lease and channel operations used to share topics between router instances
+#[async_trait::async_trait]
+pub(crate) trait TopicStore: Send + Sync {
+    /// Returns `true` if `owner` acquired the lease, which must not be held by anybody else
+    async fn acquire(&self, key: &str, owner: &str, lease: Duration) -> Result<bool, BoxError>;
+
+    /// Returns `true` if `owner` still holds the lease and extended it
+    async fn renew(&self, key: &str, owner: &str, lease: Duration) -> Result<bool, BoxError>;
+
+    /// Releases the lease if `owner` still holds it
+    async fn release(&self, key: &str, owner: &str) -> Result<(), BoxError>;
+
+    /// Returns the number of router instances that received the payload
+    async fn publish(&self, channel: &str, payload: String) -> Result<i64, BoxError>;
+
+    /// Streams the payloads published on a channel, from the moment this method returns
+    async fn subscribe(&self, channel: &str) -> Result<BoxStream<'static, Bytes>, BoxError>;
+
+    /// Stops streaming the payloads published on a channel
+    async fn unsubscribe(&self, channel: &str) -> Result<(), BoxError>;
+}
+
+#[derive(Clone)]
+struct RedisStore {
+    storage: RedisCacheStorage,
+    subscriber: RedisClient,
+}
+
+#[async_trait::async_trait]
+impl TopicStore for RedisStore {
+    async fn acquire(&self, key: &str, owner: &str, lease: Duration) -> Result<bool, BoxError> {
+        let res: Option<String> = self
+            .storage
+            .client()
+            .set(
+                self.storage.make_key(RedisKey(key)),
+                owner,
+                Some(Expiration::PX(lease.as_millis() as i64)),
+                Some(SetOptions::NX),
+                false,
+            )
+            .await?;
+
+        Ok(res.is_some())
+    }
+
+    async fn renew(&self, key: &str, owner: &str, lease: Duration) -> Result<bool, BoxError> {
+        let res: i64 = self
+            .storage
+            .client()
+            .eval(
+                RENEW_LEASE_SCRIPT,
+                vec![self.storage.make_key(RedisKey(key))],
+                vec![owner.to_string(), lease.as_millis().to_string()],
+            )
+            .await?;
+
+        Ok(res == 1)
+    }
+
+    async fn release(&self, key: &str, owner: &str) -> Result<(), BoxError> {
+        let _: i64 = self
+            .storage
+            .client()
+            .eval(
+                RELEASE_LEASE_SCRIPT,
+                vec![self.storage.make_key(RedisKey(key))],
+                vec![owner.to_string()],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn publish(&self, channel: &str, payload: String) -> Result<i64, BoxError> {
+        Ok(self
+            .storage
+            .client()
+            .publish(self.storage.make_key(RedisKey(channel)), payload)
+            .await?)
+    }
+
+    async fn subscribe(&self, channel: &str) -> Result<BoxStream<'static, Bytes>, BoxError> {
+        let channel = self.storage.make_key(RedisKey(channel));
+        // Listen to messages before subscribing to not miss any event
+        let messages = BroadcastStream::new(self.subscriber.message_rx());
+        let _: () = self.subscriber.subscribe(channel.clone()).await?;
+
+        Ok(messages
+            .filter_map(move |message| {
+                // Lagging messages are skipped
+                let payload = message
+                    .ok()
+                    .filter(|message| *message.channel == *channel)
+                    .map(|message| {
+                        message
+                            .value
+                            .as_bytes()
+                            .map(Bytes::copy_from_slice)
+                            .unwrap_or_default()
+                    });
+                futures::future::ready(payload)
+            })
+            .boxed())
+    }
+
+    async fn unsubscribe(&self, channel: &str) -> Result<(), BoxError> {
+        Ok(self
+            .subscriber
+            .unsubscribe(self.storage.make_key(RedisKey(channel)))
+            .await?)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct RedisTopics {
+    store: Arc<dyn TopicStore>,
+    instance_id: Arc<String>,
+    lease: Duration,
+    takeovers: Arc<Mutex<HashMap<String, oneshot::Receiver<()>>>>,
+}
+
+impl RedisTopics {
+    pub(crate) async fn new(config: RedisCache, lease: Duration) -> Result<Self, BoxError> {
+        let storage = RedisCacheStorage::new(config).await?;
+        let subscriber = storage.subscriber().await?;
+
+        Ok(Self::with_store(
+            Arc::new(RedisStore {
+                storage,
+                subscriber,
+            }),
+            lease,
+        ))
+    }
+
+    pub(crate) fn with_store(store: Arc<dyn TopicStore>, lease: Duration) -> Self {
+        Self {
+            store,
+            instance_id: Arc::new(Uuid::new_v4().to_string()),
+            lease,
+            takeovers: Default::default(),
+        }
+    }
+
+    fn lease_key(&self, topic: &str) -> String {
+        format!("subscription:owner:{topic}")
+    }
+
+    fn channel(&self, topic: &str) -> String {
+        format!("subscription:events:{topic}")
+    }
+
+    /// Returns `true` if this instance acquired the lease
+    async fn acquire(&self, topic: &str) -> Result<bool, BoxError> {
+        self.store
+            .acquire(&self.lease_key(topic), &self.instance_id, self.lease)
+            .await
+    }
+
+    /// Returns `true` if this instance still owns the lease
+    async fn renew(&self, topic: &str) -> Result<bool, BoxError> {
+        self.store
+            .renew(&self.lease_key(topic), &self.instance_id, self.lease)
+            .await
+    }
+
+    async fn release(&self, topic: &str) {
+        let res = self
+            .store
+            .release(&self.lease_key(topic), &self.instance_id)
+            .await;
+        if let Err(err) = res {
+            tracing::error!("cannot release the lease on subscription {topic}: {err}");
+        }
+    }
+
+    /// Returns the number of router instances that received the event
+    async fn publish<V: Serialize>(&self, topic: &str, event: &Event<V>) -> Result<i64, BoxError> {
+        let payload = serde_json::to_string(event)?;
+        self.store.publish(&self.channel(topic), payload).await
+    }
+
+    /// Publish the events of a topic owned by this instance, until the topic is closed locally
+    /// or nobody listens to it anymore. If the lease is lost, another instance may own the topic
+    /// already, so the local topic is closed without publishing anything else.
+    async fn lead<V>(
+        self,
+        topic: String,
+        msg_sender: broadcast::Sender<Option<V>>,
+        close: Box<dyn FnOnce() + Send>,
+    ) where
+        V: Serialize + Clone + Send + 'static,
+    {
+        let mut msg_receiver = msg_sender.subscribe();
+        let mut renewal = tokio::time::interval(self.lease / 3);
+        let mut renewed_at = Instant::now();
+
+        loop {
+            tokio::select! {
+                _ = renewal.tick() => {
+                    match self.renew(&topic).await {
+                        Ok(true) => renewed_at = Instant::now(),
+                        Ok(false) => {
+                            tracing::warn!("lost the lease on subscription {topic}");
+                            close();
+                            return;
+                        }
+                        Err(err) => {
+                            tracing::error!("cannot renew the lease on subscription {topic}: {err}");
+                            // Other instances can claim the topic once the lease expires
+                            if renewed_at.elapsed() >= self.lease {
+                                close();
+                                return;
+                            }
+                        }
+                    }
+                    let followers = self.publish::<V>(&topic, &Event::Ping).await.unwrap_or_default();
+                    // The only local receiver left is the one of this task
+                    if followers == 0 && msg_sender.receiver_count() <= 1 {
+                        close();
+                        break;
+                    }
+                }
+                message = msg_receiver.recv() => {
+                    let event = match message {
+                        Ok(Some(value)) => Event::Next(value),
+                        Ok(None) | Err(broadcast::error::RecvError::Closed) => Event::Complete,
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    };
+                    let complete = matches!(event, Event::Complete);
+                    if let Err(err) = self.publish(&topic, &event).await {
+                        tracing::error!("cannot publish an event of subscription {topic}: {err}");
+                    }
+                    if complete {
+                        break;
+                    }
+                }
+            }
+        }
+
+        self.release(&topic).await;
+    }
+
+    /// Relay the events published by the owner of a topic, until the topic is complete or nobody
+    /// listens to it on this instance anymore. Takes the topic over if the owner goes away.
+    async fn follow<V>(
+        self,
+        topic: String,
+        mut messages: BoxStream<'static, Bytes>,
+        msg_sender: broadcast::Sender<Option<V>>,
+        close: Box<dyn FnOnce() + Send>,
+        takeover: oneshot::Sender<()>,
+    ) where
+        V: Serialize + DeserializeOwned + Clone + Send + 'static,
+    {
+        let mut deadline = Instant::now() + self.lease;
+        let mut taken_over = false;
+
+        loop {
+            tokio::select! {
+                payload = messages.next() => {
+                    let payload = match payload {
+                        Some(payload) => payload,
+                        None => break,
+                    };
+                    deadline = Instant::now() + self.lease;
+
+                    match serde_json::from_slice::<Event<V>>(&payload).ok() {
+                        Some(Event::Next(value)) => {
+                            if msg_sender.send(Some(value)).is_err() {
+                                break;
+                            }
+                        }
+                        Some(Event::Complete) => {
+                            let _ = msg_sender.send(None);
+                            break;
+                        }
+                        Some(Event::Ping) => {
+                            if msg_sender.receiver_count() == 0 {
+                                break;
+                            }
+                        }
+                        None => tracing::error!("cannot deserialize an event of subscription {topic}"),
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline) => {
+                    if msg_sender.receiver_count() == 0 {
+                        break;
+                    }
+                    match self.acquire(&topic).await {
+                        Ok(true) => {
+                            tracing::info!("taking over subscription {topic}");
+                            taken_over = true;
+                            break;
+                        }
+                        Ok(false) => deadline = Instant::now() + self.lease,
+                        Err(err) => {
+                            tracing::error!("cannot take over subscription {topic}: {err}");
+                            deadline = Instant::now() + self.lease;
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Err(err) = self.store.unsubscribe(&self.channel(&topic)).await {
+            tracing::error!("cannot unsubscribe from subscription {topic}: {err}");
+        }
+        // Nothing is relayed anymore, even if this instance leads the topic now
+        drop(messages);
+
+        self.takeovers.lock().remove(&topic);
+        if taken_over {
+            if takeover.send(()).is_ok() {
+                self.lead(topic, msg_sender, close).await;
+            } else {
+                // Nobody on this instance is waiting to open the source again
+                self.release(&topic).await;
+                close();
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<K, V> DistributedTopics<K, V> for RedisTopics
+where
+    K: Display + Send + 'static,
+    V: Serialize + DeserializeOwned + Clone + Send + 'static,
+{
+    async fn claim(
+        &self,
+        topic: K,
+        msg_sender: broadcast::Sender<Option<V>>,
+        close: Box<dyn FnOnce() + Send>,
+    ) -> bool {
+        let topic = topic.to_string();
+        match self.acquire(&topic).await {
+            Ok(true) => {
+                tokio::task::spawn(self.clone().lead(topic, msg_sender, close));
+                true
+            }
+            Ok(false) => {
+                let messages = match self.store.subscribe(&self.channel(&topic)).await {
+                    Ok(messages) => messages,
+                    Err(err) => {
+                        tracing::error!("cannot subscribe to subscription {topic}: {err}");
+                        return true;
+                    }
+                };
+
+                let (takeover_tx, takeover_rx) = oneshot::channel();
+                self.takeovers.lock().insert(topic.clone(), takeover_rx);
+                tokio::task::spawn(self.clone().follow(
+                    topic,
+                    messages,
+                    msg_sender,
+                    close,
+                    takeover_tx,
+                ));
+                false
+            }
+            Err(err) => {
+                // Without Redis, the source is opened by this instance only
+                tracing::error!("cannot claim subscription {topic}: {err}");
+                true
+            }
+        }
+    }
+
+    async fn takeover(&self, topic: K) -> bool {
+        let takeover = self.takeovers.lock().remove(&topic.to_string());
+        match takeover {
+            Some(takeover) => takeover.await.is_ok(),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::graphql;
+    use crate::notification::Notify;
+
+    /// Opens a connection to the Redis instance used by the Redis integration tests
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    async fn topics(lease: Duration) -> RedisTopics {
+        let config: RedisCache =
+            serde_json::from_value(json!({ "urls": ["redis://127.0.0.1:6379"] })).unwrap();
+        RedisTopics::new(config, lease).await.unwrap()
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn closed() -> (Box<dyn FnOnce() + Send>, oneshot::Receiver<()>) {
+        let (tx, rx) = oneshot::channel();
+        (
+            Box::new(move || {
+                let _ = tx.send(());
+            }),
+            rx,
+        )
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn follower_takes_over_when_the_leader_loses_its_lease() {
+        let lease = Duration::from_millis(600);
+        let topic = Uuid::new_v4().to_string();
+        let leader = topics(lease).await;
+        let follower = topics(lease).await;
+
+        let (leader_sender, _leader_receiver) = broadcast::channel(16);
+        let (leader_close, leader_closed) = closed();
+        assert!(
+            DistributedTopics::<String, graphql::Response>::claim(
+                &leader,
+                topic.clone(),
+                leader_sender.clone(),
+                leader_close,
+            )
+            .await
+        );
+
+        let (follower_sender, mut follower_receiver) = broadcast::channel(16);
+        let (follower_close, _follower_closed) = closed();
+        assert!(
+            !DistributedTopics::<String, graphql::Response>::claim(
+                &follower,
+                topic.clone(),
+                follower_sender,
+                follower_close,
+            )
+            .await
+        );
+
+        // the events of the leader are relayed by the follower
+        let event = graphql::Response::builder()
+            .data(serde_json_bytes::json!({ "userWasCreated": { "name": "Ada" } }))
+            .build();
+        leader_sender.send(Some(event.clone())).unwrap();
+        let relayed = tokio::time::timeout(Duration::from_secs(5), follower_receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(relayed, Some(event));
+
+        // another instance steals the lease until it expires: the leader stops publishing
+        let lease_key = leader.lease_key(&topic);
+        leader.release(&topic).await;
+        assert!(leader
+            .store
+            .acquire(&lease_key, "another instance", lease)
+            .await
+            .unwrap());
+        tokio::time::timeout(Duration::from_secs(5), leader_closed)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // then the follower claims the topic once nobody renews the lease anymore
+        assert!(tokio::time::timeout(
+            Duration::from_secs(10),
+            DistributedTopics::<String, graphql::Response>::takeover(&follower, topic.clone()),
+        )
+        .await
+        .unwrap());
+        assert!(follower
+            .store
+            .renew(&lease_key, &follower.instance_id, lease)
+            .await
+            .unwrap());
+    }
+
+    /// Keeps the leases and channels shared by router instances in memory
+    #[derive(Default)]
+    struct MemoryStore {
+        leases: Mutex<HashMap<String, (String, Instant)>>,
+        channels: Mutex<HashMap<String, broadcast::Sender<Bytes>>>,
+    }
+
+    impl MemoryStore {
+        fn owner(&self, key: &str) -> Option<String> {
+            self.leases
+                .lock()
+                .get(key)
+                .filter(|(_, expires_at)| *expires_at > Instant::now())
+                .map(|(owner, _)| owner.clone())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl TopicStore for MemoryStore {
+        async fn acquire(&self, key: &str, owner: &str, lease: Duration) -> Result<bool, BoxError> {
+            if self.owner(key).is_some() {
+                return Ok(false);
+            }
+            self.leases
+                .lock()
+                .insert(key.to_string(), (owner.to_string(), Instant::now() + lease));
+            Ok(true)
+        }
+
+        async fn renew(&self, key: &str, owner: &str, lease: Duration) -> Result<bool, BoxError> {
+            if self.owner(key).as_deref() != Some(owner) {
+                return Ok(false);
+            }
+            self.leases
+                .lock()
+                .insert(key.to_string(), (owner.to_string(), Instant::now() + lease));
+            Ok(true)
+        }
+
+        async fn release(&self, key: &str, owner: &str) -> Result<(), BoxError> {
+            if self.owner(key).as_deref() == Some(owner) {
+                self.leases.lock().remove(key);
+            }
+            Ok(())
+        }
+
+        async fn publish(&self, channel: &str, payload: String) -> Result<i64, BoxError> {
+            let received = self
+                .channels
+                .lock()
+                .get(channel)
+                .and_then(|sender| sender.send(Bytes::from(payload)).ok())
+                .unwrap_or_default();
+            Ok(received as i64)
+        }
+
+        async fn subscribe(&self, channel: &str) -> Result<BoxStream<'static, Bytes>, BoxError> {
+            let receiver = self
+                .channels
+                .lock()
+                .entry(channel.to_string())
+                .or_insert_with(|| broadcast::channel(16).0)
+                .subscribe();
+            Ok(BroadcastStream::new(receiver)
+                .filter_map(|payload| futures::future::ready(payload.ok()))
+                .boxed())
+        }
+
+        async fn unsubscribe(&self, _channel: &str) -> Result<(), BoxError> {
+            // The receiver of the channel is dropped with its stream
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn follower_takes_over_when_the_lease_is_released() {
+        let lease = Duration::from_secs(3);
+        let topic = Uuid::new_v4().to_string();
+        let store = Arc::new(MemoryStore::default());
+        let leader = RedisTopics::with_store(store.clone(), lease);
+        let follower = RedisTopics::with_store(store.clone(), lease);
+
+        let mut leader_notify: Notify<String, graphql::Response> = Notify::builder().build();
+        leader_notify
+            .set_distributed(Some(Arc::new(leader.clone())))
+            .await
+            .unwrap();
+        let mut follower_notify: Notify<String, graphql::Response> = Notify::builder().build();
+        follower_notify
+            .set_distributed(Some(Arc::new(follower.clone())))
+            .await
+            .unwrap();
+
+        let (leader_handle, created) = leader_notify
+            .create_or_subscribe(topic.clone(), false)
+            .await
+            .unwrap();
+        assert!(created);
+        let (follower_handle, created) = follower_notify
+            .create_or_subscribe(topic.clone(), false)
+            .await
+            .unwrap();
+        assert!(!created);
+
+        // the events of the leader are relayed to the subscribers of the follower
+        let (mut leader_sink, _leader_stream) = leader_handle.split();
+        let mut follower_stream = follower_handle.into_stream();
+        let event = graphql::Response::builder()
+            .data(serde_json_bytes::json!({ "userWasCreated": { "name": "Ada" } }))
+            .build();
+        leader_sink.send_sync(event.clone()).unwrap();
+        assert_eq!(follower_stream.next().await, Some(event));
+
+        // once the lease is released, the leader closes its topic and the follower takes it over
+        leader.release(&topic).await;
+        assert!(follower_notify.takeover(topic.clone()).await.unwrap());
+        assert_eq!(
+            store.owner(&follower.lease_key(&topic)).as_deref(),
+            Some(follower.instance_id.as_str())
+        );
+        assert!(!leader_notify.exist(topic.clone()).await.unwrap());
+    }
+
+    #[test]
+    fn event_serialization() {
+        let event = Event::Next(
+            graphql::Response::builder()
+                .data(serde_json_bytes::json!({ "userWasCreated": { "name": "Ada" } }))
+                .build(),
+        );
+        let payload = serde_json::to_value(&event).unwrap();
+        assert_eq!(
+            payload,
+            json!({ "kind": "next", "payload": { "data": { "userWasCreated": { "name": "Ada" } } } })
+        );
+        assert_eq!(
+            serde_json::from_value::<Event<graphql::Response>>(payload).unwrap(),
+            event
+        );
+
+        assert_eq!(
+            serde_json::to_value(&Event::<graphql::Response>::Ping).unwrap(),
+            json!({ "kind": "ping" })
+        );
+        assert_eq!(
+            serde_json::to_value(&Event::<graphql::Response>::Complete).unwrap(),
+            json!({ "kind": "complete" })
+        );
+    }
+}
@@ -0,0 +1,378 @@
+//! Authentication of clients with static API keys, checked against a file of salted hashes
+
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures::StreamExt;
+use http::HeaderName;
+use http::StatusCode;
+use parking_lot::RwLock;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+use sha2::Digest;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use tower::BoxError;
+
+use super::reject;
+use super::AuthenticationError;
+use super::APOLLO_AUTHENTICATION_API_KEY;
+use super::APOLLO_AUTHENTICATION_JWT_CLAIMS;
+use crate::plugins::authorization::GRANTED_SCOPES_KEY;
+use crate::services::router;
+
+const AUTHENTICATION_KIND: &str = "api_key";
+
+/// Authenticate clients with static API keys
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(super) struct ApiKeyConf {
+    /// YAML or JSON file listing the salted hashes of the accepted keys, watched for changes
+    keys_file: PathBuf,
+    /// HTTP header expected to contain the API key
+    #[serde(default = "default_api_key_header_name")]
+    header_name: String,
+    /// Query parameter containing the API key, used if the header is absent. Beware that query
+    /// parameters are often written to access logs
+    query_parameter: Option<String>,
+}
+
+fn default_api_key_header_name() -> String {
+    "x-api-key".to_string()
+}
+
+/// Entry of the keys file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyEntry {
+    /// Identifier of the key, which is not secret: keys are formatted as `<id>.<secret>`
+    id: String,
+    /// Name of the client using the key
+    client_name: String,
+    /// Salt prepended to the key before hashing
+    salt: String,
+    /// Hex encoded SHA-256 hash of the salt followed by the whole key, including its identifier
+    hash: String,
+    /// Scopes granted to the client, used by `@requiresScopes`
+    #[serde(default)]
+    scopes: Vec<String>,
+    /// Rate limit tier of the client
+    rate_limit_tier: Option<String>,
+}
+
+/// Metadata of an API key, added to the context of the requests using it
+#[derive(Clone, Debug, Serialize)]
+struct ApiKeyMetadata {
+    client_name: String,
+    scopes: Vec<String>,
+    rate_limit_tier: Option<String>,
+}
+
+struct StoredKey {
+    salt: String,
+    hash: Vec<u8>,
+    metadata: ApiKeyMetadata,
+}
+
+impl StoredKey {
+    fn matches(&self, key: &str) -> bool {
+        let hash = Sha256::new()
+            .chain_update(&self.salt)
+            .chain_update(key)
+            .finalize();
+        // the comparison time must not tell how much of the hash matched
+        hash.as_slice().ct_eq(&self.hash).into()
+    }
+}
+
+pub(super) struct ApiKeys {
+    header_name: HeaderName,
+    query_parameter: Option<String>,
+    /// Keys by identifier, so that a single hash is checked for each request
+    keys: Arc<RwLock<HashMap<String, StoredKey>>>,
+}
+
+impl ApiKeys {
+    pub(super) async fn new(conf: ApiKeyConf) -> Result<Self, BoxError> {
+        let header_name = HeaderName::try_from(conf.header_name.as_str())?;
+        let keys = Arc::new(RwLock::new(read_keys(&conf.keys_file).await?));
+        tracing::info!(
+            "API key authentication using {} keys from {}",
+            keys.read().len(),
+            conf.keys_file.display()
+        );
+
+        // the keys are read again when the file changes, until the plugin is dropped
+        let weak = Arc::downgrade(&keys);
+        let path = conf.keys_file;
+        let mut changes = crate::files::watch(&path).boxed();
+        tokio::task::spawn(async move {
+            while changes.next().await.is_some() {
+                let Some(keys) = weak.upgrade() else {
+                    break;
+                };
+                match read_keys(&path).await {
+                    Ok(updated) => {
+                        tracing::info!(
+                            "reloaded {} API keys from {}",
+                            updated.len(),
+                            path.display()
+                        );
+                        *keys.write() = updated;
+                    }
+                    // the previous keys are kept
+                    Err(e) => tracing::error!(
+                        "could not reload the API keys from {}: {e}",
+                        path.display()
+                    ),
+                }
+            }
+        });
+
+        Ok(Self {
+            header_name,
+            query_parameter: conf.query_parameter,
+            keys,
+        })
+    }
+
+    pub(super) fn authenticate(
+        &self,
+        request: router::Request,
+    ) -> ControlFlow<router::Response, router::Request> {
+        let key = match self.extract_key(&request) {
+            None => return ControlFlow::Continue(request),
+            Some(Err(error)) => {
+                return reject(
+                    AUTHENTICATION_KIND,
+                    request.context,
+                    error,
+                    StatusCode::BAD_REQUEST,
+                )
+            }
+            Some(Ok(key)) => key,
+        };
+
+        let metadata = key.split_once('.').and_then(|(id, _)| {
+            self.keys
+                .read()
+                .get(id)
+                .filter(|stored| stored.matches(&key))
+                .map(|stored| stored.metadata.clone())
+        });
+        let Some(metadata) = metadata else {
+            return reject(
+                AUTHENTICATION_KIND,
+                request.context,
+                AuthenticationError::InvalidApiKey,
+                StatusCode::UNAUTHORIZED,
+            );
+        };
+
+        // the client is the principal, so that `@authenticated` and `@requiresScopes` handle it
+        // like a JWT, and the rate limits keyed by `jwt_claim` apply to it
+        let mut claims = json!({
+            "sub": metadata.client_name,
+            "scope": metadata.scopes.join(" "),
+        });
+        if let Some(tier) = &metadata.rate_limit_tier {
+            claims["rate_limit_tier"] = json!(tier);
+        }
+        let mut scopes = metadata.scopes.clone();
+        scopes.sort();
+        scopes.dedup();
+        if let Err(e) = request
+            .context
+            .insert(APOLLO_AUTHENTICATION_JWT_CLAIMS, claims)
+            .and_then(|_| request.context.insert(GRANTED_SCOPES_KEY, scopes))
+            .and_then(|_| {
+                request
+                    .context
+                    .insert(APOLLO_AUTHENTICATION_API_KEY, metadata)
+            })
+        {
+            return reject(
+                AUTHENTICATION_KIND,
+                request.context,
+                AuthenticationError::CannotInsertClaimsIntoContext(e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            );
+        }
+        // This is a metric and will not appear in the logs
+        tracing::info!(
+            monotonic_counter.apollo_authentication_success_count = 1u64,
+            kind = %AUTHENTICATION_KIND
+        );
+        ControlFlow::Continue(request)
+    }
+
+    /// Finds the key in the configured header, or else in the query parameter
+    fn extract_key(
+        &self,
+        request: &router::Request,
+    ) -> Option<Result<String, AuthenticationError<'static>>> {
+        if let Some(value) = request.router_request.headers().get(&self.header_name) {
+            return Some(
+                value
+                    .to_str()
+                    .map(|key| key.trim().to_string())
+                    .map_err(|_| AuthenticationError::CannotConvertToString),
+            );
+        }
+
+        let name = self.query_parameter.as_deref()?;
+        let query = request.router_request.uri().query()?;
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(parameter, _)| parameter == name)
+            .map(|(_, key)| Ok(key.into_owned()))
+    }
+}
+
+async fn read_keys(path: &Path) -> Result<HashMap<String, StoredKey>, BoxError> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| format!("could not read the API keys file {}: {e}", path.display()))?;
+    if content.trim().is_empty() {
+        return Ok(HashMap::new());
+    }
+    let entries: Vec<KeyEntry> = serde_yaml::from_str(&content)?;
+    let mut keys = HashMap::with_capacity(entries.len());
+    for entry in entries {
+        if entry.id.is_empty() || entry.id.contains('.') {
+            return Err(format!(
+                "invalid identifier '{}' for the API key of client '{}', it must be non empty and must not contain '.'",
+                entry.id, entry.client_name
+            )
+            .into());
+        }
+        if keys.contains_key(&entry.id) {
+            return Err(format!("duplicate API key identifier '{}'", entry.id).into());
+        }
+        let hash = hex::decode(&entry.hash)
+            .ok()
+            .filter(|hash| hash.len() == 32)
+            .ok_or_else(|| {
+                format!(
+                    "invalid hash for the API key of client '{}', expected a hex encoded SHA-256 hash",
+                    entry.client_name
+                )
+            })?;
+        keys.insert(
+            entry.id,
+            StoredKey {
+                salt: entry.salt,
+                hash,
+                metadata: ApiKeyMetadata {
+                    client_name: entry.client_name,
+                    scopes: entry.scopes,
+                    rate_limit_tier: entry.rate_limit_tier,
+                },
+            },
+        );
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::Value;
+
+    use super::*;
+
+    fn entry(client_name: &str, salt: &str, key: &str) -> String {
+        let hash = hex::encode(
+            Sha256::new()
+                .chain_update(salt)
+                .chain_update(key)
+                .finalize(),
+        );
+        let (id, _) = key.split_once('.').unwrap();
+        format!("- id: {id}\n  client_name: {client_name}\n  salt: {salt}\n  hash: {hash}\n  scopes: [read:products]\n  rate_limit_tier: gold\n")
+    }
+
+    fn request(header: Option<&str>, uri: &str) -> router::Request {
+        let mut builder = http::Request::builder().uri(uri);
+        if let Some(key) = header {
+            builder = builder.header("x-api-key", key);
+        }
+        builder.body(router::Body::empty()).unwrap().into()
+    }
+
+    #[tokio::test]
+    async fn keys_are_checked_against_the_reloaded_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.yaml");
+        std::fs::write(&path, entry("billing", "s1", "k1.secret1")).unwrap();
+
+        let api_keys = ApiKeys::new(ApiKeyConf {
+            keys_file: path.clone(),
+            header_name: default_api_key_header_name(),
+            query_parameter: Some("api_key".to_string()),
+        })
+        .await
+        .unwrap();
+
+        match api_keys.authenticate(request(Some("k1.secret1"), "http://localhost/")) {
+            ControlFlow::Continue(request) => {
+                let claims: Value = request
+                    .context
+                    .get(APOLLO_AUTHENTICATION_JWT_CLAIMS)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(claims["sub"], "billing");
+                assert_eq!(claims["rate_limit_tier"], "gold");
+                let metadata: Value = request
+                    .context
+                    .get(APOLLO_AUTHENTICATION_API_KEY)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(metadata["rate_limit_tier"], "gold");
+                let scopes: Vec<String> = request.context.get(GRANTED_SCOPES_KEY).unwrap().unwrap();
+                assert_eq!(scopes, vec!["read:products".to_string()]);
+            }
+            ControlFlow::Break(_) => panic!("the key should be accepted"),
+        }
+        assert!(matches!(
+            api_keys.authenticate(request(None, "http://localhost/?api_key=k1.secret1")),
+            ControlFlow::Continue(_)
+        ));
+        assert!(matches!(
+            api_keys.authenticate(request(None, "http://localhost/")),
+            ControlFlow::Continue(_)
+        ));
+        for key in ["k2.secret2", "k1.secret2", "secret1"] {
+            match api_keys.authenticate(request(Some(key), "http://localhost/")) {
+                ControlFlow::Break(response) => {
+                    assert_eq!(response.response.status(), StatusCode::UNAUTHORIZED)
+                }
+                ControlFlow::Continue(_) => panic!("the key should be rejected"),
+            }
+        }
+
+        std::fs::write(&path, entry("shipping", "s2", "k2.secret2")).unwrap();
+        for _ in 0..50 {
+            if matches!(
+                api_keys.authenticate(request(Some("k2.secret2"), "http://localhost/")),
+                ControlFlow::Continue(_)
+            ) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(matches!(
+            api_keys.authenticate(request(Some("k2.secret2"), "http://localhost/")),
+            ControlFlow::Continue(_)
+        ));
+        assert!(matches!(
+            api_keys.authenticate(request(Some("k1.secret1"), "http://localhost/")),
+            ControlFlow::Break(_)
+        ));
+    }
+}
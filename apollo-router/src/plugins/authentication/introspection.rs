@@ -0,0 +1,277 @@
+//! Authentication of opaque tokens with an OAuth2 token introspection endpoint (RFC 7662)
+
+use std::ops::ControlFlow;
+use std::str::FromStr;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use http::header::ACCEPT;
+use http::StatusCode;
+use mime::APPLICATION_JSON;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Digest;
+use sha2::Sha256;
+use tower::BoxError;
+use url::Url;
+
+use super::default_header_name;
+use super::default_header_value_prefix;
+use super::extract_jwt;
+use super::reject;
+use super::token_sources;
+use super::AuthenticationError;
+use super::Header;
+use super::Source;
+use super::APOLLO_AUTHENTICATION_JWT_CLAIMS;
+use super::CLIENT;
+use super::DEFAULT_AUTHENTICATION_NETWORK_TIMEOUT;
+use crate::cache::storage::CacheStorage;
+use crate::configuration::Cache;
+use crate::services::router;
+
+const AUTHENTICATION_KIND: &str = "introspection";
+
+/// Validate opaque tokens with an OAuth2 token introspection endpoint (RFC 7662)
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(super) struct IntrospectionConf {
+    /// URL of the introspection endpoint
+    url: String,
+    /// Client identifier, sent to the introspection endpoint with HTTP Basic authentication
+    client_id: Option<String>,
+    /// Client secret, sent with the client identifier
+    client_secret: Option<String>,
+    /// Value of the `token_type_hint` parameter, like `access_token`
+    token_type_hint: Option<String>,
+    /// List of headers to add to the introspection request
+    #[serde(default)]
+    headers: Vec<Header>,
+    /// Timeout of the introspection request in human-readable format; defaults to 15s
+    #[serde(
+        deserialize_with = "humantime_serde::deserialize",
+        default = "default_timeout"
+    )]
+    #[schemars(with = "String", default = "default_timeout")]
+    timeout: Duration,
+    /// HTTP header expected to contain the token
+    #[serde(default = "default_header_name")]
+    header_name: String,
+    /// Header value prefix
+    #[serde(default = "default_header_value_prefix")]
+    header_value_prefix: String,
+    /// Alternative sources to extract the token
+    #[serde(default)]
+    sources: Vec<Source>,
+    /// Cache of the active tokens, until they expire. Tokens without an `exp` are not cached
+    #[serde(default)]
+    cache: Cache,
+}
+
+fn default_timeout() -> Duration {
+    DEFAULT_AUTHENTICATION_NETWORK_TIMEOUT
+}
+
+pub(super) struct Introspector {
+    url: Url,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    token_type_hint: Option<String>,
+    headers: Vec<Header>,
+    timeout: Duration,
+    sources: Vec<Source>,
+    cache: CacheStorage<String, Value>,
+}
+
+impl Introspector {
+    pub(super) async fn new(conf: IntrospectionConf) -> Result<Self, BoxError> {
+        let sources = token_sources(&conf.header_name, &conf.header_value_prefix, conf.sources)?;
+        let url = Url::from_str(&conf.url)?;
+        let cache = CacheStorage::new(
+            conf.cache.in_memory.limit,
+            conf.cache.redis,
+            "token introspection",
+        )
+        .await?;
+
+        tracing::info!(%url, "opaque token authentication using introspection endpoint");
+
+        Ok(Self {
+            url,
+            client_id: conf.client_id,
+            client_secret: conf.client_secret,
+            token_type_hint: conf.token_type_hint,
+            headers: conf.headers,
+            timeout: conf.timeout,
+            sources,
+            cache,
+        })
+    }
+
+    pub(super) async fn authenticate(
+        &self,
+        request: router::Request,
+    ) -> ControlFlow<router::Response, router::Request> {
+        let mut token = None;
+        for source in &self.sources {
+            match extract_jwt(source, request.router_request.headers()) {
+                None => continue,
+                Some(Err(error)) => {
+                    return reject(
+                        AUTHENTICATION_KIND,
+                        request.context,
+                        error,
+                        StatusCode::BAD_REQUEST,
+                    )
+                }
+                Some(Ok(extracted)) => {
+                    token = Some(extracted.to_string());
+                    break;
+                }
+            }
+        }
+
+        let token = match token {
+            Some(token) => token,
+            None => return ControlFlow::Continue(request),
+        };
+
+        let claims = match self.introspect(&token).await {
+            Ok(claims) => claims,
+            Err((error, status)) => {
+                return reject(AUTHENTICATION_KIND, request.context, error, status)
+            }
+        };
+
+        if let Err(e) = request
+            .context
+            .insert(APOLLO_AUTHENTICATION_JWT_CLAIMS, claims)
+        {
+            return reject(
+                AUTHENTICATION_KIND,
+                request.context,
+                AuthenticationError::CannotInsertClaimsIntoContext(e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            );
+        }
+        // This is a metric and will not appear in the logs
+        tracing::info!(
+            monotonic_counter.apollo_authentication_success_count = 1u64,
+            kind = %AUTHENTICATION_KIND
+        );
+        ControlFlow::Continue(request)
+    }
+
+    /// Returns the introspection response of an active token, from the cache if possible
+    async fn introspect(
+        &self,
+        token: &str,
+    ) -> Result<Value, (AuthenticationError<'static>, StatusCode)> {
+        // tokens are credentials: only their hash is used as cache key
+        let key = format!("token_introspection:{}", hex::encode(Sha256::digest(token)));
+        if let Some(claims) = self.cache.get(&key).await {
+            // the in memory cache does not expire entries by itself
+            if remaining_lifetime(&claims).is_some() {
+                return Ok(claims);
+            }
+        }
+
+        let claims = self.request(token).await.map_err(|e| {
+            tracing::error!(url = %self.url, "could not introspect token: {e}");
+            (
+                AuthenticationError::IntrospectionFailed,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })?;
+
+        if claims.get("active") != Some(&Value::Bool(true)) {
+            return Err((AuthenticationError::InactiveToken, StatusCode::UNAUTHORIZED));
+        }
+
+        if claims.get("exp").is_some() {
+            match remaining_lifetime(&claims) {
+                Some(ttl) => {
+                    self.cache
+                        .insert_with_ttl(key, claims.clone(), Some(ttl))
+                        .await
+                }
+                None => return Err((AuthenticationError::ExpiredToken, StatusCode::UNAUTHORIZED)),
+            }
+        }
+
+        Ok(claims)
+    }
+
+    async fn request(&self, token: &str) -> Result<Value, BoxError> {
+        let client = CLIENT.as_ref().map_err(|e| e.to_string())?;
+
+        let mut form = vec![("token", token)];
+        if let Some(token_type_hint) = &self.token_type_hint {
+            form.push(("token_type_hint", token_type_hint.as_str()));
+        }
+
+        let mut builder = client
+            .post(self.url.clone())
+            .header(ACCEPT, APPLICATION_JSON.essence_str())
+            .form(&form)
+            .timeout(self.timeout);
+        for header in &self.headers {
+            builder = builder.header(header.name.clone(), header.value.clone());
+        }
+        if let Some(client_id) = &self.client_id {
+            builder = builder.basic_auth(client_id, self.client_secret.as_ref());
+        }
+
+        let response = builder.send().await?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "the introspection endpoint replied with status {}",
+                response.status()
+            )
+            .into());
+        }
+        let claims: Value = response.json().await?;
+        if !claims.is_object() {
+            return Err("the introspection response is not a JSON object".into());
+        }
+        Ok(claims)
+    }
+}
+
+/// Time until the `exp` claim, which may have a fractional part. Returns `None` if there is no
+/// such claim or the token has expired
+fn remaining_lifetime(claims: &Value) -> Option<Duration> {
+    let exp = claims.get("exp")?.as_f64()?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("we should not run before EPOCH")
+        .as_secs_f64();
+    Some(exp - now)
+        .filter(|remaining| *remaining > 0.0)
+        .and_then(|remaining| Duration::try_from_secs_f64(remaining).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn lifetime_of_tokens() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let remaining = remaining_lifetime(&json!({ "active": true, "exp": now + 60 })).unwrap();
+        assert!(remaining > Duration::from_secs(55) && remaining <= Duration::from_secs(60));
+        assert!(remaining_lifetime(&json!({ "active": true, "exp": now - 1 })).is_none());
+        assert!(remaining_lifetime(&json!({ "active": true })).is_none());
+        let remaining =
+            remaining_lifetime(&json!({ "active": true, "exp": now as f64 + 60.5 })).unwrap();
+        assert!(remaining > Duration::from_secs(55) && remaining <= Duration::from_millis(60_500));
+        assert!(remaining_lifetime(&json!({ "active": true, "exp": now as f64 - 0.5 })).is_none());
+    }
+}
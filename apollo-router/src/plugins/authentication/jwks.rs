@@ -24,6 +24,7 @@ use tower::BoxError;
 use tracing_futures::Instrument;
 use url::Url;
 
+use super::validation::TokenValidation;
 use super::Header;
 use super::CLIENT;
 use super::DEFAULT_AUTHENTICATION_NETWORK_TIMEOUT;
@@ -42,6 +43,7 @@ pub(super) struct JwksConfig {
     pub(super) algorithms: Option<HashSet<Algorithm>>,
     pub(super) poll_interval: Duration,
     pub(super) headers: Vec<Header>,
+    pub(super) validation: Arc<TokenValidation>,
 }
 
 #[derive(Clone)]
@@ -49,6 +51,7 @@ pub(super) struct JwkSetInfo {
     pub(super) jwks: JwkSet,
     pub(super) issuer: Option<String>,
     pub(super) algorithms: Option<HashSet<Algorithm>>,
+    pub(super) validation: Arc<TokenValidation>,
 }
 
 impl JwksManager {
@@ -256,6 +259,7 @@ impl<'a> Iterator for Iter<'a> {
                                 jwks: jwks.clone(),
                                 issuer: config.issuer.clone(),
                                 algorithms: config.algorithms.clone(),
+                                validation: config.validation.clone(),
                             });
                         }
                     } else {
@@ -3,11 +3,13 @@
 use std::collections::HashMap;
 use std::ops::ControlFlow;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
 use displaydoc::Display;
+use futures::FutureExt;
 use http::HeaderMap;
 use http::HeaderName;
 use http::HeaderValue;
@@ -36,10 +38,17 @@ use tower::ServiceBuilder;
 use tower::ServiceExt;
 use url::Url;
 
+use self::api_key::ApiKeyConf;
+use self::api_key::ApiKeys;
+use self::introspection::IntrospectionConf;
+use self::introspection::Introspector;
 use self::jwks::JwksManager;
 use self::subgraph::SigningParams;
 use self::subgraph::SigningParamsConfig;
 use self::subgraph::SubgraphAuth;
+use self::validation::decode_error;
+use self::validation::ClaimMatcherConf;
+use self::validation::TokenValidation;
 use crate::graphql;
 use crate::layers::ServiceBuilderExt;
 use crate::plugin::serde::deserialize_header_name;
@@ -49,17 +58,22 @@ use crate::plugin::PluginInit;
 use crate::plugins::authentication::jwks::JwkSetInfo;
 use crate::plugins::authentication::jwks::JwksConfig;
 use crate::register_plugin;
+use crate::services::http::proxy::ClientBuilderExt;
 use crate::services::router;
 use crate::Context;
 
+mod api_key;
+mod introspection;
 mod jwks;
 pub(crate) mod subgraph;
+pub(crate) mod validation;
 
 #[cfg(test)]
 mod tests;
 
 pub(crate) const AUTHENTICATION_SPAN_NAME: &str = "authentication_plugin";
 pub(crate) const APOLLO_AUTHENTICATION_JWT_CLAIMS: &str = "apollo_authentication::JWT::claims";
+pub(crate) const APOLLO_AUTHENTICATION_API_KEY: &str = "apollo_authentication::api_key";
 const HEADER_TOKEN_TRUNCATED: &str = "(truncated)";
 
 #[derive(Debug, Display, Error)]
@@ -99,17 +113,81 @@ pub(crate) enum AuthenticationError<'a> {
 
     /// Unsupported key algorithm: {0}
     UnsupportedKeyAlgorithm(KeyAlgorithm),
+
+    /// Token has expired
+    ExpiredToken,
+
+    /// Token is not valid yet
+    TokenNotYetValid,
+
+    /// Invalid audience: the token's `aud` does not contain any of the expected audiences
+    InvalidAudience,
+
+    /// Missing required claim: '{0}'
+    MissingClaim(String),
+
+    /// Invalid value for claim: '{0}'
+    InvalidClaim(String),
+
+    /// Invalid token type: '{0}'
+    InvalidTokenType(String),
+
+    /// Missing token type
+    MissingTokenType,
+
+    /// Token is not active
+    InactiveToken,
+
+    /// Cannot introspect token
+    IntrospectionFailed,
+
+    /// Invalid API key
+    InvalidApiKey,
+}
+
+impl AuthenticationError<'_> {
+    /// Reason of the failure, used as a metric attribute
+    fn reason(&self) -> &'static str {
+        match self {
+            AuthenticationError::CannotConvertToString
+            | AuthenticationError::InvalidPrefix(..)
+            | AuthenticationError::MissingJWT(_) => "invalid_header",
+            AuthenticationError::InvalidHeader(..) => "invalid_jwt_header",
+            AuthenticationError::CannotCreateDecodingKey(_)
+            | AuthenticationError::JWKHasNoAlgorithm
+            | AuthenticationError::UnsupportedKeyAlgorithm(_) => "invalid_key",
+            AuthenticationError::CannotDecodeJWT(_) => "invalid_token",
+            AuthenticationError::CannotInsertClaimsIntoContext(_) => "internal_error",
+            AuthenticationError::CannotFindKID(_)
+            | AuthenticationError::CannotFindSuitableKey(..) => "key_not_found",
+            AuthenticationError::InvalidIssuer { .. } => "invalid_issuer",
+            AuthenticationError::ExpiredToken => "expired",
+            AuthenticationError::TokenNotYetValid => "not_yet_valid",
+            AuthenticationError::InvalidAudience => "invalid_audience",
+            AuthenticationError::MissingClaim(_) => "missing_claim",
+            AuthenticationError::InvalidClaim(_) => "invalid_claim",
+            AuthenticationError::InvalidTokenType(_) | AuthenticationError::MissingTokenType => {
+                "invalid_type"
+            }
+            AuthenticationError::InactiveToken => "inactive",
+            AuthenticationError::IntrospectionFailed => "introspection_error",
+            AuthenticationError::InvalidApiKey => "invalid_api_key",
+        }
+    }
 }
 
 const DEFAULT_AUTHENTICATION_NETWORK_TIMEOUT: Duration = Duration::from_secs(15);
 const DEFAULT_AUTHENTICATION_DOWNLOAD_INTERVAL: Duration = Duration::from_secs(60);
 
-static CLIENT: Lazy<Result<Client, BoxError>> = Lazy::new(|| Ok(Client::new()));
+static CLIENT: Lazy<Result<Client, BoxError>> =
+    Lazy::new(|| Ok(Client::builder().outbound_proxy().build()?));
 
 #[derive(Error, Debug)]
 pub(crate) enum Error {
     #[error("header_value_prefix must not contain whitespace")]
     BadHeaderValuePrefix,
+    #[error("jwt and introspection cannot both be configured for router authentication")]
+    ConflictingMechanisms,
 }
 
 struct Router {
@@ -119,6 +197,8 @@ struct Router {
 
 struct AuthenticationPlugin {
     router: Option<Router>,
+    introspection: Option<Arc<Introspector>>,
+    api_keys: Option<Arc<ApiKeys>>,
     subgraph: Option<SubgraphAuth>,
 }
 
@@ -160,6 +240,17 @@ struct JwksConf {
     /// List of headers to add to the JWKS request
     #[serde(default)]
     headers: Vec<Header>,
+    /// Accepted audiences. If set, the `aud` claim of tokens verified by that JWKS must contain one of them
+    audiences: Option<Vec<String>>,
+    /// Claims that must be present in tokens verified by that JWKS, with their expected value. Nested claims can be designated with a dot separated path
+    #[serde(default)]
+    required_claims: HashMap<String, ClaimMatcherConf>,
+    /// Accepted values of the `typ` header parameter, like `JWT` or `at+jwt`. If set, tokens without `typ` are rejected
+    allowed_types: Option<Vec<String>>,
+    /// Clock skew tolerated when checking the `exp` and `nbf` claims, in human-readable format; defaults to 60s
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    leeway: Option<Duration>,
 }
 
 #[derive(Clone, Debug, JsonSchema, Deserialize)]
@@ -204,14 +295,16 @@ struct Conf {
     subgraph: Option<subgraph::Config>,
 }
 
-// We may support additional authentication mechanisms in future, so all
-// configuration (which is currently JWT specific) is isolated to the
-// JWTConf structure.
+// Each authentication mechanism has its own configuration structure.
 #[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 struct RouterConf {
     /// The JWT configuration
-    jwt: JWTConf,
+    jwt: Option<JWTConf>,
+    /// The opaque token configuration, validated by an OAuth2 introspection endpoint
+    introspection: Option<IntrospectionConf>,
+    /// The API key configuration. Requests with an API key are not checked for a token
+    api_key: Option<ApiKeyConf>,
 }
 
 fn default_header_name() -> String {
@@ -226,6 +319,38 @@ fn default_poll_interval() -> Duration {
     DEFAULT_AUTHENTICATION_DOWNLOAD_INTERVAL
 }
 
+/// Checks the prefixes of the token sources, and adds the configured header as the first source
+fn token_sources(
+    header_name: &str,
+    header_value_prefix: &str,
+    mut sources: Vec<Source>,
+) -> Result<Vec<Source>, BoxError> {
+    if header_value_prefix
+        .as_bytes()
+        .iter()
+        .any(u8::is_ascii_whitespace)
+    {
+        return Err(Error::BadHeaderValuePrefix.into());
+    }
+
+    for source in &sources {
+        if let Source::Header { value_prefix, .. } = source {
+            if value_prefix.as_bytes().iter().any(u8::is_ascii_whitespace) {
+                return Err(Error::BadHeaderValuePrefix.into());
+            }
+        }
+    }
+
+    sources.insert(
+        0,
+        Source::Header {
+            name: header_name.to_string(),
+            value_prefix: header_value_prefix.to_string(),
+        },
+    );
+    Ok(sources)
+}
+
 #[derive(Debug, Default)]
 struct JWTCriteria {
     alg: Algorithm,
@@ -240,7 +365,7 @@ struct JWTCriteria {
 fn search_jwks(
     jwks_manager: &JwksManager,
     criteria: &JWTCriteria,
-) -> Option<Vec<(Option<String>, Jwk)>> {
+) -> Option<Vec<(Option<String>, Arc<TokenValidation>, Jwk)>> {
     const HIGHEST_SCORE: usize = 2;
     let mut candidates = vec![];
     let mut found_highest_score = false;
@@ -248,6 +373,7 @@ fn search_jwks(
         jwks,
         issuer,
         algorithms,
+        validation,
     } in jwks_manager.iter_jwks()
     {
         // filter accepted algorithms
@@ -355,7 +481,7 @@ fn search_jwks(
                 found_highest_score = true;
             }
 
-            candidates.push((key_score, (issuer.clone(), key)));
+            candidates.push((key_score, (issuer.clone(), validation.clone(), key)));
         }
     }
 
@@ -363,7 +489,7 @@ fn search_jwks(
         "jwk candidates: {:?}",
         candidates
             .iter()
-            .map(|(score, (_, candidate))| (
+            .map(|(score, (_, _, candidate))| (
                 score,
                 &candidate.common.key_id,
                 candidate.common.key_algorithm
@@ -430,35 +556,20 @@ impl Plugin for AuthenticationPlugin {
             None
         };
 
-        let router = if let Some(mut router_conf) = init.config.router {
-            if router_conf
-                .jwt
-                .header_value_prefix
-                .as_bytes()
-                .iter()
-                .any(u8::is_ascii_whitespace)
-            {
-                return Err(Error::BadHeaderValuePrefix.into());
-            }
+        let router_conf = init.config.router.unwrap_or_default();
+        if router_conf.jwt.is_some() && router_conf.introspection.is_some() {
+            return Err(Error::ConflictingMechanisms.into());
+        }
 
-            for source in &router_conf.jwt.sources {
-                if let Source::Header { value_prefix, .. } = source {
-                    if value_prefix.as_bytes().iter().any(u8::is_ascii_whitespace) {
-                        return Err(Error::BadHeaderValuePrefix.into());
-                    }
-                }
-            }
-
-            router_conf.jwt.sources.insert(
-                0,
-                Source::Header {
-                    name: router_conf.jwt.header_name.clone(),
-                    value_prefix: router_conf.jwt.header_value_prefix.clone(),
-                },
-            );
+        let router = if let Some(mut jwt_conf) = router_conf.jwt {
+            jwt_conf.sources = token_sources(
+                &jwt_conf.header_name,
+                &jwt_conf.header_value_prefix,
+                std::mem::take(&mut jwt_conf.sources),
+            )?;
 
             let mut list = vec![];
-            for jwks_conf in &router_conf.jwt.jwks {
+            for jwks_conf in &jwt_conf.jwks {
                 let url: Url = Url::from_str(jwks_conf.url.as_str())?;
                 list.push(JwksConfig {
                     url,
@@ -469,49 +580,108 @@ impl Plugin for AuthenticationPlugin {
                         .map(|algs| algs.iter().cloned().collect()),
                     poll_interval: jwks_conf.poll_interval,
                     headers: jwks_conf.headers.clone(),
+                    validation: Arc::new(TokenValidation::new(
+                        jwks_conf.audiences.clone(),
+                        &jwks_conf.required_claims,
+                        jwks_conf.allowed_types.clone(),
+                        jwks_conf.leeway,
+                    )?),
                 });
             }
 
-            tracing::info!(jwks=?router_conf.jwt.jwks, "JWT authentication using JWKSets from");
+            tracing::info!(jwks=?jwt_conf.jwks, "JWT authentication using JWKSets from");
 
             let jwks_manager = JwksManager::new(list).await?;
 
             Some(Router {
-                configuration: router_conf.jwt,
+                configuration: jwt_conf,
                 jwks_manager,
             })
         } else {
             None
         };
 
-        Ok(Self { router, subgraph })
+        let introspection = match router_conf.introspection {
+            Some(conf) => Some(Arc::new(Introspector::new(conf).await?)),
+            None => None,
+        };
+
+        let api_keys = match router_conf.api_key {
+            Some(conf) => Some(Arc::new(ApiKeys::new(conf).await?)),
+            None => None,
+        };
+
+        Ok(Self {
+            router,
+            introspection,
+            api_keys,
+            subgraph,
+        })
     }
 
     fn router_service(&self, service: router::BoxService) -> router::BoxService {
-        if let Some(config) = &self.router {
+        fn authentication_service_span() -> impl Fn(&router::Request) -> tracing::Span + Clone {
+            move |_request: &router::Request| {
+                tracing::info_span!(
+                    AUTHENTICATION_SPAN_NAME,
+                    "authentication service" = stringify!(router::Request),
+                    "otel.kind" = "INTERNAL"
+                )
+            }
+        }
+
+        if self.router.is_none() && self.introspection.is_none() && self.api_keys.is_none() {
+            return service;
+        }
+
+        // requests authenticated with an API key are not checked for a token
+        let service = if let Some(introspector) = &self.introspection {
+            let introspector = introspector.clone();
+            ServiceBuilder::new()
+                .oneshot_checkpoint_async(move |request: router::Request| {
+                    let introspector = introspector.clone();
+                    async move {
+                        if request.context.contains_key(APOLLO_AUTHENTICATION_API_KEY) {
+                            return Ok(ControlFlow::Continue(request));
+                        }
+                        Ok(introspector.authenticate(request).await)
+                    }
+                    .boxed()
+                })
+                .service(service)
+                .boxed()
+        } else if let Some(config) = &self.router {
             let jwks_manager = config.jwks_manager.clone();
             let configuration = config.configuration.clone();
 
-            fn authentication_service_span() -> impl Fn(&router::Request) -> tracing::Span + Clone {
-                move |_request: &router::Request| {
-                    tracing::info_span!(
-                        AUTHENTICATION_SPAN_NAME,
-                        "authentication service" = stringify!(router::Request),
-                        "otel.kind" = "INTERNAL"
-                    )
-                }
-            }
-
             ServiceBuilder::new()
-                .instrument(authentication_service_span())
                 .checkpoint(move |request: router::Request| {
+                    if request.context.contains_key(APOLLO_AUTHENTICATION_API_KEY) {
+                        return Ok(ControlFlow::Continue(request));
+                    }
                     Ok(authenticate(&configuration, &jwks_manager, request))
                 })
                 .service(service)
                 .boxed()
         } else {
             service
-        }
+        };
+
+        let service = match &self.api_keys {
+            Some(api_keys) => {
+                let api_keys = api_keys.clone();
+                ServiceBuilder::new()
+                    .checkpoint(move |request: router::Request| Ok(api_keys.authenticate(request)))
+                    .service(service)
+                    .boxed()
+            }
+            None => service,
+        };
+
+        ServiceBuilder::new()
+            .instrument(authentication_service_span())
+            .service(service)
+            .boxed()
     }
 
     fn subgraph_service(
@@ -542,10 +712,6 @@ fn authenticate(
         status: StatusCode,
     ) -> ControlFlow<router::Response, router::Request> {
         // This is a metric and will not appear in the logs
-        tracing::info!(
-            monotonic_counter.apollo_authentication_failure_count = 1u64,
-            kind = %AUTHENTICATION_KIND
-        );
         tracing::info!(
             monotonic_counter
                 .apollo
@@ -553,20 +719,10 @@ fn authenticate(
                 .operations
                 .authentication
                 .jwt = 1,
-            authentication.jwt.failed = true
+            authentication.jwt.failed = true,
+            authentication.jwt.failure_reason = error.reason()
         );
-        tracing::info!(message = %error, "jwt authentication failure");
-        let response = router::Response::infallible_builder()
-            .error(
-                graphql::Error::builder()
-                    .message(error.to_string())
-                    .extension_code("AUTH_ERROR")
-                    .build(),
-            )
-            .status_code(status)
-            .context(context)
-            .build();
-        ControlFlow::Break(response)
+        reject(AUTHENTICATION_KIND, context, error, status)
     }
 
     let mut jwt = None;
@@ -603,6 +759,7 @@ fn authenticate(
     };
 
     // Extract our search criteria from our jwt
+    let typ = jwt_header.typ;
     let criteria = JWTCriteria {
         kid: jwt_header.kid,
         alg: jwt_header.alg,
@@ -612,7 +769,7 @@ fn authenticate(
     // Note: This will search through JWKS in the order in which they are defined
     // in configuration.
     if let Some(keys) = search_jwks(jwks_manager, &criteria) {
-        let (issuer, token_data) = match decode_jwt(jwt, keys, criteria) {
+        let (issuer, validation, token_data) = match decode_jwt(jwt, keys, criteria) {
             Ok(data) => data,
             Err((auth_error, status_code)) => {
                 return failure_message(request.context, auth_error, status_code);
@@ -639,6 +796,13 @@ fn authenticate(
             }
         }
 
+        if let Err(error) = validation
+            .check_type(typ.as_deref())
+            .and_then(|()| validation.check_claims(&token_data.claims))
+        {
+            return failure_message(request.context, error, StatusCode::UNAUTHORIZED);
+        }
+
         if let Err(e) = request
             .context
             .insert(APOLLO_AUTHENTICATION_JWT_CLAIMS, token_data.claims)
@@ -674,6 +838,33 @@ fn authenticate(
     }
 }
 
+/// Rejects a request, counting the failure with the authentication mechanism (`kind`) that rejected it
+fn reject(
+    kind: &'static str,
+    context: Context,
+    error: AuthenticationError,
+    status: StatusCode,
+) -> ControlFlow<router::Response, router::Request> {
+    // This is a metric and will not appear in the logs
+    tracing::info!(
+        monotonic_counter.apollo_authentication_failure_count = 1u64,
+        kind = %kind,
+        reason = error.reason()
+    );
+    tracing::info!(message = %error, "{kind} authentication failure");
+    let response = router::Response::infallible_builder()
+        .error(
+            graphql::Error::builder()
+                .message(error.to_string())
+                .extension_code("AUTH_ERROR")
+                .build(),
+        )
+        .status_code(status)
+        .context(context)
+        .build();
+    ControlFlow::Break(response)
+}
+
 fn extract_jwt<'a, 'b: 'a>(
     source: &'a Source,
     headers: &'b HeaderMap,
@@ -764,11 +955,18 @@ fn extract_jwt<'a, 'b: 'a>(
 
 fn decode_jwt(
     jwt: &str,
-    keys: Vec<(Option<String>, Jwk)>,
+    keys: Vec<(Option<String>, Arc<TokenValidation>, Jwk)>,
     criteria: JWTCriteria,
-) -> Result<(Option<String>, TokenData<serde_json::Value>), (AuthenticationError, StatusCode)> {
+) -> Result<
+    (
+        Option<String>,
+        Arc<TokenValidation>,
+        TokenData<serde_json::Value>,
+    ),
+    (AuthenticationError, StatusCode),
+> {
     let mut error = None;
-    for (issuer, jwk) in keys.into_iter() {
+    for (issuer, token_validation, jwk) in keys.into_iter() {
         let decoding_key = match DecodingKey::from_jwk(&jwk) {
             Ok(k) => k,
             Err(e) => {
@@ -805,16 +1003,14 @@ fn decode_jwt(
         let mut validation = Validation::new(algorithm);
         validation.validate_nbf = true;
         // if set to true, it will reject tokens containing an `aud` claim if the validation does not specify an audience
-        // we don't validate audience yet, so this is deactivated
+        // so it is only activated when audiences are configured
         validation.validate_aud = false;
+        token_validation.configure(&mut validation);
 
         match decode::<serde_json::Value>(jwt, &decoding_key, &validation) {
-            Ok(v) => return Ok((issuer, v)),
+            Ok(v) => return Ok((issuer, token_validation, v)),
             Err(e) => {
-                error = Some((
-                    AuthenticationError::CannotDecodeJWT(e),
-                    StatusCode::UNAUTHORIZED,
-                ));
+                error = Some((decode_error(e), StatusCode::UNAUTHORIZED));
             }
         };
     }
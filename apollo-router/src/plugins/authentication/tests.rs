@@ -26,9 +26,14 @@ use p256::ecdsa::SigningKey;
 use p256::pkcs8::EncodePrivateKey;
 use rand_core::OsRng;
 use serde::Serialize;
+use serde_json::json;
 use serde_json::Value;
 use tracing::subscriber;
 
+use super::introspection::IntrospectionConf;
+use super::introspection::Introspector;
+use super::validation::ClaimMatcherConf;
+use super::validation::TokenValidation;
 use super::Header;
 use super::*;
 use crate::assert_snapshot_subscriber;
@@ -801,6 +806,7 @@ async fn build_jwks_search_components() -> JwksManager {
             algorithms: None,
             poll_interval: Duration::from_secs(60),
             headers: Vec::new(),
+            validation: Default::default(),
         });
     }
 
@@ -816,7 +822,7 @@ async fn it_finds_key_with_criteria_kid_and_algorithm() {
         alg: Algorithm::HS256,
     };
 
-    let (_issuer, key) = search_jwks(&jwks_manager, &criteria)
+    let (_issuer, _validation, key) = search_jwks(&jwks_manager, &criteria)
         .expect("found a key")
         .pop()
         .expect("list isn't empty");
@@ -833,7 +839,7 @@ async fn it_finds_best_matching_key_with_criteria_algorithm() {
         alg: Algorithm::HS256,
     };
 
-    let (_issuer, key) = search_jwks(&jwks_manager, &criteria)
+    let (_issuer, _validation, key) = search_jwks(&jwks_manager, &criteria)
         .expect("found a key")
         .pop()
         .expect("list isn't empty");
@@ -862,7 +868,7 @@ async fn it_finds_key_with_criteria_algorithm_ec() {
         alg: Algorithm::ES256,
     };
 
-    let (_issuer, key) = search_jwks(&jwks_manager, &criteria)
+    let (_issuer, _validation, key) = search_jwks(&jwks_manager, &criteria)
         .expect("found a key")
         .pop()
         .expect("list isn't empty");
@@ -882,7 +888,7 @@ async fn it_finds_key_with_criteria_algorithm_rsa() {
         alg: Algorithm::RS256,
     };
 
-    let (_issuer, key) = search_jwks(&jwks_manager, &criteria)
+    let (_issuer, _validation, key) = search_jwks(&jwks_manager, &criteria)
         .expect("found a key")
         .pop()
         .expect("list isn't empty");
@@ -912,6 +918,7 @@ fn make_manager(jwk: &Jwk, issuer: Option<String>) -> JwksManager {
         algorithms: None,
         poll_interval: Duration::from_secs(60),
         headers: Vec::new(),
+        validation: Default::default(),
     }];
     let map = HashMap::from([(url, jwks); 1]);
 
@@ -1098,6 +1105,147 @@ async fn issuer_check() {
     }
 }
 
+#[tokio::test]
+async fn claims_validation() {
+    let signing_key = SigningKey::random(&mut OsRng);
+    let verifying_key = signing_key.verifying_key();
+    let point = verifying_key.to_encoded_point(false);
+
+    let encoding_key = EncodingKey::from_ec_der(&signing_key.to_pkcs8_der().unwrap().to_bytes());
+
+    let jwk = Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_operations: Some(vec![KeyOperations::Verify]),
+            key_algorithm: Some(KeyAlgorithm::ES256),
+            key_id: Some("hello".to_string()),
+            ..Default::default()
+        },
+        algorithm: AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+            key_type: EllipticCurveKeyType::EC,
+            curve: EllipticCurve::P256,
+            x: BASE64_URL_SAFE_NO_PAD.encode(point.x().unwrap()),
+            y: BASE64_URL_SAFE_NO_PAD.encode(point.y().unwrap()),
+        }),
+    };
+
+    let url = Url::from_str("file:///jwks.json").unwrap();
+    let manager = JwksManager::new_test(
+        vec![JwksConfig {
+            url: url.clone(),
+            issuer: None,
+            algorithms: None,
+            poll_interval: Duration::from_secs(60),
+            headers: Vec::new(),
+            validation: Arc::new(
+                TokenValidation::new(
+                    Some(vec!["products-api".to_string()]),
+                    &HashMap::from([(
+                        "scope".to_string(),
+                        ClaimMatcherConf::Contains(json!("read:products")),
+                    )]),
+                    Some(vec!["at+jwt".to_string()]),
+                    Some(Duration::from_secs(5)),
+                )
+                .unwrap(),
+            ),
+        }],
+        HashMap::from([(url, JwkSet { keys: vec![jwk] })]),
+    );
+
+    let mut config = JWTConf::default();
+    config.sources.push(Source::Header {
+        name: super::default_header_name(),
+        value_prefix: super::default_header_value_prefix(),
+    });
+
+    let now = get_current_timestamp();
+    let valid = json!({
+        "sub": "test",
+        "exp": now + 60,
+        "aud": ["accounts-api", "products-api"],
+        "scope": "read:accounts read:products",
+    });
+
+    let authenticate_with = |typ: &str, claims: &Value| {
+        let mut header = jsonwebtoken::Header::new(Algorithm::ES256);
+        header.typ = Some(typ.to_string());
+        let token = encode(&header, claims, &encoding_key).unwrap();
+        let request = supergraph::Request::canned_builder()
+            .operation_name("me".to_string())
+            .header(http::header::AUTHORIZATION, format!("Bearer {token}"))
+            .build()
+            .unwrap();
+        authenticate(&config, &manager, request.try_into().unwrap())
+    };
+    let rejection = |typ: &str, claims: Value| {
+        let result = authenticate_with(typ, &claims);
+        async move {
+            match result {
+                ControlFlow::Break(res) => {
+                    assert_eq!(res.response.status(), StatusCode::UNAUTHORIZED);
+                    let response: graphql::Response = serde_json::from_slice(
+                        &hyper::body::to_bytes(res.response.into_body())
+                            .await
+                            .unwrap(),
+                    )
+                    .unwrap();
+                    response.errors[0].message.clone()
+                }
+                ControlFlow::Continue(_) => panic!("the token should have been rejected"),
+            }
+        }
+    };
+
+    assert!(matches!(
+        authenticate_with("application/at+jwt", &valid),
+        ControlFlow::Continue(_)
+    ));
+
+    assert_eq!(
+        rejection("JWT", valid.clone()).await,
+        "Invalid token type: 'JWT'"
+    );
+
+    let mut claims = valid.clone();
+    claims["aud"] = json!("accounts-api");
+    assert_eq!(
+        rejection("at+jwt", claims).await,
+        "Invalid audience: the token's `aud` does not contain any of the expected audiences"
+    );
+
+    let mut claims = valid.clone();
+    claims.as_object_mut().unwrap().remove("aud");
+    assert_eq!(
+        rejection("at+jwt", claims).await,
+        "Missing required claim: 'aud'"
+    );
+
+    let mut claims = valid.clone();
+    claims["scope"] = json!("read:accounts");
+    assert_eq!(
+        rejection("at+jwt", claims).await,
+        "Invalid value for claim: 'scope'"
+    );
+
+    // expired, beyond the leeway
+    let mut claims = valid.clone();
+    claims["exp"] = json!(now - 10);
+    assert_eq!(rejection("at+jwt", claims).await, "Token has expired");
+
+    // expired, within the leeway
+    let mut claims = valid.clone();
+    claims["exp"] = json!(now - 2);
+    assert!(matches!(
+        authenticate_with("at+jwt", &claims),
+        ControlFlow::Continue(_)
+    ));
+
+    let mut claims = valid;
+    claims["nbf"] = json!(now + 30);
+    assert_eq!(rejection("at+jwt", claims).await, "Token is not valid yet");
+}
+
 #[tokio::test]
 async fn it_rejects_key_with_restricted_algorithm() {
     let mut sets = vec![];
@@ -1115,6 +1263,7 @@ async fn it_rejects_key_with_restricted_algorithm() {
             algorithms: Some(HashSet::from([Algorithm::RS256])),
             poll_interval: Duration::from_secs(60),
             headers: Vec::new(),
+            validation: Default::default(),
         });
     }
 
@@ -1147,6 +1296,7 @@ async fn it_rejects_and_accepts_keys_with_restricted_algorithms_and_unknown_jwks
             algorithms: Some(HashSet::from([Algorithm::RS256])),
             poll_interval: Duration::from_secs(60),
             headers: Vec::new(),
+            validation: Default::default(),
         });
     }
 
@@ -1186,6 +1336,7 @@ async fn it_accepts_key_without_use_or_keyops() {
             algorithms: None,
             poll_interval: Duration::from_secs(60),
             headers: Vec::new(),
+            validation: Default::default(),
         });
     }
 
@@ -1217,6 +1368,7 @@ async fn it_accepts_elliptic_curve_key_without_alg() {
             algorithms: None,
             poll_interval: Duration::from_secs(60),
             headers: Vec::new(),
+            validation: Default::default(),
         });
     }
 
@@ -1248,6 +1400,7 @@ async fn it_accepts_rsa_key_without_alg() {
             algorithms: None,
             poll_interval: Duration::from_secs(60),
             headers: Vec::new(),
+            validation: Default::default(),
         });
     }
 
@@ -1319,9 +1472,87 @@ async fn jwks_send_headers() {
             name: HeaderName::from_static("jwks-authz"),
             value: HeaderValue::from_static("user1"),
         }],
+        validation: Default::default(),
     }])
     .await
     .unwrap();
 
     assert!(got_header.load(Ordering::Acquire));
 }
+
+#[tokio::test]
+async fn introspection() {
+    let mock_server = wiremock::MockServer::start().await;
+    let exp = get_current_timestamp() + 60;
+    wiremock::Mock::given(wiremock::matchers::method("POST"))
+        .and(wiremock::matchers::path("/introspect"))
+        .and(wiremock::matchers::header(
+            "authorization",
+            "Basic cm91dGVyOnNlY3JldA==",
+        ))
+        .and(wiremock::matchers::body_string_contains(
+            "token=active-token",
+        ))
+        .respond_with(wiremock::ResponseTemplate::new(200).set_body_json(json!({
+            "active": true,
+            "sub": "user1",
+            "scope": "read:products",
+            "exp": exp,
+        })))
+        // the second request is served from the cache
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    wiremock::Mock::given(wiremock::matchers::method("POST"))
+        .and(wiremock::matchers::path("/introspect"))
+        .and(wiremock::matchers::body_string_contains(
+            "token=inactive-token",
+        ))
+        .respond_with(wiremock::ResponseTemplate::new(200).set_body_json(json!({
+            "active": false,
+        })))
+        .mount(&mock_server)
+        .await;
+
+    let introspector = Introspector::new(
+        serde_json::from_value::<IntrospectionConf>(json!({
+            "url": format!("{}/introspect", mock_server.uri()),
+            "client_id": "router",
+            "client_secret": "secret",
+        }))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+
+    let request = |token: &str| -> router::Request {
+        supergraph::Request::canned_builder()
+            .header(http::header::AUTHORIZATION, format!("Bearer {token}"))
+            .build()
+            .unwrap()
+            .try_into()
+            .unwrap()
+    };
+
+    for _ in 0..2 {
+        match introspector.authenticate(request("active-token")).await {
+            ControlFlow::Continue(request) => {
+                let claims: Value = request
+                    .context
+                    .get(APOLLO_AUTHENTICATION_JWT_CLAIMS)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(claims["sub"], "user1");
+                assert_eq!(claims["scope"], "read:products");
+            }
+            ControlFlow::Break(_) => panic!("the active token should be accepted"),
+        }
+    }
+
+    match introspector.authenticate(request("inactive-token")).await {
+        ControlFlow::Break(response) => {
+            assert_eq!(response.response.status(), StatusCode::UNAUTHORIZED);
+        }
+        ControlFlow::Continue(_) => panic!("the inactive token should be rejected"),
+    }
+}
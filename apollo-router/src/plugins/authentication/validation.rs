@@ -0,0 +1,259 @@
+//! Validation of the claims and type of a JWT, once its signature is verified
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use jsonwebtoken::errors::Error as JWTError;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::Validation;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::Value;
+use tower::BoxError;
+
+use super::AuthenticationError;
+
+/// Expected value of a claim
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub(super) enum ClaimMatcherConf {
+    /// The claim is present, whatever its value
+    Exists,
+    /// The claim is equal to this value
+    Equals(Value),
+    /// The claim is equal to one of these values
+    OneOf(Vec<Value>),
+    /// The claim is an array containing this value, or a space delimited string containing this word
+    Contains(Value),
+    /// The claim is a string entirely matching this regular expression
+    Matches(String),
+}
+
+#[derive(Clone, Debug)]
+enum ClaimMatcher {
+    Exists,
+    Equals(Value),
+    OneOf(Vec<Value>),
+    Contains(Value),
+    Matches(Regex),
+}
+
+impl ClaimMatcher {
+    fn new(conf: &ClaimMatcherConf) -> Result<Self, BoxError> {
+        Ok(match conf {
+            ClaimMatcherConf::Exists => ClaimMatcher::Exists,
+            ClaimMatcherConf::Equals(value) => ClaimMatcher::Equals(value.clone()),
+            ClaimMatcherConf::OneOf(values) => ClaimMatcher::OneOf(values.clone()),
+            ClaimMatcherConf::Contains(value) => ClaimMatcher::Contains(value.clone()),
+            ClaimMatcherConf::Matches(pattern) => ClaimMatcher::Matches(
+                Regex::new(&format!("^(?:{pattern})$"))
+                    .map_err(|e| format!("invalid regular expression '{pattern}': {e}"))?,
+            ),
+        })
+    }
+
+    fn is_match(&self, claim: &Value) -> bool {
+        match self {
+            ClaimMatcher::Exists => true,
+            ClaimMatcher::Equals(value) => claim == value,
+            ClaimMatcher::OneOf(values) => values.contains(claim),
+            ClaimMatcher::Contains(value) => match (claim, value) {
+                (Value::Array(elements), _) => elements.contains(value),
+                (Value::String(words), Value::String(word)) => {
+                    words.split_whitespace().any(|w| w == word)
+                }
+                _ => false,
+            },
+            ClaimMatcher::Matches(regex) => claim.as_str().is_some_and(|s| regex.is_match(s)),
+        }
+    }
+}
+
+/// Requirements for the tokens verified by a JWKS, in addition to their signature and issuer
+#[derive(Clone, Debug, Default)]
+pub(super) struct TokenValidation {
+    audiences: Option<Vec<String>>,
+    required_claims: Vec<(String, ClaimMatcher)>,
+    allowed_types: Option<Vec<String>>,
+    leeway: Option<Duration>,
+}
+
+impl TokenValidation {
+    pub(super) fn new(
+        audiences: Option<Vec<String>>,
+        required_claims: &HashMap<String, ClaimMatcherConf>,
+        allowed_types: Option<Vec<String>>,
+        leeway: Option<Duration>,
+    ) -> Result<Self, BoxError> {
+        let mut claims = required_claims
+            .iter()
+            .map(|(name, conf)| -> Result<_, BoxError> {
+                Ok((name.clone(), ClaimMatcher::new(conf)?))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // check the claims in a stable order, so that the same token always gets the same error
+        claims.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        Ok(Self {
+            audiences,
+            required_claims: claims,
+            allowed_types: allowed_types
+                .map(|types| types.iter().map(|t| normalize_type(t)).collect()),
+            leeway,
+        })
+    }
+
+    /// Sets the audience and leeway checks performed while decoding the token
+    pub(super) fn configure(&self, validation: &mut Validation) {
+        if let Some(leeway) = self.leeway {
+            validation.leeway = leeway.as_secs();
+        }
+        if let Some(audiences) = &self.audiences {
+            validation.set_audience(audiences);
+            validation.validate_aud = true;
+            // tokens without an audience are rejected too
+            validation.set_required_spec_claims(&["exp", "aud"]);
+        }
+    }
+
+    /// Checks the `typ` parameter of the JWT header
+    pub(super) fn check_type(&self, typ: Option<&str>) -> Result<(), AuthenticationError<'static>> {
+        match &self.allowed_types {
+            None => Ok(()),
+            Some(allowed) if typ.is_some_and(|typ| allowed.contains(&normalize_type(typ))) => {
+                Ok(())
+            }
+            Some(_) => Err(match typ {
+                Some(typ) => AuthenticationError::InvalidTokenType(typ.to_string()),
+                None => AuthenticationError::MissingTokenType,
+            }),
+        }
+    }
+
+    /// Checks the required claims of a decoded token
+    pub(super) fn check_claims(&self, claims: &Value) -> Result<(), AuthenticationError<'static>> {
+        for (name, matcher) in &self.required_claims {
+            match claim_by_path(claims, name) {
+                None | Some(Value::Null) => {
+                    return Err(AuthenticationError::MissingClaim(name.clone()))
+                }
+                Some(claim) if !matcher.is_match(claim) => {
+                    return Err(AuthenticationError::InvalidClaim(name.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Media types of `typ` are compared case-insensitively, and the `application/` prefix is optional
+fn normalize_type(typ: &str) -> String {
+    let typ = typ.to_ascii_lowercase();
+    match typ.strip_prefix("application/") {
+        Some(stripped) => stripped.to_string(),
+        None => typ,
+    }
+}
+
+/// Maps the validation errors of jsonwebtoken to their own authentication errors
+pub(super) fn decode_error(error: JWTError) -> AuthenticationError<'static> {
+    match error.kind() {
+        ErrorKind::ExpiredSignature => AuthenticationError::ExpiredToken,
+        ErrorKind::ImmatureSignature => AuthenticationError::TokenNotYetValid,
+        ErrorKind::InvalidAudience => AuthenticationError::InvalidAudience,
+        ErrorKind::MissingRequiredClaim(claim) => AuthenticationError::MissingClaim(claim.clone()),
+        _ => AuthenticationError::CannotDecodeJWT(error),
+    }
+}
+
+/// Finds a claim by name, or else by a dot separated path in nested objects, like
+/// `realm_access.roles`
+pub(crate) fn claim_by_path<'a>(claims: &'a Value, path: &str) -> Option<&'a Value> {
+    let object = claims.as_object()?;
+    if let Some(value) = object.get(path) {
+        return Some(value);
+    }
+    path.split('.')
+        .try_fold(claims, |value, segment| value.as_object()?.get(segment))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn claim_matchers() {
+        let claims = json!({
+            "sub": "user1",
+            "scope": "read:products write:products",
+            "roles": ["admin", "editor"],
+            "email": "user1@example.com",
+            "realm_access": { "roles": ["support"] },
+            "https://example.com/tenant": "acme",
+        });
+        let check = |name: &str, conf: ClaimMatcherConf| {
+            TokenValidation::new(None, &HashMap::from([(name.to_string(), conf)]), None, None)
+                .unwrap()
+                .check_claims(&claims)
+                .map_err(|e| e.to_string())
+        };
+
+        assert!(check("sub", ClaimMatcherConf::Exists).is_ok());
+        assert!(check(
+            "https://example.com/tenant",
+            ClaimMatcherConf::Equals(json!("acme"))
+        )
+        .is_ok());
+        assert!(check(
+            "sub",
+            ClaimMatcherConf::OneOf(vec![json!("user1"), json!("user2")])
+        )
+        .is_ok());
+        assert!(check("scope", ClaimMatcherConf::Contains(json!("write:products"))).is_ok());
+        assert!(check("scope", ClaimMatcherConf::Contains(json!("write"))).is_err());
+        assert!(check("roles", ClaimMatcherConf::Contains(json!("admin"))).is_ok());
+        assert!(check(
+            "realm_access.roles",
+            ClaimMatcherConf::Contains(json!("support"))
+        )
+        .is_ok());
+        assert!(check(
+            "email",
+            ClaimMatcherConf::Matches(".*@example\\.com".into())
+        )
+        .is_ok());
+        assert!(check("email", ClaimMatcherConf::Matches("example".into())).is_err());
+
+        assert_eq!(
+            check("org", ClaimMatcherConf::Exists).unwrap_err(),
+            "Missing required claim: 'org'"
+        );
+        assert_eq!(
+            check("sub", ClaimMatcherConf::Equals(json!("user2"))).unwrap_err(),
+            "Invalid value for claim: 'sub'"
+        );
+    }
+
+    #[test]
+    fn token_types() {
+        let validation = TokenValidation::new(
+            None,
+            &HashMap::new(),
+            Some(vec!["at+jwt".to_string()]),
+            None,
+        )
+        .unwrap();
+        assert!(validation.check_type(Some("at+JWT")).is_ok());
+        assert!(validation.check_type(Some("application/at+jwt")).is_ok());
+        assert!(validation.check_type(Some("JWT")).is_err());
+        assert_eq!(
+            validation.check_type(None).unwrap_err().to_string(),
+            "Missing token type"
+        );
+        assert!(TokenValidation::default().check_type(None).is_ok());
+    }
+}
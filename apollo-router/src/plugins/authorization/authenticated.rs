@@ -1669,6 +1669,8 @@ mod tests {
             multipart_subscription: true,
             json: true,
             wildcard: true,
+            event_stream: false,
+            websocket: false,
         });
         let request = supergraph::Request::fake_builder()
             .query("query { orga(id: 1) { id creatorUser { id } ... @defer { nonNullId } } }")
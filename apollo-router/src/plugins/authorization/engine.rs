@@ -0,0 +1,660 @@
+//! Authorization plugin
+//!
+//! Native evaluation of the policies required by the `@policy` directive.
+//!
+//! Each policy is configured as a boolean expression over the JWT claims, the client request
+//! headers and the context entries:
+//!
+//! ```text
+//! claims.org == headers["x-org"] && "admin" in claims.roles
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::iter::Peekable;
+use std::ops::Range;
+use std::str::CharIndices;
+
+use http::HeaderMap;
+use serde_json_bytes::Value;
+use tower::BoxError;
+
+use super::REQUIRED_POLICIES_KEY;
+use crate::plugins::authentication::APOLLO_AUTHENTICATION_JWT_CLAIMS;
+use crate::services::supergraph;
+use crate::Context;
+
+/// Policies evaluated by the router
+pub(crate) struct PolicyEngine {
+    policies: HashMap<String, Expression>,
+}
+
+impl PolicyEngine {
+    pub(crate) fn new(policies: &HashMap<String, String>) -> Result<Self, BoxError> {
+        let policies = policies
+            .iter()
+            .map(|(name, expression)| -> Result<_, BoxError> {
+                let expression = Parser::new(expression)
+                    .parse()
+                    .map_err(|e| format!("invalid expression for policy '{name}': {e}"))?;
+                Ok((name.clone(), expression))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { policies })
+    }
+
+    /// Sets the result of the policies required by the query that have an expression.
+    ///
+    /// The other policies are left to Rhai scripts and coprocessors.
+    pub(crate) fn evaluate(&self, request: &supergraph::Request) {
+        let Some(Value::Object(mut required)) =
+            request.context.get_json_value(REQUIRED_POLICIES_KEY)
+        else {
+            return;
+        };
+
+        let input = Input {
+            claims: request
+                .context
+                .get_json_value(APOLLO_AUTHENTICATION_JWT_CLAIMS)
+                .unwrap_or(Value::Null),
+            headers: request.supergraph_request.headers(),
+            context: &request.context,
+        };
+        let mut evaluated = false;
+        for (policy, result) in required.iter_mut() {
+            if let Some(expression) = self.policies.get(policy.as_str()) {
+                *result = Value::Bool(is_true(&expression.evaluate(&input)));
+                evaluated = true;
+            }
+        }
+
+        if evaluated {
+            request
+                .context
+                .insert_json_value(REQUIRED_POLICIES_KEY, Value::Object(required));
+        }
+    }
+}
+
+struct Input<'a> {
+    claims: Value,
+    headers: &'a HeaderMap,
+    context: &'a Context,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expression {
+    Literal(Value),
+    Array(Vec<Expression>),
+    Path(Root, Vec<Segment>),
+    Not(Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Compare(Box<Expression>, Operator, Box<Expression>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Root {
+    Claims,
+    Headers,
+    Context,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    In,
+}
+
+impl Expression {
+    fn evaluate(&self, input: &Input) -> Value {
+        match self {
+            Expression::Literal(value) => value.clone(),
+            Expression::Array(elements) => Value::Array(
+                elements
+                    .iter()
+                    .map(|element| element.evaluate(input))
+                    .collect(),
+            ),
+            Expression::Path(root, segments) => resolve(*root, segments, input),
+            Expression::Not(expression) => Value::Bool(!is_true(&expression.evaluate(input))),
+            Expression::And(left, right) => {
+                Value::Bool(is_true(&left.evaluate(input)) && is_true(&right.evaluate(input)))
+            }
+            Expression::Or(left, right) => {
+                Value::Bool(is_true(&left.evaluate(input)) || is_true(&right.evaluate(input)))
+            }
+            Expression::Compare(left, operator, right) => Value::Bool(compare(
+                &left.evaluate(input),
+                *operator,
+                &right.evaluate(input),
+            )),
+        }
+    }
+}
+
+/// Only `true` is true: a missing claim or a string does not satisfy a policy
+fn is_true(value: &Value) -> bool {
+    matches!(value, Value::Bool(true))
+}
+
+fn resolve(root: Root, segments: &[Segment], input: &Input) -> Value {
+    let (value, rest) = match root {
+        Root::Claims => (input.claims.clone(), segments),
+        Root::Headers => {
+            return match segments {
+                [Segment::Key(name)] => input
+                    .headers
+                    .get(name.as_str())
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| Value::String(value.to_string().into()))
+                    .unwrap_or(Value::Null),
+                _ => Value::Null,
+            }
+        }
+        Root::Context => match segments.split_first() {
+            Some((Segment::Key(key), rest)) => (
+                input
+                    .context
+                    .get_json_value(key.as_str())
+                    .unwrap_or(Value::Null),
+                rest,
+            ),
+            _ => return Value::Null,
+        },
+    };
+
+    rest.iter()
+        .try_fold(value, |value, segment| match (value, segment) {
+            (Value::Object(mut object), Segment::Key(key)) => object.remove(key.as_str()),
+            (Value::Array(mut array), Segment::Index(index)) if *index < array.len() => {
+                Some(array.swap_remove(*index))
+            }
+            _ => None,
+        })
+        .unwrap_or(Value::Null)
+}
+
+fn compare(left: &Value, operator: Operator, right: &Value) -> bool {
+    match operator {
+        Operator::Eq => equals(left, right),
+        Operator::Ne => !equals(left, right),
+        Operator::Lt | Operator::Le | Operator::Gt | Operator::Ge => {
+            let ordering = match (left, right) {
+                (Value::Number(l), Value::Number(r)) => l
+                    .as_f64()
+                    .zip(r.as_f64())
+                    .and_then(|(l, r)| l.partial_cmp(&r)),
+                (Value::String(l), Value::String(r)) => Some(l.as_str().cmp(r.as_str())),
+                _ => None,
+            };
+            match ordering {
+                None => false,
+                Some(ordering) => match operator {
+                    Operator::Lt => ordering.is_lt(),
+                    Operator::Le => ordering.is_le(),
+                    Operator::Gt => ordering.is_gt(),
+                    _ => ordering.is_ge(),
+                },
+            }
+        }
+        // an element of an array, a word of a space delimited string like `scope`, or a key of an object
+        Operator::In => match (left, right) {
+            (_, Value::Array(elements)) => elements.iter().any(|element| equals(left, element)),
+            (Value::String(word), Value::String(words)) => words
+                .as_str()
+                .split_whitespace()
+                .any(|w| w == word.as_str()),
+            (Value::String(key), Value::Object(object)) => object.contains_key(key.as_str()),
+            _ => false,
+        },
+    }
+}
+
+/// Numbers are compared by value, so that `1` is equal to `1.0`
+fn equals(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Number(l), Value::Number(r)) => l.as_f64() == r.as_f64(),
+        _ => left == right,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Identifier(String),
+    String(String),
+    Number(serde_json::Number),
+    LeftParen,
+    RightParen,
+    LeftBracket,
+    RightBracket,
+    Dot,
+    Comma,
+    Operator(Operator),
+    And,
+    Or,
+    Not,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Identifier(identifier) => f.write_str(identifier),
+            Token::String(s) => write!(f, "{s:?}"),
+            Token::Number(n) => write!(f, "{n}"),
+            Token::LeftParen => f.write_str("("),
+            Token::RightParen => f.write_str(")"),
+            Token::LeftBracket => f.write_str("["),
+            Token::RightBracket => f.write_str("]"),
+            Token::Dot => f.write_str("."),
+            Token::Comma => f.write_str(","),
+            Token::Operator(operator) => f.write_str(match operator {
+                Operator::Eq => "==",
+                Operator::Ne => "!=",
+                Operator::Lt => "<",
+                Operator::Le => "<=",
+                Operator::Gt => ">",
+                Operator::Ge => ">=",
+                Operator::In => "in",
+            }),
+            Token::And => f.write_str("&&"),
+            Token::Or => f.write_str("||"),
+            Token::Not => f.write_str("!"),
+        }
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    peeked: Option<Option<(Token, Range<usize>)>>,
+    /// Position in the source of the last token returned by `next`, empty at the end
+    span: Range<usize>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            chars: source.char_indices().peekable(),
+            peeked: None,
+            span: 0..0,
+        }
+    }
+
+    fn parse(mut self) -> Result<Expression, String> {
+        let expression = self.or()?;
+        match self.next()? {
+            None => Ok(expression),
+            Some(_) => Err(self.unexpected()),
+        }
+    }
+
+    // expression parsing, by increasing precedence
+
+    fn or(&mut self) -> Result<Expression, String> {
+        let mut left = self.and()?;
+        while self.next_if(&Token::Or)? {
+            left = Expression::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expression, String> {
+        let mut left = self.comparison()?;
+        while self.next_if(&Token::And)? {
+            left = Expression::And(Box::new(left), Box::new(self.comparison()?));
+        }
+        Ok(left)
+    }
+
+    fn comparison(&mut self) -> Result<Expression, String> {
+        let left = self.unary()?;
+        match self.peek()? {
+            Some(Token::Operator(operator)) => {
+                let operator = *operator;
+                self.next()?;
+                Ok(Expression::Compare(
+                    Box::new(left),
+                    operator,
+                    Box::new(self.unary()?),
+                ))
+            }
+            _ => Ok(left),
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        if self.next_if(&Token::Not)? {
+            return Ok(Expression::Not(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expression, String> {
+        match self.next()? {
+            None => Err("unexpected end of expression".to_string()),
+            Some(Token::String(s)) => Ok(Expression::Literal(Value::String(s.into()))),
+            Some(Token::Number(n)) => Ok(Expression::Literal(Value::Number(n))),
+            Some(Token::LeftParen) => {
+                let expression = self.or()?;
+                self.expect(Token::RightParen)?;
+                Ok(expression)
+            }
+            Some(Token::LeftBracket) => {
+                let mut elements = Vec::new();
+                if !self.next_if(&Token::RightBracket)? {
+                    loop {
+                        elements.push(self.or()?);
+                        if self.next_if(&Token::RightBracket)? {
+                            break;
+                        }
+                        self.expect(Token::Comma)?;
+                    }
+                }
+                Ok(Expression::Array(elements))
+            }
+            Some(Token::Identifier(identifier)) => match identifier.as_str() {
+                "true" => Ok(Expression::Literal(Value::Bool(true))),
+                "false" => Ok(Expression::Literal(Value::Bool(false))),
+                "null" => Ok(Expression::Literal(Value::Null)),
+                "claims" => self.path(Root::Claims),
+                "headers" => self.path(Root::Headers),
+                "context" => self.path(Root::Context),
+                _ => Err(format!(
+                    "unknown variable '{identifier}', expected claims, headers or context"
+                )),
+            },
+            Some(_) => Err(self.unexpected()),
+        }
+    }
+
+    fn path(&mut self, root: Root) -> Result<Expression, String> {
+        let mut segments = Vec::new();
+        loop {
+            if self.next_if(&Token::Dot)? {
+                match self.next()? {
+                    Some(Token::Identifier(key)) => segments.push(Segment::Key(key)),
+                    _ => return Err(self.expected("a name after '.'")),
+                }
+            } else if self.next_if(&Token::LeftBracket)? {
+                match self.next()? {
+                    Some(Token::String(key)) => segments.push(Segment::Key(key)),
+                    Some(Token::Number(n)) if n.is_u64() => segments.push(Segment::Index(
+                        n.as_u64().expect("the number is an integer; qed") as usize,
+                    )),
+                    _ => return Err(self.expected("a string or an index after '['")),
+                }
+                self.expect(Token::RightBracket)?;
+            } else {
+                break;
+            }
+        }
+        // header names are case insensitive
+        if root == Root::Headers {
+            for segment in &mut segments {
+                if let Segment::Key(name) = segment {
+                    name.make_ascii_lowercase();
+                }
+            }
+        }
+        Ok(Expression::Path(root, segments))
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next()? {
+            Some(token) if token == expected => Ok(()),
+            _ => Err(self.expected(&format!("'{expected}'"))),
+        }
+    }
+
+    /// Error for the last token returned by `next`, where `expected` was expected
+    fn expected(&self, expected: &str) -> String {
+        let position = self.span.start;
+        match &self.source[self.span.clone()] {
+            "" => format!("expected {expected} at position {position}"),
+            found => format!("expected {expected} at position {position}, found '{found}'"),
+        }
+    }
+
+    /// Error for the last token returned by `next`
+    fn unexpected(&self) -> String {
+        format!(
+            "unexpected '{}' at position {}",
+            &self.source[self.span.clone()],
+            self.span.start
+        )
+    }
+
+    // tokenization
+
+    fn next_if(&mut self, expected: &Token) -> Result<bool, String> {
+        if self.peek()? == Some(expected) {
+            self.next()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn peek(&mut self) -> Result<Option<&Token>, String> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.token()?);
+        }
+        Ok(self
+            .peeked
+            .as_ref()
+            .and_then(Option::as_ref)
+            .map(|(token, _)| token))
+    }
+
+    fn next(&mut self) -> Result<Option<Token>, String> {
+        let next = match self.peeked.take() {
+            Some(next) => next,
+            None => self.token()?,
+        };
+        match next {
+            Some((token, span)) => {
+                self.span = span;
+                Ok(Some(token))
+            }
+            None => {
+                self.span = self.source.len()..self.source.len();
+                Ok(None)
+            }
+        }
+    }
+
+    fn token(&mut self) -> Result<Option<(Token, Range<usize>)>, String> {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+        let Some((start, c)) = self.chars.next() else {
+            return Ok(None);
+        };
+
+        let token = match c {
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            '[' => Token::LeftBracket,
+            ']' => Token::RightBracket,
+            '.' => Token::Dot,
+            ',' => Token::Comma,
+            '&' | '|' | '=' => {
+                if self.chars.next_if(|(_, next)| *next == c).is_none() {
+                    return Err(format!("expected '{c}{c}' at position {start}"));
+                }
+                match c {
+                    '&' => Token::And,
+                    '|' => Token::Or,
+                    _ => Token::Operator(Operator::Eq),
+                }
+            }
+            '!' | '<' | '>' => {
+                let or_equal = self.chars.next_if(|(_, next)| *next == '=').is_some();
+                match (c, or_equal) {
+                    ('!', false) => Token::Not,
+                    ('!', true) => Token::Operator(Operator::Ne),
+                    ('<', false) => Token::Operator(Operator::Lt),
+                    ('<', true) => Token::Operator(Operator::Le),
+                    ('>', false) => Token::Operator(Operator::Gt),
+                    (_, _) => Token::Operator(Operator::Ge),
+                }
+            }
+            '"' | '\'' => {
+                let mut s = String::new();
+                loop {
+                    match self.chars.next() {
+                        None => return Err(format!("unterminated string at position {start}")),
+                        Some((_, '\\')) => match self.chars.next() {
+                            Some((_, escaped)) => s.push(escaped),
+                            None => return Err(format!("unterminated string at position {start}")),
+                        },
+                        Some((_, end)) if end == c => break,
+                        Some((_, other)) => s.push(other),
+                    }
+                }
+                Token::String(s)
+            }
+            '-' | '0'..='9' => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, next)) = self
+                    .chars
+                    .next_if(|(_, next)| next.is_ascii_digit() || matches!(next, '.' | 'e' | 'E'))
+                {
+                    end = i + next.len_utf8();
+                }
+                let number = &self.source[start..end];
+                Token::Number(
+                    number
+                        .parse()
+                        .map_err(|_| format!("invalid number '{number}' at position {start}"))?,
+                )
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, next)) = self
+                    .chars
+                    .next_if(|(_, next)| next.is_alphanumeric() || matches!(next, '_' | '-'))
+                {
+                    end = i + next.len_utf8();
+                }
+                match &self.source[start..end] {
+                    "in" => Token::Operator(Operator::In),
+                    identifier => Token::Identifier(identifier.to_string()),
+                }
+            }
+            c => return Err(format!("unexpected character '{c}' at position {start}")),
+        };
+        let end = self
+            .chars
+            .peek()
+            .map(|(end, _)| *end)
+            .unwrap_or(self.source.len());
+        Ok(Some((token, start..end)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+
+    fn evaluate(expression: &str, claims: Value) -> bool {
+        let context = Context::new();
+        context
+            .insert("tenant", json!({ "plan": "enterprise" }))
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-org", "acme".parse().unwrap());
+
+        let expression = Parser::new(expression).parse().unwrap();
+        is_true(&expression.evaluate(&Input {
+            claims,
+            headers: &headers,
+            context: &context,
+        }))
+    }
+
+    #[test]
+    fn expressions() {
+        let claims = json!({
+            "org": "acme",
+            "roles": ["admin", "editor"],
+            "scope": "read:products write:products",
+            "level": 3,
+            "https://example.com/groups": ["support"],
+            "address": { "country": "FR" },
+        });
+        let check = |expression: &str| evaluate(expression, claims.clone());
+
+        assert!(check(
+            r#"claims.org == headers["x-org"] && "admin" in claims.roles"#
+        ));
+        assert!(check(r#"claims.org == headers["X-Org"]"#));
+        assert!(!check(r#"claims.org != headers.x-org"#));
+        assert!(check(r#"'write:products' in claims.scope"#));
+        assert!(!check(r#""write" in claims.scope"#));
+        assert!(check(
+            r#""support" in claims["https://example.com/groups"]"#
+        ));
+        assert!(check(r#"claims.address.country in ["FR", "DE"]"#));
+        assert!(check(r#"claims.roles[1] == "editor""#));
+        assert!(check(
+            r#"claims.level >= 3 && claims.level < 3.5 && claims.level == 3.0"#
+        ));
+        assert!(check(r#"context.tenant.plan == "enterprise""#));
+        assert!(check(r#"!claims.banned && claims.missing == null"#));
+        assert!(check(
+            r#"false || !(claims.org == "other" || claims.level > 5)"#
+        ));
+        // only true satisfies a policy
+        assert!(!check(r#"claims.org"#));
+        assert!(!evaluate(r#""admin" in claims.roles"#, Value::Null));
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        let parse = |expression: &str| Parser::new(expression).parse().unwrap_err();
+
+        assert_eq!(
+            parse("user.org == 'acme'"),
+            "unknown variable 'user', expected claims, headers or context"
+        );
+        assert_eq!(parse("claims.org = 'acme'"), "expected '==' at position 11");
+        assert_eq!(parse("claims.org == "), "unexpected end of expression");
+        assert_eq!(
+            parse("claims.org == 'acme"),
+            "unterminated string at position 14"
+        );
+        assert_eq!(parse("(claims.admin"), "expected ')' at position 13");
+        assert_eq!(
+            parse("claims.org == 'acme')"),
+            "unexpected ')' at position 20"
+        );
+        assert_eq!(parse("[1 2]"), "expected ',' at position 3, found '2'");
+        assert_eq!(
+            parse("claims. == 1"),
+            "expected a name after '.' at position 8, found '=='"
+        );
+        assert_eq!(
+            parse("claims.roles[,]"),
+            "expected a string or an index after '[' at position 13, found ','"
+        );
+        assert!(PolicyEngine::new(&HashMap::from([(
+            "admin".to_string(),
+            "claims.admin ==".to_string()
+        )]))
+        .is_err());
+    }
+}
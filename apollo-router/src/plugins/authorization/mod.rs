@@ -3,6 +3,7 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::ops::ControlFlow;
+use std::sync::Arc;
 
 use apollo_compiler::ast;
 use apollo_compiler::ast::Document;
@@ -19,6 +20,7 @@ use self::authenticated::AuthenticatedCheckVisitor;
 use self::authenticated::AuthenticatedVisitor;
 use self::authenticated::AUTHENTICATED_SPEC_BASE_URL;
 use self::authenticated::AUTHENTICATED_SPEC_VERSION_RANGE;
+use self::engine::PolicyEngine;
 use self::policy::PolicyExtractionVisitor;
 use self::policy::PolicyFilteringVisitor;
 use self::policy::POLICY_SPEC_BASE_URL;
@@ -34,6 +36,7 @@ use crate::json_ext::Path;
 use crate::layers::ServiceBuilderExt;
 use crate::plugin::Plugin;
 use crate::plugin::PluginInit;
+use crate::plugins::authentication::validation::claim_by_path;
 use crate::plugins::authentication::APOLLO_AUTHENTICATION_JWT_CLAIMS;
 use crate::query_planner::FilteredQuery;
 use crate::query_planner::QueryKey;
@@ -49,12 +52,14 @@ use crate::Configuration;
 use crate::Context;
 
 pub(crate) mod authenticated;
+mod engine;
 pub(crate) mod policy;
 pub(crate) mod scopes;
 
 const AUTHENTICATED_KEY: &str = "apollo_authorization::authenticated::required";
 const REQUIRED_SCOPES_KEY: &str = "apollo_authorization::scopes::required";
-const REQUIRED_POLICIES_KEY: &str = "apollo_authorization::policies::required";
+pub(crate) const GRANTED_SCOPES_KEY: &str = "apollo_authorization::scopes::granted";
+pub(crate) const REQUIRED_POLICIES_KEY: &str = "apollo_authorization::policies::required";
 
 #[derive(Clone, Debug, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct CacheKeyMetadata {
@@ -90,6 +95,52 @@ pub(crate) struct Directives {
     /// authorization errors behaviour
     #[serde(default)]
     errors: ErrorConfig,
+    /// expressions evaluating the `@policy` policies, by policy name. Policies without an expression are left to Rhai scripts and coprocessors
+    #[serde(default)]
+    policies: HashMap<String, String>,
+    /// scopes of the request used by `@requiresScopes`, extracted from its JWT claims. By default, they are read from the `scope` claim
+    scopes: Option<ScopesConf>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ScopesConf {
+    /// claim containing the scopes, either a space delimited string or an array of strings. Nested claims are designated with a dot separated path, like `realm_access.roles` (default: `scope`)
+    claim: Option<String>,
+    /// prefix removed from the claim values that start with it, like `ROLE_`
+    strip_prefix: Option<String>,
+    /// scopes granted by claim values, like roles. Values that are not in this table are used as scopes
+    #[serde(default)]
+    mapping: HashMap<String, Vec<String>>,
+}
+
+impl ScopesConf {
+    fn scopes(&self, claims: &serde_json::Value) -> Vec<String> {
+        let values: Vec<&str> =
+            match claim_by_path(claims, self.claim.as_deref().unwrap_or("scope")) {
+                Some(serde_json::Value::String(scopes)) => scopes.split_whitespace().collect(),
+                Some(serde_json::Value::Array(scopes)) => {
+                    scopes.iter().filter_map(|scope| scope.as_str()).collect()
+                }
+                _ => Vec::new(),
+            };
+
+        let mut scopes = Vec::new();
+        for value in values {
+            let value = self
+                .strip_prefix
+                .as_deref()
+                .and_then(|prefix| value.strip_prefix(prefix))
+                .unwrap_or(value);
+            match self.mapping.get(value) {
+                Some(mapped) => scopes.extend(mapped.iter().cloned()),
+                None => scopes.push(value.to_string()),
+            }
+        }
+        scopes.sort();
+        scopes.dedup();
+        scopes
+    }
 }
 
 #[derive(
@@ -133,6 +184,8 @@ fn default_enable_directives() -> bool {
 
 pub(crate) struct AuthorizationPlugin {
     require_authentication: bool,
+    policies: Option<Arc<PolicyEngine>>,
+    scopes: Option<Arc<ScopesConf>>,
 }
 
 impl AuthorizationPlugin {
@@ -248,16 +301,25 @@ impl AuthorizationPlugin {
     pub(crate) fn update_cache_key(context: &Context) {
         let is_authenticated = context.contains_key(APOLLO_AUTHENTICATION_JWT_CLAIMS);
 
-        let request_scopes = context
-            .get_json_value(APOLLO_AUTHENTICATION_JWT_CLAIMS)
-            .and_then(|value| {
-                value.as_object().and_then(|object| {
-                    object.get("scope").and_then(|v| {
-                        v.as_str()
-                            .map(|s| s.split(' ').map(|s| s.to_string()).collect::<HashSet<_>>())
+        // the scopes extracted by the plugin configuration or set by a script, or else the `scope` claim
+        let request_scopes = match context.get_json_value(GRANTED_SCOPES_KEY) {
+            Some(granted) => granted.as_array().map(|v| {
+                v.iter()
+                    .filter_map(|s| s.as_str().map(|s| s.to_string()))
+                    .collect::<HashSet<_>>()
+            }),
+            None => context
+                .get_json_value(APOLLO_AUTHENTICATION_JWT_CLAIMS)
+                .and_then(|value| {
+                    value.as_object().and_then(|object| {
+                        object.get("scope").and_then(|v| {
+                            v.as_str().map(|s| {
+                                s.split(' ').map(|s| s.to_string()).collect::<HashSet<_>>()
+                            })
+                        })
                     })
-                })
-            });
+                }),
+        };
         let query_scopes = context.get_json_value(REQUIRED_SCOPES_KEY).and_then(|v| {
             v.as_array().map(|v| {
                 v.iter()
@@ -535,12 +597,67 @@ impl Plugin for AuthorizationPlugin {
     type Config = Conf;
 
     async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let policies = if init.config.directives.policies.is_empty() {
+            None
+        } else {
+            Some(Arc::new(PolicyEngine::new(
+                &init.config.directives.policies,
+            )?))
+        };
+
         Ok(AuthorizationPlugin {
             require_authentication: init.config.require_authentication,
+            policies,
+            scopes: init.config.directives.scopes.map(Arc::new),
         })
     }
 
     fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        // the scopes and policies are evaluated before the query is planned and filtered, and
+        // before Rhai scripts and coprocessors run
+        let service = match &self.scopes {
+            Some(scopes) => {
+                let scopes = scopes.clone();
+                ServiceBuilder::new()
+                    .map_request(move |request: supergraph::Request| {
+                        // scopes granted by the authentication plugin, like those of an API key,
+                        // are kept as is
+                        if request.context.contains_key(GRANTED_SCOPES_KEY) {
+                            return request;
+                        }
+                        let claims = request
+                            .context
+                            .get::<_, serde_json::Value>(APOLLO_AUTHENTICATION_JWT_CLAIMS)
+                            .ok()
+                            .flatten();
+                        if let Some(claims) = claims {
+                            request
+                                .context
+                                .insert(GRANTED_SCOPES_KEY, scopes.scopes(&claims))
+                                .unwrap();
+                        }
+                        request
+                    })
+                    .service(service)
+                    .boxed()
+            }
+            None => service,
+        };
+
+        let service = match &self.policies {
+            Some(policies) => {
+                let policies = policies.clone();
+                ServiceBuilder::new()
+                    .map_request(move |request: supergraph::Request| {
+                        policies.evaluate(&request);
+                        request
+                    })
+                    .service(service)
+                    .boxed()
+            }
+            None => service,
+        };
+
         if self.require_authentication {
             ServiceBuilder::new()
                 .checkpoint(move |request: supergraph::Request| {
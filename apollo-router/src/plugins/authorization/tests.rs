@@ -1015,3 +1015,381 @@ async fn errors_in_extensions() {
 
     insta::assert_json_snapshot!(response);
 }
+
+const POLICY_SCHEMA: &str = r#"schema
+  @link(url: "https://specs.apollo.dev/link/v1.0")
+  @link(url: "https://specs.apollo.dev/join/v0.3", for: EXECUTION)
+  @link(url: "https://specs.apollo.dev/policy/v0.1", for: SECURITY)
+  {
+    query: Query
+}
+directive @link(url: String, as: String, for: link__Purpose, import: [link__Import]) repeatable on SCHEMA
+directive @join__enumValue(graph: join__Graph!) repeatable on ENUM_VALUE
+directive @join__field(graph: join__Graph, requires: join__FieldSet, provides: join__FieldSet, type: String, external: Boolean, override: String, usedOverridden: Boolean) repeatable on FIELD_DEFINITION | INPUT_FIELD_DEFINITION
+directive @join__graph(name: String!, url: String!) on ENUM_VALUE
+directive @join__implements(graph: join__Graph!, interface: String!) repeatable on OBJECT | INTERFACE
+directive @join__type(graph: join__Graph!, key: join__FieldSet, extension: Boolean! = false, resolvable: Boolean! = true, isInterfaceObject: Boolean! = false) repeatable on OBJECT | INTERFACE | UNION | ENUM | INPUT_OBJECT | SCALAR
+directive @join__unionMember(graph: join__Graph!, member: String!) repeatable on UNION
+
+scalar link__Import
+enum link__Purpose {
+    """
+    `SECURITY` features provide metadata necessary to securely resolve fields.
+    """
+    SECURITY
+  
+    """
+    `EXECUTION` features provide metadata necessary for operation execution.
+    """
+    EXECUTION
+  }
+
+scalar federation__Policy
+directive @policy(policies: [[federation__Policy!]!]!) on OBJECT | FIELD_DEFINITION | INTERFACE | SCALAR | ENUM
+
+scalar join__FieldSet
+enum join__Graph {
+   USER @join__graph(name: "user", url: "http://localhost:4001/graphql")
+   ORGA @join__graph(name: "orga", url: "http://localhost:4002/graphql")
+}
+
+type Query
+@join__type(graph: ORGA)
+@join__type(graph: USER){
+   currentUser: User @join__field(graph: USER)
+   orga(id: ID): Organization @join__field(graph: ORGA)
+}
+type User
+@join__type(graph: ORGA, key: "id")
+@join__type(graph: USER, key: "id") {
+   id: ID!
+   name: String
+   phone: String @policy(policies: [["pii"]])
+   activeOrganization: Organization
+}
+type Organization
+@join__type(graph: ORGA, key: "id")
+@join__type(graph: USER, key: "id") {
+   id: ID
+   creatorUser: User
+   name: String
+   nonNullId: ID!
+   suborga: [Organization]
+}"#;
+
+#[tokio::test]
+async fn policy_expressions() {
+    let subgraphs = MockedSubgraphs([
+    ("user", MockSubgraph::builder().with_json(
+            serde_json::json!{{
+                "query": "query($representations:[_Any!]!){_entities(representations:$representations){...on User{name}}}",
+                "variables": {"representations": [{ "__typename": "User", "id":0 }],}
+            }},
+            serde_json::json! {{ "data": { "_entities":[{"name":"Ada"}] } }},
+        ).with_json(
+            serde_json::json!{{
+                "query": "query($representations:[_Any!]!){_entities(representations:$representations){...on User{name phone}}}",
+                "variables": {"representations": [{ "__typename": "User", "id":0 }],}
+            }},
+            serde_json::json! {{ "data": { "_entities":[{"name":"Ada", "phone": "1234"}] } }},
+        ).build()),
+    ("orga", MockSubgraph::builder().with_json(
+        serde_json::json!{{"query":"{orga(id:1){id creatorUser{__typename id}}}"}},
+        serde_json::json!{{"data": {"orga": { "id": 1, "creatorUser": { "__typename": "User", "id": 0 } }}}}
+    )
+    .build())
+].into_iter().collect());
+
+    let service = TestHarness::builder()
+        .configuration_json(serde_json::json!({
+        "include_subgraph_errors": {
+            "all": true
+        },
+        "authorization": {
+            "directives": {
+                "enabled": true,
+                "policies": {
+                    "pii": "claims.org == headers[\"x-org\"] && \"support\" in claims.roles"
+                }
+            }
+        }}))
+        .unwrap()
+        .schema(POLICY_SCHEMA)
+        .extra_plugin(subgraphs)
+        .build_router()
+        .await
+        .unwrap();
+
+    let req = graphql::Request {
+        query: Some("query { orga(id: 1) { id creatorUser { id name phone } } }".to_string()),
+        ..Default::default()
+    };
+
+    for (org, expected) in [
+        (
+            "acme",
+            serde_json::json!({ "orga": { "id": 1, "creatorUser": { "id": 0, "name": "Ada", "phone": "1234" } } }),
+        ),
+        (
+            "other",
+            serde_json::json!({ "orga": { "id": 1, "creatorUser": { "id": 0, "name": "Ada", "phone": null } } }),
+        ),
+    ] {
+        let context = Context::new();
+        context
+            .insert(
+                "apollo_authentication::JWT::claims",
+                json! {{ "org": "acme", "roles": ["support"] }},
+            )
+            .unwrap();
+        let request = router::Request {
+            context,
+            router_request: http::Request::builder()
+                .method("POST")
+                .header(CONTENT_TYPE, "application/json")
+                .header(ACCEPT, "application/json")
+                .header("x-org", org)
+                .body(serde_json::to_vec(&req).unwrap().into())
+                .unwrap(),
+        };
+
+        let response = service
+            .clone()
+            .oneshot(request)
+            .await
+            .unwrap()
+            .into_graphql_response_stream()
+            .await
+            .next()
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            serde_json::to_value(response.data).unwrap(),
+            expected,
+            "x-org: {org}"
+        );
+    }
+}
+
+#[test]
+fn scopes_from_claims() {
+    let conf: super::ScopesConf = serde_json::from_value(serde_json::json!({
+        "claim": "realm_access.roles",
+        "strip_prefix": "ROLE_",
+        "mapping": {
+            "support": ["user:read", "pii"]
+        }
+    }))
+    .unwrap();
+    let claims = serde_json::json!({
+        "scope": "user:read",
+        "realm_access": { "roles": ["ROLE_support", "ROLE_admin", "other", "ROLE_support"] }
+    });
+    assert_eq!(conf.scopes(&claims), ["admin", "other", "pii", "user:read"]);
+
+    let conf: super::ScopesConf = serde_json::from_value(serde_json::json!({})).unwrap();
+    assert_eq!(conf.scopes(&claims), ["user:read"]);
+    assert!(conf
+        .scopes(&serde_json::json!({ "scope": ["user:read"] }))
+        .contains(&"user:read".to_string()));
+    assert!(conf.scopes(&serde_json::json!({ "scp": "pii" })).is_empty());
+}
+
+#[tokio::test]
+async fn scopes_directive_from_configured_claim() {
+    let subgraphs = MockedSubgraphs([
+    ("user", MockSubgraph::builder().with_json(
+            serde_json::json!{{
+                "query": "query($representations:[_Any!]!){_entities(representations:$representations){...on User{name}}}",
+                "variables": {"representations": [{ "__typename": "User", "id":0 }],}
+            }},
+            serde_json::json! {{ "data": { "_entities":[{"name":"Ada"}] } }},
+        ).with_json(
+            serde_json::json!{{
+                "query": "query($representations:[_Any!]!){_entities(representations:$representations){...on User{name phone}}}",
+                "variables": {"representations": [{ "__typename": "User", "id":0 }],}
+            }},
+            serde_json::json! {{ "data": { "_entities":[{"name":"Ada", "phone": "1234"}] } }},
+        ).build()),
+    ("orga", MockSubgraph::builder().with_json(
+        serde_json::json!{{"query":"{orga(id:1){id creatorUser{__typename id}}}"}},
+        serde_json::json!{{"data": {"orga": { "id": 1, "creatorUser": { "__typename": "User", "id": 0 } }}}}
+    )
+    .build())
+].into_iter().collect());
+
+    let service = TestHarness::builder()
+        .configuration_json(serde_json::json!({
+        "include_subgraph_errors": {
+            "all": true
+        },
+        "authorization": {
+            "directives": {
+                "enabled": true,
+                "scopes": {
+                    "claim": "realm_access.roles",
+                    "strip_prefix": "ROLE_",
+                    "mapping": {
+                        "support": ["user:read", "pii"]
+                    }
+                }
+            }
+        }}))
+        .unwrap()
+        .schema(SCOPES_SCHEMA)
+        .extra_plugin(subgraphs)
+        .build_router()
+        .await
+        .unwrap();
+
+    let req = graphql::Request {
+        query: Some("query { orga(id: 1) { id creatorUser { id name phone } } }".to_string()),
+        ..Default::default()
+    };
+
+    for (roles, expected) in [
+        (
+            serde_json::json!(["ROLE_support"]),
+            serde_json::json!({ "orga": { "id": 1, "creatorUser": { "id": 0, "name": "Ada", "phone": "1234" } } }),
+        ),
+        (
+            serde_json::json!(["ROLE_user:read"]),
+            serde_json::json!({ "orga": { "id": 1, "creatorUser": { "id": 0, "name": "Ada", "phone": null } } }),
+        ),
+    ] {
+        let context = Context::new();
+        context
+            .insert(
+                "apollo_authentication::JWT::claims",
+                serde_json::json!({ "scope": "user:read pii", "realm_access": { "roles": roles } }),
+            )
+            .unwrap();
+        let request = router::Request {
+            context,
+            router_request: http::Request::builder()
+                .method("POST")
+                .header(CONTENT_TYPE, "application/json")
+                .header(ACCEPT, "application/json")
+                .body(serde_json::to_vec(&req).unwrap().into())
+                .unwrap(),
+        };
+
+        let response = service
+            .clone()
+            .oneshot(request)
+            .await
+            .unwrap()
+            .into_graphql_response_stream()
+            .await
+            .next()
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(serde_json::to_value(response.data).unwrap(), expected);
+    }
+}
+
+#[tokio::test]
+async fn api_key_principal() {
+    use sha2::Digest;
+
+    let dir = tempfile::tempdir().unwrap();
+    let keys_file = dir.path().join("keys.yaml");
+    let hash = hex::encode(sha2::Sha256::digest("s1billing.secret"));
+    std::fs::write(
+        &keys_file,
+        format!("- id: billing\n  client_name: billing\n  salt: s1\n  hash: {hash}\n  scopes: [user:read]\n"),
+    )
+    .unwrap();
+
+    let subgraphs = || {
+        MockedSubgraphs([
+    ("user", MockSubgraph::builder().with_json(
+            serde_json::json!{{
+                "query": "query($representations:[_Any!]!){_entities(representations:$representations){...on User{name}}}",
+                "variables": {"representations": [{ "__typename": "User", "id":0 }],}
+            }},
+            serde_json::json! {{ "data": { "_entities":[{"name":"Ada"}] } }},
+        ).with_json(
+            serde_json::json!{{
+                "query": "query($representations:[_Any!]!){_entities(representations:$representations){...on User{name phone}}}",
+                "variables": {"representations": [{ "__typename": "User", "id":0 }],}
+            }},
+            serde_json::json! {{ "data": { "_entities":[{"name":"Ada", "phone": "1234"}] } }},
+        ).build()),
+    ("orga", MockSubgraph::builder().with_json(
+        serde_json::json!{{"query":"{orga(id:1){id creatorUser{__typename id}}}"}},
+        serde_json::json!{{"data": {"orga": { "id": 1, "creatorUser": { "__typename": "User", "id": 0 } }}}}
+    )
+    .build())
+].into_iter().collect())
+    };
+
+    let req = graphql::Request {
+        query: Some("query { orga(id: 1) { id creatorUser { id name phone } } }".to_string()),
+        ..Default::default()
+    };
+
+    // `@authenticated` fields are accessible, and the scopes of the key are granted, but not `pii`
+    for (schema, expected) in [
+        (
+            AUTHENTICATED_SCHEMA,
+            serde_json::json!({ "orga": { "id": 1, "creatorUser": { "id": 0, "name": "Ada", "phone": "1234" } } }),
+        ),
+        (
+            SCOPES_SCHEMA,
+            serde_json::json!({ "orga": { "id": 1, "creatorUser": { "id": 0, "name": "Ada", "phone": null } } }),
+        ),
+    ] {
+        let service = TestHarness::builder()
+            .configuration_json(serde_json::json!({
+            "include_subgraph_errors": {
+                "all": true
+            },
+            "authentication": {
+                "router": {
+                    "api_key": {
+                        "keys_file": keys_file
+                    }
+                }
+            },
+            "authorization": {
+                "directives": {
+                    "enabled": true
+                }
+            }}))
+            .unwrap()
+            .schema(schema)
+            .extra_plugin(subgraphs())
+            .build_router()
+            .await
+            .unwrap();
+
+        let request = router::Request {
+            context: Context::new(),
+            router_request: http::Request::builder()
+                .method("POST")
+                .header(CONTENT_TYPE, "application/json")
+                .header(ACCEPT, "application/json")
+                .header("x-api-key", "billing.secret")
+                .body(serde_json::to_vec(&req).unwrap().into())
+                .unwrap(),
+        };
+
+        let response = service
+            .oneshot(request)
+            .await
+            .unwrap()
+            .into_graphql_response_stream()
+            .await
+            .next()
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(serde_json::to_value(response.data).unwrap(), expected);
+    }
+}
@@ -151,6 +151,14 @@ impl CacheControl {
         Ok(result)
     }
 
+    /// A response that must not be stored
+    pub(crate) fn no_store() -> Self {
+        CacheControl {
+            no_store: true,
+            ..Default::default()
+        }
+    }
+
     pub(crate) fn to_headers(&self, headers: &mut HeaderMap) -> Result<(), BoxError> {
         let mut s = String::new();
         let mut prev = false;
@@ -275,6 +283,23 @@ impl CacheControl {
         }
     }
 
+    pub(crate) fn is_private(&self) -> bool {
+        self.private
+    }
+
+    pub(crate) fn is_no_store(&self) -> bool {
+        self.no_store
+    }
+
+    /// Sets the age to the time elapsed since this was created, for responses served from a cache
+    pub(crate) fn with_age(&self) -> CacheControl {
+        let elapsed = now_epoch_seconds().saturating_sub(self.created) as u32;
+        CacheControl {
+            age: Some(self.age.unwrap_or_default() + elapsed),
+            ..self.clone()
+        }
+    }
+
     pub(crate) fn should_store(&self) -> bool {
         // FIXME: should we add support for must-understand?
         // public will be the default case
@@ -1,9 +1,14 @@
 use std::collections::HashMap;
+use std::num::NonZeroUsize;
 use std::ops::ControlFlow;
 use std::sync::Arc;
 use std::time::Duration;
 
+use apollo_compiler::schema::ExtendedType;
+use apollo_compiler::validation::WithErrors;
+use apollo_compiler::Parser;
 use http::header;
+use multimap::MultiMap;
 use schemars::JsonSchema;
 use serde::Deserialize;
 use serde::Serialize;
@@ -19,10 +24,13 @@ use tracing::Instrument;
 use tracing::Level;
 
 use super::cache_control::CacheControl;
+use super::invalidation::Invalidation;
+use super::invalidation::InvalidationEndpointConfig;
+use super::invalidation::InvalidationService;
 use super::metrics::CacheMetricsService;
+use super::storage::EntityStorage;
+use super::storage::SubgraphStorage;
 use crate::cache::redis::RedisCacheStorage;
-use crate::cache::redis::RedisKey;
-use crate::cache::redis::RedisValue;
 use crate::configuration::RedisCache;
 use crate::error::FetchError;
 use crate::graphql;
@@ -39,25 +47,40 @@ use crate::services::subgraph;
 use crate::services::supergraph;
 use crate::spec::TYPENAME;
 use crate::Context;
+use crate::Endpoint;
+use crate::ListenAddr;
 
 pub(crate) const ENTITIES: &str = "_entities";
 pub(crate) const REPRESENTATIONS: &str = "representations";
 pub(crate) const CONTEXT_CACHE_KEY: &str = "apollo_entity_cache::key";
+const DEFAULT_IN_MEMORY_MAX_TTL: Duration = Duration::from_secs(30);
 
 register_plugin!("apollo", "preview_entity_cache", EntityCache);
 
 pub(crate) struct EntityCache {
-    storage: Option<RedisCacheStorage>,
+    storage: Option<EntityStorage<CacheEntry>>,
+    in_memory: Option<InMemory>,
+    invalidation: Option<Invalidation>,
+    invalidation_endpoint: Option<InvalidationEndpointConfig>,
     subgraphs: Arc<HashMap<String, Subgraph>>,
+    entity_keys: Arc<HashMap<String, EntityKeys>>,
     enabled: Option<bool>,
     metrics: Metrics,
 }
 
+/// `@key` fields of the entity types of a subgraph, by type name
+type EntityKeys = HashMap<String, Vec<KeyFields>>;
+
 /// Configuration for entity caching
 #[derive(Clone, Debug, JsonSchema, Deserialize)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub(crate) struct Config {
-    redis: RedisCache,
+    /// Redis storage, shared by router instances. Optional if the in memory tier is configured
+    #[serde(default)]
+    redis: Option<RedisCache>,
+    /// In memory tier, in front of Redis if it is configured
+    #[serde(default)]
+    in_memory: Option<InMemory>,
     /// activates caching for all subgraphs, unless overriden in subgraph specific configuration
     #[serde(default)]
     enabled: Option<bool>,
@@ -68,6 +91,10 @@ pub(crate) struct Config {
     /// Entity caching evaluation metrics
     #[serde(default)]
     metrics: Metrics,
+
+    /// Exposes an endpoint to invalidate cache entries
+    #[serde(default)]
+    invalidation: Option<InvalidationEndpointConfig>,
 }
 
 /// Per subgraph configuration for entity caching
@@ -80,6 +107,22 @@ pub(crate) struct Subgraph {
     /// activates caching for this subgraph, overrides the global configuration
     #[serde(default)]
     enabled: Option<bool>,
+
+    /// In memory tier for this subgraph, overrides the global configuration
+    #[serde(default)]
+    in_memory: Option<InMemory>,
+}
+
+/// In memory tier of the entity cache
+#[derive(Clone, Debug, JsonSchema, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) struct InMemory {
+    /// Number of entries kept in memory for each subgraph
+    pub(crate) limit: NonZeroUsize,
+    /// Maximum time an entry is kept in memory, even if its TTL is longer. Invalidation only
+    /// clears the in memory tier of the router instance receiving it, so the other instances
+    /// can serve an invalidated entry for this long (default: 30s)
+    pub(crate) max_ttl: Option<Ttl>,
 }
 
 /// Per subgraph configuration for entity caching
@@ -113,29 +156,44 @@ impl Plugin for EntityCache {
     where
         Self: Sized,
     {
-        let required_to_start = init.config.redis.required_to_start;
-        // we need to explicitely disable TTL reset because it is managed directly by this plugin
-        let mut redis_config = init.config.redis.clone();
-        redis_config.reset_ttl = false;
-        let storage = match RedisCacheStorage::new(redis_config).await {
-            Ok(storage) => Some(storage),
-            Err(e) => {
-                tracing::error!(
-                    cache = "entity",
-                    e,
-                    "could not open connection to Redis for caching",
-                );
-                if required_to_start {
-                    return Err(e);
+        if init.config.redis.is_none() && init.config.in_memory.is_none() {
+            return Err("entity caching requires a Redis or an in memory storage".into());
+        }
+
+        let redis = match init.config.redis.clone() {
+            Some(mut redis_config) => {
+                let required_to_start = redis_config.required_to_start;
+                // we need to explicitely disable TTL reset because it is managed directly by this plugin
+                redis_config.reset_ttl = false;
+                match RedisCacheStorage::new(redis_config).await {
+                    Ok(storage) => Some(storage),
+                    Err(e) => {
+                        tracing::error!(
+                            cache = "entity",
+                            e,
+                            "could not open connection to Redis for caching",
+                        );
+                        if required_to_start {
+                            return Err(e);
+                        }
+                        None
+                    }
                 }
-                None
             }
+            None => None,
         };
+        let storage =
+            (redis.is_some() || init.config.in_memory.is_some()).then(|| EntityStorage::new(redis));
+        let entity_keys = entity_keys(&init.supergraph_sdl);
 
         Ok(Self {
+            invalidation: storage.clone().map(Invalidation::new),
+            invalidation_endpoint: init.config.invalidation,
             storage,
+            in_memory: init.config.in_memory,
             enabled: init.config.enabled,
             subgraphs: Arc::new(init.config.subgraphs),
+            entity_keys: Arc::new(entity_keys),
             metrics: init.config.metrics,
         })
     }
@@ -165,6 +223,28 @@ impl Plugin for EntityCache {
             None => return service,
         };
 
+        let in_memory = self
+            .subgraphs
+            .get(name)
+            .and_then(|config| config.in_memory.as_ref())
+            .or(self.in_memory.as_ref())
+            .map(|in_memory| {
+                let max_ttl = in_memory
+                    .max_ttl
+                    .as_ref()
+                    .map(|ttl| ttl.0)
+                    .unwrap_or(DEFAULT_IN_MEMORY_MAX_TTL);
+                (in_memory.limit, max_ttl)
+            });
+        let storage = storage.subgraph(
+            name,
+            in_memory,
+            self.subgraphs
+                .get(name)
+                .and_then(|config| config.ttl.as_ref())
+                .map(|ttl| ttl.0),
+        );
+
         let (subgraph_ttl, subgraph_enabled) = if let Some(config) = self.subgraphs.get(name) {
             (
                 config.ttl.clone().map(|t| t.0).or_else(|| storage.ttl()),
@@ -175,6 +255,20 @@ impl Plugin for EntityCache {
         };
         let name = name.to_string();
 
+        if let Some(invalidation) = self.invalidation.clone() {
+            service = ServiceBuilder::new()
+                .map_response({
+                    let name = name.clone();
+                    move |mut response: subgraph::Response| {
+                        invalidation
+                            .from_extensions(&name, &mut response.response.body_mut().extensions);
+                        response
+                    }
+                })
+                .service(service)
+                .boxed();
+        }
+
         if self.metrics.enabled {
             service = CacheMetricsService::create(
                 name.to_string(),
@@ -190,11 +284,29 @@ impl Plugin for EntityCache {
                 name: name.to_string(),
                 storage,
                 subgraph_ttl,
+                entity_keys: Arc::new(self.entity_keys.get(&name).cloned().unwrap_or_default()),
             })))
         } else {
             service
         }
     }
+
+    fn web_endpoints(&self) -> MultiMap<ListenAddr, Endpoint> {
+        let mut map = MultiMap::new();
+
+        if let (Some(invalidation), Some(config)) = (
+            self.invalidation.clone(),
+            self.invalidation_endpoint.as_ref(),
+        ) {
+            let endpoint = Endpoint::from_router_service(
+                config.path.clone(),
+                InvalidationService::new(invalidation).boxed(),
+            );
+            map.insert(config.listen.clone(), endpoint);
+        }
+
+        map
+    }
 }
 
 impl EntityCache {
@@ -202,14 +314,38 @@ impl EntityCache {
     pub(crate) async fn with_mocks(
         storage: RedisCacheStorage,
         subgraphs: HashMap<String, Subgraph>,
+        supergraph_sdl: &str,
+    ) -> Result<Self, BoxError>
+    where
+        Self: Sized,
+    {
+        Self::with_storage(
+            EntityStorage::new(Some(storage)),
+            None,
+            subgraphs,
+            supergraph_sdl,
+        )
+        .await
+    }
+
+    #[cfg(test)]
+    pub(crate) async fn with_storage(
+        storage: EntityStorage<CacheEntry>,
+        in_memory: Option<InMemory>,
+        subgraphs: HashMap<String, Subgraph>,
+        supergraph_sdl: &str,
     ) -> Result<Self, BoxError>
     where
         Self: Sized,
     {
         Ok(Self {
+            invalidation: Some(Invalidation::new(storage.clone())),
+            invalidation_endpoint: None,
             storage: Some(storage),
+            in_memory,
             enabled: Some(true),
             subgraphs: Arc::new(subgraphs),
+            entity_keys: Arc::new(entity_keys(supergraph_sdl)),
             metrics: Metrics::default(),
         })
     }
@@ -219,8 +355,9 @@ struct CacheService(Option<InnerCacheService>);
 struct InnerCacheService {
     service: subgraph::BoxService,
     name: String,
-    storage: RedisCacheStorage,
+    storage: SubgraphStorage<CacheEntry>,
     subgraph_ttl: Option<Duration>,
+    entity_keys: Arc<EntityKeys>,
 }
 
 impl Service<subgraph::Request> for CacheService {
@@ -267,7 +404,7 @@ impl InnerCacheService {
                         let response = self.service.call(request).await?;
 
                         let cache_control =
-                            CacheControl::new(response.response.headers(), self.storage.ttl)?;
+                            CacheControl::new(response.response.headers(), self.storage.ttl())?;
                         update_cache_control(&response.context, &cache_control);
 
                         cache_store_root_from_response(
@@ -286,7 +423,7 @@ impl InnerCacheService {
                 self.service.call(request).await
             }
         } else {
-            match cache_lookup_entities(self.name, self.storage.clone(), request)
+            match cache_lookup_entities(self.name, self.storage.clone(), &self.entity_keys, request)
                 .instrument(tracing::info_span!("cache_lookup"))
                 .await?
             {
@@ -295,7 +432,7 @@ impl InnerCacheService {
                     let mut response = self.service.call(request).await?;
 
                     let cache_control =
-                        CacheControl::new(response.response.headers(), self.storage.ttl)?;
+                        CacheControl::new(response.response.headers(), self.storage.ttl())?;
                     update_cache_control(&response.context, &cache_control);
 
                     cache_store_entities_from_response(
@@ -315,7 +452,7 @@ impl InnerCacheService {
 
 async fn cache_lookup_root(
     name: String,
-    cache: RedisCacheStorage,
+    cache: SubgraphStorage<CacheEntry>,
     mut request: subgraph::Request,
 ) -> Result<ControlFlow<subgraph::Response, (subgraph::Request, String)>, BoxError> {
     let body = request.subgraph_request.body_mut();
@@ -328,15 +465,19 @@ async fn cache_lookup_root(
         &request.authorization,
     );
 
-    let cache_result: Option<RedisValue<CacheEntry>> = cache.get(RedisKey(key.clone())).await;
+    let cache_result = cache
+        .get(&key)
+        .await
+        // do not use that cache entry if it is stale
+        .filter(|entry| entry.control.can_use());
 
     match cache_result {
         Some(value) => {
-            request.context.extensions().lock().insert(value.0.control);
+            request.context.extensions().lock().insert(value.control);
 
             Ok(ControlFlow::Break(
                 subgraph::Response::builder()
-                    .data(value.0.data)
+                    .data(value.data)
                     .extensions(Object::new())
                     .context(request.context)
                     .build(),
@@ -350,24 +491,22 @@ struct EntityCacheResults(Vec<IntermediateResult>);
 
 async fn cache_lookup_entities(
     name: String,
-    cache: RedisCacheStorage,
+    cache: SubgraphStorage<CacheEntry>,
+    entity_keys: &EntityKeys,
     mut request: subgraph::Request,
 ) -> Result<ControlFlow<subgraph::Response, (subgraph::Request, EntityCacheResults)>, BoxError> {
     let body = request.subgraph_request.body_mut();
 
     let keys = extract_cache_keys(
         &name,
+        entity_keys,
         &request.query_hash,
         body,
         &request.context,
         &request.authorization,
     )?;
 
-    let cache_result: Vec<Option<CacheEntry>> = cache
-        .get_multiple(keys.iter().map(|k| RedisKey(k.clone())).collect::<Vec<_>>())
-        .await
-        .map(|res| res.into_iter().map(|r| r.map(|v| v.0)).collect())
-        .unwrap_or_else(|| std::iter::repeat(None).take(keys.len()).collect());
+    let cache_result: Vec<Option<CacheEntry>> = cache.get_multiple(&keys).await;
 
     let representations = body
         .variables
@@ -409,7 +548,7 @@ async fn cache_lookup_entities(
     }
 }
 
-fn update_cache_control(context: &Context, cache_control: &CacheControl) {
+pub(crate) fn update_cache_control(context: &Context, cache_control: &CacheControl) {
     if let Some(c) = context.extensions().lock().get_mut::<CacheControl>() {
         *c = c.merge(cache_control);
         return;
@@ -419,13 +558,13 @@ fn update_cache_control(context: &Context, cache_control: &CacheControl) {
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-struct CacheEntry {
+pub(crate) struct CacheEntry {
     control: CacheControl,
     data: Value,
 }
 
 async fn cache_store_root_from_response(
-    cache: RedisCacheStorage,
+    cache: SubgraphStorage<CacheEntry>,
     subgraph_ttl: Option<Duration>,
     response: &subgraph::Response,
     cache_control: CacheControl,
@@ -443,11 +582,11 @@ async fn cache_store_root_from_response(
             tokio::spawn(async move {
                 cache
                     .insert(
-                        RedisKey(cache_key),
-                        RedisValue(CacheEntry {
+                        cache_key,
+                        CacheEntry {
                             control: cache_control,
                             data,
-                        }),
+                        },
                         ttl,
                     )
                     .instrument(span)
@@ -460,7 +599,7 @@ async fn cache_store_root_from_response(
 }
 
 async fn cache_store_entities_from_response(
-    cache: RedisCacheStorage,
+    cache: SubgraphStorage<CacheEntry>,
     subgraph_ttl: Option<Duration>,
     response: &mut subgraph::Response,
     cache_control: CacheControl,
@@ -540,7 +679,8 @@ pub(crate) fn hash_additional_data(
     let mut digest = Sha256::new();
 
     let repr_key = ByteString::from(REPRESENTATIONS);
-    // Removing the representations variable because it's already part of the cache key
+    // Removing the representations variable because each representation is hashed in the cache
+    // key of its entity, so that entities fetched in different batches share cache entries
     let representations = body.variables.remove(&repr_key);
     digest.update(&serde_json::to_vec(&body.variables).unwrap());
     if let Some(representations) = representations {
@@ -565,6 +705,155 @@ pub(crate) fn hash_additional_data(
     hex::encode(digest.finalize().as_slice())
 }
 
+/// Hashes the `@key` fields of an entity. The fields are sorted, so that the entity can be found
+/// for invalidation from its key fields in any order
+pub(crate) fn hash_entity_key(entity_key: &Value) -> String {
+    fn sort_fields(value: &Value) -> Value {
+        match value {
+            Value::Object(object) => {
+                let mut fields = object
+                    .iter()
+                    .map(|(k, v)| (k.clone(), sort_fields(v)))
+                    .collect::<Vec<_>>();
+                fields.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+                Value::Object(fields.into_iter().collect())
+            }
+            Value::Array(values) => Value::Array(values.iter().map(sort_fields).collect()),
+            value => value.clone(),
+        }
+    }
+
+    // We have to hash the representation because it can contains PII
+    let mut digest = Sha256::new();
+    digest.update(
+        serde_json::to_string(&sort_fields(entity_key))
+            .unwrap()
+            .as_bytes(),
+    );
+    hex::encode(digest.finalize().as_slice())
+}
+
+/// Fields of an entity `@key`, with the selections of the fields of object types
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct KeyFields(Vec<(String, KeyFields)>);
+
+impl KeyFields {
+    /// Parses the field set of a `@key` directive, like `id` or `sku variation { id }`
+    fn parse(field_set: &str) -> Option<Self> {
+        let field_set = field_set
+            .replace('{', " { ")
+            .replace('}', " } ")
+            .replace(',', " ");
+        Self::parse_selections(&mut field_set.split_whitespace(), false)
+    }
+
+    fn parse_selections<'a>(
+        tokens: &mut impl Iterator<Item = &'a str>,
+        nested: bool,
+    ) -> Option<Self> {
+        let mut fields: Vec<(String, KeyFields)> = Vec::new();
+        loop {
+            match tokens.next() {
+                None => return (!nested && !fields.is_empty()).then_some(KeyFields(fields)),
+                Some("}") => return (nested && !fields.is_empty()).then_some(KeyFields(fields)),
+                Some("{") => {
+                    let (_, selections) = fields.last_mut()?;
+                    if !selections.0.is_empty() {
+                        return None;
+                    }
+                    *selections = Self::parse_selections(tokens, true)?;
+                }
+                Some(name) => fields.push((name.to_string(), KeyFields::default())),
+            }
+        }
+    }
+
+    /// Copies the key fields of a representation. Returns `None` if one of them is missing
+    fn select(&self, value: &Value) -> Option<Value> {
+        if self.0.is_empty() {
+            return Some(value.clone());
+        }
+        match value {
+            Value::Object(object) => self
+                .0
+                .iter()
+                .map(|(name, fields)| {
+                    let value = fields.select(object.get(name.as_str())?)?;
+                    Some((ByteString::from(name.as_str()), value))
+                })
+                .collect::<Option<Object>>()
+                .map(Value::Object),
+            Value::Array(values) => values
+                .iter()
+                .map(|value| self.select(value))
+                .collect::<Option<Vec<_>>>()
+                .map(Value::Array),
+            Value::Null => Some(Value::Null),
+            _ => None,
+        }
+    }
+}
+
+/// Extracts the `@key` fields of the entities of each subgraph from the supergraph schema
+fn entity_keys(supergraph_sdl: &str) -> HashMap<String, EntityKeys> {
+    let ast = match Parser::new().parse_ast(supergraph_sdl, "schema.graphql") {
+        Ok(ast) => ast,
+        Err(WithErrors { partial, .. }) => partial,
+    };
+    let schema = match ast.to_schema() {
+        Ok(schema) => schema,
+        Err(WithErrors { partial, .. }) => partial,
+    };
+
+    let graphs: HashMap<&str, &str> = schema
+        .get_enum("join__Graph")
+        .map(|join_enum| {
+            join_enum
+                .values
+                .iter()
+                .filter_map(|(value, definition)| {
+                    let join_directive = definition.directives.get("join__graph")?;
+                    let name = join_directive.argument_by_name("name")?.as_str()?;
+                    Some((value.as_str(), name))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut keys: HashMap<String, EntityKeys> = HashMap::new();
+    for (typename, definition) in &schema.types {
+        let directives = match definition {
+            ExtendedType::Object(object) => &object.directives,
+            ExtendedType::Interface(interface) => &interface.directives,
+            _ => continue,
+        };
+        for join_directive in directives.get_all("join__type") {
+            let subgraph = join_directive
+                .argument_by_name("graph")
+                .and_then(|graph| graph.as_enum())
+                .and_then(|graph| graphs.get(graph.as_str()));
+            let field_set = join_directive
+                .argument_by_name("key")
+                .and_then(|key| key.as_str());
+            let (Some(subgraph), Some(field_set)) = (subgraph, field_set) else {
+                continue;
+            };
+            // without its key fields, the whole representation of the entity identifies it
+            let Some(fields) = KeyFields::parse(field_set) else {
+                tracing::warn!("invalid @key fields \"{field_set}\" on type {typename}");
+                continue;
+            };
+            keys.entry(subgraph.to_string())
+                .or_default()
+                .entry(typename.to_string())
+                .or_default()
+                .push(fields);
+        }
+    }
+
+    keys
+}
+
 // build a cache key for the root operation
 fn extract_cache_key_root(
     subgraph_name: &str,
@@ -591,6 +880,7 @@ fn extract_cache_key_root(
 // build a list of keys to get from the cache in one query
 fn extract_cache_keys(
     subgraph_name: &str,
+    entity_keys: &EntityKeys,
     query_hash: &QueryHash,
     body: &mut graphql::Request,
     context: &Context,
@@ -618,20 +908,29 @@ fn extract_cache_keys(
 
         let typename = opt_type.as_str().unwrap_or("-");
 
-        // We have to hash the representation because it can contains PII
-        let mut digest = Sha256::new();
-        digest.update(serde_json::to_string(&representation).unwrap().as_bytes());
-        let hashed_entity_key = hex::encode(digest.finalize().as_slice());
+        // the representation can contain more than the key, like the fields required by `@requires`
+        let entity_key = entity_keys
+            .get(typename)
+            .and_then(|keys| keys.iter().find_map(|key| key.select(representation)))
+            .unwrap_or_else(|| representation.clone());
+        let hashed_entity_key = hash_entity_key(&entity_key);
+        let hashed_representation = hash_entity_key(representation);
 
         // the cache key is written to easily find keys matching a prefix for deletion:
         // - subgraph name: caching is done per subgraph
         // - type: can invalidate all instances of a type
         // - entity key: invalidate a specific entity
+        // - representation: separate cache entries depending on the fields required by `@requires`
         // - query hash: invalidate the entry for a specific query and operation name
         // - additional data: separate cache entries depending on info like authorization status
         let key = format!(
-            "subgraph:{}:{}:{}:{}:{}",
-            subgraph_name, &typename, hashed_entity_key, query_hash, additional_data_hash
+            "subgraph:{}:{}:{}:{}:{}:{}",
+            subgraph_name,
+            &typename,
+            hashed_entity_key,
+            hashed_representation,
+            query_hash,
+            additional_data_hash
         );
 
         representation
@@ -734,7 +1033,7 @@ fn filter_representations(
 async fn insert_entities_in_result(
     entities: &mut Vec<Value>,
     errors: &[Error],
-    cache: RedisCacheStorage,
+    cache: SubgraphStorage<CacheEntry>,
     subgraph_ttl: Option<Duration>,
     cache_control: CacheControl,
     result: &mut Vec<IntermediateResult>,
@@ -801,11 +1100,11 @@ async fn insert_entities_in_result(
 
                     if !has_errors {
                         to_insert.push((
-                            RedisKey(key),
-                            RedisValue(CacheEntry {
+                            key,
+                            CacheEntry {
                                 control: cache_control.clone(),
                                 data: value.clone(),
-                            }),
+                            },
                         ));
                     }
                 }
@@ -819,10 +1118,7 @@ async fn insert_entities_in_result(
         let span = tracing::info_span!("cache_store");
 
         tokio::spawn(async move {
-            cache
-                .insert_multiple(&to_insert, ttl)
-                .instrument(span)
-                .await;
+            cache.insert_multiple(to_insert, ttl).instrument(span).await;
         });
     }
 
@@ -839,3 +1135,124 @@ struct Key {
     opt_type: Option<Value>,
     id: Value,
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+
+    const SCHEMA: &str = r#"
+        directive @join__graph(name: String!, url: String!) on ENUM_VALUE
+        directive @join__type(graph: join__Graph!, key: join__FieldSet) repeatable on OBJECT | INTERFACE
+        scalar join__FieldSet
+        enum join__Graph {
+            INVENTORY @join__graph(name: "inventory", url: "http://localhost:4001")
+            PRODUCTS @join__graph(name: "products", url: "http://localhost:4002")
+        }
+        type Query {
+            products: [Product]
+        }
+        type Product
+        @join__type(graph: INVENTORY, key: "upc sku { id }")
+        @join__type(graph: PRODUCTS, key: "id")
+        @join__type(graph: PRODUCTS, key: "upc") {
+            id: ID!
+            upc: String!
+            sku: Sku
+            weight: Int
+        }
+        type Sku {
+            id: ID!
+            name: String
+        }
+    "#;
+
+    #[test]
+    fn entity_keys_from_the_supergraph() {
+        let keys = entity_keys(SCHEMA);
+        assert_eq!(
+            keys["inventory"]["Product"],
+            vec![KeyFields(vec![
+                ("upc".to_string(), KeyFields::default()),
+                (
+                    "sku".to_string(),
+                    KeyFields(vec![("id".to_string(), KeyFields::default())])
+                ),
+            ])]
+        );
+        assert_eq!(keys["products"]["Product"].len(), 2);
+        assert!(!keys["products"].contains_key("Sku"));
+    }
+
+    #[test]
+    fn entity_key_excludes_required_fields() {
+        let keys = entity_keys(SCHEMA);
+        let key = &keys["inventory"]["Product"][0];
+
+        // `weight` is required by another field, it does not identify the entity
+        let representation = json!({ "upc": "1", "sku": { "id": "2", "name": "a" }, "weight": 3 });
+        assert_eq!(
+            key.select(&representation),
+            Some(json!({ "upc": "1", "sku": { "id": "2" } }))
+        );
+        assert_eq!(key.select(&json!({ "upc": "1" })), None);
+
+        // the first key found in the representation is used
+        let representation = json!({ "upc": "1", "weight": 3 });
+        assert_eq!(
+            keys["products"]["Product"]
+                .iter()
+                .find_map(|key| key.select(&representation)),
+            Some(json!({ "upc": "1" }))
+        );
+    }
+
+    #[test]
+    fn cache_keys_depend_on_required_fields() {
+        let keys = entity_keys(SCHEMA);
+        let mut body = graphql::Request::builder()
+            .query("query($representations:[_Any!]!){_entities(representations:$representations){...on Product{shippingEstimate}}}")
+            .variables(
+                json!({ "representations": [
+                    { "__typename": "Product", "upc": "1", "sku": { "id": "2" }, "weight": 3 },
+                    { "__typename": "Product", "upc": "1", "sku": { "id": "2" }, "weight": 4 },
+                    { "__typename": "Product", "weight": 3, "sku": { "id": "2" }, "upc": "1" },
+                ]})
+                .as_object()
+                .unwrap()
+                .clone(),
+            )
+            .build();
+
+        let cache_keys = extract_cache_keys(
+            "inventory",
+            &keys["inventory"],
+            &QueryHash(vec![1, 2, 3]),
+            &mut body,
+            &Context::new(),
+            &CacheKeyMetadata::default(),
+        )
+        .unwrap();
+
+        // the entries of the same entity with different `@requires` fields miss each other
+        assert_ne!(cache_keys[0], cache_keys[1]);
+        // but they can still be invalidated together from the entity key
+        let entity_prefix = format!(
+            "subgraph:inventory:Product:{}:",
+            hash_entity_key(&json!({ "upc": "1", "sku": { "id": "2" } }))
+        );
+        assert!(cache_keys[0].starts_with(&entity_prefix));
+        assert!(cache_keys[1].starts_with(&entity_prefix));
+        // the order of the fields doesn't matter
+        assert_eq!(cache_keys[0], cache_keys[2]);
+    }
+
+    #[test]
+    fn invalid_key_fields() {
+        assert_eq!(KeyFields::parse(""), None);
+        assert_eq!(KeyFields::parse("{ id }"), None);
+        assert_eq!(KeyFields::parse("sku { id"), None);
+        assert_eq!(KeyFields::parse("sku { id } }"), None);
+    }
+}
@@ -0,0 +1,275 @@
+use std::task::Poll;
+
+use bytes::Buf;
+use futures::future::BoxFuture;
+use http::Method;
+use http::StatusCode;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json_bytes::Value;
+use tower::BoxError;
+use tower::Service;
+use tracing::Instrument;
+
+use super::entity::hash_entity_key;
+use super::entity::CacheEntry;
+use super::storage::EntityStorage;
+use crate::json_ext::Object;
+use crate::services::router;
+use crate::ListenAddr;
+
+pub(crate) const INVALIDATION: &str = "invalidation";
+
+/// Entity cache invalidation endpoint configuration
+#[derive(Clone, Debug, JsonSchema, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) struct InvalidationEndpointConfig {
+    /// Listen address of the invalidation endpoint (default: 127.0.0.1:4000)
+    #[serde(default = "default_listen_addr")]
+    pub(crate) listen: ListenAddr,
+    /// Path of the invalidation endpoint (default: /invalidation)
+    #[serde(default = "default_path")]
+    pub(crate) path: String,
+}
+
+fn default_listen_addr() -> ListenAddr {
+    ListenAddr::SocketAddr("127.0.0.1:4000".parse().expect("valid ListenAddr"))
+}
+
+fn default_path() -> String {
+    String::from("/invalidation")
+}
+
+/// Entries to remove from the entity cache
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum InvalidationRequest {
+    /// All the entries of a subgraph
+    Subgraph { subgraph: String },
+    /// All the entities of a type
+    Type {
+        subgraph: String,
+        #[serde(rename = "type")]
+        typename: String,
+    },
+    /// One entity, identified by its `@key` fields
+    Entity {
+        subgraph: String,
+        #[serde(rename = "type")]
+        typename: String,
+        key: Object,
+    },
+}
+
+impl InvalidationRequest {
+    fn subgraph(&self) -> &str {
+        match self {
+            InvalidationRequest::Subgraph { subgraph }
+            | InvalidationRequest::Type { subgraph, .. }
+            | InvalidationRequest::Entity { subgraph, .. } => subgraph,
+        }
+    }
+
+    /// Prefix of the keys to remove, following the key format of the entity cache
+    fn key_prefix(&self) -> String {
+        match self {
+            InvalidationRequest::Subgraph { subgraph } => format!("subgraph:{subgraph}:"),
+            InvalidationRequest::Type { subgraph, typename } => {
+                format!("subgraph:{subgraph}:{typename}:")
+            }
+            InvalidationRequest::Entity {
+                subgraph,
+                typename,
+                key,
+            } => format!(
+                "subgraph:{subgraph}:{typename}:{}:",
+                hash_entity_key(&Value::Object(key.clone()))
+            ),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Invalidation {
+    storage: EntityStorage<CacheEntry>,
+}
+
+impl Invalidation {
+    pub(crate) fn new(storage: EntityStorage<CacheEntry>) -> Self {
+        Self { storage }
+    }
+
+    /// Returns the number of removed entries
+    pub(crate) async fn invalidate(
+        &self,
+        requests: Vec<InvalidationRequest>,
+    ) -> Result<u64, BoxError> {
+        let mut count = 0;
+        for request in requests {
+            let prefix = request.key_prefix();
+            let deleted = self.storage.delete_prefix(&prefix).await?;
+            tracing::debug!("invalidated {deleted} entity cache entries starting with {prefix}");
+            count += deleted;
+        }
+
+        tracing::info!(
+            monotonic_counter
+                .apollo
+                .router
+                .operations
+                .entity
+                .invalidation = count,
+        );
+        Ok(count)
+    }
+
+    /// Applies the invalidation requests sent by a subgraph in the `invalidation` extension of
+    /// its response. The extension is removed from the response. A subgraph can only invalidate
+    /// its own entries.
+    pub(crate) fn from_extensions(&self, subgraph_name: &str, extensions: &mut Object) {
+        let requests = match extensions.remove(INVALIDATION) {
+            Some(requests) => requests,
+            None => return,
+        };
+
+        match serde_json_bytes::from_value::<Vec<InvalidationRequest>>(requests) {
+            Ok(mut requests) => {
+                requests.retain(|request| {
+                    let allowed = request.subgraph() == subgraph_name;
+                    if !allowed {
+                        tracing::error!(
+                            "subgraph {subgraph_name} cannot invalidate the entity cache entries of subgraph {}",
+                            request.subgraph()
+                        );
+                    }
+                    allowed
+                });
+                let invalidation = self.clone();
+                let span = tracing::info_span!("cache_invalidation");
+                tokio::spawn(
+                    async move {
+                        if let Err(err) = invalidation.invalidate(requests).await {
+                            tracing::error!("cannot invalidate entity cache entries: {err}");
+                        }
+                    }
+                    .instrument(span),
+                );
+            }
+            Err(err) => {
+                tracing::error!("invalid invalidation extension in subgraph response: {err}");
+            }
+        }
+    }
+}
+
+/// Web endpoint accepting a list of invalidation requests
+#[derive(Clone)]
+pub(crate) struct InvalidationService {
+    invalidation: Invalidation,
+}
+
+impl InvalidationService {
+    pub(crate) fn new(invalidation: Invalidation) -> Self {
+        Self { invalidation }
+    }
+}
+
+impl Service<router::Request> for InvalidationService {
+    type Response = router::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, req: router::Request) -> Self::Future {
+        let invalidation = self.invalidation.clone();
+        Box::pin(async move {
+            let (parts, body) = req.router_request.into_parts();
+            if parts.method != Method::POST {
+                return Ok(router::Response {
+                    response: http::Response::builder()
+                        .status(StatusCode::METHOD_NOT_ALLOWED)
+                        .body("".into())
+                        .map_err(BoxError::from)?,
+                    context: req.context,
+                });
+            }
+
+            let requests = hyper::body::to_bytes(body)
+                .await
+                .map_err(|e| format!("failed to get the request body: {e}"))
+                .and_then(|bytes| {
+                    serde_json::from_reader::<_, Vec<InvalidationRequest>>(bytes.reader()).map_err(
+                        |err| format!("failed to deserialize the request body into JSON: {err}"),
+                    )
+                });
+            let requests = match requests {
+                Ok(requests) => requests,
+                Err(err) => {
+                    return Ok(router::Response {
+                        response: http::Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(err.into())
+                            .map_err(BoxError::from)?,
+                        context: req.context,
+                    });
+                }
+            };
+
+            let (status, body) = match invalidation.invalidate(requests).await {
+                Ok(count) => (StatusCode::OK, serde_json::json!({ "count": count })),
+                Err(err) => (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    serde_json::json!({ "error": err.to_string() }),
+                ),
+            };
+
+            Ok(router::Response {
+                response: http::Response::builder()
+                    .status(status)
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(serde_json::to_vec(&body)?.into())
+                    .map_err(BoxError::from)?,
+                context: req.context,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn invalidation_request_prefixes() {
+        let requests: Vec<InvalidationRequest> = serde_json::from_value(json!([
+            { "kind": "subgraph", "subgraph": "products" },
+            { "kind": "type", "subgraph": "products", "type": "Product" },
+            { "kind": "entity", "subgraph": "products", "type": "Product", "key": { "id": "1" } },
+        ]))
+        .unwrap();
+
+        assert_eq!(requests[0].key_prefix(), "subgraph:products:");
+        assert_eq!(requests[1].key_prefix(), "subgraph:products:Product:");
+        assert_eq!(
+            requests[2].key_prefix(),
+            format!(
+                "subgraph:products:Product:{}:",
+                hash_entity_key(&serde_json_bytes::json!({ "id": "1" }))
+            )
+        );
+    }
+
+    #[test]
+    fn entity_key_fields_order_does_not_matter() {
+        assert_eq!(
+            hash_entity_key(&serde_json_bytes::json!({ "id": "1", "upc": "a" })),
+            hash_entity_key(&serde_json_bytes::json!({ "upc": "a", "id": "1" }))
+        );
+    }
+}
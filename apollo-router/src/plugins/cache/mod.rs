@@ -1,5 +1,8 @@
 pub(crate) mod cache_control;
 pub(crate) mod entity;
+pub(crate) mod invalidation;
 pub(crate) mod metrics;
+pub(crate) mod response;
+pub(crate) mod storage;
 #[cfg(test)]
 pub(crate) mod tests;
@@ -0,0 +1,499 @@
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::ready;
+use futures::stream::once;
+use futures::StreamExt;
+use http::HeaderMap;
+use http::StatusCode;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+use tower_service::Service;
+use tracing::Instrument;
+
+use super::cache_control::CacheControl;
+use super::entity::update_cache_control;
+use super::entity::Ttl;
+use crate::cache::storage::CacheStorage;
+use crate::configuration::Cache;
+use crate::context::OPERATION_KIND;
+use crate::graphql;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::plugins::authorization::AuthorizationPlugin;
+use crate::plugins::authorization::CacheKeyMetadata;
+use crate::plugins::authorization::REQUIRED_POLICIES_KEY;
+use crate::query_planner::OperationKind;
+use crate::services::subgraph;
+use crate::services::supergraph;
+use crate::spec::Schema;
+
+register_plugin!("apollo", "preview_response_cache", ResponseCache);
+
+pub(crate) struct ResponseCache {
+    storage: Option<CacheStorage<String, CachedResponse>>,
+    schema_id: Arc<String>,
+    ttl: Duration,
+    private_id: Option<PrivateId>,
+}
+
+/// Configuration for supergraph response caching
+#[derive(Clone, Debug, JsonSchema, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) struct Config {
+    /// activates caching of query responses
+    #[serde(default)]
+    enabled: bool,
+    /// maximum time to live of a response, also used if subgraphs do not send a Cache-Control header
+    ttl: Ttl,
+    /// Response storage: the in memory cache is always active, Redis is optional
+    #[serde(default)]
+    cache: Cache,
+    /// Identifies the client of a request, to cache responses marked as private per client.
+    /// Private responses are not cached if it is not set
+    #[serde(default)]
+    private_id: Option<PrivateId>,
+}
+
+/// Where to find the identifier of the client
+#[derive(Clone, Debug, JsonSchema, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum PrivateId {
+    /// Name of a request header
+    Header(String),
+    /// Key of a context entry, set by a plugin or by the authentication plugin
+    Context(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct CachedResponse {
+    control: CacheControl,
+    response: graphql::Response,
+}
+
+#[async_trait::async_trait]
+impl Plugin for ResponseCache {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError>
+    where
+        Self: Sized,
+    {
+        let storage = if init.config.enabled {
+            // the TTL of entries is set from their Cache-Control, reading them must not extend it
+            let redis_config = init.config.cache.redis.clone().map(|mut redis_config| {
+                redis_config.reset_ttl = false;
+                redis_config
+            });
+            Some(
+                CacheStorage::new(init.config.cache.in_memory.limit, redis_config, "response")
+                    .await?,
+            )
+        } else {
+            None
+        };
+
+        Ok(Self {
+            storage,
+            schema_id: Arc::new(Schema::schema_id(&init.supergraph_sdl)),
+            ttl: init.config.ttl.0,
+            private_id: init.config.private_id,
+        })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        let storage = match self.storage.clone() {
+            Some(storage) => storage,
+            None => return service,
+        };
+
+        tower::util::BoxService::new(CacheService(Some(InnerCacheService {
+            service,
+            storage,
+            schema_id: self.schema_id.clone(),
+            ttl: self.ttl,
+            private_id: self.private_id.clone(),
+        })))
+    }
+
+    fn subgraph_service(&self, _name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        if self.storage.is_none() {
+            return service;
+        }
+
+        // merge the Cache-Control headers of all subgraph responses in the context
+        ServiceBuilder::new()
+            .map_response(|response: subgraph::Response| {
+                match CacheControl::new(response.response.headers(), None) {
+                    Ok(cache_control) => update_cache_control(&response.context, &cache_control),
+                    Err(err) => {
+                        tracing::debug!("invalid Cache-Control header in subgraph response: {err}");
+                        // we cannot know for how long this response can be used
+                        update_cache_control(&response.context, &CacheControl::no_store());
+                    }
+                }
+                response
+            })
+            .service(service)
+            .boxed()
+    }
+}
+
+struct CacheService(Option<InnerCacheService>);
+struct InnerCacheService {
+    service: supergraph::BoxService,
+    storage: CacheStorage<String, CachedResponse>,
+    schema_id: Arc<String>,
+    ttl: Duration,
+    private_id: Option<PrivateId>,
+}
+
+impl Service<supergraph::Request> for CacheService {
+    type Response = supergraph::Response;
+    type Error = BoxError;
+    type Future = <supergraph::BoxService as Service<supergraph::Request>>::Future;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        match &mut self.0 {
+            Some(s) => s.service.poll_ready(cx),
+            None => panic!("service should have been called only once"),
+        }
+    }
+
+    fn call(&mut self, request: supergraph::Request) -> Self::Future {
+        match self.0.take() {
+            None => panic!("service should have been called only once"),
+            Some(s) => Box::pin(s.call_inner(request)),
+        }
+    }
+}
+
+impl InnerCacheService {
+    async fn call_inner(
+        mut self,
+        request: supergraph::Request,
+    ) -> Result<supergraph::Response, BoxError> {
+        let is_query = matches!(
+            request.context.get::<_, OperationKind>(OPERATION_KIND),
+            Ok(Some(OperationKind::Query))
+        );
+        // the result of @policy directives is only known once coprocessors or plugins ran,
+        // so we cannot know from here which entry such a query would use
+        if !is_query || request.context.contains_key(REQUIRED_POLICIES_KEY) {
+            return self.service.call(request).await;
+        }
+
+        let keys = self.cache_keys(&request);
+        match self
+            .cache_lookup(request, keys)
+            .instrument(tracing::info_span!("cache_lookup"))
+            .await
+        {
+            ControlFlow::Break(response) => Ok(response),
+            ControlFlow::Continue((request, keys)) => {
+                let response = self.service.call(request).await?;
+                self.cache_store(response, keys).await
+            }
+        }
+    }
+
+    async fn cache_lookup(
+        &self,
+        request: supergraph::Request,
+        keys: CacheKeys,
+    ) -> ControlFlow<supergraph::Response, (supergraph::Request, CacheKeys)> {
+        // a private entry takes precedence over the shared one
+        for key in keys.private.iter().chain(std::iter::once(&keys.public)) {
+            if let Some(entry) = self.storage.get(key).await {
+                if !entry.control.can_use() {
+                    continue;
+                }
+
+                let mut response = supergraph::Response::new_from_graphql_response(
+                    entry.response,
+                    request.context,
+                );
+                if let Err(err) = entry
+                    .control
+                    .with_age()
+                    .to_headers(response.response.headers_mut())
+                {
+                    tracing::debug!("cannot write the Cache-Control header: {err}");
+                }
+                return ControlFlow::Break(response);
+            }
+        }
+
+        ControlFlow::Continue((request, keys))
+    }
+
+    async fn cache_store(
+        &self,
+        response: supergraph::Response,
+        keys: CacheKeys,
+    ) -> Result<supergraph::Response, BoxError> {
+        // the configured TTL applies if subgraphs did not send a shorter one
+        let default_control = CacheControl::new(&HeaderMap::new(), Some(self.ttl))?;
+        let cache_control = match response.context.extensions().lock().get::<CacheControl>() {
+            Some(cache_control) => default_control.merge(cache_control),
+            None => default_control,
+        };
+
+        let supergraph::Response {
+            response: mut http_response,
+            context,
+        } = response;
+        let _ = cache_control.to_headers(http_response.headers_mut());
+
+        // `no-store` applies to private responses too
+        let key = if cache_control.is_no_store() {
+            None
+        } else if cache_control.is_private() {
+            keys.private
+        } else {
+            Some(keys.public)
+        };
+        let key = match key {
+            Some(key) if http_response.status() == StatusCode::OK => key,
+            _ => {
+                return Ok(supergraph::Response::new_from_response(
+                    http_response,
+                    context,
+                ))
+            }
+        };
+
+        let (parts, mut stream) = http_response.into_parts();
+        let first = stream.next().await;
+        let first = match first {
+            // deferred responses are not cached
+            Some(first) if first.has_next != Some(true) => first,
+            _ => {
+                let stream = futures::stream::iter(first).chain(stream).boxed();
+                return Ok(supergraph::Response::new_from_response(
+                    http::Response::from_parts(parts, stream),
+                    context,
+                ));
+            }
+        };
+
+        if first.errors.is_empty() {
+            let storage = self.storage.clone();
+            let ttl = cache_control
+                .ttl()
+                .map(|secs| Duration::from_secs(secs as u64));
+            let entry = CachedResponse {
+                control: cache_control,
+                response: first.clone(),
+            };
+            let span = tracing::info_span!("cache_store");
+            tokio::spawn(async move {
+                storage
+                    .insert_with_ttl(key, entry, ttl)
+                    .instrument(span)
+                    .await;
+            });
+        }
+
+        Ok(supergraph::Response::new_from_response(
+            http::Response::from_parts(parts, once(ready(first)).boxed()),
+            context,
+        ))
+    }
+
+    fn cache_keys(&self, request: &supergraph::Request) -> CacheKeys {
+        let body = request.supergraph_request.body();
+
+        // same authorization metadata as the one used by the query planner cache
+        AuthorizationPlugin::update_cache_key(&request.context);
+        let authorization = request
+            .context
+            .extensions()
+            .lock()
+            .get::<CacheKeyMetadata>()
+            .cloned()
+            .unwrap_or_default();
+
+        let mut digest = Sha256::new();
+        digest.update(body.query.as_deref().unwrap_or("-").as_bytes());
+        digest.update(&[0u8; 1][..]);
+        digest.update(body.operation_name.as_deref().unwrap_or("-").as_bytes());
+        digest.update(&[0u8; 1][..]);
+        digest.update(&serde_json::to_vec(&body.variables).unwrap());
+        digest.update(&serde_json::to_vec(&authorization).unwrap());
+        let query_hash = hex::encode(digest.finalize().as_slice());
+
+        // the cache key is written to easily find keys matching a prefix for deletion:
+        // - schema: responses from a previous schema are not used
+        // - query hash: query, operation name, variables and authorization status
+        // - private id: entries for a specific client
+        let public = format!("response:{}:{}", self.schema_id, query_hash);
+        let private = private_id(self.private_id.as_ref(), request)
+            .map(|private_id| format!("{public}:{private_id}"));
+
+        CacheKeys { public, private }
+    }
+}
+
+struct CacheKeys {
+    public: String,
+    private: Option<String>,
+}
+
+fn private_id(config: Option<&PrivateId>, request: &supergraph::Request) -> Option<String> {
+    // The identifier is hashed because it can contain PII
+    let mut digest = Sha256::new();
+    match config? {
+        PrivateId::Header(name) => {
+            digest.update(request.supergraph_request.headers().get(name)?.as_bytes());
+        }
+        PrivateId::Context(key) => {
+            digest.update(&serde_json::to_vec(&request.context.get_json_value(key)?).ok()?);
+        }
+    }
+
+    Some(hex::encode(digest.finalize().as_slice()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    use http::header::CACHE_CONTROL;
+    use serde_json_bytes::json;
+
+    use super::*;
+    use crate::plugin::test::MockSupergraphService;
+
+    async fn response_cache(private_id: Option<PrivateId>) -> ResponseCache {
+        ResponseCache {
+            storage: Some(
+                CacheStorage::new(crate::cache::DEFAULT_CACHE_CAPACITY, None, "response")
+                    .await
+                    .unwrap(),
+            ),
+            schema_id: Arc::new("schema".to_string()),
+            ttl: Duration::from_secs(60),
+            private_id,
+        }
+    }
+
+    fn request(user: &str) -> supergraph::Request {
+        let request = supergraph::Request::fake_builder()
+            .query("query { currentUser { name } }")
+            .header("x-user", user)
+            .build()
+            .unwrap();
+        request
+            .context
+            .insert(OPERATION_KIND, OperationKind::Query)
+            .unwrap();
+        request
+    }
+
+    fn mock_service(calls: Arc<AtomicUsize>, cache_control: &'static str) -> MockSupergraphService {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service
+            .expect_call()
+            .returning(move |request: supergraph::Request| {
+                let n = calls.fetch_add(1, Ordering::SeqCst);
+                let headers =
+                    HeaderMap::from_iter([(CACHE_CONTROL, cache_control.parse().unwrap())]);
+                update_cache_control(
+                    &request.context,
+                    &CacheControl::new(&headers, None).unwrap(),
+                );
+                Ok(supergraph::Response::fake_builder()
+                    .data(json!({ "currentUser": { "name": format!("user {n}") } }))
+                    .context(request.context)
+                    .build()
+                    .unwrap())
+            });
+        mock_service
+    }
+
+    async fn call(
+        cache: &ResponseCache,
+        calls: Arc<AtomicUsize>,
+        cache_control: &'static str,
+        user: &str,
+    ) -> (HeaderMap, graphql::Response) {
+        let service = cache.supergraph_service(mock_service(calls, cache_control).boxed());
+        let mut response = service.oneshot(request(user)).await.unwrap();
+        let response_body = response.next_response().await.unwrap();
+        // let the cache store the response
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        (response.response.headers().clone(), response_body)
+    }
+
+    #[tokio::test]
+    async fn caches_public_responses() {
+        let cache = response_cache(None).await;
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let (headers, first) = call(&cache, calls.clone(), "max-age=30", "a").await;
+        assert_eq!(headers.get(CACHE_CONTROL).unwrap(), "max-age=30");
+        let (headers, second) = call(&cache, calls.clone(), "max-age=30", "b").await;
+        assert_eq!(headers.get(CACHE_CONTROL).unwrap(), "max-age=30");
+
+        assert_eq!(first, second);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn does_not_cache_no_store_responses() {
+        let cache = response_cache(None).await;
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let (_, first) = call(&cache, calls.clone(), "no-store", "a").await;
+        let (_, second) = call(&cache, calls.clone(), "no-store", "a").await;
+
+        assert_ne!(first, second);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn caches_private_responses_per_client() {
+        let cache = response_cache(Some(PrivateId::Header("x-user".to_string()))).await;
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let (headers, first_a) = call(&cache, calls.clone(), "private,max-age=30", "a").await;
+        assert_eq!(headers.get(CACHE_CONTROL).unwrap(), "max-age=30,private");
+        let (_, first_b) = call(&cache, calls.clone(), "private,max-age=30", "b").await;
+        let (_, second_a) = call(&cache, calls.clone(), "private,max-age=30", "a").await;
+
+        assert_ne!(first_a, first_b);
+        assert_eq!(first_a, second_a);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // without an identifier, private responses are not cached
+        let cache = response_cache(None).await;
+        let calls = Arc::new(AtomicUsize::new(0));
+        call(&cache, calls.clone(), "private,max-age=30", "a").await;
+        call(&cache, calls.clone(), "private,max-age=30", "a").await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn does_not_cache_private_no_store_responses() {
+        let cache = response_cache(Some(PrivateId::Header("x-user".to_string()))).await;
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let (_, first) = call(&cache, calls.clone(), "private, no-store", "a").await;
+        let (_, second) = call(&cache, calls.clone(), "private, no-store", "a").await;
+
+        assert_ne!(first, second);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
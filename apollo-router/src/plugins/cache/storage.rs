@@ -0,0 +1,348 @@
+//! Storage of the entity cache: an optional in memory tier per subgraph, in front of an optional
+//! Redis instance shared by all subgraphs.
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
+
+use lru::LruCache;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tower::BoxError;
+
+use crate::cache::redis::RedisCacheStorage;
+use crate::cache::redis::RedisKey;
+use crate::cache::redis::RedisValue;
+use crate::cache::storage::ValueType;
+
+#[derive(Clone)]
+pub(crate) struct EntityStorage<V: ValueType> {
+    redis: Option<RedisCacheStorage>,
+    in_memory: Arc<parking_lot::Mutex<HashMap<String, InMemoryStorage<V>>>>,
+}
+
+impl<V> EntityStorage<V>
+where
+    V: ValueType,
+{
+    pub(crate) fn new(redis: Option<RedisCacheStorage>) -> Self {
+        Self {
+            redis,
+            in_memory: Default::default(),
+        }
+    }
+
+    /// Storage for one subgraph. The in memory tier of a subgraph is created on first use and
+    /// then shared by all its requests. `ttl` is the TTL configured for the subgraph
+    pub(crate) fn subgraph(
+        &self,
+        name: &str,
+        in_memory: Option<(NonZeroUsize, Duration)>,
+        ttl: Option<Duration>,
+    ) -> SubgraphStorage<V> {
+        let in_memory = in_memory.map(|(limit, max_ttl)| {
+            self.in_memory
+                .lock()
+                .entry(name.to_string())
+                .or_insert_with(|| InMemoryStorage {
+                    inner: Arc::new(Mutex::new(LruCache::new(limit))),
+                    max_ttl,
+                })
+                .clone()
+        });
+
+        SubgraphStorage {
+            redis: self.redis.clone(),
+            in_memory,
+            subgraph_ttl: ttl,
+        }
+    }
+
+    /// Deletes the entries with a key starting with `prefix`. Returns the number of deleted entries
+    pub(crate) async fn delete_prefix(&self, prefix: &str) -> Result<u64, BoxError> {
+        let in_memory = self.in_memory.lock().values().cloned().collect::<Vec<_>>();
+        let mut count = 0;
+        for storage in in_memory {
+            count += storage.delete_prefix(prefix).await;
+        }
+
+        if let Some(redis) = self.redis.as_ref() {
+            count += redis.delete_matching(&format!("{prefix}*")).await?;
+        }
+
+        Ok(count)
+    }
+}
+
+#[derive(Clone)]
+struct InMemoryStorage<V: ValueType> {
+    inner: Arc<Mutex<LruCache<String, (Instant, V)>>>,
+    max_ttl: Duration,
+}
+
+impl<V> InMemoryStorage<V>
+where
+    V: ValueType,
+{
+    async fn get(&self, key: &str) -> Option<V> {
+        let mut inner = self.inner.lock().await;
+        match inner.get(key) {
+            Some((deadline, _)) if *deadline <= Instant::now() => {
+                inner.pop(key);
+                None
+            }
+            Some((_, value)) => Some(value.clone()),
+            None => None,
+        }
+    }
+
+    async fn insert(&self, key: String, value: V, ttl: Option<Duration>) {
+        // Entries are never kept longer than `max_ttl`, so they can't stay stale forever on
+        // router instances that did not receive an invalidation
+        let ttl = ttl.map_or(self.max_ttl, |ttl| std::cmp::min(ttl, self.max_ttl));
+        self.inner
+            .lock()
+            .await
+            .put(key, (Instant::now() + ttl, value));
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> u64 {
+        let mut inner = self.inner.lock().await;
+        let keys = inner
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in &keys {
+            inner.pop(key);
+        }
+        keys.len() as u64
+    }
+}
+
+/// Entity cache storage of one subgraph
+#[derive(Clone)]
+pub(crate) struct SubgraphStorage<V: ValueType> {
+    redis: Option<RedisCacheStorage>,
+    in_memory: Option<InMemoryStorage<V>>,
+    subgraph_ttl: Option<Duration>,
+}
+
+impl<V> SubgraphStorage<V>
+where
+    V: ValueType,
+{
+    /// Default TTL of the Redis configuration
+    pub(crate) fn ttl(&self) -> Option<Duration> {
+        self.redis.as_ref().and_then(|redis| redis.ttl())
+    }
+
+    /// TTL of the entries copied from Redis to memory. The remaining TTL of the Redis entry is
+    /// unknown, so it is the TTL configured for the subgraph, or the default one of Redis
+    fn copy_ttl(&self) -> Option<Duration> {
+        self.subgraph_ttl.or_else(|| self.ttl())
+    }
+
+    pub(crate) async fn get(&self, key: &str) -> Option<V> {
+        if let Some(in_memory) = self.in_memory.as_ref() {
+            if let Some(value) = in_memory.get(key).await {
+                return Some(value);
+            }
+        }
+
+        let value: V = self.redis.as_ref()?.get(RedisKey(key.to_string())).await?.0;
+        if let Some(in_memory) = self.in_memory.as_ref() {
+            in_memory
+                .insert(key.to_string(), value.clone(), self.copy_ttl())
+                .await;
+        }
+        Some(value)
+    }
+
+    pub(crate) async fn get_multiple(&self, keys: &[String]) -> Vec<Option<V>> {
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            values.push(match self.in_memory.as_ref() {
+                Some(in_memory) => in_memory.get(key).await,
+                None => None,
+            });
+        }
+
+        let missing = keys
+            .iter()
+            .zip(values.iter())
+            .filter(|(_, value)| value.is_none())
+            .map(|(key, _)| RedisKey(key.clone()))
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return values;
+        }
+
+        if let Some(redis) = self.redis.as_ref() {
+            let mut from_redis = redis
+                .get_multiple::<String, V>(missing)
+                .await
+                .unwrap_or_default()
+                .into_iter();
+            for (key, value) in keys.iter().zip(values.iter_mut()) {
+                if value.is_some() {
+                    continue;
+                }
+                *value = from_redis.next().flatten().map(|v| v.0);
+                if let (Some(in_memory), Some(value)) = (self.in_memory.as_ref(), value.as_ref()) {
+                    in_memory
+                        .insert(key.clone(), value.clone(), self.copy_ttl())
+                        .await;
+                }
+            }
+        }
+
+        values
+    }
+
+    pub(crate) async fn insert(&self, key: String, value: V, ttl: Option<Duration>) {
+        if let Some(in_memory) = self.in_memory.as_ref() {
+            in_memory
+                .insert(key.clone(), value.clone(), ttl.or(self.ttl()))
+                .await;
+        }
+        if let Some(redis) = self.redis.as_ref() {
+            redis.insert(RedisKey(key), RedisValue(value), ttl).await;
+        }
+    }
+
+    pub(crate) async fn insert_multiple(&self, data: Vec<(String, V)>, ttl: Option<Duration>) {
+        if let Some(in_memory) = self.in_memory.as_ref() {
+            for (key, value) in &data {
+                in_memory
+                    .insert(key.clone(), value.clone(), ttl.or(self.ttl()))
+                    .await;
+            }
+        }
+        if let Some(redis) = self.redis.as_ref() {
+            let data = data
+                .into_iter()
+                .map(|(key, value)| (RedisKey(key), RedisValue(value)))
+                .collect::<Vec<_>>();
+            redis.insert_multiple(&data, ttl).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use fred::error::RedisErrorKind;
+    use fred::mocks::MockCommand;
+    use fred::mocks::Mocks;
+    use fred::prelude::RedisError;
+    use fred::prelude::RedisValue as FredValue;
+
+    use super::*;
+
+    fn limit(n: usize) -> NonZeroUsize {
+        NonZeroUsize::new(n).unwrap()
+    }
+
+    #[tokio::test]
+    async fn in_memory_tier() {
+        let storage: EntityStorage<String> = EntityStorage::new(None);
+        let products =
+            storage.subgraph("products", Some((limit(2), Duration::from_secs(60))), None);
+
+        products
+            .insert("a".to_string(), "1".to_string(), None)
+            .await;
+        products
+            .insert_multiple(
+                vec![
+                    ("b".to_string(), "2".to_string()),
+                    ("c".to_string(), "3".to_string()),
+                ],
+                None,
+            )
+            .await;
+
+        // the least recently used entry was evicted
+        assert_eq!(
+            products
+                .get_multiple(&["a".to_string(), "b".to_string(), "c".to_string()])
+                .await,
+            vec![None, Some("2".to_string()), Some("3".to_string())]
+        );
+
+        // the in memory tier is shared by the requests of a subgraph
+        let products =
+            storage.subgraph("products", Some((limit(2), Duration::from_secs(60))), None);
+        assert_eq!(products.get("b").await, Some("2".to_string()));
+        let inventory =
+            storage.subgraph("inventory", Some((limit(2), Duration::from_secs(60))), None);
+        assert_eq!(inventory.get("b").await, None);
+
+        assert_eq!(storage.delete_prefix("b").await.unwrap(), 1);
+        assert_eq!(products.get("b").await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn in_memory_ttl_is_capped() {
+        let storage: EntityStorage<String> = EntityStorage::new(None);
+        let products = storage.subgraph("products", Some((limit(8), Duration::from_secs(5))), None);
+
+        products
+            .insert(
+                "a".to_string(),
+                "1".to_string(),
+                Some(Duration::from_secs(60)),
+            )
+            .await;
+        products
+            .insert(
+                "b".to_string(),
+                "2".to_string(),
+                Some(Duration::from_secs(2)),
+            )
+            .await;
+
+        tokio::time::advance(Duration::from_secs(3)).await;
+        assert_eq!(products.get("a").await, Some("1".to_string()));
+        assert_eq!(products.get("b").await, None);
+
+        tokio::time::advance(Duration::from_secs(3)).await;
+        assert_eq!(products.get("a").await, None);
+    }
+
+    /// Answers every GET with the same value
+    #[derive(Debug)]
+    struct RedisEntry(&'static str);
+
+    impl Mocks for RedisEntry {
+        fn process_command(&self, command: MockCommand) -> Result<FredValue, RedisError> {
+            match &*command.cmd {
+                "GET" => Ok(FredValue::Bytes(Bytes::from(self.0))),
+                _ => Err(RedisError::new(RedisErrorKind::NotFound, "mock not found")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn entries_copied_from_redis_expire() {
+        let redis = RedisCacheStorage::from_mocks(Arc::new(RedisEntry("\"1\"")))
+            .await
+            .unwrap();
+        let storage: EntityStorage<String> = EntityStorage::new(Some(redis));
+        let products = storage.subgraph(
+            "products",
+            Some((limit(8), Duration::from_secs(60))),
+            Some(Duration::from_secs(2)),
+        );
+
+        assert_eq!(products.get("a").await, Some("1".to_string()));
+        let in_memory = products.in_memory.as_ref().unwrap();
+        assert_eq!(in_memory.get("a").await, Some("1".to_string()));
+
+        // the entry is kept in memory for the TTL of the subgraph
+        tokio::time::pause();
+        tokio::time::advance(Duration::from_secs(3)).await;
+        assert_eq!(in_memory.get("a").await, None);
+    }
+}
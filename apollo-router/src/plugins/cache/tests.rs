@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::num::NonZeroUsize;
 use std::sync::Arc;
 
 use bytes::Bytes;
@@ -11,6 +12,8 @@ use parking_lot::Mutex;
 use tower::ServiceExt;
 
 use super::entity::EntityCache;
+use super::entity::InMemory;
+use super::storage::EntityStorage;
 use crate::cache::redis::RedisCacheStorage;
 use crate::plugin::test::MockSubgraph;
 use crate::services::supergraph;
@@ -91,7 +94,7 @@ impl Mocks for Mock1 {
                         if *set {
                             return Ok(RedisValue::Bytes(Bytes::from(USER_RESPONSE)));
                         }
-                    } else if b == &b"subgraph:orga:Organization:5811967f540d300d249ab30ae681359a7815fdb5d3dc71a94be1d491006a6b27:655f22a6af21d7ffe671d3ce4b33464a76ddfea0bf179740b15e804b11983c04:d9d84a3c7ffc27b0190a671212f3740e5b8478e84e23825830e97822e25cf05c"[..] {
+                    } else if b == &b"subgraph:orga:Organization:5811967f540d300d249ab30ae681359a7815fdb5d3dc71a94be1d491006a6b27:5811967f540d300d249ab30ae681359a7815fdb5d3dc71a94be1d491006a6b27:655f22a6af21d7ffe671d3ce4b33464a76ddfea0bf179740b15e804b11983c04:d9d84a3c7ffc27b0190a671212f3740e5b8478e84e23825830e97822e25cf05c"[..] {
                         return Ok(RedisValue::Bytes(Bytes::from(ORGA_RESPONSE)));
                     }
                 }
@@ -152,7 +155,7 @@ async fn insert() {
     let redis_cache = RedisCacheStorage::from_mocks(Arc::new(Mock1::new()))
         .await
         .unwrap();
-    let entity_cache = EntityCache::with_mocks(redis_cache.clone(), HashMap::new())
+    let entity_cache = EntityCache::with_mocks(redis_cache.clone(), HashMap::new(), SCHEMA)
         .await
         .unwrap();
 
@@ -182,7 +185,7 @@ async fn insert() {
     insta::assert_json_snapshot!(response);
 
     // Now testing without any mock subgraphs, all the data should come from the cache
-    let entity_cache = EntityCache::with_mocks(redis_cache.clone(), HashMap::new())
+    let entity_cache = EntityCache::with_mocks(redis_cache.clone(), HashMap::new(), SCHEMA)
         .await
         .unwrap();
 
@@ -210,3 +213,108 @@ async fn insert() {
 
     insta::assert_json_snapshot!(response);
 }
+
+#[tokio::test]
+async fn in_memory_only() {
+    let query = "query { currentUser { activeOrganization { id creatorUser { __typename id } } } }";
+
+    let subgraphs = MockedSubgraphs([
+        ("user", MockSubgraph::builder().with_json(
+                serde_json::json!{{"query":"{currentUser{activeOrganization{__typename id}}}"}},
+                serde_json::json!{{"data": {"currentUser": { "activeOrganization": {
+                    "__typename": "Organization",
+                    "id": "1"
+                } }}}}
+            ).build()),
+        ("orga", MockSubgraph::builder().with_json(
+            serde_json::json!{{
+                "query": "query($representations:[_Any!]!){_entities(representations:$representations){...on Organization{creatorUser{__typename id}}}}",
+            "variables": {
+                "representations": [
+                    {
+                        "id": "1",
+                        "__typename": "Organization",
+                    }
+                ]
+            }}},
+            serde_json::json!{{"data": {
+                "_entities": [{
+                    "creatorUser": {
+                        "__typename": "User",
+                        "id": 2
+                    }
+                }]
+            }}}
+        ).build())
+    ].into_iter().collect());
+
+    let storage = EntityStorage::new(None);
+    let in_memory = InMemory {
+        limit: NonZeroUsize::new(10).unwrap(),
+        max_ttl: None,
+    };
+    let expected = serde_json::json!({"data":{"currentUser":{"activeOrganization":{"id":"1","creatorUser":{"__typename":"User","id":2}}}}});
+
+    let entity_cache = EntityCache::with_storage(
+        storage.clone(),
+        Some(in_memory.clone()),
+        HashMap::new(),
+        SCHEMA,
+    )
+    .await
+    .unwrap();
+
+    let service = TestHarness::builder()
+        .configuration_json(serde_json::json!({"include_subgraph_errors": { "all": true } }))
+        .unwrap()
+        .schema(SCHEMA)
+        .extra_plugin(entity_cache)
+        .extra_plugin(subgraphs)
+        .build_supergraph()
+        .await
+        .unwrap();
+
+    let request = supergraph::Request::fake_builder()
+        .query(query)
+        .context(Context::new())
+        .build()
+        .unwrap();
+    let response = service
+        .oneshot(request)
+        .await
+        .unwrap()
+        .next_response()
+        .await
+        .unwrap();
+
+    assert_eq!(serde_json::to_value(&response).unwrap(), expected);
+
+    // Now testing without any mock subgraphs, all the data should come from the in memory tier
+    let entity_cache = EntityCache::with_storage(storage, Some(in_memory), HashMap::new(), SCHEMA)
+        .await
+        .unwrap();
+
+    let service = TestHarness::builder()
+        .configuration_json(serde_json::json!({"include_subgraph_errors": { "all": true } }))
+        .unwrap()
+        .schema(SCHEMA)
+        .extra_plugin(entity_cache)
+        .build_supergraph()
+        .await
+        .unwrap();
+
+    let request = supergraph::Request::fake_builder()
+        .query(query)
+        .context(Context::new())
+        .build()
+        .unwrap();
+    let response = service
+        .oneshot(request)
+        .await
+        .unwrap()
+        .next_response()
+        .await
+        .unwrap();
+
+    assert_eq!(serde_json::to_value(&response).unwrap(), expected);
+}
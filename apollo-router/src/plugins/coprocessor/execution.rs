@@ -68,6 +68,7 @@ impl ExecutionStage {
         http_client: C,
         service: execution::BoxService,
         coprocessor_url: String,
+        protocol: Protocol,
         sdl: Arc<String>,
     ) -> execution::BoxService
     where
@@ -95,6 +96,7 @@ impl ExecutionStage {
                     let result = process_execution_request_stage(
                         http_client,
                         coprocessor_url,
+                        protocol,
                         sdl,
                         request,
                         request_config,
@@ -136,6 +138,7 @@ impl ExecutionStage {
                     let result = process_execution_response_stage(
                         http_client,
                         coprocessor_url,
+                        protocol,
                         sdl,
                         response,
                         response_config,
@@ -183,6 +186,7 @@ impl ExecutionStage {
 async fn process_execution_request_stage<C>(
     http_client: C,
     coprocessor_url: String,
+    protocol: Protocol,
     sdl: Arc<String>,
     mut request: execution::Request,
     request_config: ExecutionRequestConf,
@@ -232,7 +236,9 @@ where
     tracing::debug!(?payload, "externalized output");
     let guard = request.context.enter_active_request();
     let start = Instant::now();
-    let co_processor_result = payload.call(http_client, &coprocessor_url).await;
+    let co_processor_result = payload
+        .call_with_protocol(http_client, &coprocessor_url, protocol)
+        .await;
     let duration = start.elapsed().as_secs_f64();
     drop(guard);
     tracing::info!(
@@ -325,6 +331,7 @@ where
 async fn process_execution_response_stage<C>(
     http_client: C,
     coprocessor_url: String,
+    protocol: Protocol,
     sdl: Arc<String>,
     response: execution::Response,
     response_config: ExecutionResponseConf,
@@ -378,7 +385,9 @@ where
     tracing::debug!(?payload, "externalized output");
     let guard = response.context.enter_active_request();
     let start = Instant::now();
-    let co_processor_result = payload.call(http_client.clone(), &coprocessor_url).await;
+    let co_processor_result = payload
+        .call_with_protocol(http_client.clone(), &coprocessor_url, protocol)
+        .await;
     let duration = start.elapsed().as_secs_f64();
     drop(guard);
     tracing::info!(
@@ -453,7 +462,7 @@ where
                 tracing::debug!(?payload, "externalized output");
                 let guard = generator_map_context.enter_active_request();
                 let co_processor_result = payload
-                    .call(generator_client, &generator_coprocessor_url)
+                    .call_with_protocol(generator_client, &generator_coprocessor_url, protocol)
                     .await;
                 drop(guard);
                 tracing::debug!(?co_processor_result, "co-processor returned");
@@ -682,6 +691,7 @@ mod tests {
             mock_http_client,
             mock_execution_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             Arc::new("".to_string()),
         );
 
@@ -751,6 +761,7 @@ mod tests {
             mock_http_client,
             mock_execution_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             Arc::new("".to_string()),
         );
 
@@ -876,6 +887,7 @@ mod tests {
             mock_http_client,
             mock_execution_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             Arc::new("".to_string()),
         );
 
@@ -987,6 +999,7 @@ mod tests {
             mock_http_client,
             mock_execution_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             Arc::new("".to_string()),
         );
 
@@ -16,10 +16,7 @@ use http::header;
 use http::HeaderMap;
 use http::HeaderName;
 use http::HeaderValue;
-use hyper::client::HttpConnector;
 use hyper::Body;
-use hyper_rustls::ConfigBuilderExt;
-use hyper_rustls::HttpsConnector;
 use schemars::JsonSchema;
 use serde::Deserialize;
 use serde::Serialize;
@@ -35,6 +32,9 @@ use crate::layers::async_checkpoint::OneShotAsyncCheckpointLayer;
 use crate::layers::ServiceBuilderExt;
 use crate::plugin::Plugin;
 use crate::plugin::PluginInit;
+use crate::plugins::coprocessor::transport::coprocessor_uri;
+use crate::plugins::coprocessor::transport::Protocol;
+use crate::plugins::coprocessor::transport::TransportClient;
 use crate::register_plugin;
 use crate::services;
 use crate::services::external::externalize_header_map;
@@ -45,54 +45,33 @@ use crate::services::external::DEFAULT_EXTERNALIZATION_TIMEOUT;
 use crate::services::external::EXTERNALIZABLE_VERSION;
 use crate::services::router;
 use crate::services::subgraph;
-use crate::services::trust_dns_connector::new_async_http_connector;
-use crate::services::trust_dns_connector::AsyncHyperResolver;
 
 #[cfg(test)]
 mod test;
 
 mod execution;
+mod query_planner;
 mod supergraph;
+mod transport;
 
 pub(crate) const EXTERNAL_SPAN_NAME: &str = "external_plugin";
-const POOL_IDLE_TIMEOUT_DURATION: Option<Duration> = Some(Duration::from_secs(5));
 const COPROCESSOR_ERROR_EXTENSION: &str = "ERROR";
 const COPROCESSOR_DESERIALIZATION_ERROR_EXTENSION: &str = "EXTERNAL_DESERIALIZATION_ERROR";
 
-type HTTPClientService =
-    tower::timeout::Timeout<hyper::Client<HttpsConnector<HttpConnector<AsyncHyperResolver>>, Body>>;
+type HTTPClientService = tower::timeout::Timeout<TransportClient>;
 
 #[async_trait::async_trait]
 impl Plugin for CoprocessorPlugin<HTTPClientService> {
     type Config = Conf;
 
     async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
-        let mut http_connector = new_async_http_connector()?;
-        http_connector.set_nodelay(true);
-        http_connector.set_keepalive(Some(std::time::Duration::from_secs(60)));
-        http_connector.enforce_http(false);
-
-        let tls_config = rustls::ClientConfig::builder()
-            .with_safe_defaults()
-            .with_native_roots()
-            .with_no_client_auth();
-
-        let connector = hyper_rustls::HttpsConnectorBuilder::new()
-            .with_tls_config(tls_config)
-            .https_or_http()
-            .enable_http1()
-            .enable_http2()
-            .wrap_connector(http_connector);
-
+        let mut configuration = init.config;
         let http_client = ServiceBuilder::new()
-            .layer(TimeoutLayer::new(init.config.timeout))
-            .service(
-                hyper::Client::builder()
-                    .pool_idle_timeout(POOL_IDLE_TIMEOUT_DURATION)
-                    .build(connector),
-            );
+            .layer(TimeoutLayer::new(configuration.timeout))
+            .service(TransportClient::new(configuration.protocol)?);
+        configuration.url = coprocessor_uri(&configuration.url);
 
-        CoprocessorPlugin::new(http_client, init.config, init.supergraph_sdl)
+        CoprocessorPlugin::new(http_client, configuration, init.supergraph_sdl)
     }
 
     fn router_service(&self, service: router::BoxService) -> router::BoxService {
@@ -143,7 +122,7 @@ where
         + Send
         + Sync
         + 'static,
-    <C as tower::Service<http::Request<Body>>>::Future: Send + Sync + 'static,
+    <C as tower::Service<http::Request<Body>>>::Future: Send + 'static,
 {
     http_client: C,
     configuration: Conf,
@@ -157,7 +136,7 @@ where
         + Send
         + Sync
         + 'static,
-    <C as tower::Service<http::Request<Body>>>::Future: Send + Sync + 'static,
+    <C as tower::Service<http::Request<Body>>>::Future: Send + 'static,
 {
     fn new(http_client: C, configuration: Conf, sdl: Arc<String>) -> Result<Self, BoxError> {
         Ok(Self {
@@ -172,6 +151,7 @@ where
             self.http_client.clone(),
             service,
             self.configuration.url.clone(),
+            self.configuration.protocol,
             self.sdl.clone(),
         )
     }
@@ -184,6 +164,7 @@ where
             self.http_client.clone(),
             service,
             self.configuration.url.clone(),
+            self.configuration.protocol,
             self.sdl.clone(),
         )
     }
@@ -192,10 +173,18 @@ where
         &self,
         service: services::execution::BoxService,
     ) -> services::execution::BoxService {
-        self.configuration.execution.as_service(
+        let service = self.configuration.execution.as_service(
+            self.http_client.clone(),
+            service,
+            self.configuration.url.clone(),
+            self.configuration.protocol,
+            self.sdl.clone(),
+        );
+        self.configuration.query_planner.as_service(
             self.http_client.clone(),
             service,
             self.configuration.url.clone(),
+            self.configuration.protocol,
             self.sdl.clone(),
         )
     }
@@ -205,6 +194,7 @@ where
             self.http_client.clone(),
             service,
             self.configuration.url.clone(),
+            self.configuration.protocol,
             name.to_string(),
         )
     }
@@ -280,8 +270,11 @@ pub(super) struct SubgraphResponseConf {
 #[derive(Clone, Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 struct Conf {
-    /// The url you'd like to offload processing to
+    /// The url you'd like to offload processing to. Use `unix:///path/to/socket` to connect over a Unix domain socket
     url: String,
+    /// The protocol used to exchange data with the coprocessor
+    #[serde(default)]
+    protocol: Protocol,
     /// The timeout for external requests
     #[serde(deserialize_with = "humantime_serde::deserialize")]
     #[schemars(with = "String", default = "default_timeout")]
@@ -293,6 +286,9 @@ struct Conf {
     /// The supergraph stage request/response configuration
     #[serde(default)]
     supergraph: supergraph::SupergraphStage,
+    /// The query planner stage response configuration
+    #[serde(default)]
+    query_planner: query_planner::QueryPlannerStage,
     /// The execution stage request/response configuration
     #[serde(default)]
     execution: execution::ExecutionStage,
@@ -320,6 +316,7 @@ impl RouterStage {
         http_client: C,
         service: router::BoxService,
         coprocessor_url: String,
+        protocol: Protocol,
         sdl: Arc<String>,
     ) -> router::BoxService
     where
@@ -347,6 +344,7 @@ impl RouterStage {
                     let result = process_router_request_stage(
                         http_client,
                         coprocessor_url,
+                        protocol,
                         sdl,
                         request,
                         request_config,
@@ -385,6 +383,7 @@ impl RouterStage {
                     let result = process_router_response_stage(
                         http_client,
                         coprocessor_url,
+                        protocol,
                         sdl,
                         response,
                         response_config,
@@ -453,6 +452,7 @@ impl SubgraphStage {
         http_client: C,
         service: subgraph::BoxService,
         coprocessor_url: String,
+        protocol: Protocol,
         service_name: String,
     ) -> subgraph::BoxService
     where
@@ -479,6 +479,7 @@ impl SubgraphStage {
                     let result = process_subgraph_request_stage(
                         http_client,
                         coprocessor_url,
+                        protocol,
                         service_name,
                         request,
                         request_config,
@@ -518,6 +519,7 @@ impl SubgraphStage {
                     let result = process_subgraph_response_stage(
                         http_client,
                         coprocessor_url,
+                        protocol,
                         service_name,
                         response,
                         response_config,
@@ -564,6 +566,7 @@ impl SubgraphStage {
 async fn process_router_request_stage<C>(
     http_client: C,
     coprocessor_url: String,
+    protocol: Protocol,
     sdl: Arc<String>,
     mut request: router::Request,
     request_config: RouterRequestConf,
@@ -614,7 +617,9 @@ where
     tracing::debug!(?payload, "externalized output");
     let guard = request.context.enter_active_request();
     let start = Instant::now();
-    let co_processor_result = payload.call(http_client, &coprocessor_url).await;
+    let co_processor_result = payload
+        .call_with_protocol(http_client, &coprocessor_url, protocol)
+        .await;
     let duration = start.elapsed().as_secs_f64();
     drop(guard);
     tracing::info!(
@@ -717,6 +722,7 @@ where
 async fn process_router_response_stage<C>(
     http_client: C,
     coprocessor_url: String,
+    protocol: Protocol,
     sdl: Arc<String>,
     mut response: router::Response,
     response_config: RouterResponseConf,
@@ -778,7 +784,9 @@ where
     tracing::debug!(?payload, "externalized output");
     let guard = response.context.enter_active_request();
     let start = Instant::now();
-    let co_processor_result = payload.call(http_client.clone(), &coprocessor_url).await;
+    let co_processor_result = payload
+        .call_with_protocol(http_client.clone(), &coprocessor_url, protocol)
+        .await;
     let duration = start.elapsed().as_secs_f64();
     drop(guard);
     tracing::info!(
@@ -861,7 +869,7 @@ where
                 tracing::debug!(?payload, "externalized output");
                 let guard = generator_map_context.enter_active_request();
                 let co_processor_result = payload
-                    .call(generator_client, &generator_coprocessor_url)
+                    .call_with_protocol(generator_client, &generator_coprocessor_url, protocol)
                     .await;
                 drop(guard);
                 tracing::debug!(?co_processor_result, "co-processor returned");
@@ -905,6 +913,7 @@ where
 async fn process_subgraph_request_stage<C>(
     http_client: C,
     coprocessor_url: String,
+    protocol: Protocol,
     service_name: String,
     mut request: subgraph::Request,
     request_config: SubgraphRequestConf,
@@ -950,7 +959,9 @@ where
     tracing::debug!(?payload, "externalized output");
     let guard = request.context.enter_active_request();
     let start = Instant::now();
-    let co_processor_result = payload.call(http_client, &coprocessor_url).await;
+    let co_processor_result = payload
+        .call_with_protocol(http_client, &coprocessor_url, protocol)
+        .await;
     let duration = start.elapsed().as_secs_f64();
     drop(guard);
     tracing::info!(
@@ -1050,6 +1061,7 @@ where
 async fn process_subgraph_response_stage<C>(
     http_client: C,
     coprocessor_url: String,
+    protocol: Protocol,
     service_name: String,
     mut response: subgraph::Response,
     response_config: SubgraphResponseConf,
@@ -1095,7 +1107,9 @@ where
     tracing::debug!(?payload, "externalized output");
     let guard = response.context.enter_active_request();
     let start = Instant::now();
-    let co_processor_result = payload.call(http_client, &coprocessor_url).await;
+    let co_processor_result = payload
+        .call_with_protocol(http_client, &coprocessor_url, protocol)
+        .await;
     let duration = start.elapsed().as_secs_f64();
     drop(guard);
     tracing::info!(
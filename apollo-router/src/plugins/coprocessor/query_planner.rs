@@ -0,0 +1,432 @@
+use std::ops::ControlFlow;
+use std::sync::Arc;
+
+use futures::future;
+use futures::stream;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower_service::Service;
+
+use super::*;
+use crate::graphql;
+use crate::layers::async_checkpoint::OneShotAsyncCheckpointLayer;
+use crate::layers::ServiceBuilderExt;
+use crate::plugins::coprocessor::EXTERNAL_SPAN_NAME;
+use crate::services::execution;
+
+/// What information is passed to a query planner response stage
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct QueryPlannerResponseConf {
+    /// Send the context
+    pub(super) context: bool,
+    /// Send the body
+    pub(super) body: bool,
+    /// Send the SDL
+    pub(super) sdl: bool,
+    /// Send the query plan
+    pub(super) query_plan: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize, JsonSchema)]
+#[serde(default)]
+pub(super) struct QueryPlannerStage {
+    /// The response configuration
+    pub(super) response: QueryPlannerResponseConf,
+}
+
+impl QueryPlannerStage {
+    /// The query planner stage runs for every operation once its plan is known, before the
+    /// execution stage and before any subgraph is fetched.
+    pub(crate) fn as_service<C>(
+        &self,
+        http_client: C,
+        service: execution::BoxService,
+        coprocessor_url: String,
+        protocol: Protocol,
+        sdl: Arc<String>,
+    ) -> execution::BoxService
+    where
+        C: Service<hyper::Request<Body>, Response = hyper::Response<Body>, Error = BoxError>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        <C as tower::Service<http::Request<Body>>>::Future: Send + 'static,
+    {
+        let response_layer = (self.response != Default::default()).then_some({
+            let response_config = self.response.clone();
+            let coprocessor_url = coprocessor_url.clone();
+            let http_client = http_client.clone();
+            let sdl = sdl.clone();
+
+            OneShotAsyncCheckpointLayer::new(move |request: execution::Request| {
+                let response_config = response_config.clone();
+                let coprocessor_url = coprocessor_url.clone();
+                let http_client = http_client.clone();
+                let sdl = sdl.clone();
+
+                async move {
+                    let mut succeeded = true;
+                    let result = process_query_planner_response_stage(
+                        http_client,
+                        coprocessor_url,
+                        protocol,
+                        sdl,
+                        request,
+                        response_config,
+                    )
+                    .await
+                    .map_err(|error| {
+                        succeeded = false;
+                        tracing::error!(
+                            "external extensibility: query planner response stage error: {error}"
+                        );
+                        error
+                    });
+
+                    u64_counter!(
+                        "apollo.router.operations.coprocessor",
+                        "Total operations with co-processors enabled",
+                        1,
+                        "coprocessor.stage" = PipelineStep::QueryPlannerResponse,
+                        "coprocessor.succeeded" = succeeded
+                    );
+                    result
+                }
+            })
+        });
+
+        fn external_service_span() -> impl Fn(&execution::Request) -> tracing::Span + Clone {
+            move |_request: &execution::Request| {
+                tracing::info_span!(
+                    EXTERNAL_SPAN_NAME,
+                    "external service" = "query_planner::Response",
+                    "otel.kind" = "INTERNAL"
+                )
+            }
+        }
+
+        ServiceBuilder::new()
+            .instrument(external_service_span())
+            .option_layer(response_layer)
+            .service(service)
+            .boxed()
+    }
+}
+
+async fn process_query_planner_response_stage<C>(
+    http_client: C,
+    coprocessor_url: String,
+    protocol: Protocol,
+    sdl: Arc<String>,
+    request: execution::Request,
+    response_config: QueryPlannerResponseConf,
+) -> Result<ControlFlow<execution::Response, execution::Request>, BoxError>
+where
+    C: Service<hyper::Request<Body>, Response = hyper::Response<Body>, Error = BoxError>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    <C as tower::Service<http::Request<Body>>>::Future: Send + 'static,
+{
+    // Externalize the operation and the plan the router is about to execute. The plan is
+    // serialized in the same shape as the `apolloQueryPlan` extension of `expose_query_plan`.
+    let body_to_send = response_config
+        .body
+        .then(|| serde_json::to_value(request.supergraph_request.body()))
+        .transpose()?;
+    let context_to_send = response_config.context.then(|| request.context.clone());
+    let sdl_to_send = response_config.sdl.then(|| sdl.clone().to_string());
+    let query_plan = response_config.query_plan.then(|| {
+        json!({
+            "object": { "kind": "QueryPlan", "node": request.query_plan.root },
+            "text": request.query_plan.formatted_query_plan,
+        })
+    });
+
+    let payload = Externalizable::query_planner_builder()
+        .stage(PipelineStep::QueryPlannerResponse)
+        .control(Control::default())
+        .id(request.context.id.clone())
+        .and_body(body_to_send)
+        .and_context(context_to_send)
+        .and_sdl(sdl_to_send)
+        .and_query_plan(query_plan)
+        .build();
+
+    tracing::debug!(?payload, "externalized output");
+    let guard = request.context.enter_active_request();
+    let start = Instant::now();
+    let co_processor_result = payload
+        .call_with_protocol(http_client, &coprocessor_url, protocol)
+        .await;
+    let duration = start.elapsed().as_secs_f64();
+    drop(guard);
+    tracing::info!(
+        histogram.apollo.router.operations.coprocessor.duration = duration,
+        coprocessor.stage = %PipelineStep::QueryPlannerResponse,
+    );
+
+    tracing::debug!(?co_processor_result, "co-processor returned");
+    let co_processor_output = co_processor_result?;
+    validate_coprocessor_output(&co_processor_output, PipelineStep::QueryPlannerResponse)?;
+
+    if let Some(context) = co_processor_output.context {
+        for (key, value) in context.try_into_iter()? {
+            request
+                .context
+                .upsert_json_value(key, move |_current| value);
+        }
+    }
+
+    // The plan itself cannot be changed, but the coprocessor may veto it before any fetch happens.
+    let control = co_processor_output.control.unwrap_or_default();
+    if matches!(control, Control::Break(_)) {
+        // Ensure the code is a valid http status code
+        let code = control.get_http_status()?;
+
+        let graphql_response: graphql::Response =
+            serde_json::from_value(co_processor_output.body.unwrap_or(serde_json::Value::Null))
+                .unwrap_or_else(|error| {
+                    graphql::Response::builder()
+                        .errors(vec![Error::builder()
+                            .message(format!(
+                                "couldn't deserialize coprocessor output body: {error}"
+                            ))
+                            .extension_code("EXTERNAL_DESERIALIZATION_ERROR")
+                            .build()])
+                        .build()
+                });
+
+        let mut http_response = http::Response::builder()
+            .status(code)
+            .body(stream::once(future::ready(graphql_response)).boxed())?;
+        if let Some(headers) = co_processor_output.headers {
+            *http_response.headers_mut() = internalize_header_map(headers)?;
+        }
+
+        return Ok(ControlFlow::Break(execution::Response {
+            response: http_response,
+            context: request.context,
+        }));
+    }
+
+    Ok(ControlFlow::Continue(request))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::future::BoxFuture;
+    use http::StatusCode;
+    use hyper::Body;
+    use serde_json::json;
+    use tower::BoxError;
+    use tower::ServiceExt;
+
+    use super::super::*;
+    use super::*;
+    use crate::plugin::test::MockExecutionService;
+    use crate::plugin::test::MockHttpClientService;
+    use crate::services::execution;
+
+    #[allow(clippy::type_complexity)]
+    fn mock_with_callback(
+        callback: fn(
+            hyper::Request<Body>,
+        ) -> BoxFuture<'static, Result<hyper::Response<Body>, BoxError>>,
+    ) -> MockHttpClientService {
+        let mut mock_http_client = MockHttpClientService::new();
+        mock_http_client.expect_clone().returning(move || {
+            let mut mock_http_client = MockHttpClientService::new();
+
+            mock_http_client.expect_clone().returning(move || {
+                let mut mock_http_client = MockHttpClientService::new();
+                mock_http_client.expect_call().returning(callback);
+                mock_http_client
+            });
+            mock_http_client
+        });
+
+        mock_http_client
+    }
+
+    #[tokio::test]
+    async fn external_plugin_query_planner_response() {
+        let query_planner_stage = QueryPlannerStage {
+            response: QueryPlannerResponseConf {
+                context: true,
+                body: true,
+                sdl: false,
+                query_plan: true,
+            },
+        };
+
+        let mut mock_execution_service = MockExecutionService::new();
+
+        mock_execution_service
+            .expect_call()
+            .returning(|req: execution::Request| {
+                assert_eq!(
+                    req.context
+                        .get::<&str, u8>("this-is-a-test-context")
+                        .unwrap()
+                        .unwrap(),
+                    42
+                );
+
+                Ok(execution::Response::builder()
+                    .data(json!({ "test": 1234_u32 }))
+                    .errors(Vec::new())
+                    .extensions(crate::json_ext::Object::new())
+                    .context(req.context)
+                    .build()
+                    .unwrap())
+            });
+
+        let mock_http_client = mock_with_callback(move |req: hyper::Request<Body>| {
+            Box::pin(async {
+                let payload: serde_json::Value =
+                    serde_json::from_slice(&hyper::body::to_bytes(req.into_body()).await.unwrap())
+                        .unwrap();
+
+                assert_eq!(EXTERNALIZABLE_VERSION, payload["version"]);
+                assert_eq!(
+                    PipelineStep::QueryPlannerResponse.to_string(),
+                    payload["stage"]
+                );
+                assert_eq!(
+                    json!({ "query": "query Long {\n  me {\n  name\n}\n}" }),
+                    payload["body"]
+                );
+                assert_eq!(
+                    json!({
+                        "object": { "kind": "QueryPlan", "node": { "kind": "Sequence", "nodes": [] } },
+                        "text": null
+                    }),
+                    payload["queryPlan"]
+                );
+
+                Ok(hyper::Response::builder()
+                    .body(Body::from(
+                        r#"{
+                                "version": 1,
+                                "stage": "QueryPlannerResponse",
+                                "control": "continue",
+                                "context": {
+                                    "entries": {
+                                      "this-is-a-test-context": 42
+                                    }
+                                }
+                            }"#,
+                    ))
+                    .unwrap())
+            })
+        });
+
+        let service = query_planner_stage.as_service(
+            mock_http_client,
+            mock_execution_service.boxed(),
+            "http://test".to_string(),
+            Protocol::Json,
+            Arc::new("".to_string()),
+        );
+
+        let request = execution::Request::fake_builder()
+            .supergraph_request(
+                http::Request::builder()
+                    .body(
+                        graphql::Request::builder()
+                            .query("query Long {\n  me {\n  name\n}\n}")
+                            .build(),
+                    )
+                    .unwrap(),
+            )
+            .build();
+
+        assert_eq!(
+            serde_json_bytes::json!({ "test": 1234_u32 }),
+            service
+                .oneshot(request)
+                .await
+                .unwrap()
+                .response
+                .into_body()
+                .next()
+                .await
+                .unwrap()
+                .data
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn external_plugin_query_planner_response_controlflow_break() {
+        let query_planner_stage = QueryPlannerStage {
+            response: QueryPlannerResponseConf {
+                context: false,
+                body: false,
+                sdl: false,
+                query_plan: true,
+            },
+        };
+
+        // This will never be called because the coprocessor vetoes the plan.
+        let mock_execution_service = MockExecutionService::new();
+
+        let mock_http_client = mock_with_callback(move |_: hyper::Request<Body>| {
+            Box::pin(async {
+                Ok(hyper::Response::builder()
+                    .body(Body::from(
+                        r#"{
+                                "version": 1,
+                                "stage": "QueryPlannerResponse",
+                                "control": {
+                                    "break": 400
+                                },
+                                "body": {
+                                    "errors": [{ "message": "too many subgraphs" }]
+                                },
+                                "context": {
+                                    "entries": {
+                                        "testKey": true
+                                    }
+                                }
+                            }"#,
+                    ))
+                    .unwrap())
+            })
+        });
+
+        let service = query_planner_stage.as_service(
+            mock_http_client,
+            mock_execution_service.boxed(),
+            "http://test".to_string(),
+            Protocol::Json,
+            Arc::new("".to_string()),
+        );
+
+        let request = execution::Request::fake_builder().build();
+
+        let crate::services::execution::Response {
+            mut response,
+            context,
+        } = service.oneshot(request).await.unwrap();
+
+        assert!(context.get::<_, bool>("testKey").unwrap().unwrap());
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.body_mut().next().await.unwrap().errors[0]
+                .message
+                .as_str(),
+            "too many subgraphs"
+        );
+    }
+}
@@ -66,6 +66,7 @@ impl SupergraphStage {
         http_client: C,
         service: supergraph::BoxService,
         coprocessor_url: String,
+        protocol: Protocol,
         sdl: Arc<String>,
     ) -> supergraph::BoxService
     where
@@ -93,6 +94,7 @@ impl SupergraphStage {
                     let result = process_supergraph_request_stage(
                         http_client,
                         coprocessor_url,
+                        protocol,
                         sdl,
                         request,
                         request_config,
@@ -132,6 +134,7 @@ impl SupergraphStage {
                     let result = process_supergraph_response_stage(
                         http_client,
                         coprocessor_url,
+                        protocol,
                         sdl,
                         response,
                         response_config,
@@ -177,6 +180,7 @@ impl SupergraphStage {
 async fn process_supergraph_request_stage<C>(
     http_client: C,
     coprocessor_url: String,
+    protocol: Protocol,
     sdl: Arc<String>,
     mut request: supergraph::Request,
     request_config: SupergraphRequestConf,
@@ -222,7 +226,9 @@ where
     tracing::debug!(?payload, "externalized output");
     let guard = request.context.enter_active_request();
     let start = Instant::now();
-    let co_processor_result = payload.call(http_client, &coprocessor_url).await;
+    let co_processor_result = payload
+        .call_with_protocol(http_client, &coprocessor_url, protocol)
+        .await;
     let duration = start.elapsed().as_secs_f64();
     drop(guard);
     tracing::info!(
@@ -315,6 +321,7 @@ where
 async fn process_supergraph_response_stage<C>(
     http_client: C,
     coprocessor_url: String,
+    protocol: Protocol,
     sdl: Arc<String>,
     response: supergraph::Response,
     response_config: SupergraphResponseConf,
@@ -368,7 +375,9 @@ where
     tracing::debug!(?payload, "externalized output");
     let guard = response.context.enter_active_request();
     let start = Instant::now();
-    let co_processor_result = payload.call(http_client.clone(), &coprocessor_url).await;
+    let co_processor_result = payload
+        .call_with_protocol(http_client.clone(), &coprocessor_url, protocol)
+        .await;
     let duration = start.elapsed().as_secs_f64();
     drop(guard);
     tracing::info!(
@@ -443,7 +452,7 @@ where
                 tracing::debug!(?payload, "externalized output");
                 let guard = generator_map_context.enter_active_request();
                 let co_processor_result = payload
-                    .call(generator_client, &generator_coprocessor_url)
+                    .call_with_protocol(generator_client, &generator_coprocessor_url, protocol)
                     .await;
                 drop(guard);
                 tracing::debug!(?co_processor_result, "co-processor returned");
@@ -674,6 +683,7 @@ mod tests {
             mock_http_client,
             mock_supergraph_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             Arc::new("".to_string()),
         );
 
@@ -742,6 +752,7 @@ mod tests {
             mock_http_client,
             mock_supergraph_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             Arc::new("".to_string()),
         );
 
@@ -867,6 +878,7 @@ mod tests {
             mock_http_client,
             mock_supergraph_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             Arc::new("".to_string()),
         );
 
@@ -978,6 +990,7 @@ mod tests {
             mock_http_client,
             mock_supergraph_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             Arc::new("".to_string()),
         );
 
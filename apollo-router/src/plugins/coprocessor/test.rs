@@ -133,6 +133,7 @@ mod tests {
             mock_http_client,
             mock_router_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             Arc::new("".to_string()),
         );
 
@@ -192,6 +193,7 @@ mod tests {
             mock_http_client,
             mock_router_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             Arc::new("".to_string()),
         );
 
@@ -250,6 +252,7 @@ mod tests {
             mock_http_client,
             mock_router_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             Arc::new("".to_string()),
         );
 
@@ -308,6 +311,7 @@ mod tests {
             mock_http_client,
             mock_subgraph_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             "my_subgraph_service_name".to_string(),
         );
 
@@ -438,6 +442,7 @@ mod tests {
             mock_http_client,
             mock_subgraph_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             "my_subgraph_service_name".to_string(),
         );
 
@@ -504,6 +509,7 @@ mod tests {
             mock_http_client,
             mock_subgraph_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             "my_subgraph_service_name".to_string(),
         );
 
@@ -562,6 +568,7 @@ mod tests {
             mock_http_client,
             mock_subgraph_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             "my_subgraph_service_name".to_string(),
         );
 
@@ -670,6 +677,7 @@ mod tests {
             mock_http_client,
             mock_subgraph_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             "my_subgraph_service_name".to_string(),
         );
 
@@ -808,6 +816,7 @@ mod tests {
             mock_http_client,
             mock_router_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             Arc::new("".to_string()),
         );
 
@@ -933,6 +942,7 @@ mod tests {
             mock_http_client,
             mock_router_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             Arc::new("".to_string()),
         );
 
@@ -1003,6 +1013,7 @@ mod tests {
             mock_http_client,
             mock_router_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             Arc::new("".to_string()),
         );
 
@@ -1081,6 +1092,7 @@ mod tests {
             mock_http_client,
             mock_router_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             Arc::new("".to_string()),
         );
 
@@ -1207,6 +1219,7 @@ mod tests {
             mock_http_client,
             mock_router_service.boxed(),
             "http://test".to_string(),
+            Protocol::Json,
             Arc::new("".to_string()),
         );
 
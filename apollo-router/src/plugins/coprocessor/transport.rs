@@ -0,0 +1,479 @@
+//! Transports to the coprocessor: HTTP or Unix domain sockets, with JSON or gRPC payloads
+
+use std::fmt::Debug;
+use std::task::Poll;
+use std::time::Duration;
+
+use http::header::CONTENT_TYPE;
+use http::header::TE;
+use http::HeaderMap;
+use http::HeaderValue;
+use http::Method;
+use http::Uri;
+use http_body::Body as _;
+use hyper::client::HttpConnector;
+use hyper::Body;
+use hyper_rustls::ConfigBuilderExt;
+use hyper_rustls::HttpsConnector;
+#[cfg(unix)]
+use hyperlocal::UnixConnector;
+use prost::Message;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use tower::BoxError;
+use tower::Service;
+
+use crate::services::external::inject_trace_context;
+use crate::services::external::Control;
+use crate::services::external::Externalizable;
+use crate::services::http::proxy::ProxyConnector;
+use crate::services::trust_dns_connector::new_async_http_connector;
+use crate::services::trust_dns_connector::AsyncHyperResolver;
+
+#[allow(unreachable_pub)]
+pub(crate) mod proto {
+    #![allow(clippy::derive_partial_eq_without_eq)]
+    tonic::include_proto!("coprocessor");
+}
+
+const POOL_IDLE_TIMEOUT_DURATION: Option<Duration> = Some(Duration::from_secs(5));
+const GRPC_PROCESS_PATH: &str = "/coprocessor.Coprocessor/Process";
+const GRPC_STATUS: &str = "grpc-status";
+const GRPC_MESSAGE: &str = "grpc-message";
+// compression flag and message length
+const GRPC_FRAME_HEADER_LENGTH: usize = 5;
+
+static APPLICATION_GRPC: HeaderValue = HeaderValue::from_static("application/grpc");
+static TRAILERS: HeaderValue = HeaderValue::from_static("trailers");
+
+/// Protocol used to exchange data with the coprocessor
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Protocol {
+    /// JSON payloads over HTTP
+    #[default]
+    Json,
+    /// gRPC calls to the `coprocessor.Coprocessor/Process` method, with protobuf payloads over HTTP/2
+    Grpc,
+}
+
+/// Converts a `unix:///path/to/socket` URL to a URI that the Unix socket connector can use
+pub(super) fn coprocessor_uri(url: &str) -> String {
+    #[cfg(unix)]
+    // there is no specified format for unix socket URLs (cf https://github.com/whatwg/url/issues/577)
+    // so the socket path is hidden in a hex encoded authority, like for subgraph URLs
+    if let Some(path) = url.strip_prefix("unix://") {
+        return Uri::from(hyperlocal::Uri::new(path, "/")).to_string();
+    }
+    url.to_string()
+}
+
+type HttpsClient =
+    hyper::Client<HttpsConnector<ProxyConnector<HttpConnector<AsyncHyperResolver>>>, Body>;
+#[cfg(unix)]
+type UnixClient = hyper::Client<UnixConnector, Body>;
+
+/// Sends requests over HTTP, or over a Unix domain socket for `unix://` URLs
+#[derive(Clone)]
+pub(super) struct TransportClient {
+    http_client: HttpsClient,
+    #[cfg(unix)]
+    unix_client: UnixClient,
+}
+
+impl TransportClient {
+    pub(super) fn new(protocol: Protocol) -> Result<Self, BoxError> {
+        let mut http_connector = new_async_http_connector()?;
+        http_connector.set_nodelay(true);
+        http_connector.set_keepalive(Some(std::time::Duration::from_secs(60)));
+        http_connector.enforce_http(false);
+
+        let tls_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_native_roots()
+            .with_no_client_auth();
+
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(tls_config)
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
+            .wrap_connector(ProxyConnector::new(http_connector));
+
+        // gRPC requires HTTP/2, including over cleartext connections
+        let http2_only = protocol == Protocol::Grpc;
+
+        Ok(Self {
+            http_client: hyper::Client::builder()
+                .pool_idle_timeout(POOL_IDLE_TIMEOUT_DURATION)
+                .http2_only(http2_only)
+                .build(connector),
+            #[cfg(unix)]
+            unix_client: hyper::Client::builder()
+                .pool_idle_timeout(POOL_IDLE_TIMEOUT_DURATION)
+                .http2_only(http2_only)
+                .build(UnixConnector),
+        })
+    }
+}
+
+impl Service<hyper::Request<Body>> for TransportClient {
+    type Response = hyper::Response<Body>;
+    type Error = hyper::Error;
+    type Future = hyper::client::ResponseFuture;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: hyper::Request<Body>) -> Self::Future {
+        #[cfg(unix)]
+        if request.uri().scheme_str() == Some("unix") {
+            return self.unix_client.request(request);
+        }
+        self.http_client.request(request)
+    }
+}
+
+impl<T> Externalizable<T>
+where
+    T: Debug + DeserializeOwned + Serialize + Send + Sync,
+{
+    /// Sends the payload to the coprocessor, encoded for the configured protocol
+    pub(super) async fn call_with_protocol<C>(
+        self,
+        client: C,
+        uri: &str,
+        protocol: Protocol,
+    ) -> Result<Self, BoxError>
+    where
+        C: Service<hyper::Request<Body>, Response = hyper::Response<Body>, Error = BoxError>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+    {
+        match protocol {
+            Protocol::Json => self.call(client, uri).await,
+            Protocol::Grpc => self.call_grpc(client, uri).await,
+        }
+    }
+
+    async fn call_grpc<C>(self, mut client: C, uri: &str) -> Result<Self, BoxError>
+    where
+        C: Service<hyper::Request<Body>, Response = hyper::Response<Body>, Error = BoxError>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+    {
+        let message = to_message(self)?;
+        tracing::debug!(?message, "forwarding protobuf");
+
+        let mut uri = uri.parse::<Uri>()?.into_parts();
+        uri.path_and_query = Some(GRPC_PROCESS_PATH.parse()?);
+        let mut request = hyper::Request::builder()
+            .uri(Uri::from_parts(uri)?)
+            .method(Method::POST)
+            .header(CONTENT_TYPE, APPLICATION_GRPC.clone())
+            .header(TE, TRAILERS.clone())
+            .body(Body::from(encode_frame(&message)))?;
+        inject_trace_context(request.headers_mut());
+
+        let response = client.call(request).await?;
+        let (parts, mut body) = response.into_parts();
+        // errors can be sent without a body, in the headers
+        check_grpc_status(&parts.headers)?;
+        let bytes = hyper::body::to_bytes(&mut body).await?;
+        if let Some(trailers) = body.trailers().await? {
+            check_grpc_status(&trailers)?;
+        }
+        from_message(decode_frame(&bytes)?)
+    }
+}
+
+fn check_grpc_status(headers: &HeaderMap) -> Result<(), BoxError> {
+    match headers.get(GRPC_STATUS).map(|status| status.as_bytes()) {
+        None | Some(b"0") => Ok(()),
+        Some(status) => Err(format!(
+            "coprocessor gRPC call failed with status {}: {}",
+            String::from_utf8_lossy(status),
+            headers
+                .get(GRPC_MESSAGE)
+                .map(|message| String::from_utf8_lossy(message.as_bytes()))
+                .unwrap_or_default()
+        )
+        .into()),
+    }
+}
+
+fn encode_frame(message: &proto::Externalizable) -> Vec<u8> {
+    let length = message.encoded_len();
+    let mut buf = Vec::with_capacity(GRPC_FRAME_HEADER_LENGTH + length);
+    // uncompressed
+    buf.push(0);
+    buf.extend_from_slice(&(length as u32).to_be_bytes());
+    message
+        .encode(&mut buf)
+        .expect("the buffer has enough capacity");
+    buf
+}
+
+fn decode_frame(bytes: &[u8]) -> Result<proto::Externalizable, BoxError> {
+    if bytes.len() < GRPC_FRAME_HEADER_LENGTH {
+        return Err("coprocessor gRPC response is missing a message".into());
+    }
+    if bytes[0] != 0 {
+        return Err("compressed coprocessor gRPC responses are not supported".into());
+    }
+    let length = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]) as usize;
+    let message = bytes
+        .get(GRPC_FRAME_HEADER_LENGTH..GRPC_FRAME_HEADER_LENGTH + length)
+        .ok_or("coprocessor gRPC response message is truncated")?;
+    Ok(proto::Externalizable::decode(message)?)
+}
+
+/// Builds the protobuf message. Values of arbitrary shape are sent as JSON
+fn to_message<T: Serialize>(payload: Externalizable<T>) -> Result<proto::Externalizable, BoxError> {
+    Ok(proto::Externalizable {
+        version: payload.version.into(),
+        stage: payload.stage,
+        control: payload.control.map(|control| proto::Control {
+            break_status_code: match control {
+                Control::Continue => None,
+                Control::Break(status) => Some(status.into()),
+            },
+        }),
+        id: payload.id,
+        headers: payload.headers.map(|headers| proto::Headers {
+            headers: headers
+                .into_iter()
+                .map(|(name, values)| (name, proto::HeaderValues { values }))
+                .collect(),
+        }),
+        body: payload
+            .body
+            .map(|body| serde_json::to_vec(&body))
+            .transpose()?,
+        context: payload
+            .context
+            .map(|context| serde_json::to_vec(&context))
+            .transpose()?,
+        sdl: payload.sdl,
+        uri: payload.uri,
+        method: payload.method,
+        path: payload.path,
+        service_name: payload.service_name,
+        status_code: payload.status_code.map(Into::into),
+        has_next: payload.has_next,
+        query_plan: payload
+            .query_plan
+            .map(|query_plan| serde_json::to_vec(&query_plan))
+            .transpose()?,
+    })
+}
+
+fn from_message<T: DeserializeOwned>(
+    message: proto::Externalizable,
+) -> Result<Externalizable<T>, BoxError> {
+    Ok(Externalizable {
+        version: message.version.try_into()?,
+        stage: message.stage,
+        control: message
+            .control
+            .map(|control| {
+                control
+                    .break_status_code
+                    .map(|status| status.try_into().map(Control::Break))
+                    .transpose()
+                    .map(Option::unwrap_or_default)
+            })
+            .transpose()?,
+        id: message.id,
+        headers: message.headers.map(|headers| {
+            headers
+                .headers
+                .into_iter()
+                .map(|(name, values)| (name, values.values))
+                .collect()
+        }),
+        body: message
+            .body
+            .map(|body| serde_json::from_slice(&body))
+            .transpose()?,
+        context: message
+            .context
+            .map(|context| serde_json::from_slice(&context))
+            .transpose()?,
+        sdl: message.sdl,
+        uri: message.uri,
+        method: message.method,
+        path: message.path,
+        service_name: message.service_name,
+        status_code: message.status_code.map(u16::try_from).transpose()?,
+        has_next: message.has_next,
+        query_plan: message
+            .query_plan
+            .map(|query_plan| serde_json::from_slice(&query_plan))
+            .transpose()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::services::external::PipelineStep;
+    use crate::Context;
+
+    fn payload() -> Externalizable<Value> {
+        let context = Context::new();
+        context.insert("key", "value".to_string()).unwrap();
+        Externalizable::subgraph_builder()
+            .stage(PipelineStep::SubgraphRequest)
+            .control(Control::Break(401))
+            .id("1b19c05fdafc521016df33148ad63c1b".to_string())
+            .and_headers(Some(HashMap::from([(
+                "cookie".to_string(),
+                vec!["a=1".to_string(), "b=2".to_string()],
+            )])))
+            .body(json!({ "query": "{ me { name } }" }))
+            .context(context)
+            .method("POST".to_string())
+            .service_name("accounts".to_string())
+            .uri("http://accounts/graphql".to_string())
+            .build()
+    }
+
+    #[test]
+    fn it_converts_externalizable_to_protobuf_and_back() {
+        let payload = payload();
+        let message = to_message(payload.clone()).unwrap();
+        assert_eq!(
+            message.control.as_ref().unwrap().break_status_code,
+            Some(401)
+        );
+        assert_eq!(
+            message.body.as_deref(),
+            Some(br#"{"query":"{ me { name } }"}"#.as_slice())
+        );
+        assert_eq!(decode_frame(&encode_frame(&message)).unwrap(), message);
+
+        let decoded: Externalizable<Value> = from_message(message).unwrap();
+        assert_eq!(
+            serde_json::to_value(decoded).unwrap(),
+            serde_json::to_value(payload).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn it_sends_grpc_requests() {
+        let client = tower::service_fn(|request: hyper::Request<Body>| async move {
+            assert_eq!(request.uri().path(), GRPC_PROCESS_PATH);
+            assert_eq!(
+                request.headers().get(CONTENT_TYPE).unwrap(),
+                "application/grpc"
+            );
+
+            let bytes = hyper::body::to_bytes(request.into_body()).await?;
+            let mut message = decode_frame(&bytes)?;
+            assert_eq!(message.stage, "RouterRequest");
+            message.control = Some(proto::Control {
+                break_status_code: Some(403),
+            });
+            message.body = Some(serde_json::to_vec(&json!("forbidden"))?);
+
+            Ok::<_, BoxError>(
+                hyper::Response::builder()
+                    .header(CONTENT_TYPE, "application/grpc")
+                    .header(GRPC_STATUS, "0")
+                    .body(Body::from(encode_frame(&message)))?,
+            )
+        });
+
+        let payload = Externalizable::<String>::router_builder()
+            .stage(PipelineStep::RouterRequest)
+            .control(Control::Continue)
+            .id("1b19c05fdafc521016df33148ad63c1b".to_string())
+            .body("{\"query\": \"{ me { name } }\"}".to_string())
+            .build();
+
+        let response = payload
+            .call_with_protocol(client, "http://127.0.0.1:8081", Protocol::Grpc)
+            .await
+            .unwrap();
+        assert_eq!(response.stage, "RouterRequest");
+        assert_eq!(response.control, Some(Control::Break(403)));
+        assert_eq!(response.body.as_deref(), Some("forbidden"));
+    }
+
+    #[tokio::test]
+    async fn it_fails_on_grpc_errors() {
+        let client = tower::service_fn(|_: hyper::Request<Body>| async {
+            Ok::<_, BoxError>(
+                hyper::Response::builder()
+                    .header(CONTENT_TYPE, "application/grpc")
+                    .header(GRPC_STATUS, "12")
+                    .header(GRPC_MESSAGE, "unimplemented")
+                    .body(Body::empty())?,
+            )
+        });
+
+        let payload = Externalizable::<String>::router_builder()
+            .stage(PipelineStep::RouterRequest)
+            .id(String::default())
+            .build();
+
+        let error = payload
+            .call_with_protocol(client, "http://127.0.0.1:8081", Protocol::Grpc)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "coprocessor gRPC call failed with status 12: unimplemented"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn it_sends_requests_over_unix_sockets() {
+        use hyperlocal::UnixServerExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("coprocessor.sock");
+        let server =
+            hyper::Server::bind_unix(&path)
+                .unwrap()
+                .serve(hyper::service::make_service_fn(|_| async {
+                    Ok::<_, hyper::Error>(hyper::service::service_fn(
+                        |request: hyper::Request<Body>| async move {
+                            Ok::<_, hyper::Error>(hyper::Response::new(request.into_body()))
+                        },
+                    ))
+                }));
+        tokio::spawn(server);
+
+        let uri = coprocessor_uri(&format!("unix://{}", path.display()));
+        let request = hyper::Request::builder()
+            .method("POST")
+            .uri(uri)
+            .body(Body::from("hello"))
+            .unwrap();
+        let response = TransportClient::new(Protocol::Json)
+            .unwrap()
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "hello"
+        );
+    }
+}
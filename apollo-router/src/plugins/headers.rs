@@ -19,6 +19,7 @@ use http::header::TE;
 use http::header::TRAILER;
 use http::header::TRANSFER_ENCODING;
 use http::header::UPGRADE;
+use http::HeaderMap;
 use http::HeaderValue;
 use regex::Regex;
 use schemars::JsonSchema;
@@ -39,6 +40,7 @@ use crate::plugin::serde::deserialize_regex;
 use crate::plugin::Plugin;
 use crate::plugin::PluginInit;
 use crate::register_plugin;
+use crate::services::router;
 use crate::services::subgraph;
 use crate::services::SubgraphRequest;
 
@@ -48,9 +50,11 @@ register_plugin!("apollo", "headers", Headers);
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 struct HeadersLocation {
     /// Propagate/Insert/Remove headers from request
+    #[serde(default)]
     request: Vec<Operation>,
-    // Propagate/Insert/Remove headers from response
-    // response: Option<Operation>
+    /// Propagate/Insert/Remove headers from the subgraph response to the client response
+    #[serde(default)]
+    response: Vec<ResponseOperation>,
 }
 
 #[derive(Clone, JsonSchema, Deserialize)]
@@ -180,6 +184,77 @@ enum Propagate {
     },
 }
 
+#[derive(Clone, JsonSchema, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum ResponseOperation {
+    Insert(ResponseInsert),
+    Remove(Remove),
+    Propagate(ResponsePropagate),
+}
+
+#[derive(Clone, JsonSchema, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+#[serde(untagged)]
+/// Insert header
+enum ResponseInsert {
+    /// Insert static header
+    Static(InsertStatic),
+    /// Insert header with a value coming from context key (works only for a string in the context)
+    FromContext(InsertFromContext),
+}
+
+#[derive(Clone, JsonSchema, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+#[serde(untagged)]
+/// Propagate header
+enum ResponsePropagate {
+    /// Propagate header given a header name
+    Named {
+        /// The source header name
+        #[schemars(with = "String")]
+        #[serde(deserialize_with = "deserialize_header_name")]
+        named: HeaderName,
+
+        /// An optional target header name
+        #[schemars(with = "Option<String>", default)]
+        #[serde(deserialize_with = "deserialize_option_header_name", default)]
+        rename: Option<HeaderName>,
+
+        /// Default value for the header.
+        #[schemars(with = "Option<String>", default)]
+        #[serde(deserialize_with = "deserialize_option_header_value", default)]
+        default: Option<HeaderValue>,
+
+        /// How to merge the header with the values propagated from other subgraph responses
+        #[serde(default)]
+        merge: MergeStrategy,
+    },
+    /// Propagate header given a regex to match header name
+    Matching {
+        /// The regex on header name
+        #[schemars(schema_with = "propagate_matching")]
+        #[serde(deserialize_with = "deserialize_regex")]
+        matching: Regex,
+
+        /// How to merge the headers with the values propagated from other subgraph responses
+        #[serde(default)]
+        merge: MergeStrategy,
+    },
+}
+
+/// Merge strategy for a header propagated from several subgraph responses
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, JsonSchema, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum MergeStrategy {
+    /// Keep the values of the first subgraph response containing the header
+    First,
+    /// Keep the values of the last subgraph response containing the header
+    #[default]
+    Last,
+    /// Append the values of all the subgraph responses containing the header
+    Append,
+}
+
 /// Configuration for header propagation
 #[derive(Clone, JsonSchema, Default, Deserialize)]
 #[serde(rename_all = "snake_case", deny_unknown_fields, default)]
@@ -193,6 +268,8 @@ struct Config {
 struct Headers {
     all_operations: Arc<Vec<Operation>>,
     subgraph_operations: HashMap<String, Arc<Vec<Operation>>>,
+    all_response_operations: Arc<Vec<ResponseOperation>>,
+    subgraph_response_operations: HashMap<String, Arc<Vec<ResponseOperation>>>,
 }
 
 #[async_trait::async_trait]
@@ -217,13 +294,66 @@ impl Plugin for Headers {
             })
             .collect();
 
+        // Subgraph specific response rules are applied after the rules for all subgraphs,
+        // so they can override them
+        let response_operations: Vec<ResponseOperation> = init
+            .config
+            .all
+            .as_ref()
+            .map(|a| a.response.clone())
+            .unwrap_or_default();
+        let subgraph_response_operations = init
+            .config
+            .subgraphs
+            .iter()
+            .map(|(subgraph_name, op)| {
+                let mut operations = response_operations.clone();
+                operations.append(&mut op.response.clone());
+                (subgraph_name.clone(), Arc::new(operations))
+            })
+            .collect();
+
         Ok(Headers {
             all_operations: Arc::new(operations),
             subgraph_operations,
+            all_response_operations: Arc::new(response_operations),
+            subgraph_response_operations,
         })
     }
 
+    fn router_service(&self, service: router::BoxService) -> router::BoxService {
+        if self.all_response_operations.is_empty()
+            && self
+                .subgraph_response_operations
+                .values()
+                .all(|operations| operations.is_empty())
+        {
+            return service;
+        }
+
+        ServiceBuilder::new()
+            .map_response(|mut response: router::Response| {
+                let propagated = response
+                    .context
+                    .extensions()
+                    .lock()
+                    .remove::<PropagatedResponseHeaders>();
+                if let Some(propagated) = propagated {
+                    propagated.apply(response.response.headers_mut());
+                }
+                response
+            })
+            .service(service)
+            .boxed()
+    }
+
     fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        let response_operations = self
+            .subgraph_response_operations
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| self.all_response_operations.clone());
+
         ServiceBuilder::new()
             .layer(HeadersLayer::new(
                 self.subgraph_operations
@@ -231,11 +361,141 @@ impl Plugin for Headers {
                     .cloned()
                     .unwrap_or_else(|| self.all_operations.clone()),
             ))
+            .map_response(move |response: subgraph::Response| {
+                if !response_operations.is_empty() {
+                    propagate_response_headers(&response_operations, &response);
+                }
+                response
+            })
             .service(service)
             .boxed()
     }
 }
 
+/// Headers propagated from the subgraph responses, merged into the client response
+#[derive(Default)]
+struct PropagatedResponseHeaders {
+    headers: HashMap<HeaderName, (MergeStrategy, Vec<HeaderValue>)>,
+}
+
+impl PropagatedResponseHeaders {
+    fn merge(&mut self, name: HeaderName, merge: MergeStrategy, mut values: Vec<HeaderValue>) {
+        match self.headers.get_mut(&name) {
+            None => {
+                self.headers.insert(name, (merge, values));
+            }
+            Some(_) if merge == MergeStrategy::First => {}
+            Some(existing) if merge == MergeStrategy::Append => {
+                existing.0 = merge;
+                existing.1.append(&mut values);
+            }
+            Some(existing) => *existing = (merge, values),
+        }
+    }
+
+    fn apply(self, headers: &mut HeaderMap) {
+        for (name, (merge, values)) in self.headers {
+            if merge != MergeStrategy::Append {
+                headers.remove(&name);
+            }
+            for value in values {
+                headers.append(&name, value);
+            }
+        }
+    }
+}
+
+/// Collects the headers of a subgraph response that must be propagated to the client response
+fn propagate_response_headers(operations: &[ResponseOperation], response: &subgraph::Response) {
+    let subgraph_headers = response.response.headers();
+    let mut headers: HashMap<HeaderName, (MergeStrategy, Vec<HeaderValue>)> = HashMap::new();
+
+    for operation in operations {
+        match operation {
+            ResponseOperation::Insert(ResponseInsert::Static(static_insert)) => {
+                headers.insert(
+                    static_insert.name.clone(),
+                    (MergeStrategy::Last, vec![static_insert.value.clone()]),
+                );
+            }
+            ResponseOperation::Insert(ResponseInsert::FromContext(insert_from_context)) => {
+                if let Some(val) = response
+                    .context
+                    .get::<_, String>(&insert_from_context.from_context)
+                    .ok()
+                    .flatten()
+                {
+                    match HeaderValue::from_str(&val) {
+                        Ok(header_value) => {
+                            headers.insert(
+                                insert_from_context.name.clone(),
+                                (MergeStrategy::Last, vec![header_value]),
+                            );
+                        }
+                        Err(err) => {
+                            tracing::error!("cannot convert from the context into a header value for header name '{}': {:?}", insert_from_context.name, err);
+                        }
+                    }
+                }
+            }
+            ResponseOperation::Remove(Remove::Named(name)) => {
+                headers.remove(name);
+            }
+            ResponseOperation::Remove(Remove::Matching(matching)) => {
+                headers.retain(|name, _| !matching.is_match(name.as_str()));
+            }
+            ResponseOperation::Propagate(ResponsePropagate::Named {
+                named,
+                rename,
+                default,
+                merge,
+            }) => {
+                let name = rename.as_ref().unwrap_or(named);
+                // Reserved headers describe the subgraph connection, not the client response
+                if RESERVED_HEADERS.contains(named) || RESERVED_HEADERS.contains(name) {
+                    continue;
+                }
+                let mut values: Vec<HeaderValue> =
+                    subgraph_headers.get_all(named).iter().cloned().collect();
+                if values.is_empty() {
+                    values.extend(default.clone());
+                }
+                if !values.is_empty() {
+                    headers.insert(name.clone(), (*merge, values));
+                }
+            }
+            ResponseOperation::Propagate(ResponsePropagate::Matching { matching, merge }) => {
+                for name in subgraph_headers.keys().filter(|name| {
+                    !RESERVED_HEADERS.contains(*name) && matching.is_match(name.as_str())
+                }) {
+                    headers.insert(
+                        name.clone(),
+                        (
+                            *merge,
+                            subgraph_headers.get_all(name).iter().cloned().collect(),
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    if headers.is_empty() {
+        return;
+    }
+
+    let mut extensions = response.context.extensions().lock();
+    if extensions.get::<PropagatedResponseHeaders>().is_none() {
+        extensions.insert(PropagatedResponseHeaders::default());
+    }
+    let propagated = extensions
+        .get_mut::<PropagatedResponseHeaders>()
+        .expect("inserted above");
+    for (name, (merge, values)) in headers {
+        propagated.merge(name, merge, values);
+    }
+}
+
 struct HeadersLayer {
     operations: Arc<Vec<Operation>>,
     reserved_headers: Arc<HashSet<&'static HeaderName>>,
@@ -839,6 +1099,7 @@ mod test {
             connection_closed_signal: None,
             query_hash: Default::default(),
             authorization: Default::default(),
+            deferred: false,
         };
         service.modify_request(&mut request);
         let headers = request
@@ -910,6 +1171,7 @@ mod test {
             connection_closed_signal: None,
             query_hash: Default::default(),
             authorization: Default::default(),
+            deferred: false,
         };
         service.modify_request(&mut request);
         let headers = request
@@ -926,6 +1188,155 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_response_config() {
+        serde_yaml::from_str::<Config>(
+            r#"
+        all:
+            response:
+                - propagate:
+                    named: "set-cookie"
+                    merge: append
+                - propagate:
+                    matching: "x-ratelimit-.*"
+                    merge: first
+                - insert:
+                    name: "test"
+                    value: "test"
+                - remove:
+                    named: "test"
+        "#,
+        )
+        .unwrap();
+
+        assert!(serde_yaml::from_str::<Config>(
+            r#"
+        all:
+            response:
+                - propagate:
+                    named: "set-cookie"
+                    merge: concat
+        "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_propagate_response_headers() -> Result<(), BoxError> {
+        let operations = vec![
+            ResponseOperation::Propagate(ResponsePropagate::Named {
+                named: HeaderName::from_static("set-cookie"),
+                rename: None,
+                default: None,
+                merge: MergeStrategy::Append,
+            }),
+            ResponseOperation::Propagate(ResponsePropagate::Matching {
+                matching: Regex::from_str("x-ratelimit-.*")?,
+                merge: MergeStrategy::First,
+            }),
+            ResponseOperation::Propagate(ResponsePropagate::Named {
+                named: HeaderName::from_static("x-version"),
+                rename: Some(HeaderName::from_static("x-subgraph-version")),
+                default: None,
+                merge: MergeStrategy::Last,
+            }),
+            ResponseOperation::Insert(ResponseInsert::Static(InsertStatic {
+                name: "x-internal-id".try_into()?,
+                value: "secret".try_into()?,
+            })),
+            ResponseOperation::Remove(Remove::Matching(Regex::from_str("x-internal-.*")?)),
+        ];
+
+        let context = Context::new();
+        for (cookie, remaining, version) in [("a=1", "10", "1"), ("b=2", "5", "2")] {
+            let response = SubgraphResponse::new_from_response(
+                http::Response::builder()
+                    .header("set-cookie", cookie)
+                    .header("x-ratelimit-remaining", remaining)
+                    .header("x-version", version)
+                    .header("x-internal-id", "secret")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(crate::graphql::Response::default())
+                    .expect("expecting valid response"),
+                context.clone(),
+            );
+            propagate_response_headers(&operations, &response);
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert("set-cookie", HeaderValue::from_static("router=1"));
+        context
+            .extensions()
+            .lock()
+            .remove::<PropagatedResponseHeaders>()
+            .expect("headers were propagated")
+            .apply(&mut headers);
+
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.to_str().unwrap()))
+            .collect::<HashSet<_>>();
+        assert_eq!(
+            headers,
+            [
+                ("content-type", "application/json"),
+                ("set-cookie", "router=1"),
+                ("set-cookie", "a=1"),
+                ("set-cookie", "b=2"),
+                ("x-ratelimit-remaining", "10"),
+                ("x-subgraph-version", "2"),
+            ]
+            .into_iter()
+            .collect::<HashSet<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_propagate_response_headers_skips_reserved_headers() {
+        let named = |named: HeaderName, rename: Option<HeaderName>| {
+            ResponseOperation::Propagate(ResponsePropagate::Named {
+                named,
+                rename,
+                default: None,
+                merge: MergeStrategy::Last,
+            })
+        };
+        let operations = vec![
+            named(CONTENT_LENGTH, None),
+            named(TRANSFER_ENCODING, None),
+            named(CONNECTION, None),
+            named(HeaderName::from_static("x-length"), Some(CONTENT_LENGTH)),
+            named(HeaderName::from_static("x-version"), None),
+        ];
+
+        let context = Context::new();
+        let response = SubgraphResponse::new_from_response(
+            http::Response::builder()
+                .header(CONTENT_LENGTH, "1234")
+                .header(TRANSFER_ENCODING, "chunked")
+                .header(CONNECTION, "close")
+                .header("x-length", "12")
+                .header("x-version", "1")
+                .body(crate::graphql::Response::default())
+                .expect("expecting valid response"),
+            context.clone(),
+        );
+        propagate_response_headers(&operations, &response);
+
+        let mut headers = HeaderMap::new();
+        context
+            .extensions()
+            .lock()
+            .remove::<PropagatedResponseHeaders>()
+            .expect("headers were propagated")
+            .apply(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["x-version"], "1");
+    }
+
     fn example_response(_: SubgraphRequest) -> Result<SubgraphResponse, BoxError> {
         Ok(SubgraphResponse::new_from_response(
             http::Response::default(),
@@ -971,6 +1382,7 @@ mod test {
             connection_closed_signal: None,
             query_hash: Default::default(),
             authorization: Default::default(),
+            deferred: false,
         }
     }
 
@@ -34,6 +34,7 @@ pub(crate) mod override_url;
 pub(crate) mod progressive_override;
 mod record_replay;
 pub(crate) mod rhai;
+mod subgraph_discovery;
 pub(crate) mod subscription;
 pub(crate) mod telemetry;
 pub(crate) mod traffic_shaping;
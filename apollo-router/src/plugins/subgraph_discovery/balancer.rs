@@ -0,0 +1,356 @@
+//! Load balancing across the endpoints of a subgraph, with passive outlier ejection
+
+use std::cmp::Ordering as CmpOrdering;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use http::uri::Authority;
+use parking_lot::Mutex;
+use parking_lot::RwLock;
+use tokio::time::Instant;
+
+/// Time after which an observed latency only weighs for 1/e in the moving average
+const EWMA_DECAY: Duration = Duration::from_secs(10);
+
+/// How requests are spread across the endpoints
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Policy {
+    /// Each endpoint in turn
+    RoundRobin,
+    /// The endpoint with the fewest requests in flight
+    LeastRequests,
+    /// The endpoint with the lowest moving average of latency, weighted by its requests in flight
+    Ewma,
+}
+
+/// Endpoints are ejected for some time after consecutive failures
+#[derive(Clone, Debug)]
+pub(crate) struct OutlierDetection {
+    pub(crate) consecutive_failures: u32,
+    pub(crate) ejection_time: Duration,
+    /// Maximum share of the endpoints that can be ejected at the same time, in percent
+    pub(crate) max_ejection_percent: u8,
+}
+
+struct Endpoint {
+    authority: Authority,
+    in_flight: AtomicUsize,
+    stats: Mutex<EndpointStats>,
+}
+
+#[derive(Default)]
+struct EndpointStats {
+    /// Moving average of the latency in seconds, if a response was received
+    latency: Option<f64>,
+    last_response: Option<Instant>,
+    consecutive_failures: u32,
+    ejected_until: Option<Instant>,
+}
+
+impl Endpoint {
+    fn new(authority: Authority) -> Self {
+        Self {
+            authority,
+            in_flight: AtomicUsize::new(0),
+            stats: Mutex::new(EndpointStats::default()),
+        }
+    }
+
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.stats
+            .lock()
+            .ejected_until
+            .is_some_and(|until| until > now)
+    }
+}
+
+/// Endpoints of a subgraph, shared by all its requests
+pub(crate) struct Balancer {
+    subgraph: String,
+    policy: Policy,
+    outlier_detection: Option<OutlierDetection>,
+    endpoints: RwLock<Arc<Vec<Arc<Endpoint>>>>,
+    next: AtomicUsize,
+}
+
+impl Balancer {
+    pub(crate) fn new(
+        subgraph: String,
+        policy: Policy,
+        outlier_detection: Option<OutlierDetection>,
+    ) -> Self {
+        Self {
+            subgraph,
+            policy,
+            outlier_detection,
+            endpoints: Default::default(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Replaces the endpoints. The statistics of the endpoints that remain are kept
+    pub(crate) fn update(&self, authorities: Vec<Authority>) {
+        let mut endpoints = self.endpoints.write();
+        let mut updated: Vec<Arc<Endpoint>> = Vec::with_capacity(authorities.len());
+        for authority in authorities {
+            if updated
+                .iter()
+                .any(|endpoint| endpoint.authority == authority)
+            {
+                continue;
+            }
+            let endpoint = endpoints
+                .iter()
+                .find(|endpoint| endpoint.authority == authority)
+                .cloned()
+                .unwrap_or_else(|| Arc::new(Endpoint::new(authority)));
+            updated.push(endpoint);
+        }
+
+        tracing::info!(
+            value.apollo.router.subgraph.discovery.endpoints = updated.len() as u64,
+            subgraph = self.subgraph.as_str(),
+        );
+        *endpoints = Arc::new(updated);
+    }
+
+    /// Chooses the endpoint of a request. Returns `None` if no endpoint was resolved
+    pub(crate) fn select(self: &Arc<Self>) -> Option<Selected> {
+        let endpoints = self.endpoints.read().clone();
+        if endpoints.is_empty() {
+            return None;
+        }
+
+        let now = Instant::now();
+        let available = endpoints
+            .iter()
+            .filter(|endpoint| !endpoint.is_ejected(now))
+            .collect::<Vec<_>>();
+        // if all the endpoints are ejected, they are all used again rather than failing every request
+        let candidates = if available.is_empty() {
+            endpoints.iter().collect()
+        } else {
+            available
+        };
+
+        // ties are broken in turn, starting from a different endpoint for each request
+        let offset = self.next.fetch_add(1, Ordering::Relaxed);
+        let in_turn = (0..candidates.len()).map(|i| candidates[(offset + i) % candidates.len()]);
+        let endpoint = match self.policy {
+            Policy::RoundRobin => candidates[offset % candidates.len()],
+            Policy::LeastRequests => in_turn
+                .min_by_key(|endpoint| endpoint.in_flight.load(Ordering::Relaxed))
+                .expect("there is at least one candidate; qed"),
+            Policy::Ewma => {
+                let latencies = candidates
+                    .iter()
+                    .map(|endpoint| endpoint.stats.lock().latency)
+                    .collect::<Vec<_>>();
+                // endpoints without a response yet are assumed to be as slow as the slowest one
+                let unknown = latencies
+                    .iter()
+                    .flatten()
+                    .copied()
+                    .fold(None, |max: Option<f64>, latency| {
+                        Some(max.map_or(latency, |max| max.max(latency)))
+                    })
+                    .unwrap_or(1.0);
+                in_turn
+                    .map(|endpoint| {
+                        let index = candidates
+                            .iter()
+                            .position(|candidate| Arc::ptr_eq(candidate, endpoint))
+                            .expect("the endpoint is a candidate; qed");
+                        let latency = latencies[index].unwrap_or(unknown);
+                        let in_flight = endpoint.in_flight.load(Ordering::Relaxed);
+                        (endpoint, latency * (in_flight + 1) as f64)
+                    })
+                    .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(CmpOrdering::Equal))
+                    .map(|(endpoint, _)| endpoint)
+                    .expect("there is at least one candidate; qed")
+            }
+        };
+
+        Some(Selected::new(self.clone(), endpoint.clone()))
+    }
+
+    fn record(&self, endpoint: &Endpoint, latency: Duration, success: bool) {
+        let now = Instant::now();
+        {
+            let mut stats = endpoint.stats.lock();
+            if success {
+                // peak EWMA: latency spikes are taken into account immediately, and then decay
+                let latency = latency.as_secs_f64();
+                stats.latency = Some(match (stats.latency, stats.last_response) {
+                    (Some(average), Some(last)) if latency < average => {
+                        let weight = (-(now - last).as_secs_f64() / EWMA_DECAY.as_secs_f64()).exp();
+                        average * weight + latency * (1.0 - weight)
+                    }
+                    _ => latency,
+                });
+                stats.last_response = Some(now);
+                stats.consecutive_failures = 0;
+                return;
+            }
+
+            stats.consecutive_failures += 1;
+            match &self.outlier_detection {
+                Some(detection)
+                    if stats.consecutive_failures >= detection.consecutive_failures
+                        && stats.ejected_until.map_or(true, |until| until <= now) => {}
+                _ => return,
+            }
+        }
+
+        let Some(detection) = &self.outlier_detection else {
+            return;
+        };
+        let endpoints = self.endpoints.read().clone();
+        let ejected = endpoints
+            .iter()
+            .filter(|endpoint| endpoint.is_ejected(now))
+            .count();
+        if (ejected + 1) * 100 > endpoints.len() * detection.max_ejection_percent as usize {
+            return;
+        }
+
+        let mut stats = endpoint.stats.lock();
+        stats.ejected_until = Some(now + detection.ejection_time);
+        stats.consecutive_failures = 0;
+        drop(stats);
+
+        tracing::warn!(
+            "ejecting endpoint {} of subgraph {} for {:?} after {} consecutive failures",
+            endpoint.authority,
+            self.subgraph,
+            detection.ejection_time,
+            detection.consecutive_failures
+        );
+        u64_counter!(
+            "apollo.router.subgraph.discovery.ejections",
+            "Number of subgraph endpoints ejected after consecutive failures",
+            1,
+            subgraph = self.subgraph.clone()
+        );
+    }
+}
+
+/// Endpoint chosen for a request, counted as in flight until it is dropped
+pub(crate) struct Selected {
+    balancer: Arc<Balancer>,
+    endpoint: Arc<Endpoint>,
+    start: Instant,
+}
+
+impl Selected {
+    fn new(balancer: Arc<Balancer>, endpoint: Arc<Endpoint>) -> Self {
+        endpoint.in_flight.fetch_add(1, Ordering::Relaxed);
+        Self {
+            balancer,
+            endpoint,
+            start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn authority(&self) -> &Authority {
+        &self.endpoint.authority
+    }
+
+    /// Records the outcome of the request. Without it, the request does not change the
+    /// statistics of the endpoint
+    pub(crate) fn complete(self, success: bool) {
+        self.balancer
+            .record(&self.endpoint, self.start.elapsed(), success);
+    }
+}
+
+impl Drop for Selected {
+    fn drop(&mut self) {
+        self.endpoint.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balancer(policy: Policy) -> Arc<Balancer> {
+        let balancer = Arc::new(Balancer::new(
+            "test".to_string(),
+            policy,
+            Some(OutlierDetection {
+                consecutive_failures: 2,
+                ejection_time: Duration::from_secs(30),
+                max_ejection_percent: 50,
+            }),
+        ));
+        balancer.update(vec![
+            Authority::from_static("a:4001"),
+            Authority::from_static("b:4001"),
+        ]);
+        balancer
+    }
+
+    fn select(balancer: &Arc<Balancer>) -> Selected {
+        balancer.select().unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ejects_failing_endpoints() {
+        let balancer = balancer(Policy::RoundRobin);
+        assert_eq!(select(&balancer).authority(), "a:4001");
+        assert_eq!(select(&balancer).authority(), "b:4001");
+
+        for _ in 0..2 {
+            let selected = select(&balancer);
+            assert_eq!(selected.authority(), "a:4001");
+            selected.complete(false);
+            select(&balancer).complete(true);
+        }
+        // a is ejected
+        assert_eq!(select(&balancer).authority(), "b:4001");
+        assert_eq!(select(&balancer).authority(), "b:4001");
+
+        // b cannot be ejected: it would eject more than half of the endpoints
+        for _ in 0..2 {
+            select(&balancer).complete(false);
+        }
+        assert_eq!(select(&balancer).authority(), "b:4001");
+
+        tokio::time::advance(Duration::from_secs(31)).await;
+        let authorities = (0..2)
+            .map(|_| select(&balancer).authority().to_string())
+            .collect::<Vec<_>>();
+        assert!(authorities.contains(&"a:4001".to_string()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn least_requests_and_ewma() {
+        let balancer = balancer(Policy::LeastRequests);
+        let first = select(&balancer);
+        let second = select(&balancer);
+        assert_ne!(first.authority(), second.authority());
+        drop(first);
+        let third = select(&balancer);
+        assert_ne!(third.authority(), second.authority());
+
+        let balancer = self::balancer(Policy::Ewma);
+        let slow = select(&balancer);
+        let fast = select(&balancer);
+        let (slow_authority, fast_authority) = (slow.authority().clone(), fast.authority().clone());
+        tokio::time::advance(Duration::from_millis(250)).await;
+        fast.complete(true);
+        tokio::time::advance(Duration::from_millis(850)).await;
+        slow.complete(true);
+        assert_eq!(select(&balancer).authority(), &fast_authority);
+
+        // the fast endpoint is chosen until its requests in flight outweigh its lower latency
+        let in_flight = (0..4).map(|_| select(&balancer)).collect::<Vec<_>>();
+        assert!(in_flight
+            .iter()
+            .all(|selected| selected.authority() == &fast_authority));
+        assert_eq!(select(&balancer).authority(), &slow_authority);
+    }
+}
@@ -0,0 +1,384 @@
+//! Discovery of subgraph endpoints.
+//!
+//! The endpoints of a subgraph are resolved from DNS SRV records or from a watched file, and each
+//! request is sent to one of them. The request keeps the subgraph URL, so its `Host` header and
+//! TLS server name do not change, and the HTTP client connects to the chosen endpoint.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use http::uri::Authority;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+use tower::BoxError;
+use tower::Service;
+use tower::ServiceExt;
+
+use self::balancer::Balancer;
+use self::balancer::OutlierDetection;
+use self::balancer::Policy;
+use self::resolver::DnsSrvResolver;
+use self::resolver::FileResolver;
+use self::resolver::Resolver;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::register_plugin;
+use crate::services::http::Endpoint;
+use crate::services::subgraph;
+
+mod balancer;
+mod resolver;
+
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_CONSECUTIVE_FAILURES: u32 = 5;
+const DEFAULT_EJECTION_TIME: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_EJECTION_PERCENT: u8 = 50;
+
+/// Resolve the endpoints of subgraphs with service discovery, and balance requests across them
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Endpoint discovery, by subgraph name
+    #[serde(default)]
+    subgraphs: HashMap<String, SubgraphDiscoveryConf>,
+}
+
+/// Endpoint discovery of a subgraph
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct SubgraphDiscoveryConf {
+    /// Where the endpoints come from
+    source: SourceConf,
+    /// How requests are spread across the endpoints (default: round_robin)
+    load_balancing: Option<LoadBalancingConf>,
+    /// Eject endpoints for some time after consecutive failures. Disabled if not set
+    outlier_detection: Option<OutlierDetectionConf>,
+}
+
+/// Source of the endpoints of a subgraph
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+enum SourceConf {
+    /// DNS SRV records of a service name. Only the records with the lowest priority value are used
+    DnsSrv {
+        /// Service name, like `_graphql._tcp.products.example.com`
+        name: String,
+        #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+        #[schemars(with = "String", default)]
+        /// Delay between DNS lookups (default: 30s)
+        refresh_interval: Option<Duration>,
+    },
+    /// YAML or JSON file containing a list of `host:port` endpoints, watched for changes
+    File {
+        /// Path of the file
+        path: PathBuf,
+    },
+}
+
+/// How requests are spread across the endpoints of a subgraph
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum LoadBalancingConf {
+    /// Each endpoint in turn
+    RoundRobin,
+    /// The endpoint with the fewest requests in flight
+    LeastRequests,
+    /// The endpoint with the lowest moving average of latency, weighted by its requests in flight
+    Ewma,
+}
+
+/// Passive outlier ejection. Transport errors and 5xx responses are failures
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct OutlierDetectionConf {
+    /// Number of consecutive failures after which an endpoint is ejected (default: 5)
+    consecutive_failures: Option<u32>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Time during which an ejected endpoint does not receive requests (default: 30s)
+    ejection_time: Option<Duration>,
+    /// Maximum share of the endpoints ejected at the same time, in percent (default: 50)
+    max_ejection_percent: Option<u8>,
+}
+
+impl From<LoadBalancingConf> for Policy {
+    fn from(conf: LoadBalancingConf) -> Self {
+        match conf {
+            LoadBalancingConf::RoundRobin => Policy::RoundRobin,
+            LoadBalancingConf::LeastRequests => Policy::LeastRequests,
+            LoadBalancingConf::Ewma => Policy::Ewma,
+        }
+    }
+}
+
+impl OutlierDetectionConf {
+    fn outlier_detection(&self) -> Result<OutlierDetection, BoxError> {
+        let max_ejection_percent = self
+            .max_ejection_percent
+            .unwrap_or(DEFAULT_MAX_EJECTION_PERCENT);
+        if max_ejection_percent > 100 {
+            return Err("max_ejection_percent must be between 0 and 100".into());
+        }
+        Ok(OutlierDetection {
+            consecutive_failures: self
+                .consecutive_failures
+                .unwrap_or(DEFAULT_CONSECUTIVE_FAILURES)
+                .max(1),
+            ejection_time: self.ejection_time.unwrap_or(DEFAULT_EJECTION_TIME),
+            max_ejection_percent,
+        })
+    }
+}
+
+struct SubgraphDiscovery {
+    discoveries: HashMap<String, Arc<Discovery>>,
+}
+
+/// Endpoints of a subgraph, kept up to date by a background task until the plugin and the
+/// services using them are dropped
+struct Discovery {
+    balancer: Arc<Balancer>,
+    resolution: JoinHandle<()>,
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        self.resolution.abort();
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for SubgraphDiscovery {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let mut discoveries = HashMap::new();
+        for (name, conf) in init.config.subgraphs {
+            let outlier_detection = conf
+                .outlier_detection
+                .as_ref()
+                .map(OutlierDetectionConf::outlier_detection)
+                .transpose()?;
+            let balancer = Arc::new(Balancer::new(
+                name.clone(),
+                conf.load_balancing
+                    .map(Policy::from)
+                    .unwrap_or(Policy::RoundRobin),
+                outlier_detection,
+            ));
+
+            let resolver: Box<dyn Resolver> = match conf.source {
+                SourceConf::DnsSrv {
+                    name,
+                    refresh_interval,
+                } => Box::new(DnsSrvResolver::new(
+                    name,
+                    refresh_interval.unwrap_or(DEFAULT_REFRESH_INTERVAL),
+                )?),
+                SourceConf::File { path } => Box::new(FileResolver::new(path)),
+            };
+
+            // the first endpoints are resolved before the router starts
+            let mut endpoints = resolver.endpoints();
+            if let Some(result) = endpoints.next().await {
+                update(&name, &balancer, result);
+            }
+
+            let weak = Arc::downgrade(&balancer);
+            let subgraph = name.clone();
+            let resolution = tokio::task::spawn(async move {
+                while let Some(result) = endpoints.next().await {
+                    let Some(balancer) = weak.upgrade() else {
+                        break;
+                    };
+                    update(&subgraph, &balancer, result);
+                }
+            });
+
+            discoveries.insert(
+                name,
+                Arc::new(Discovery {
+                    balancer,
+                    resolution,
+                }),
+            );
+        }
+
+        Ok(SubgraphDiscovery { discoveries })
+    }
+
+    fn subgraph_service(
+        &self,
+        subgraph_name: &str,
+        service: subgraph::BoxService,
+    ) -> subgraph::BoxService {
+        match self.discoveries.get(subgraph_name) {
+            Some(discovery) => DiscoveryService {
+                service,
+                discovery: discovery.clone(),
+            }
+            .boxed(),
+            None => service,
+        }
+    }
+}
+
+fn update(subgraph: &str, balancer: &Balancer, result: Result<Vec<Authority>, BoxError>) {
+    match result {
+        Ok(endpoints) => {
+            if endpoints.is_empty() {
+                tracing::warn!(
+                    "no endpoint found for subgraph {subgraph}, requests are sent to its routing URL"
+                );
+            }
+            balancer.update(endpoints);
+        }
+        // the previous endpoints are kept
+        Err(e) => tracing::error!("could not resolve the endpoints of subgraph {subgraph}: {e}"),
+    }
+}
+
+/// Sends each subgraph request to one of the endpoints chosen by the balancer
+struct DiscoveryService {
+    service: subgraph::BoxService,
+    discovery: Arc<Discovery>,
+}
+
+impl Service<subgraph::Request> for DiscoveryService {
+    type Response = subgraph::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: subgraph::Request) -> Self::Future {
+        // Unix socket URLs are left untouched
+        let selected = if matches!(
+            request.subgraph_request.uri().scheme_str(),
+            Some("http" | "https")
+        ) {
+            self.discovery.balancer.select()
+        } else {
+            None
+        };
+        if let Some(selected) = &selected {
+            request
+                .subgraph_request
+                .extensions_mut()
+                .insert(Endpoint(selected.authority().clone()));
+        }
+
+        let response = self.service.call(request);
+        Box::pin(async move {
+            let response = response.await;
+            if let Some(selected) = selected {
+                selected.complete(matches!(
+                    &response,
+                    Ok(response) if !response.response.status().is_server_error()
+                ));
+            }
+            response
+        })
+    }
+}
+
+register_plugin!(
+    "apollo",
+    "experimental_subgraph_discovery",
+    SubgraphDiscovery
+);
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use serde_json::Value;
+    use tower::util::BoxService;
+    use tower::Service;
+    use tower::ServiceExt;
+
+    use crate::plugin::test::MockSubgraphService;
+    use crate::plugin::DynPlugin;
+    use crate::services::http::Endpoint;
+    use crate::services::SubgraphRequest;
+    use crate::services::SubgraphResponse;
+
+    #[tokio::test]
+    async fn requests_are_sent_to_the_resolved_endpoints() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("endpoints.yaml");
+        std::fs::write(&path, "- 10.0.0.1:4001\n- 10.0.0.2:4001\n").unwrap();
+
+        let mut mock_service = MockSubgraphService::new();
+        let mut sequence = mockall::Sequence::new();
+        for authority in ["10.0.0.1:4001", "10.0.0.2:4001", "10.0.0.1:4001"] {
+            mock_service
+                .expect_call()
+                .withf(move |req| {
+                    req.subgraph_request.uri() == "http://products.example.com/graphql"
+                        && req
+                            .subgraph_request
+                            .extensions()
+                            .get::<Endpoint>()
+                            .map(|endpoint| endpoint.0.as_str())
+                            == Some(authority)
+                })
+                .times(1)
+                .in_sequence(&mut sequence)
+                .returning(|req: SubgraphRequest| {
+                    Ok(SubgraphResponse::fake_builder()
+                        .context(req.context)
+                        .build())
+                });
+        }
+
+        let dyn_plugin: Box<dyn DynPlugin> = crate::plugin::plugins()
+            .find(|factory| factory.name == "apollo.experimental_subgraph_discovery")
+            .expect("Plugin not found")
+            .create_instance(
+                &Value::from_str(&format!(
+                    r#"{{
+                        "subgraphs": {{
+                            "products": {{
+                                "source": {{ "file": {{ "path": {:?} }} }}
+                            }}
+                        }}
+                    }}"#,
+                    path.to_string_lossy()
+                ))
+                .unwrap(),
+                Default::default(),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        let mut subgraph_service =
+            dyn_plugin.subgraph_service("products", BoxService::new(mock_service));
+
+        for _ in 0..3 {
+            let request = SubgraphRequest::fake_builder()
+                .subgraph_request(
+                    http::Request::builder()
+                        .uri("http://products.example.com/graphql")
+                        .body(Default::default())
+                        .unwrap(),
+                )
+                .build();
+            subgraph_service
+                .ready()
+                .await
+                .unwrap()
+                .call(request)
+                .await
+                .unwrap();
+        }
+    }
+}
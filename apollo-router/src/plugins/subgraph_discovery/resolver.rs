@@ -0,0 +1,146 @@
+//! Sources of subgraph endpoints
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use futures::future;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use http::uri::Authority;
+use tower::BoxError;
+
+use crate::services::trust_dns_connector::AsyncHyperResolver;
+
+/// Resolves the endpoints of a subgraph.
+///
+/// The stream yields the complete list of endpoints each time it may have changed, starting with
+/// the current one. It ends when the resolver cannot find new endpoints anymore.
+pub(crate) trait Resolver: Send + Sync + 'static {
+    fn endpoints(&self) -> BoxStream<'static, Result<Vec<Authority>, BoxError>>;
+}
+
+/// Endpoints from the DNS SRV records of a service name, looked up periodically
+pub(crate) struct DnsSrvResolver {
+    name: String,
+    refresh_interval: Duration,
+    resolver: AsyncHyperResolver,
+}
+
+impl DnsSrvResolver {
+    pub(crate) fn new(name: String, refresh_interval: Duration) -> Result<Self, BoxError> {
+        Ok(Self {
+            name,
+            refresh_interval,
+            resolver: AsyncHyperResolver::new_from_system_conf()?,
+        })
+    }
+}
+
+impl Resolver for DnsSrvResolver {
+    fn endpoints(&self) -> BoxStream<'static, Result<Vec<Authority>, BoxError>> {
+        let name = self.name.clone();
+        let resolver = self.resolver.clone();
+        let refresh_interval = self.refresh_interval;
+
+        futures::stream::unfold(true, move |first| {
+            let name = name.clone();
+            let resolver = resolver.clone();
+            async move {
+                if !first {
+                    tokio::time::sleep(refresh_interval).await;
+                }
+                Some((lookup_srv(&resolver, &name).await, false))
+            }
+        })
+        .boxed()
+    }
+}
+
+async fn lookup_srv(resolver: &AsyncHyperResolver, name: &str) -> Result<Vec<Authority>, BoxError> {
+    let targets = resolver.lookup_srv(name).await?;
+    // only the targets with the highest priority (the lowest value) are used
+    let Some(priority) = targets.first().map(|target| target.priority) else {
+        return Ok(Vec::new());
+    };
+    targets
+        .into_iter()
+        .take_while(|target| target.priority == priority)
+        .map(|target| -> Result<Authority, BoxError> {
+            Ok(format!("{}:{}", target.host, target.port).parse()?)
+        })
+        .collect()
+}
+
+/// Endpoints listed in a YAML or JSON file, read again when it changes
+pub(crate) struct FileResolver {
+    path: PathBuf,
+}
+
+impl FileResolver {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl Resolver for FileResolver {
+    fn endpoints(&self) -> BoxStream<'static, Result<Vec<Authority>, BoxError>> {
+        let path = self.path.clone();
+
+        futures::stream::once(future::ready(()))
+            .chain(crate::files::watch(&self.path))
+            .then(move |_| {
+                let path = path.clone();
+                async move { read_endpoints(&path).await }
+            })
+            .boxed()
+    }
+}
+
+async fn read_endpoints(path: &Path) -> Result<Vec<Authority>, BoxError> {
+    let content = tokio::fs::read_to_string(path).await.map_err(|e| {
+        format!(
+            "could not read the endpoints file {}: {e}",
+            path.to_string_lossy()
+        )
+    })?;
+    if content.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let endpoints: Vec<String> = serde_yaml::from_str(&content)?;
+    endpoints
+        .iter()
+        .map(|endpoint| -> Result<Authority, BoxError> {
+            endpoint.parse().map_err(|e| {
+                format!("invalid endpoint '{endpoint}', expected host:port: {e}").into()
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_the_file_again_when_it_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("endpoints.yaml");
+        std::fs::write(&path, "- 10.0.0.1:4001\n- products.internal:4001\n").unwrap();
+
+        let mut endpoints = FileResolver::new(path.clone()).endpoints();
+        assert_eq!(
+            endpoints.next().await.unwrap().unwrap(),
+            vec![
+                Authority::from_static("10.0.0.1:4001"),
+                Authority::from_static("products.internal:4001")
+            ]
+        );
+
+        std::fs::write(&path, r#"["10.0.0.2:4001"]"#).unwrap();
+        assert_eq!(
+            endpoints.next().await.unwrap().unwrap(),
+            vec![Authority::from_static("10.0.0.2:4001")]
+        );
+    }
+}
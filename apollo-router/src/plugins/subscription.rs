@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::ops::ControlFlow;
+use std::sync::Arc;
 use std::task::Poll;
 use std::time::Duration;
 
@@ -26,11 +27,14 @@ use tower::ServiceExt;
 use tracing_futures::Instrument;
 use uuid::Uuid;
 
+use crate::configuration::RedisCache;
 use crate::context::Context;
 use crate::graphql;
 use crate::graphql::Response;
 use crate::json_ext::Object;
 use crate::layers::ServiceBuilderExt;
+use crate::notification::redis::RedisTopics;
+use crate::notification::DistributedTopics;
 use crate::notification::Notify;
 use crate::plugin::Plugin;
 use crate::plugin::PluginInit;
@@ -74,6 +78,8 @@ pub(crate) struct SubscriptionConfig {
     pub(crate) max_opened_subscriptions: Option<usize>,
     /// It represent the capacity of the in memory queue to know how many events we can keep in a buffer
     pub(crate) queue_capacity: Option<usize>,
+    /// Deduplicate passthrough subscriptions across router instances, so that only one of them opens each websocket to a subgraph
+    pub(crate) distributed_deduplication: Option<DistributedDeduplication>,
 }
 
 impl Default for SubscriptionConfig {
@@ -84,10 +90,27 @@ impl Default for SubscriptionConfig {
             enable_deduplication: true,
             max_opened_subscriptions: None,
             queue_capacity: None,
+            distributed_deduplication: None,
         }
     }
 }
 
+/// Distributed deduplication of subscriptions configuration
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DistributedDeduplication {
+    /// Redis instance shared by the router instances
+    pub(crate) redis: RedisCache,
+    /// How long a router instance owns a subscription without renewing it. Other instances take the subscription over once it expires (default: 10s)
+    #[serde(with = "humantime_serde", default = "default_lease")]
+    #[schemars(with = "String", default = "default_lease")]
+    pub(crate) lease: Duration,
+}
+
+fn default_lease() -> Duration {
+    Duration::from_secs(10)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct SubscriptionModeConfig {
@@ -245,6 +268,34 @@ impl Plugin for Subscription {
             }
         }
 
+        match &init.config.distributed_deduplication {
+            Some(config) if init.config.enable_deduplication => {
+                let required_to_start = config.redis.required_to_start;
+                let distributed =
+                    match RedisTopics::new(config.redis.clone(), config.lease).await {
+                        Ok(topics) => Some(Arc::new(topics)
+                            as Arc<dyn DistributedTopics<String, graphql::Response>>),
+                        Err(e) => {
+                            tracing::error!(
+                                e,
+                                "could not open connection to Redis for subscription deduplication",
+                            );
+                            if required_to_start {
+                                return Err(e);
+                            }
+                            None
+                        }
+                    };
+                init.notify.set_distributed(distributed).await?;
+            }
+            _ => {
+                // The notifier is kept across reloads, it must stop sharing topics if it did.
+                // The notifier of tests has no task to update.
+                #[cfg(not(test))]
+                init.notify.set_distributed(None).await?;
+            }
+        }
+
         Ok(Subscription {
             notify: init.notify,
             callback_hmac_key,
@@ -0,0 +1,435 @@
+//! Circuit breaker for subgraph requests. Implemented as a tower Layer.
+//!
+//! The circuit opens when a subgraph fails too often, and requests to that subgraph fail
+//! immediately until `open_duration` has elapsed. Then a few probe requests are let through:
+//! the circuit closes if they all succeed, and opens again at the first failure.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::task::Poll;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::time::Instant;
+use tower::BoxError;
+use tower::Layer;
+use tower::ServiceExt;
+
+use super::RateLimited;
+use crate::error::FetchError;
+use crate::services::SubgraphRequest;
+use crate::services::SubgraphResponse;
+
+/// Circuit breakers of the current and previous configurations, reported on the health check
+static CIRCUIT_BREAKERS: Lazy<Mutex<HashMap<String, Weak<Mutex<Inner>>>>> =
+    Lazy::new(Default::default);
+
+/// State of the circuit breakers, by subgraph
+pub(crate) fn circuit_breaker_states() -> BTreeMap<String, CircuitState> {
+    let mut breakers = CIRCUIT_BREAKERS.lock();
+    breakers.retain(|_, inner| inner.strong_count() > 0);
+    breakers
+        .iter()
+        .filter_map(|(name, inner)| Some((name.clone(), inner.upgrade()?.lock().state.kind())))
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CircuitState {
+    Closed,
+    HalfOpen,
+    Open,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Settings {
+    pub(crate) consecutive_failures: Option<NonZeroU32>,
+    pub(crate) error_rate: Option<f64>,
+    pub(crate) minimum_requests: u32,
+    pub(crate) window: Duration,
+    pub(crate) open_duration: Duration,
+    pub(crate) probes: NonZeroU32,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { sent: u32, succeeded: u32 },
+}
+
+impl State {
+    fn kind(&self) -> CircuitState {
+        match self {
+            State::Closed => CircuitState::Closed,
+            State::Open { .. } => CircuitState::Open,
+            State::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+}
+
+struct Inner {
+    state: State,
+    /// Incremented at each transition, so that probe slots are only given back to the half open
+    /// state that granted them
+    generation: u64,
+    consecutive_failures: u32,
+    window_start: Instant,
+    window_requests: u32,
+    window_failures: u32,
+}
+
+#[derive(Clone)]
+pub(crate) struct CircuitBreakerLayer {
+    subgraph: Arc<String>,
+    settings: Arc<Settings>,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl CircuitBreakerLayer {
+    pub(crate) fn new(subgraph: &str, settings: Settings) -> Self {
+        let inner = Arc::new(Mutex::new(Inner {
+            state: State::Closed,
+            generation: 0,
+            consecutive_failures: 0,
+            window_start: Instant::now(),
+            window_requests: 0,
+            window_failures: 0,
+        }));
+        CIRCUIT_BREAKERS
+            .lock()
+            .insert(subgraph.to_string(), Arc::downgrade(&inner));
+
+        Self {
+            subgraph: Arc::new(subgraph.to_string()),
+            settings: Arc::new(settings),
+            inner,
+        }
+    }
+
+    /// Returns `None` if the request must not be sent
+    fn acquire(&self) -> Option<Permit> {
+        let mut inner = self.inner.lock();
+        if let State::Open { until } = inner.state {
+            if Instant::now() < until {
+                return None;
+            }
+            self.transition(
+                &mut inner,
+                State::HalfOpen {
+                    sent: 0,
+                    succeeded: 0,
+                },
+            );
+        }
+
+        let probe = match &mut inner.state {
+            State::Closed => false,
+            State::HalfOpen { sent, .. } if *sent < self.settings.probes.get() => {
+                *sent += 1;
+                true
+            }
+            _ => return None,
+        };
+        Some(Permit {
+            breaker: self.clone(),
+            probe: probe.then_some(inner.generation),
+        })
+    }
+
+    /// Gives back a probe slot that was not used
+    fn release(&self, generation: u64) {
+        let mut inner = self.inner.lock();
+        if inner.generation != generation {
+            return;
+        }
+        if let State::HalfOpen { sent, .. } = &mut inner.state {
+            *sent = sent.saturating_sub(1);
+        }
+    }
+
+    fn record(&self, success: bool) {
+        let mut inner = self.inner.lock();
+        match &mut inner.state {
+            State::Closed => {}
+            State::Open { .. } => return,
+            State::HalfOpen { succeeded, .. } => {
+                if !success {
+                    self.open(&mut inner);
+                } else {
+                    *succeeded += 1;
+                    if *succeeded >= self.settings.probes.get() {
+                        inner.consecutive_failures = 0;
+                        inner.window_start = Instant::now();
+                        inner.window_requests = 0;
+                        inner.window_failures = 0;
+                        self.transition(&mut inner, State::Closed);
+                    }
+                }
+                return;
+            }
+        }
+
+        let now = Instant::now();
+        if now.duration_since(inner.window_start) > self.settings.window {
+            inner.window_start = now;
+            inner.window_requests = 0;
+            inner.window_failures = 0;
+        }
+        inner.window_requests += 1;
+        if success {
+            inner.consecutive_failures = 0;
+            return;
+        }
+        inner.consecutive_failures += 1;
+        inner.window_failures += 1;
+
+        let too_many_consecutive_failures = self
+            .settings
+            .consecutive_failures
+            .map(|max| inner.consecutive_failures >= max.get())
+            .unwrap_or(false);
+        let error_rate_exceeded = self
+            .settings
+            .error_rate
+            .map(|max| {
+                inner.window_requests >= self.settings.minimum_requests
+                    && inner.window_failures as f64 / inner.window_requests as f64 >= max
+            })
+            .unwrap_or(false);
+        if too_many_consecutive_failures || error_rate_exceeded {
+            self.open(&mut inner);
+        }
+    }
+
+    fn open(&self, inner: &mut Inner) {
+        let until = Instant::now() + self.settings.open_duration;
+        self.transition(inner, State::Open { until });
+    }
+
+    fn transition(&self, inner: &mut Inner, state: State) {
+        let kind = state.kind();
+        inner.state = state;
+        inner.generation += 1;
+        match kind {
+            CircuitState::Open => {
+                tracing::warn!("opening the circuit breaker of subgraph {}", self.subgraph)
+            }
+            CircuitState::HalfOpen => {
+                tracing::info!("probing subgraph {}", self.subgraph)
+            }
+            CircuitState::Closed => {
+                tracing::info!("closing the circuit breaker of subgraph {}", self.subgraph)
+            }
+        }
+        tracing::info!(
+            value.apollo.router.circuit_breaker.state = kind as u64,
+            subgraph = self.subgraph.as_str(),
+        );
+    }
+}
+
+/// Permission to send a request. In the half open state, the probe slot is given back if the
+/// permit is dropped without recording the outcome of the request, like when the request is
+/// cancelled or rate limited before being sent
+struct Permit {
+    breaker: CircuitBreakerLayer,
+    /// Generation of the half open state, if this is a probe
+    probe: Option<u64>,
+}
+
+impl Permit {
+    fn complete(mut self, success: bool) {
+        self.probe = None;
+        self.breaker.record(success);
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(generation) = self.probe {
+            self.breaker.release(generation);
+        }
+    }
+}
+
+impl<S> Layer<S> for CircuitBreakerLayer
+where
+    S: tower::Service<SubgraphRequest, Response = SubgraphResponse, Error = BoxError> + Clone,
+{
+    type Service = CircuitBreakerService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        CircuitBreakerService {
+            service,
+            breaker: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct CircuitBreakerService<S> {
+    service: S,
+    breaker: CircuitBreakerLayer,
+}
+
+impl<S> tower::Service<SubgraphRequest> for CircuitBreakerService<S>
+where
+    S: tower::Service<SubgraphRequest, Response = SubgraphResponse, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    <S as tower::Service<SubgraphRequest>>::Future: Send + 'static,
+{
+    type Response = SubgraphResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // the inner service is only made ready if the circuit lets the request through
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: SubgraphRequest) -> Self::Future {
+        let breaker = self.breaker.clone();
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let Some(permit) = breaker.acquire() else {
+                return Err(FetchError::SubrequestCircuitOpen {
+                    service: breaker.subgraph.to_string(),
+                }
+                .into());
+            };
+
+            let res = service.oneshot(request).await;
+            match &res {
+                Ok(response) => permit.complete(!response.response.status().is_server_error()),
+                // the subgraph is not at fault if the router did not send the request
+                Err(e) if e.is::<RateLimited>() => drop(permit),
+                Err(_) => permit.complete(false),
+            }
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings {
+            consecutive_failures: NonZeroU32::new(2),
+            error_rate: None,
+            minimum_requests: 10,
+            window: Duration::from_secs(10),
+            open_duration: Duration::from_secs(5),
+            probes: NonZeroU32::new(2).unwrap(),
+        }
+    }
+
+    fn state(breaker: &CircuitBreakerLayer) -> CircuitState {
+        breaker.inner.lock().state.kind()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn opens_on_consecutive_failures() {
+        let breaker = CircuitBreakerLayer::new("consecutive", settings());
+
+        assert!(breaker.acquire().is_some());
+        breaker.record(false);
+        breaker.record(true);
+        breaker.record(false);
+        assert_eq!(state(&breaker), CircuitState::Closed);
+        breaker.record(false);
+        assert_eq!(state(&breaker), CircuitState::Open);
+        assert!(breaker.acquire().is_none());
+        assert_eq!(
+            circuit_breaker_states().get("consecutive"),
+            Some(&CircuitState::Open)
+        );
+
+        // two probes are let through, then the circuit closes if they succeed
+        tokio::time::advance(Duration::from_secs(5)).await;
+        let first = breaker.acquire().unwrap();
+        let second = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_none());
+        assert_eq!(state(&breaker), CircuitState::HalfOpen);
+        first.complete(true);
+        second.complete(true);
+        assert_eq!(state(&breaker), CircuitState::Closed);
+        assert!(breaker.acquire().is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_probe_opens_the_circuit() {
+        let breaker = CircuitBreakerLayer::new("probe", settings());
+
+        breaker.record(false);
+        breaker.record(false);
+        tokio::time::advance(Duration::from_secs(5)).await;
+        breaker.acquire().unwrap().complete(false);
+        assert_eq!(state(&breaker), CircuitState::Open);
+        assert!(breaker.acquire().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_probe_gives_back_its_slot() {
+        let breaker = CircuitBreakerLayer::new(
+            "cancelled",
+            Settings {
+                probes: NonZeroU32::new(1).unwrap(),
+                ..settings()
+            },
+        );
+
+        breaker.record(false);
+        breaker.record(false);
+        tokio::time::advance(Duration::from_secs(5)).await;
+
+        // the probe never gets a response, and is cancelled by the timeout
+        let service = breaker.layer(tower::service_fn(|_: SubgraphRequest| {
+            std::future::pending::<Result<SubgraphResponse, BoxError>>()
+        }));
+        let probe = service.oneshot(SubgraphRequest::fake_builder().build());
+        assert!(tokio::time::timeout(Duration::from_secs(1), probe)
+            .await
+            .is_err());
+        assert_eq!(state(&breaker), CircuitState::HalfOpen);
+
+        // so another probe can be sent
+        let permit = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_none());
+        permit.complete(true);
+        assert_eq!(state(&breaker), CircuitState::Closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn opens_on_error_rate() {
+        let breaker = CircuitBreakerLayer::new(
+            "error_rate",
+            Settings {
+                consecutive_failures: None,
+                error_rate: Some(0.5),
+                minimum_requests: 4,
+                ..settings()
+            },
+        );
+
+        breaker.record(false);
+        breaker.record(false);
+        // not enough requests in the window
+        assert_eq!(state(&breaker), CircuitState::Closed);
+        breaker.record(true);
+        breaker.record(false);
+        assert_eq!(state(&breaker), CircuitState::Open);
+    }
+}
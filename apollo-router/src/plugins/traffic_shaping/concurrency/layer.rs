@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use http::HeaderName;
+use tower::Layer;
+
+use super::limiter::Limiter;
+use super::ConcurrencyLimit;
+use super::Settings;
+use crate::graphql;
+
+/// Priority of requests in the queue of a concurrency limit, derived from the client request.
+/// Requests without a priority have priority 0
+#[derive(Debug, Default)]
+pub(crate) struct Priorities {
+    /// Header containing the priority, as an integer
+    pub(crate) header: Option<HeaderName>,
+    /// Priority of operations, by name. The header takes precedence
+    pub(crate) operations: HashMap<String, i32>,
+}
+
+impl Priorities {
+    pub(crate) fn priority(&self, request: &http::Request<graphql::Request>) -> i32 {
+        self.header
+            .as_ref()
+            .and_then(|name| request.headers().get(name))
+            .and_then(|value| value.to_str().ok()?.trim().parse().ok())
+            .or_else(|| {
+                let operation_name = request.body().operation_name.as_ref()?;
+                self.operations.get(operation_name).copied()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Applies an adaptive concurrency limit to the underlying service
+#[derive(Clone)]
+pub(crate) struct ConcurrencyLimitLayer {
+    pub(super) limiter: Arc<Limiter>,
+    pub(super) priorities: Arc<Priorities>,
+}
+
+impl ConcurrencyLimitLayer {
+    /// `service` names the limited service in logs and metrics
+    pub(crate) fn new(service: &str, settings: Settings, priorities: Priorities) -> Self {
+        Self {
+            limiter: Limiter::new(service, settings),
+            priorities: Arc::new(priorities),
+        }
+    }
+}
+
+impl<S> Layer<S> for ConcurrencyLimitLayer {
+    type Service = ConcurrencyLimit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ConcurrencyLimit {
+            inner: service,
+            layer: self.clone(),
+        }
+    }
+}
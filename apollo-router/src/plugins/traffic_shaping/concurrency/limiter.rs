@@ -0,0 +1,392 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+use super::Overloaded;
+
+/// How the concurrency limit follows the observed latency
+#[derive(Clone, Debug)]
+pub(crate) enum Algorithm {
+    /// Additive increase, multiplicative decrease: the limit grows by one for each request
+    /// faster than `latency_threshold`, and is multiplied by `backoff_ratio` on overload
+    Aimd {
+        latency_threshold: Duration,
+        backoff_ratio: f64,
+    },
+    /// The limit follows the ratio between the long term average latency and the latency of
+    /// each request, with some headroom for queueing
+    Gradient { tolerance: f64 },
+}
+
+/// Weight of each sample in the long term average latency of the gradient algorithm
+const GRADIENT_LONG_TERM_WEIGHT: f64 = 1.0 / 600.0;
+/// Weight of the new limit computed for each sample in the gradient algorithm
+const GRADIENT_SMOOTHING: f64 = 0.2;
+
+#[derive(Clone, Debug)]
+pub(crate) struct Settings {
+    pub(crate) algorithm: Algorithm,
+    pub(crate) initial_limit: usize,
+    pub(crate) min_limit: usize,
+    pub(crate) max_limit: usize,
+    pub(crate) max_queue_size: usize,
+    pub(crate) queue_timeout: Duration,
+}
+
+/// Position in the queue: higher priorities first, then in arrival order
+type QueueKey = (Reverse<i32>, u64);
+
+struct State {
+    limit: f64,
+    in_flight: usize,
+    /// Long term average latency in seconds, for the gradient algorithm
+    long_term_latency: Option<f64>,
+    queue: BTreeMap<QueueKey, oneshot::Sender<Permit>>,
+    next_sequence: u64,
+}
+
+/// Adaptive concurrency limiter shared by all the requests of a service
+pub(crate) struct Limiter {
+    service: String,
+    settings: Settings,
+    state: Mutex<State>,
+}
+
+/// Outcome of a request, used to adjust the limit
+enum Sample {
+    /// The request completed after this time
+    Latency(Duration),
+    /// The service is overloaded: the request timed out, or was rejected by the service
+    Overloaded,
+}
+
+impl Limiter {
+    pub(crate) fn new(service: &str, settings: Settings) -> Arc<Self> {
+        let limiter = Arc::new(Self {
+            service: service.to_string(),
+            state: Mutex::new(State {
+                limit: settings.initial_limit as f64,
+                in_flight: 0,
+                long_term_latency: None,
+                queue: BTreeMap::new(),
+                next_sequence: 0,
+            }),
+            settings,
+        });
+        limiter.record_limit(limiter.settings.initial_limit as f64);
+        limiter
+    }
+
+    /// Waits until the request can be sent. Requests are shed if the queue is full of requests
+    /// with a higher priority, or if they wait for longer than the queue timeout
+    pub(crate) async fn acquire(self: &Arc<Self>, priority: i32) -> Result<Permit, Overloaded> {
+        let (key, mut receiver) = {
+            let mut state = self.state.lock();
+            if state.queue.is_empty() && state.in_flight < state.limit as usize {
+                state.in_flight += 1;
+                return Ok(Permit::new(self.clone()));
+            }
+
+            if state.queue.len() >= self.settings.max_queue_size {
+                // make room by shedding the last request of the queue if it has a lower priority
+                match state.queue.last_key_value() {
+                    Some((&(Reverse(lowest), _), _)) if lowest < priority => {
+                        state.queue.pop_last();
+                    }
+                    _ => {
+                        drop(state);
+                        self.record_shed();
+                        return Err(Overloaded::new());
+                    }
+                }
+            }
+
+            let key = (Reverse(priority), state.next_sequence);
+            state.next_sequence += 1;
+            let (sender, receiver) = oneshot::channel();
+            state.queue.insert(key, sender);
+            (key, receiver)
+        };
+
+        let permit = match tokio::time::timeout(self.settings.queue_timeout, &mut receiver).await {
+            Ok(res) => res.ok(),
+            Err(_) => {
+                self.state.lock().queue.remove(&key);
+                // the request may have been granted just before it was removed from the queue
+                receiver.try_recv().ok()
+            }
+        };
+
+        permit.ok_or_else(|| {
+            self.record_shed();
+            Overloaded::new()
+        })
+    }
+
+    fn release(self: &Arc<Self>, sample: Option<Sample>) {
+        let mut state = self.state.lock();
+        state.in_flight -= 1;
+
+        if let Some(sample) = sample {
+            let previous = state.limit;
+            self.update_limit(&mut state, sample);
+            if state.limit as usize != previous as usize {
+                self.record_limit(state.limit);
+            }
+        }
+
+        while state.in_flight < state.limit as usize {
+            let Some((_, sender)) = state.queue.pop_first() else {
+                break;
+            };
+            state.in_flight += 1;
+            if let Err(mut permit) = sender.send(Permit::new(self.clone())) {
+                // the request was cancelled while waiting
+                permit.active = false;
+                state.in_flight -= 1;
+            }
+        }
+    }
+
+    fn update_limit(&self, state: &mut State, sample: Sample) {
+        let limit = state.limit;
+        // the limit only grows if it is actually used
+        let in_use = state.in_flight * 2 >= limit as usize;
+
+        let new_limit = match &self.settings.algorithm {
+            Algorithm::Aimd {
+                latency_threshold,
+                backoff_ratio,
+            } => match sample {
+                Sample::Latency(latency) if latency <= *latency_threshold => {
+                    if in_use {
+                        limit + 1.0
+                    } else {
+                        limit
+                    }
+                }
+                _ => limit * backoff_ratio,
+            },
+            Algorithm::Gradient { tolerance } => {
+                let gradient = match sample {
+                    Sample::Latency(latency) => {
+                        let latency = latency.as_secs_f64();
+                        let long_term = match state.long_term_latency {
+                            Some(average) => {
+                                average * (1.0 - GRADIENT_LONG_TERM_WEIGHT)
+                                    + latency * GRADIENT_LONG_TERM_WEIGHT
+                            }
+                            None => latency,
+                        };
+                        state.long_term_latency = Some(long_term);
+                        if latency == 0.0 {
+                            1.0
+                        } else {
+                            (tolerance * long_term / latency).clamp(0.5, 1.0)
+                        }
+                    }
+                    Sample::Overloaded => 0.5,
+                };
+
+                if gradient >= 1.0 && !in_use {
+                    limit
+                } else {
+                    let target = limit * gradient + limit.sqrt();
+                    limit * (1.0 - GRADIENT_SMOOTHING) + target * GRADIENT_SMOOTHING
+                }
+            }
+        };
+
+        state.limit = new_limit.clamp(
+            self.settings.min_limit as f64,
+            self.settings.max_limit as f64,
+        );
+    }
+
+    fn record_limit(&self, limit: f64) {
+        tracing::info!(
+            value.apollo.router.concurrency.limit = limit as u64,
+            service = self.service.as_str(),
+        );
+    }
+
+    fn record_shed(&self) {
+        tracing::debug!("shedding a request to {}", self.service);
+        u64_counter!(
+            "apollo.router.concurrency.shed",
+            "Number of requests shed by the adaptive concurrency limit",
+            1,
+            service = self.service.clone()
+        );
+    }
+}
+
+/// A request in flight. It frees its slot when dropped
+pub(crate) struct Permit {
+    limiter: Arc<Limiter>,
+    start: Instant,
+    overloaded: Option<bool>,
+    /// False if the slot was already freed
+    active: bool,
+}
+
+impl Permit {
+    fn new(limiter: Arc<Limiter>) -> Self {
+        Self {
+            limiter,
+            start: Instant::now(),
+            overloaded: None,
+            active: true,
+        }
+    }
+
+    /// Records the outcome of the request. Without it, the request does not change the limit
+    pub(crate) fn complete(mut self, overloaded: bool) {
+        self.overloaded = Some(overloaded);
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if !self.active {
+            return;
+        }
+        let sample = self.overloaded.map(|overloaded| {
+            if overloaded {
+                Sample::Overloaded
+            } else {
+                Sample::Latency(self.start.elapsed())
+            }
+        });
+        self.limiter.release(sample);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(algorithm: Algorithm) -> Settings {
+        Settings {
+            algorithm,
+            initial_limit: 2,
+            min_limit: 1,
+            max_limit: 10,
+            max_queue_size: 1,
+            queue_timeout: Duration::from_secs(1),
+        }
+    }
+
+    fn aimd() -> Algorithm {
+        Algorithm::Aimd {
+            latency_threshold: Duration::from_secs(1),
+            backoff_ratio: 0.5,
+        }
+    }
+
+    fn limit(limiter: &Limiter) -> usize {
+        limiter.state.lock().limit as usize
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queues_then_sheds() {
+        let limiter = Limiter::new("queue", settings(aimd()));
+
+        let first = limiter.acquire(0).await.unwrap();
+        let _second = limiter.acquire(0).await.unwrap();
+
+        // the third request waits in the queue until the first one completes
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire(0).await.map(|_| ()) }
+        });
+        tokio::task::yield_now().await;
+        // the queue is full and this request does not have a higher priority
+        assert!(limiter.acquire(0).await.is_err());
+        drop(first);
+        assert!(waiting.await.unwrap().is_ok());
+
+        // requests waiting for too long are shed
+        let _third = limiter.acquire(0).await.unwrap();
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire(0).await.map(|_| ()) }
+        });
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert!(waiting.await.unwrap().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn higher_priorities_go_first() {
+        let limiter = Limiter::new("priority", settings(aimd()));
+
+        let first = limiter.acquire(0).await.unwrap();
+        let _second = limiter.acquire(0).await.unwrap();
+
+        let low = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire(0).await.map(|_| ()) }
+        });
+        tokio::task::yield_now().await;
+        // the queue is full, the request with the lower priority is shed
+        let high = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire(10).await.map(|_| ()) }
+        });
+        tokio::task::yield_now().await;
+        assert!(low.await.unwrap().is_err());
+
+        drop(first);
+        assert!(high.await.unwrap().is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn aimd_follows_latency() {
+        let limiter = Limiter::new("aimd", settings(aimd()));
+
+        let first = limiter.acquire(0).await.unwrap();
+        let second = limiter.acquire(0).await.unwrap();
+        first.complete(false);
+        assert_eq!(limit(&limiter), 3);
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        second.complete(false);
+        assert_eq!(limit(&limiter), 1);
+
+        // the limit does not go below the minimum
+        limiter.acquire(0).await.unwrap().complete(true);
+        assert_eq!(limit(&limiter), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gradient_decreases_when_latency_grows() {
+        let limiter = Limiter::new(
+            "gradient",
+            Settings {
+                initial_limit: 8,
+                ..settings(Algorithm::Gradient { tolerance: 1.0 })
+            },
+        );
+
+        for _ in 0..4 {
+            let permits = futures::future::join_all((0..4).map(|_| limiter.acquire(0))).await;
+            tokio::time::advance(Duration::from_millis(100)).await;
+            permits
+                .into_iter()
+                .for_each(|permit| permit.unwrap().complete(false));
+        }
+        let stable = limiter.state.lock().limit;
+
+        let permits = futures::future::join_all((0..4).map(|_| limiter.acquire(0))).await;
+        tokio::time::advance(Duration::from_secs(1)).await;
+        permits
+            .into_iter()
+            .for_each(|permit| permit.unwrap().complete(false));
+        assert!(limiter.state.lock().limit < stable);
+    }
+}
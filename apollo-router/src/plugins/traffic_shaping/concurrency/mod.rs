@@ -0,0 +1,17 @@
+//! Adaptive concurrency limit.
+//!
+//! The number of requests in flight to a service is limited, and the limit follows the observed
+//! latency. Requests over the limit wait in a bounded priority queue, and are shed when the queue
+//! is full or when they waited for too long.
+
+mod error;
+mod layer;
+mod limiter;
+mod service;
+
+pub(crate) use self::error::Overloaded;
+pub(crate) use self::layer::ConcurrencyLimitLayer;
+pub(crate) use self::layer::Priorities;
+pub(crate) use self::limiter::Algorithm;
+pub(crate) use self::limiter::Settings;
+pub(crate) use self::service::ConcurrencyLimit;
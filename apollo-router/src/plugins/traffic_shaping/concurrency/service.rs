@@ -0,0 +1,96 @@
+use std::task::Context;
+use std::task::Poll;
+
+use futures::future::BoxFuture;
+use http::StatusCode;
+use tower::BoxError;
+use tower::Service;
+use tower::ServiceExt;
+
+use super::ConcurrencyLimitLayer;
+use crate::graphql;
+use crate::plugins::traffic_shaping::Elapsed;
+use crate::services::subgraph;
+use crate::services::supergraph;
+
+/// Request going through a concurrency limit
+pub(crate) trait LimitedRequest {
+    /// The client request the priority is derived from
+    fn client_request(&self) -> &http::Request<graphql::Request>;
+}
+
+/// Response going through a concurrency limit
+pub(crate) trait LimitedResponse {
+    /// True if the service signals that it is overloaded
+    fn is_overloaded(&self) -> bool;
+}
+
+impl LimitedRequest for supergraph::Request {
+    fn client_request(&self) -> &http::Request<graphql::Request> {
+        &self.supergraph_request
+    }
+}
+
+impl LimitedResponse for supergraph::Response {
+    fn is_overloaded(&self) -> bool {
+        // errors generated by the router itself, like rate limits, are not an overload signal,
+        // only timeouts are
+        false
+    }
+}
+
+impl LimitedRequest for subgraph::Request {
+    fn client_request(&self) -> &http::Request<graphql::Request> {
+        &self.supergraph_request
+    }
+}
+
+impl LimitedResponse for subgraph::Response {
+    fn is_overloaded(&self) -> bool {
+        matches!(
+            self.response.status(),
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+        )
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ConcurrencyLimit<S> {
+    pub(super) inner: S,
+    pub(super) layer: ConcurrencyLimitLayer,
+}
+
+impl<S, Request> Service<Request> for ConcurrencyLimit<S>
+where
+    Request: LimitedRequest + Send + 'static,
+    S: Service<Request, Error = BoxError> + Clone + Send + 'static,
+    S::Response: LimitedResponse + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // the inner service is only made ready once the request got a slot
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let priority = self.layer.priorities.priority(request.client_request());
+        let limiter = self.layer.limiter.clone();
+        let service = self.inner.clone();
+
+        Box::pin(async move {
+            let permit = limiter.acquire(priority).await?;
+            let res = service.oneshot(request).await;
+            match &res {
+                Ok(response) => permit.complete(response.is_overloaded()),
+                Err(e) if e.is::<Elapsed>() => permit.complete(true),
+                // other errors say nothing about the load of the service
+                Err(_) => drop(permit),
+            }
+            res
+        })
+    }
+}
@@ -1,4 +1,4 @@
-//! De-duplicate subgraph requests in flight. Implemented as a tower Layer.
+//! De-duplicate subgraph requests and router operations in flight. Implemented as tower Layers.
 //!
 //! See [`Layer`] and [`tower::Service`] for more details.
 
@@ -6,8 +6,20 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::task::Poll;
 
+use futures::future::ready;
 use futures::future::BoxFuture;
 use futures::lock::Mutex;
+use futures::stream::once;
+use futures::StreamExt;
+use http::header::ACCEPT;
+use http::header::AUTHORIZATION;
+use http::header::COOKIE;
+use http::HeaderMap;
+use http::HeaderName;
+use http::StatusCode;
+use http::Version;
+use sha2::Digest;
+use sha2::Sha256;
 use tokio::sync::broadcast::Sender;
 use tokio::sync::broadcast::{self};
 use tokio::sync::oneshot;
@@ -15,12 +27,18 @@ use tower::BoxError;
 use tower::Layer;
 use tower::ServiceExt;
 
+use crate::context::OPERATION_KIND;
+use crate::graphql;
 use crate::graphql::Request;
 use crate::http_ext;
+use crate::plugins::authentication::APOLLO_AUTHENTICATION_JWT_CLAIMS;
+use crate::plugins::authorization::AuthorizationPlugin;
 use crate::plugins::authorization::CacheKeyMetadata;
 use crate::query_planner::fetch::OperationKind;
+use crate::services::supergraph;
 use crate::services::SubgraphRequest;
 use crate::services::SubgraphResponse;
+use crate::Context;
 
 #[derive(Default)]
 pub(crate) struct QueryDeduplicationLayer;
@@ -177,3 +195,360 @@ where
         }
     }
 }
+
+/// Request headers and context entries that must be equal for two operations to share a response
+#[derive(Clone, Debug, Default)]
+pub(crate) struct OperationKey {
+    pub(crate) headers: Vec<HeaderName>,
+    pub(crate) context: Vec<String>,
+}
+
+/// Response shared with the identical operations that were waiting for it
+#[derive(Clone)]
+struct SharedResponse {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    body: graphql::Response,
+}
+
+impl SharedResponse {
+    fn into_response(self, context: Context) -> supergraph::Response {
+        let mut response = http::Response::new(once(ready(self.body)).boxed());
+        *response.status_mut() = self.status;
+        *response.version_mut() = self.version;
+        *response.headers_mut() = self.headers;
+        supergraph::Response::new_from_response(response, context)
+    }
+}
+
+/// `None` if the response cannot be shared, like deferred responses
+type OperationWaitMap = Arc<Mutex<HashMap<String, Sender<Result<Option<SharedResponse>, String>>>>>;
+
+/// De-duplicate identical queries in flight at the router level
+#[derive(Clone)]
+pub(crate) struct OperationDeduplicationLayer {
+    key: Arc<OperationKey>,
+    // shared by all the supergraph services created for this configuration
+    wait_map: OperationWaitMap,
+}
+
+impl OperationDeduplicationLayer {
+    pub(crate) fn new(key: OperationKey) -> Self {
+        Self {
+            key: Arc::new(key),
+            wait_map: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn cache_key(&self, request: &supergraph::Request) -> String {
+        // same authorization metadata as the one used by the query planner cache
+        AuthorizationPlugin::update_cache_key(&request.context);
+        let authorization = request
+            .context
+            .extensions()
+            .lock()
+            .get::<CacheKeyMetadata>()
+            .cloned()
+            .unwrap_or_default();
+
+        let mut digest = Sha256::new();
+        // query, operation name, variables and extensions
+        digest.update(&serde_json::to_vec(request.supergraph_request.body()).unwrap());
+        digest.update(&serde_json::to_vec(&authorization).unwrap());
+        // authenticated clients only share responses with clients presenting the same claims
+        digest.update(
+            &serde_json::to_vec(
+                &request
+                    .context
+                    .get_json_value(APOLLO_AUTHENTICATION_JWT_CLAIMS),
+            )
+            .unwrap(),
+        );
+
+        // the accepted content types change the shape of the response, and credentials may be
+        // forwarded to subgraphs, so that clients only share responses with the same credentials
+        let headers = request.supergraph_request.headers();
+        for name in [ACCEPT, AUTHORIZATION, COOKIE]
+            .iter()
+            .chain(self.key.headers.iter())
+        {
+            for value in headers.get_all(name) {
+                digest.update(value.as_bytes());
+                digest.update(&[0u8; 1][..]);
+            }
+            digest.update(&[1u8; 1][..]);
+        }
+        for key in &self.key.context {
+            digest.update(&serde_json::to_vec(&request.context.get_json_value(key)).unwrap());
+        }
+
+        hex::encode(digest.finalize().as_slice())
+    }
+}
+
+impl<S> Layer<S> for OperationDeduplicationLayer
+where
+    S: tower::Service<supergraph::Request, Response = supergraph::Response, Error = BoxError>
+        + Clone,
+{
+    type Service = OperationDeduplicationService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        OperationDeduplicationService {
+            service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct OperationDeduplicationService<S: Clone> {
+    service: S,
+    layer: OperationDeduplicationLayer,
+}
+
+impl<S> OperationDeduplicationService<S>
+where
+    S: tower::Service<supergraph::Request, Response = supergraph::Response, Error = BoxError>
+        + Clone,
+{
+    async fn dedup(
+        service: S,
+        wait_map: OperationWaitMap,
+        cache_key: String,
+        request: supergraph::Request,
+    ) -> Result<supergraph::Response, BoxError> {
+        loop {
+            let mut locked_wait_map = wait_map.lock().await;
+
+            match locked_wait_map.get_mut(&cache_key) {
+                Some(waiter) => {
+                    // Register interest in key
+                    let mut receiver = waiter.subscribe();
+                    drop(locked_wait_map);
+
+                    match receiver.recv().await {
+                        Ok(Ok(Some(response))) => {
+                            return Ok(response.into_response(request.context))
+                        }
+                        // the response was streamed to the first client only
+                        Ok(Ok(None)) => return service.ready_oneshot().await?.call(request).await,
+                        Ok(Err(e)) => return Err(e.into()),
+                        // there was an issue with the broadcast channel, retry fetching
+                        Err(_) => continue,
+                    }
+                }
+                None => {
+                    let (tx, _rx) = broadcast::channel(1);
+
+                    locked_wait_map.insert(cache_key.clone(), tx.clone());
+                    drop(locked_wait_map);
+
+                    let res = {
+                        // when _drop_signal is dropped, either by getting out of the block, returning
+                        // the error from ready_oneshot or by cancellation, the drop_sentinel future will
+                        // return with Err(), then we remove the entry from the wait map
+                        let (_drop_signal, drop_sentinel) = oneshot::channel::<()>();
+                        tokio::task::spawn(async move {
+                            let _ = drop_sentinel.await;
+                            let mut locked_wait_map = wait_map.lock().await;
+                            locked_wait_map.remove(&cache_key);
+                        });
+
+                        match service.ready_oneshot().await?.call(request).await {
+                            Ok(response) => Ok(Self::share(response).await),
+                            Err(e) => Err(e),
+                        }
+                    };
+
+                    // Let our waiters know. There may be no waiters, so the result is ignored
+                    let (res, broadcast_value) = match res {
+                        Ok((response, shared)) => (Ok(response), Ok(shared)),
+                        Err(e) => {
+                            let message = e.to_string();
+                            (Err(e), Err(message))
+                        }
+                    };
+                    let _ = tx.send(broadcast_value);
+
+                    return res;
+                }
+            }
+        }
+    }
+
+    /// Reads the primary response, and returns a copy of it if it is the only response
+    async fn share(
+        response: supergraph::Response,
+    ) -> (supergraph::Response, Option<SharedResponse>) {
+        let supergraph::Response { response, context } = response;
+        let (parts, mut stream) = response.into_parts();
+
+        match stream.next().await {
+            Some(first) if first.has_next != Some(true) => {
+                let shared = SharedResponse {
+                    status: parts.status,
+                    version: parts.version,
+                    headers: parts.headers.clone(),
+                    body: first.clone(),
+                };
+                let response = http::Response::from_parts(parts, once(ready(first)).boxed());
+                (
+                    supergraph::Response::new_from_response(response, context),
+                    Some(shared),
+                )
+            }
+            first => {
+                let stream = futures::stream::iter(first).chain(stream).boxed();
+                let response = http::Response::from_parts(parts, stream);
+                (
+                    supergraph::Response::new_from_response(response, context),
+                    None,
+                )
+            }
+        }
+    }
+}
+
+impl<S> tower::Service<supergraph::Request> for OperationDeduplicationService<S>
+where
+    S: tower::Service<supergraph::Request, Response = supergraph::Response, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    <S as tower::Service<supergraph::Request>>::Future: Send + 'static,
+{
+    type Response = supergraph::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: supergraph::Request) -> Self::Future {
+        let service = self.service.clone();
+
+        let is_query = matches!(
+            request.context.get::<_, OperationKind>(OPERATION_KIND),
+            Ok(Some(OperationKind::Query))
+        );
+        if is_query {
+            let wait_map = self.layer.wait_map.clone();
+            let cache_key = self.layer.cache_key(&request);
+
+            Box::pin(async move { Self::dedup(service, wait_map, cache_key, request).await })
+        } else {
+            Box::pin(async move { service.oneshot(request).await })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use serde_json_bytes::json;
+
+    use super::*;
+
+    fn request(user: &str) -> supergraph::Request {
+        let request = supergraph::Request::fake_builder()
+            .query("query { currentUser { name } }")
+            .header("x-user", user)
+            .build()
+            .unwrap();
+        request
+            .context
+            .insert(OPERATION_KIND, OperationKind::Query)
+            .unwrap();
+        request
+    }
+
+    #[tokio::test]
+    async fn identical_operations_share_a_response() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let service = tower::service_fn({
+            let calls = calls.clone();
+            move |request: supergraph::Request| {
+                let n = calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    supergraph::Response::fake_builder()
+                        .data(json!({ "currentUser": { "name": format!("user {n}") } }))
+                        .context(request.context)
+                        .build()
+                }
+            }
+        });
+        let layer = OperationDeduplicationLayer::new(OperationKey {
+            headers: vec![HeaderName::from_static("x-user")],
+            context: Vec::new(),
+        });
+
+        let call = |user: &'static str| {
+            let service = layer.layer(service.clone());
+            async move {
+                service
+                    .oneshot(request(user))
+                    .await
+                    .unwrap()
+                    .next_response()
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let (first_a, second_a, first_b) = tokio::join!(call("a"), call("a"), call("b"));
+        assert_eq!(first_a, second_a);
+        assert_ne!(first_a, first_b);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // only operations in flight are deduplicated
+        call("a").await;
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // clients with different claims never share a response
+        let authenticated = |sub: &'static str| {
+            let service = layer.layer(service.clone());
+            async move {
+                let request = request("c");
+                request
+                    .context
+                    .insert(APOLLO_AUTHENTICATION_JWT_CLAIMS, json!({ "sub": sub }))
+                    .unwrap();
+                service
+                    .oneshot(request)
+                    .await
+                    .unwrap()
+                    .next_response()
+                    .await
+            }
+        };
+        tokio::join!(authenticated("1"), authenticated("2"));
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+
+        // nor clients with different cookies
+        let with_cookie = |cookie: &'static str| {
+            let service = layer.layer(service.clone());
+            async move {
+                let mut request = request("d");
+                request
+                    .supergraph_request
+                    .headers_mut()
+                    .insert(COOKIE, http::HeaderValue::from_static(cookie));
+                service
+                    .oneshot(request)
+                    .await
+                    .unwrap()
+                    .next_response()
+                    .await
+            }
+        };
+        tokio::join!(with_cookie("session=1"), with_cookie("session=2"));
+        assert_eq!(calls.load(Ordering::SeqCst), 7);
+    }
+}
@@ -0,0 +1,267 @@
+//! Hedged subgraph requests.
+//!
+//! When a query takes longer than a percentile of the latencies recently observed for its
+//! subgraph, a second copy of the request is sent, and the first successful response is used.
+//! The number of hedged requests is capped by a budget, like retries.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+use futures::future::select;
+use futures::future::BoxFuture;
+use futures::future::Either;
+use parking_lot::Mutex;
+use tokio::time::Instant;
+use tower::retry::budget::Budget;
+use tower::BoxError;
+use tower::Layer;
+use tower::ServiceExt;
+
+use crate::query_planner::OperationKind;
+use crate::services::subgraph;
+
+/// Number of recent latencies the percentile is computed from
+const LATENCY_WINDOW: usize = 1000;
+/// The percentile is computed again after this number of new latencies
+const THRESHOLD_REFRESH: usize = 100;
+
+struct Latencies {
+    samples: VecDeque<Duration>,
+    threshold: Option<Duration>,
+    since_refresh: usize,
+}
+
+struct HedgeState {
+    subgraph_name: String,
+    percentile: f64,
+    min_data_points: usize,
+    budget: Budget,
+    latencies: Mutex<Latencies>,
+}
+
+impl HedgeState {
+    /// Delay after which a request is hedged, if enough latencies were observed
+    fn threshold(&self) -> Option<Duration> {
+        self.latencies.lock().threshold
+    }
+
+    fn record(&self, latency: Duration) {
+        let mut latencies = self.latencies.lock();
+        if latencies.samples.len() == LATENCY_WINDOW {
+            latencies.samples.pop_front();
+        }
+        latencies.samples.push_back(latency);
+        latencies.since_refresh += 1;
+
+        let len = latencies.samples.len();
+        if len < self.min_data_points
+            || (latencies.threshold.is_some() && latencies.since_refresh < THRESHOLD_REFRESH)
+        {
+            return;
+        }
+
+        let mut sorted = latencies.samples.iter().copied().collect::<Vec<_>>();
+        sorted.sort_unstable();
+        let index = ((self.percentile / 100.0 * len as f64).ceil() as usize).clamp(1, len) - 1;
+        latencies.threshold = Some(sorted[index]);
+        latencies.since_refresh = 0;
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct HedgeLayer {
+    state: Arc<HedgeState>,
+}
+
+impl HedgeLayer {
+    pub(crate) fn new(
+        percentile: f64,
+        min_data_points: usize,
+        duration: Option<Duration>,
+        min_per_sec: Option<u32>,
+        hedge_percent: Option<f32>,
+        subgraph_name: String,
+    ) -> Self {
+        Self {
+            state: Arc::new(HedgeState {
+                subgraph_name,
+                percentile,
+                min_data_points: min_data_points.max(1),
+                budget: Budget::new(
+                    duration.unwrap_or_else(|| Duration::from_secs(10)),
+                    min_per_sec.unwrap_or(1),
+                    hedge_percent.unwrap_or(0.1),
+                ),
+                latencies: Mutex::new(Latencies {
+                    samples: VecDeque::with_capacity(LATENCY_WINDOW),
+                    threshold: None,
+                    since_refresh: 0,
+                }),
+            }),
+        }
+    }
+}
+
+impl<S> Layer<S> for HedgeLayer {
+    type Service = Hedge<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Hedge {
+            service,
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Hedge<S> {
+    service: S,
+    state: Arc<HedgeState>,
+}
+
+impl<S> tower::Service<subgraph::Request> for Hedge<S>
+where
+    S: tower::Service<subgraph::Request, Response = subgraph::Response, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    <S as tower::Service<subgraph::Request>>::Future: Send + 'static,
+{
+    type Response = subgraph::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // the inner service is made ready separately for each copy of the request
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: subgraph::Request) -> Self::Future {
+        let state = self.state.clone();
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let start = Instant::now();
+            state.budget.deposit();
+
+            // mutations and subscriptions are not idempotent
+            let delay = if request.operation_kind == OperationKind::Query {
+                state.threshold()
+            } else {
+                None
+            };
+            let Some(delay) = delay else {
+                let res = service.oneshot(request).await;
+                state.record(start.elapsed());
+                return res;
+            };
+
+            // the latency of the attempt that produced the response is recorded from the moment it
+            // was sent, so that the hedging delay does not inflate the percentile
+            let hedged_request = request.clone();
+            let mut first = Box::pin(service.clone().oneshot(request));
+            let (res, sent_at) = match tokio::time::timeout(delay, &mut first).await {
+                Ok(res) => (res, start),
+                Err(_) => {
+                    if state.budget.withdraw().is_err() {
+                        tracing::info!(
+                            monotonic_counter.apollo_router_http_request_hedge_total = 1u64,
+                            status = "aborted",
+                            subgraph = %state.subgraph_name,
+                        );
+                        (first.await, start)
+                    } else {
+                        tracing::info!(
+                            monotonic_counter.apollo_router_http_request_hedge_total = 1u64,
+                            subgraph = %state.subgraph_name,
+                        );
+                        let hedged_at = Instant::now();
+                        let second = Box::pin(service.oneshot(hedged_request));
+                        // the first successful response wins
+                        match select(first, second).await {
+                            Either::Left((Err(_), other)) => (other.await, hedged_at),
+                            Either::Right((Err(_), other)) => (other.await, start),
+                            Either::Left((res, _)) => (res, start),
+                            Either::Right((res, _)) => (res, hedged_at),
+                        }
+                    }
+                }
+            };
+
+            state.record(sent_at.elapsed());
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_queries_are_hedged() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let service = tower::service_fn({
+            let calls = calls.clone();
+            move |request: subgraph::Request| {
+                // the fourth call is very slow
+                let latency = if calls.fetch_add(1, Ordering::SeqCst) == 3 {
+                    Duration::from_secs(60)
+                } else {
+                    Duration::from_millis(100)
+                };
+                async move {
+                    tokio::time::sleep(latency).await;
+                    Ok::<_, BoxError>(
+                        subgraph::Response::fake_builder()
+                            .context(request.context)
+                            .build(),
+                    )
+                }
+            }
+        });
+        let layer = HedgeLayer::new(50.0, 3, None, Some(10), None, "test".to_string());
+
+        for _ in 0..3 {
+            layer
+                .layer(service.clone())
+                .oneshot(subgraph::Request::fake_builder().build())
+                .await
+                .unwrap();
+        }
+        assert!(layer.state.threshold().is_some());
+
+        let start = Instant::now();
+        layer
+            .layer(service.clone())
+            .oneshot(subgraph::Request::fake_builder().build())
+            .await
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+        // the latency of the hedged request does not include the hedging delay
+        assert_eq!(
+            layer.state.latencies.lock().samples.back(),
+            Some(&Duration::from_millis(100))
+        );
+
+        // mutations are never hedged
+        calls.store(3, Ordering::SeqCst);
+        let start = Instant::now();
+        layer
+            .layer(service)
+            .oneshot(
+                subgraph::Request::fake_builder()
+                    .operation_kind(OperationKind::Mutation)
+                    .build(),
+            )
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(60));
+    }
+}
@@ -1,23 +1,32 @@
 //! Traffic shaping plugin
 //!
 //! Currently includes:
-//! * Query deduplication
-//! * Timeout
+//! * Query deduplication, for subgraph requests and router operations
+//! * Timeout, with deadline propagation to subgraphs
 //! * Compression
 //! * Rate limiting
+//! * Circuit breaking
+//! * Adaptive concurrency limiting
+//! * Request hedging
 //!
+mod circuit_breaker;
+pub(crate) mod concurrency;
 mod deduplication;
+mod hedge;
 pub(crate) mod rate;
 mod retry;
 pub(crate) mod timeout;
 
 use std::collections::HashMap;
+use std::num::NonZeroU32;
 use std::num::NonZeroU64;
+use std::num::NonZeroUsize;
 use std::sync::Mutex;
 use std::time::Duration;
 
 use futures::future::BoxFuture;
 use http::header::CONTENT_ENCODING;
+use http::HeaderName;
 use http::HeaderValue;
 use schemars::JsonSchema;
 use serde::Deserialize;
@@ -29,12 +38,28 @@ use tower::Service;
 use tower::ServiceBuilder;
 use tower::ServiceExt;
 
+pub(crate) use self::circuit_breaker::circuit_breaker_states;
+use self::circuit_breaker::CircuitBreakerLayer;
+pub(crate) use self::circuit_breaker::CircuitState;
+use self::concurrency::ConcurrencyLimitLayer;
+pub(crate) use self::concurrency::Overloaded;
+use self::deduplication::OperationDeduplicationLayer;
+use self::deduplication::OperationDeduplicationService;
+use self::deduplication::OperationKey;
 use self::deduplication::QueryDeduplicationLayer;
+use self::hedge::Hedge;
+use self::hedge::HedgeLayer;
+use self::rate::keyed::KeyedRateLimit;
+use self::rate::keyed::KeyedRateLimitLayer;
 use self::rate::RateLimitLayer;
 pub(crate) use self::rate::RateLimited;
 pub(crate) use self::retry::RetryPolicy;
+use self::timeout::deadline::DeadlineFormat;
+use self::timeout::deadline::DeadlinePropagation;
 pub(crate) use self::timeout::Elapsed;
 use self::timeout::TimeoutLayer;
+use crate::cache::redis::RedisCacheStorage;
+use crate::configuration::RedisCache;
 use crate::error::ConfigurationError;
 use crate::plugin::Plugin;
 use crate::plugin::PluginInit;
@@ -45,6 +70,18 @@ use crate::services::supergraph;
 use crate::services::SubgraphRequest;
 
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_CIRCUIT_BREAKER_MINIMUM_REQUESTS: u32 = 20;
+const DEFAULT_CIRCUIT_BREAKER_WINDOW: Duration = Duration::from_secs(10);
+const DEFAULT_CIRCUIT_BREAKER_OPEN_DURATION: Duration = Duration::from_secs(30);
+const DEFAULT_HEDGING_PERCENTILE: f64 = 95.0;
+const DEFAULT_HEDGING_MIN_DATA_POINTS: u32 = 100;
+const DEFAULT_CONCURRENCY_INITIAL_LIMIT: usize = 20;
+const DEFAULT_CONCURRENCY_MAX_LIMIT: usize = 1000;
+const DEFAULT_CONCURRENCY_LATENCY_THRESHOLD: Duration = Duration::from_secs(5);
+const DEFAULT_CONCURRENCY_BACKOFF_RATIO: f64 = 0.9;
+const DEFAULT_CONCURRENCY_TOLERANCE: f64 = 1.5;
+const DEFAULT_CONCURRENCY_MAX_QUEUE_SIZE: usize = 100;
+const DEFAULT_CONCURRENCY_QUEUE_TIMEOUT: Duration = Duration::from_secs(1);
 pub(crate) const APOLLO_TRAFFIC_SHAPING: &str = "apollo.traffic_shaping";
 
 trait Merge {
@@ -65,11 +102,18 @@ struct Shaping {
     #[schemars(with = "String", default)]
     /// Enable timeout for incoming requests
     timeout: Option<Duration>,
+    /// Send the time remaining before the deadline of the client request to subgraphs
+    deadline_propagation: Option<DeadlinePropagationConf>,
     /// Retry configuration
     //  *experimental feature*: Enables request retry
     experimental_retry: Option<RetryConfig>,
+    /// Hedging configuration
+    //  *experimental feature*: Enables request hedging
+    experimental_hedging: Option<HedgingConfig>,
     /// Enable HTTP2 for subgraphs
     experimental_http2: Option<Http2Config>,
+    /// Tuning of the connection pool and HTTP client of subgraphs
+    connection_pool: Option<ConnectionPoolConfig>,
 }
 
 #[derive(PartialEq, Default, Debug, Clone, Deserialize, JsonSchema)]
@@ -92,6 +136,11 @@ impl Merge for Shaping {
                 deduplicate_query: self.deduplicate_query.or(fallback.deduplicate_query),
                 compression: self.compression.or(fallback.compression),
                 timeout: self.timeout.or(fallback.timeout),
+                deadline_propagation: self
+                    .deadline_propagation
+                    .as_ref()
+                    .or(fallback.deadline_propagation.as_ref())
+                    .cloned(),
                 global_rate_limit: self
                     .global_rate_limit
                     .as_ref()
@@ -102,16 +151,118 @@ impl Merge for Shaping {
                     .as_ref()
                     .or(fallback.experimental_retry.as_ref())
                     .cloned(),
+                experimental_hedging: self
+                    .experimental_hedging
+                    .as_ref()
+                    .or(fallback.experimental_hedging.as_ref())
+                    .cloned(),
                 experimental_http2: self
                     .experimental_http2
                     .as_ref()
                     .or(fallback.experimental_http2.as_ref())
                     .cloned(),
+                connection_pool: match (&self.connection_pool, &fallback.connection_pool) {
+                    (Some(config), fallback) => Some(config.merge(fallback.as_ref())),
+                    (None, fallback) => fallback.clone(),
+                },
+            },
+        }
+    }
+}
+
+/// Connection pool and HTTP client configuration
+#[derive(PartialEq, Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ConnectionPoolConfig {
+    /// Maximum number of idle connections kept open for each host (default: unlimited)
+    pub(crate) max_idle_per_host: Option<usize>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Idle connections are closed after this delay (default: 5s)
+    pub(crate) idle_timeout: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Maximum time to establish a TCP connection (default: no limit)
+    pub(crate) connect_timeout: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Idle time before TCP keepalive probes are sent (default: 60s)
+    pub(crate) tcp_keepalive: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Interval of the HTTP/2 keepalive pings (default: disabled)
+    pub(crate) http2_keepalive_interval: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Connections are closed if a HTTP/2 keepalive ping is not acknowledged in time (default: 20s)
+    pub(crate) http2_keepalive_timeout: Option<Duration>,
+    /// Send HTTP/2 keepalive pings on connections without requests in flight (default: false)
+    pub(crate) http2_keepalive_while_idle: Option<bool>,
+}
+
+impl Merge for ConnectionPoolConfig {
+    fn merge(&self, fallback: Option<&Self>) -> Self {
+        match fallback {
+            None => self.clone(),
+            Some(fallback) => ConnectionPoolConfig {
+                max_idle_per_host: self.max_idle_per_host.or(fallback.max_idle_per_host),
+                idle_timeout: self.idle_timeout.or(fallback.idle_timeout),
+                connect_timeout: self.connect_timeout.or(fallback.connect_timeout),
+                tcp_keepalive: self.tcp_keepalive.or(fallback.tcp_keepalive),
+                http2_keepalive_interval: self
+                    .http2_keepalive_interval
+                    .or(fallback.http2_keepalive_interval),
+                http2_keepalive_timeout: self
+                    .http2_keepalive_timeout
+                    .or(fallback.http2_keepalive_timeout),
+                http2_keepalive_while_idle: self
+                    .http2_keepalive_while_idle
+                    .or(fallback.http2_keepalive_while_idle),
             },
         }
     }
 }
 
+/// Propagation of the deadline to subgraphs, in a request header
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct DeadlinePropagationConf {
+    /// Name of the header
+    header: String,
+    /// Format of the remaining time (default: milliseconds)
+    format: Option<DeadlineFormatConf>,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum DeadlineFormatConf {
+    /// Number of milliseconds
+    Milliseconds,
+    /// gRPC timeout format, like `1500m` for milliseconds or `90S` for seconds
+    Grpc,
+}
+
+impl DeadlinePropagationConf {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        HeaderName::try_from(self.header.as_str())
+            .map(|_| ())
+            .map_err(|e| ConfigurationError::InvalidConfiguration {
+                message: "bad configuration for traffic_shaping plugin",
+                error: format!("invalid deadline propagation header: {e}"),
+            })
+    }
+
+    fn propagation(&self) -> Option<DeadlinePropagation> {
+        Some(DeadlinePropagation {
+            header: HeaderName::try_from(self.header.as_str()).ok()?,
+            format: match self.format.unwrap_or(DeadlineFormatConf::Milliseconds) {
+                DeadlineFormatConf::Milliseconds => DeadlineFormat::Milliseconds,
+                DeadlineFormatConf::Grpc => DeadlineFormat::Grpc,
+            },
+        })
+    }
+}
+
 /// Retry configuration
 #[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
@@ -148,12 +299,58 @@ impl Merge for RetryConfig {
     }
 }
 
+/// Hedging configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct HedgingConfig {
+    /// percentile of the recent latencies of the subgraph after which a second copy of a query
+    /// is sent. Must be between 0 and 100, default value is 95
+    percentile: Option<f64>,
+    /// number of requests to observe before queries are hedged. The default value is 100
+    min_data_points: Option<u32>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// how long a single deposit should be considered. Must be between 1 and 60 seconds,
+    /// default value is 10 seconds
+    ttl: Option<Duration>,
+    /// minimum rate of hedged requests allowed, for subgraphs that do not receive many
+    /// requests. The default value is 1
+    min_per_sec: Option<u32>,
+    /// percentage of requests that can be hedged. This is in addition to the hedged requests
+    /// allowed by min_per_sec. Must be between 0 and 1000, default value is 0.1
+    hedge_percent: Option<f32>,
+}
+
+impl HedgingConfig {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let ttl_range = Duration::from_secs(1)..=Duration::from_secs(60);
+        let error = if matches!(self.percentile, Some(p) if p <= 0.0 || p > 100.0) {
+            "the hedging `percentile` must be between 0 and 100"
+        } else if matches!(self.ttl, Some(ttl) if !ttl_range.contains(&ttl)) {
+            "the hedging `ttl` must be between 1 and 60 seconds"
+        } else if matches!(self.hedge_percent, Some(p) if !(0.0..=1000.0).contains(&p)) {
+            "the hedging `hedge_percent` must be between 0 and 1000"
+        } else {
+            return Ok(());
+        };
+
+        Err(ConfigurationError::InvalidConfiguration {
+            message: "bad configuration for traffic_shaping plugin",
+            error: error.to_string(),
+        })
+    }
+}
+
 // this is a wrapper struct to add subgraph specific options over Shaping
 #[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 struct SubgraphShaping {
     #[serde(flatten)]
     shaping: Shaping,
+    /// Stop sending requests to a subgraph that is failing
+    circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Limit the number of requests in flight to the subgraph, following its latency
+    concurrency_limit: Option<ConcurrencyLimitConf>,
 }
 
 impl Merge for SubgraphShaping {
@@ -162,20 +359,157 @@ impl Merge for SubgraphShaping {
             None => self.clone(),
             Some(fallback) => SubgraphShaping {
                 shaping: self.shaping.merge(Some(&fallback.shaping)),
+                circuit_breaker: match (&self.circuit_breaker, &fallback.circuit_breaker) {
+                    (Some(config), fallback) => Some(config.merge(fallback.as_ref())),
+                    (None, fallback) => fallback.clone(),
+                },
+                concurrency_limit: self
+                    .concurrency_limit
+                    .as_ref()
+                    .or(fallback.concurrency_limit.as_ref())
+                    .cloned(),
+            },
+        }
+    }
+}
+
+/// Circuit breaker configuration. The circuit opens when one of the thresholds is reached
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct CircuitBreakerConfig {
+    /// Open the circuit after this number of consecutive failed requests
+    consecutive_failures: Option<NonZeroU32>,
+    /// Open the circuit when the ratio of failed requests in the window reaches this value,
+    /// between 0 and 1
+    error_rate: Option<f64>,
+    /// Minimum number of requests in the window before the error rate is checked (default: 20)
+    minimum_requests: Option<u32>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Duration of the window used to compute the error rate (default: 10s)
+    window: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// How long the circuit stays open before probe requests are sent (default: 30s)
+    open_duration: Option<Duration>,
+    /// Number of probe requests that must succeed to close the circuit (default: 1)
+    probes: Option<NonZeroU32>,
+}
+
+impl Merge for CircuitBreakerConfig {
+    fn merge(&self, fallback: Option<&Self>) -> Self {
+        match fallback {
+            None => self.clone(),
+            Some(fallback) => CircuitBreakerConfig {
+                consecutive_failures: self.consecutive_failures.or(fallback.consecutive_failures),
+                error_rate: self.error_rate.or(fallback.error_rate),
+                minimum_requests: self.minimum_requests.or(fallback.minimum_requests),
+                window: self.window.or(fallback.window),
+                open_duration: self.open_duration.or(fallback.open_duration),
+                probes: self.probes.or(fallback.probes),
             },
         }
     }
 }
 
+impl CircuitBreakerConfig {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let error = if self.consecutive_failures.is_none() && self.error_rate.is_none() {
+            "a circuit breaker needs `consecutive_failures` or `error_rate`"
+        } else if matches!(self.error_rate, Some(rate) if rate <= 0.0 || rate > 1.0) {
+            "the `error_rate` of a circuit breaker must be greater than 0 and at most 1"
+        } else {
+            return Ok(());
+        };
+
+        Err(ConfigurationError::InvalidConfiguration {
+            message: "bad configuration for traffic_shaping plugin",
+            error: error.to_string(),
+        })
+    }
+
+    fn settings(&self) -> circuit_breaker::Settings {
+        circuit_breaker::Settings {
+            consecutive_failures: self.consecutive_failures,
+            error_rate: self.error_rate,
+            minimum_requests: self
+                .minimum_requests
+                .unwrap_or(DEFAULT_CIRCUIT_BREAKER_MINIMUM_REQUESTS),
+            window: self.window.unwrap_or(DEFAULT_CIRCUIT_BREAKER_WINDOW),
+            open_duration: self
+                .open_duration
+                .unwrap_or(DEFAULT_CIRCUIT_BREAKER_OPEN_DURATION),
+            probes: self.probes.unwrap_or(NonZeroU32::MIN),
+        }
+    }
+}
+
 #[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 struct RouterShaping {
     /// Enable global rate limiting
     global_rate_limit: Option<RateLimitConf>,
+    /// Enable rate limiting per client, identified by a key
+    keyed_rate_limit: Option<KeyedRateLimitConf>,
+    /// Limit the number of requests in flight, following the latency of the router
+    concurrency_limit: Option<ConcurrencyLimitConf>,
+    /// Enable deduplication of identical queries in flight, which share one execution and response
+    deduplicate_query: Option<bool>,
+    /// Request headers and context entries that must also be equal for queries to be deduplicated
+    deduplication_key: Option<DeduplicationKeyConf>,
     #[serde(deserialize_with = "humantime_serde::deserialize", default)]
     #[schemars(with = "String", default)]
     /// Enable timeout for incoming requests
     timeout: Option<Duration>,
+    #[serde(deserialize_with = "deserialize_operation_timeouts", default)]
+    #[schemars(with = "HashMap<String, String>")]
+    /// Timeout of specific operations, by operation name. Overrides `timeout`
+    operation_timeouts: HashMap<String, Duration>,
+}
+
+fn deserialize_operation_timeouts<'de, D>(
+    deserializer: D,
+) -> Result<HashMap<String, Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let timeouts = HashMap::<String, humantime_serde::Serde<Duration>>::deserialize(deserializer)?;
+    Ok(timeouts
+        .into_iter()
+        .map(|(operation, timeout)| (operation, timeout.into_inner()))
+        .collect())
+}
+
+/// Identifies the queries allowed to share a response, in addition to the query, its variables,
+/// the JWT claims and the authorization status of the client
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct DeduplicationKeyConf {
+    /// Client request headers
+    #[serde(default)]
+    headers: Vec<String>,
+    /// Context entries
+    #[serde(default)]
+    context: Vec<String>,
+}
+
+impl DeduplicationKeyConf {
+    fn operation_key(&self) -> Result<OperationKey, ConfigurationError> {
+        let headers = self
+            .headers
+            .iter()
+            .map(|header| HeaderName::try_from(header.as_str()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| ConfigurationError::InvalidConfiguration {
+                message: "bad configuration for traffic_shaping plugin",
+                error: format!("invalid header in the deduplication key: {e}"),
+            })?;
+
+        Ok(OperationKey {
+            headers,
+            context: self.context.clone(),
+        })
+    }
 }
 
 #[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
@@ -203,6 +537,179 @@ struct RateLimitConf {
     #[schemars(with = "String")]
     /// Per interval
     interval: Duration,
+    /// Share the rate limit between router instances through Redis
+    redis: Option<RedisCache>,
+    /// Accept requests when Redis cannot be reached (default: true). If false, requests are rejected
+    #[serde(default = "default_fail_open")]
+    fail_open: bool,
+}
+
+fn default_fail_open() -> bool {
+    true
+}
+
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct KeyedRateLimitConf {
+    /// Value identifying the client of a request. Requests without this value share the same limit
+    key: RateLimitKey,
+    /// Number of requests allowed for each key. If the `apollo_traffic_shaping::rate_limit_cost`
+    /// context entry is set, it is the number of cost units allowed instead
+    capacity: NonZeroU64,
+    #[serde(deserialize_with = "humantime_serde::deserialize")]
+    #[schemars(with = "String")]
+    /// Per interval
+    interval: Duration,
+}
+
+/// Value identifying the client of a request for keyed rate limits
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub(crate) enum RateLimitKey {
+    /// Value of a request header
+    RequestHeader(String),
+    /// Claim of the JWT authenticating the request
+    JwtClaim(String),
+    /// Client name, as sent in the client name header configured in telemetry
+    ClientName,
+    /// Operation name
+    OperationName,
+}
+
+/// Adaptive concurrency limit configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct ConcurrencyLimitConf {
+    /// Algorithm adjusting the limit to the observed latency (default: aimd)
+    algorithm: Option<ConcurrencyAlgorithm>,
+    /// Limit applied before any request was observed (default: 20)
+    initial_limit: Option<NonZeroUsize>,
+    /// Lowest value of the limit (default: 1)
+    min_limit: Option<NonZeroUsize>,
+    /// Highest value of the limit (default: 1000)
+    max_limit: Option<NonZeroUsize>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// With aimd, requests slower than this decrease the limit (default: 5s)
+    latency_threshold: Option<Duration>,
+    /// With aimd, ratio applied to the limit when it decreases, between 0 and 1 (default: 0.9)
+    backoff_ratio: Option<f64>,
+    /// With gradient, how many times the latency can exceed its long term average before the
+    /// limit decreases (default: 1.5)
+    tolerance: Option<f64>,
+    /// Maximum number of requests waiting for a slot. Requests are shed when the queue is full
+    /// (default: 100)
+    max_queue_size: Option<usize>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Maximum time a request waits for a slot before it is shed (default: 1s)
+    queue_timeout: Option<Duration>,
+    /// Priority of the requests waiting for a slot
+    priority: Option<PriorityConf>,
+}
+
+/// Algorithm of an adaptive concurrency limit
+#[derive(PartialEq, Debug, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum ConcurrencyAlgorithm {
+    /// Additive increase, multiplicative decrease
+    Aimd,
+    /// Follows the gradient of the latency
+    Gradient,
+}
+
+/// Priority of the requests waiting for a slot, the highest first. Requests have priority 0 by
+/// default
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct PriorityConf {
+    /// Client request header containing the priority, as an integer
+    header: Option<String>,
+    /// Priority of operations, by name. The header takes precedence
+    #[serde(default)]
+    operations: HashMap<String, i32>,
+}
+
+impl ConcurrencyLimitConf {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let settings = self.settings();
+        let error = if settings.min_limit > settings.max_limit
+            || settings.initial_limit < settings.min_limit
+            || settings.initial_limit > settings.max_limit
+        {
+            "the `initial_limit` of a concurrency limit must be between `min_limit` and `max_limit`"
+                .to_string()
+        } else if matches!(self.backoff_ratio, Some(ratio) if ratio <= 0.0 || ratio >= 1.0) {
+            "the `backoff_ratio` of a concurrency limit must be between 0 and 1".to_string()
+        } else if matches!(self.tolerance, Some(tolerance) if tolerance < 1.0) {
+            "the `tolerance` of a concurrency limit must be at least 1".to_string()
+        } else if let Some(Err(e)) = self
+            .priority
+            .as_ref()
+            .and_then(|priority| priority.header.as_ref())
+            .map(|header| HeaderName::try_from(header.as_str()))
+        {
+            format!("invalid priority header for a concurrency limit: {e}")
+        } else {
+            return Ok(());
+        };
+
+        Err(ConfigurationError::InvalidConfiguration {
+            message: "bad configuration for traffic_shaping plugin",
+            error,
+        })
+    }
+
+    fn settings(&self) -> concurrency::Settings {
+        let algorithm = match self.algorithm.unwrap_or(ConcurrencyAlgorithm::Aimd) {
+            ConcurrencyAlgorithm::Aimd => concurrency::Algorithm::Aimd {
+                latency_threshold: self
+                    .latency_threshold
+                    .unwrap_or(DEFAULT_CONCURRENCY_LATENCY_THRESHOLD),
+                backoff_ratio: self
+                    .backoff_ratio
+                    .unwrap_or(DEFAULT_CONCURRENCY_BACKOFF_RATIO),
+            },
+            ConcurrencyAlgorithm::Gradient => concurrency::Algorithm::Gradient {
+                tolerance: self.tolerance.unwrap_or(DEFAULT_CONCURRENCY_TOLERANCE),
+            },
+        };
+
+        concurrency::Settings {
+            algorithm,
+            initial_limit: self
+                .initial_limit
+                .map(NonZeroUsize::get)
+                .unwrap_or(DEFAULT_CONCURRENCY_INITIAL_LIMIT),
+            min_limit: self.min_limit.map(NonZeroUsize::get).unwrap_or(1),
+            max_limit: self
+                .max_limit
+                .map(NonZeroUsize::get)
+                .unwrap_or(DEFAULT_CONCURRENCY_MAX_LIMIT),
+            max_queue_size: self
+                .max_queue_size
+                .unwrap_or(DEFAULT_CONCURRENCY_MAX_QUEUE_SIZE),
+            queue_timeout: self
+                .queue_timeout
+                .unwrap_or(DEFAULT_CONCURRENCY_QUEUE_TIMEOUT),
+        }
+    }
+
+    fn layer(&self, service: &str) -> ConcurrencyLimitLayer {
+        let priorities = self
+            .priority
+            .as_ref()
+            .map(|priority| concurrency::Priorities {
+                header: priority
+                    .header
+                    .as_ref()
+                    .and_then(|header| HeaderName::try_from(header.as_str()).ok()),
+                operations: priority.operations.clone(),
+            })
+            .unwrap_or_default();
+
+        ConcurrencyLimitLayer::new(service, self.settings(), priorities)
+    }
 }
 
 impl Merge for RateLimitConf {
@@ -212,6 +719,8 @@ impl Merge for RateLimitConf {
             Some(fallback) => Self {
                 capacity: fallback.capacity,
                 interval: fallback.interval,
+                redis: fallback.redis.clone(),
+                fail_open: fallback.fail_open,
             },
         }
     }
@@ -222,7 +731,83 @@ impl Merge for RateLimitConf {
 pub(crate) struct TrafficShaping {
     config: Config,
     rate_limit_router: Option<RateLimitLayer>,
+    keyed_rate_limit_router: Option<KeyedRateLimitLayer>,
     rate_limit_subgraphs: Mutex<HashMap<String, RateLimitLayer>>,
+    circuit_breakers: Mutex<HashMap<String, CircuitBreakerLayer>>,
+    concurrency_limit_router: Option<ConcurrencyLimitLayer>,
+    deduplication_router: Option<OperationDeduplicationLayer>,
+    concurrency_limit_subgraphs: Mutex<HashMap<String, ConcurrencyLimitLayer>>,
+    hedge_subgraphs: Mutex<HashMap<String, HedgeLayer>>,
+    /// Redis connections of the distributed rate limits, one per distinct configuration
+    redis_connections: Vec<(RedisCache, RedisCacheStorage)>,
+}
+
+impl RateLimitConf {
+    fn layer(
+        &self,
+        redis_connections: &[(RedisCache, RedisCacheStorage)],
+        scope: &str,
+    ) -> RateLimitLayer {
+        let storage = self.redis.as_ref().and_then(|redis| {
+            redis_connections
+                .iter()
+                .find(|(config, _)| config == redis)
+                .map(|(_, storage)| storage.clone())
+        });
+
+        match storage {
+            Some(storage) => RateLimitLayer::distributed(
+                self.capacity,
+                self.interval,
+                storage,
+                scope,
+                self.fail_open,
+            ),
+            None => RateLimitLayer::new(self.capacity, self.interval),
+        }
+    }
+}
+
+/// Opens the Redis connections of the distributed rate limits
+async fn connect_redis(config: &Config) -> Result<Vec<(RedisCache, RedisCacheStorage)>, BoxError> {
+    let rate_limits = config
+        .router
+        .iter()
+        .filter_map(|router| router.global_rate_limit.as_ref())
+        .chain(
+            config
+                .all
+                .iter()
+                .chain(config.subgraphs.values())
+                .filter_map(|subgraph| subgraph.shaping.global_rate_limit.as_ref()),
+        );
+
+    let mut connections: Vec<(RedisCache, RedisCacheStorage)> = Vec::new();
+    for rate_limit in rate_limits {
+        let redis = match rate_limit.redis.as_ref() {
+            Some(redis) => redis,
+            None => continue,
+        };
+        if connections.iter().any(|(config, _)| config == redis) {
+            continue;
+        }
+
+        match RedisCacheStorage::new(redis.clone()).await {
+            Ok(storage) => connections.push((redis.clone(), storage)),
+            Err(e) => {
+                if redis.required_to_start || !rate_limit.fail_open {
+                    tracing::error!(e, "could not open connection to Redis for rate limiting");
+                    return Err(e);
+                }
+                tracing::error!(
+                    e,
+                    "could not open connection to Redis for rate limiting, the rate limit will apply to each router instance separately",
+                );
+            }
+        }
+    }
+
+    Ok(connections)
 }
 
 #[async_trait::async_trait]
@@ -230,6 +815,40 @@ impl Plugin for TrafficShaping {
     type Config = Config;
 
     async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        // subgraphs get their configuration merged with the `all` configuration, which is also
+        // used as is by the subgraphs that are not listed
+        let all = init.config.all.as_ref();
+        let merged = all.cloned().into_iter().chain(
+            init.config
+                .subgraphs
+                .values()
+                .filter_map(|subgraph| Self::merge_config(all, Some(subgraph))),
+        );
+        for subgraph in merged {
+            if let Some(circuit_breaker) = subgraph.circuit_breaker.as_ref() {
+                circuit_breaker.validate()?;
+            }
+            if let Some(concurrency_limit) = subgraph.concurrency_limit.as_ref() {
+                concurrency_limit.validate()?;
+            }
+            if let Some(hedging) = subgraph.shaping.experimental_hedging.as_ref() {
+                hedging.validate()?;
+            }
+            if let Some(deadline_propagation) = subgraph.shaping.deadline_propagation.as_ref() {
+                deadline_propagation.validate()?;
+            }
+        }
+        let concurrency_limit_router = init
+            .config
+            .router
+            .as_ref()
+            .and_then(|r| r.concurrency_limit.as_ref())
+            .map(|conf| {
+                conf.validate()?;
+                Ok::<_, ConfigurationError>(conf.layer("router"))
+            })
+            .transpose()?;
+        let redis_connections = connect_redis(&init.config).await?;
         let rate_limit_router = init
             .config
             .router
@@ -245,40 +864,94 @@ impl Plugin for TrafficShaping {
                         ),
                     })
                 } else {
-                    Ok(RateLimitLayer::new(
-                        router_rate_limit_conf.capacity,
-                        router_rate_limit_conf.interval,
-                    ))
+                    Ok(router_rate_limit_conf.layer(&redis_connections, "router"))
                 }
             })
             .transpose()?;
+        let keyed_rate_limit_router = init
+            .config
+            .router
+            .as_ref()
+            .and_then(|r| r.keyed_rate_limit.as_ref())
+            .map(|conf| KeyedRateLimitLayer::new(conf.key.clone(), conf.capacity, conf.interval));
+        let deduplication_router = init
+            .config
+            .router
+            .as_ref()
+            .filter(|r| r.deduplicate_query.unwrap_or_default())
+            .map(|r| {
+                r.deduplication_key
+                    .as_ref()
+                    .map(DeduplicationKeyConf::operation_key)
+                    .transpose()
+                    .map(|key| OperationDeduplicationLayer::new(key.unwrap_or_default()))
+            })
+            .transpose()?;
 
         {
             Ok(Self {
                 config: init.config,
                 rate_limit_router,
+                keyed_rate_limit_router,
                 rate_limit_subgraphs: Mutex::new(HashMap::new()),
+                circuit_breakers: Mutex::new(HashMap::new()),
+                concurrency_limit_router,
+                deduplication_router,
+                concurrency_limit_subgraphs: Mutex::new(HashMap::new()),
+                hedge_subgraphs: Mutex::new(HashMap::new()),
+                redis_connections,
             })
         }
     }
 }
 
+type RetrySubgraphService<S> = Either<
+    Retry<RetryPolicy, Either<rate::service::RateLimit<S>, S>>,
+    Either<rate::service::RateLimit<S>, S>,
+>;
+
 pub(crate) type TrafficShapingSubgraphFuture<S> = Either<
     Either<
         BoxFuture<'static, Result<subgraph::Response, BoxError>>,
-        timeout::future::ResponseFuture<
-            Oneshot<
-                Either<
-                    Retry<RetryPolicy, Either<rate::service::RateLimit<S>, S>>,
-                    Either<rate::service::RateLimit<S>, S>,
+        Either<
+            BoxFuture<'static, Result<subgraph::Response, BoxError>>,
+            Either<
+                BoxFuture<'static, Result<subgraph::Response, BoxError>>,
+                timeout::future::ResponseFuture<
+                    Oneshot<
+                        Either<Hedge<RetrySubgraphService<S>>, RetrySubgraphService<S>>,
+                        subgraph::Request,
+                    >,
                 >,
-                subgraph::Request,
             >,
         >,
     >,
     <S as Service<subgraph::Request>>::Future,
 >;
 
+type DeduplicationSupergraphService<S> = Either<OperationDeduplicationService<S>, S>;
+
+pub(crate) type TrafficShapingSupergraphFuture<S> = Either<
+    BoxFuture<'static, Result<supergraph::Response, BoxError>>,
+    timeout::future::ResponseFuture<
+        Oneshot<
+            Either<
+                KeyedRateLimit<
+                    Either<
+                        rate::service::RateLimit<DeduplicationSupergraphService<S>>,
+                        DeduplicationSupergraphService<S>,
+                    >,
+                >,
+                Either<
+                    rate::service::RateLimit<DeduplicationSupergraphService<S>>,
+                    DeduplicationSupergraphService<S>,
+                >,
+            >,
+            supergraph::Request,
+        >,
+    >,
+>;
+
 impl TrafficShaping {
     fn merge_config<T: Merge + Clone>(
         all_config: Option<&T>,
@@ -295,9 +968,7 @@ impl TrafficShaping {
         supergraph::Request,
         Response = supergraph::Response,
         Error = BoxError,
-        Future = timeout::future::ResponseFuture<
-            Oneshot<tower::util::Either<rate::service::RateLimit<S>, S>, supergraph::Request>,
-        >,
+        Future = TrafficShapingSupergraphFuture<S>,
     > + Clone
            + Send
            + Sync
@@ -308,17 +979,26 @@ impl TrafficShaping {
             + Send
             + Sync
             + 'static,
-        <S as Service<supergraph::Request>>::Future: std::marker::Send,
+        <S as Service<supergraph::Request>>::Future: std::marker::Send + 'static,
     {
+        let router_config = self.config.router.as_ref();
         ServiceBuilder::new()
-            .layer(TimeoutLayer::new(
-                self.config
-                    .router
-                    .as_ref()
-                    .and_then(|r| r.timeout)
-                    .unwrap_or(DEFAULT_TIMEOUT),
-            ))
+            .option_layer(self.concurrency_limit_router.clone())
+            .layer(
+                TimeoutLayer::new(
+                    router_config
+                        .and_then(|r| r.timeout)
+                        .unwrap_or(DEFAULT_TIMEOUT),
+                )
+                .with_operations(
+                    router_config
+                        .map(|r| r.operation_timeouts.clone())
+                        .unwrap_or_default(),
+                ),
+            )
+            .option_layer(self.keyed_rate_limit_router.clone())
             .option_layer(self.rate_limit_router.clone())
+            .option_layer(self.deduplication_router.clone())
             .service(service)
     }
 
@@ -359,11 +1039,57 @@ impl TrafficShaping {
                         .unwrap()
                         .entry(name.to_string())
                         .or_insert_with(|| {
-                            RateLimitLayer::new(rate_limit_conf.capacity, rate_limit_conf.interval)
+                            rate_limit_conf
+                                .layer(&self.redis_connections, &format!("subgraph:{name}"))
                         })
                         .clone()
                 });
 
+            let circuit_breaker = config.circuit_breaker.as_ref().map(|circuit_breaker_conf| {
+                self.circuit_breakers
+                    .lock()
+                    .unwrap()
+                    .entry(name.to_string())
+                    .or_insert_with(|| {
+                        CircuitBreakerLayer::new(name, circuit_breaker_conf.settings())
+                    })
+                    .clone()
+            });
+
+            let concurrency_limit =
+                config
+                    .concurrency_limit
+                    .as_ref()
+                    .map(|concurrency_limit_conf| {
+                        self.concurrency_limit_subgraphs
+                            .lock()
+                            .unwrap()
+                            .entry(name.to_string())
+                            .or_insert_with(|| concurrency_limit_conf.layer(name))
+                            .clone()
+                    });
+
+            let hedge = config.shaping.experimental_hedging.as_ref().map(|config| {
+                self.hedge_subgraphs
+                    .lock()
+                    .unwrap()
+                    .entry(name.to_string())
+                    .or_insert_with(|| {
+                        HedgeLayer::new(
+                            config.percentile.unwrap_or(DEFAULT_HEDGING_PERCENTILE),
+                            config
+                                .min_data_points
+                                .unwrap_or(DEFAULT_HEDGING_MIN_DATA_POINTS)
+                                as usize,
+                            config.ttl,
+                            config.min_per_sec,
+                            config.hedge_percent,
+                            name.to_string(),
+                        )
+                    })
+                    .clone()
+            });
+
             let retry = config.shaping.experimental_retry.as_ref().map(|config| {
                 let retry_policy = RetryPolicy::new(
                     config.ttl,
@@ -380,11 +1106,19 @@ impl TrafficShaping {
                 .option_layer(config.shaping.deduplicate_query.unwrap_or_default().then(
                   QueryDeduplicationLayer::default
                 ))
+                    .option_layer(concurrency_limit)
+                    .option_layer(circuit_breaker)
                     .layer(TimeoutLayer::new(
                         config.shaping
                         .timeout
                         .unwrap_or(DEFAULT_TIMEOUT),
+                    ).with_propagation(
+                        config.shaping
+                        .deadline_propagation
+                        .as_ref()
+                        .and_then(DeadlinePropagationConf::propagation),
                     ))
+                    .option_layer(hedge)
                     .option_layer(retry)
                     .option_layer(rate_limit)
                 .service(service)
@@ -409,6 +1143,15 @@ impl TrafficShaping {
         .and_then(|config| config.shaping.experimental_http2)
         .unwrap_or(Http2Config::Enable)
     }
+
+    pub(crate) fn subgraph_connection_pool(&self, service_name: &str) -> ConnectionPoolConfig {
+        Self::merge_config(
+            self.config.all.as_ref(),
+            self.config.subgraphs.get(service_name),
+        )
+        .and_then(|config| config.shaping.connection_pool)
+        .unwrap_or_default()
+    }
 }
 
 register_plugin!("apollo", "traffic_shaping", TrafficShaping);
@@ -424,6 +1167,7 @@ mod test {
     use serde_json_bytes::Value;
     use tower::Service;
 
+    use super::timeout::deadline::Deadline;
     use super::*;
     use crate::json_ext::Object;
     use crate::plugin::test::MockSubgraph;
@@ -774,6 +1518,268 @@ mod test {
             .unwrap();
     }
 
+    #[tokio::test]
+    async fn it_opens_the_circuit_of_failing_subgraphs() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        subgraphs:
+            failing:
+                circuit_breaker:
+                    consecutive_failures: 2
+                    open_duration: 60s
+        "#,
+        )
+        .unwrap();
+
+        let plugin = get_traffic_shaping_plugin(&config).await;
+        let shaping = plugin.as_any().downcast_ref::<TrafficShaping>().unwrap();
+        let failing_service = tower::service_fn(|_: SubgraphRequest| async {
+            Err::<subgraph::Response, BoxError>("connection refused".into())
+        });
+
+        for _ in 0..2 {
+            let err = shaping
+                .subgraph_service_internal("failing", failing_service)
+                .oneshot(SubgraphRequest::fake_builder().build())
+                .await
+                .unwrap_err();
+            assert_eq!(err.to_string(), "connection refused");
+        }
+
+        let err = shaping
+            .subgraph_service_internal("failing", failing_service)
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<crate::error::FetchError>(),
+            Some(crate::error::FetchError::SubrequestCircuitOpen { service }) if service == "failing"
+        ));
+        assert_eq!(
+            circuit_breaker_states().get("failing"),
+            Some(&CircuitState::Open)
+        );
+    }
+
+    #[tokio::test]
+    async fn it_validates_the_merged_subgraph_configuration() {
+        let factory = crate::plugin::plugins()
+            .find(|factory| factory.name == APOLLO_TRAFFIC_SHAPING)
+            .expect("Plugin not found");
+
+        // the threshold comes from the `all` configuration
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        all:
+            circuit_breaker:
+                consecutive_failures: 3
+        subgraphs:
+            products:
+                circuit_breaker:
+                    open_duration: 10s
+        "#,
+        )
+        .unwrap();
+        assert!(factory
+            .create_instance_without_schema(&config)
+            .await
+            .is_ok());
+
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        subgraphs:
+            products:
+                circuit_breaker:
+                    open_duration: 10s
+        "#,
+        )
+        .unwrap();
+        assert!(factory
+            .create_instance_without_schema(&config)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_sheds_router_requests_over_the_concurrency_limit() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        router:
+            concurrency_limit:
+                initial_limit: 1
+                max_queue_size: 0
+        "#,
+        )
+        .unwrap();
+
+        let plugin = get_traffic_shaping_plugin(&config).await;
+        let shaping = plugin.as_any().downcast_ref::<TrafficShaping>().unwrap();
+        let slow_service = tower::service_fn(|request: SupergraphRequest| async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            SupergraphResponse::fake_builder()
+                .context(request.context)
+                .build()
+        });
+
+        let first = tokio::spawn(
+            shaping
+                .supergraph_service_internal(slow_service)
+                .oneshot(SupergraphRequest::fake_builder().build().unwrap()),
+        );
+        tokio::task::yield_now().await;
+
+        let err = shaping
+            .supergraph_service_internal(slow_service)
+            .oneshot(SupergraphRequest::fake_builder().build().unwrap())
+            .await
+            .unwrap_err();
+        assert!(err.is::<Overloaded>());
+        assert!(first.await.unwrap().is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_propagates_the_deadline_to_subgraphs() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        router:
+            timeout: 10s
+            operation_timeouts:
+                Slow: 60s
+        all:
+            timeout: 30s
+            deadline_propagation:
+                header: grpc-timeout
+                format: grpc
+        "#,
+        )
+        .unwrap();
+
+        let plugin = get_traffic_shaping_plugin(&config).await;
+        let shaping = plugin.as_any().downcast_ref::<TrafficShaping>().unwrap();
+        let supergraph_service = tower::service_fn(|request: SupergraphRequest| async move {
+            SupergraphResponse::fake_builder()
+                .context(request.context)
+                .build()
+        });
+        let remaining = Arc::new(std::sync::Mutex::new(0u64));
+        let subgraph_service = tower::service_fn({
+            let remaining = remaining.clone();
+            move |request: SubgraphRequest| {
+                let header = request.subgraph_request.headers()["grpc-timeout"]
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+                *remaining.lock().unwrap() = header.trim_end_matches('m').parse().unwrap();
+                async move {
+                    Ok::<_, BoxError>(
+                        subgraph::Response::fake_builder()
+                            .context(request.context)
+                            .build(),
+                    )
+                }
+            }
+        });
+
+        let run = |operation_name: &str| {
+            let request = SupergraphRequest::fake_builder().build().unwrap();
+            request
+                .context
+                .insert(crate::context::OPERATION_NAME, operation_name.to_string())
+                .unwrap();
+            let context = request.context.clone();
+            let supergraph = shaping
+                .supergraph_service_internal(supergraph_service)
+                .oneshot(request);
+            let subgraph = shaping.subgraph_service_internal("test", subgraph_service.clone());
+            async move {
+                supergraph.await.unwrap();
+                tokio::time::advance(Duration::from_secs(4)).await;
+                subgraph
+                    .oneshot(SubgraphRequest::fake_builder().context(context).build())
+                    .await
+                    .unwrap();
+            }
+        };
+
+        // subgraph requests are limited by the time remaining for the client request
+        run("Fast").await;
+        assert!((5_000..=6_000).contains(&*remaining.lock().unwrap()));
+
+        // the operation timeout overrides the router timeout, and the subgraph timeout applies
+        run("Slow").await;
+        assert!((29_000..=30_000).contains(&*remaining.lock().unwrap()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_only_limits_subgraph_requests_propagating_the_deadline() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        router:
+            timeout: 10s
+        all:
+            timeout: 30s
+        subgraphs:
+            propagated:
+                deadline_propagation:
+                    header: x-deadline
+        "#,
+        )
+        .unwrap();
+
+        let plugin = get_traffic_shaping_plugin(&config).await;
+        let shaping = plugin.as_any().downcast_ref::<TrafficShaping>().unwrap();
+        let supergraph_service = tower::service_fn(|request: SupergraphRequest| async move {
+            SupergraphResponse::fake_builder()
+                .context(request.context)
+                .build()
+        });
+        let subgraph_service = tower::service_fn(|request: SubgraphRequest| async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok::<_, BoxError>(
+                subgraph::Response::fake_builder()
+                    .context(request.context)
+                    .build(),
+            )
+        });
+
+        let request = SupergraphRequest::fake_builder().build().unwrap();
+        let context = request.context.clone();
+        shaping
+            .supergraph_service_internal(supergraph_service)
+            .oneshot(request)
+            .await
+            .unwrap();
+        tokio::time::advance(Duration::from_secs(15)).await;
+        let fetch = |subgraph: &str| {
+            shaping
+                .subgraph_service_internal(subgraph, subgraph_service)
+                .oneshot(
+                    SubgraphRequest::fake_builder()
+                        .context(context.clone())
+                        .build(),
+                )
+        };
+
+        // the client deadline has passed
+        assert!(fetch("propagated").await.is_err());
+        assert!(fetch("other").await.is_ok());
+
+        // deferred fragments can be fetched after the primary response was sent
+        let mut deferred = SubgraphRequest::fake_builder()
+            .context(context.clone())
+            .build();
+        deferred.deferred = true;
+        assert!(shaping
+            .subgraph_service_internal("propagated", subgraph_service)
+            .oneshot(deferred)
+            .await
+            .is_ok());
+
+        // like the fetches of subscription events
+        Deadline::clear(&context);
+        assert!(fetch("propagated").await.is_ok());
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn it_rate_limit_router_requests() {
         let config = serde_yaml::from_str::<serde_json::Value>(
@@ -838,4 +1844,80 @@ mod test {
             .await
             .unwrap();
     }
+
+    #[tokio::test]
+    async fn it_rate_limit_router_requests_by_key() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        router:
+            keyed_rate_limit:
+                key:
+                    request_header: x-tenant
+                capacity: 1
+                interval: 10s
+        "#,
+        )
+        .unwrap();
+
+        let plugin = get_traffic_shaping_plugin(&config).await;
+        let shaping = plugin.as_any().downcast_ref::<TrafficShaping>().unwrap();
+
+        // the service is cloned by the timeout layer, then by the keyed rate limit layer
+        fn mocked(depth: usize) -> MockSupergraphService {
+            let mut mock_service = MockSupergraphService::new();
+            if depth == 0 {
+                mock_service.expect_call().times(0..2).returning(move |_| {
+                    Ok(SupergraphResponse::fake_builder()
+                        .data(json!({ "test": 1234_u32 }))
+                        .build()
+                        .unwrap())
+                });
+            } else {
+                mock_service
+                    .expect_clone()
+                    .returning(move || mocked(depth - 1));
+            }
+            mock_service
+        }
+
+        let request = |tenant: &str| {
+            SupergraphRequest::fake_builder()
+                .header("x-tenant", tenant)
+                .build()
+                .unwrap()
+        };
+
+        let response = shaping
+            .supergraph_service_internal(mocked(2))
+            .oneshot(request("a"))
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), http::StatusCode::OK);
+
+        let mut response = shaping
+            .supergraph_service_internal(mocked(2))
+            .oneshot(request("a"))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.response.status(),
+            http::StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            response.response.headers().get(http::header::RETRY_AFTER),
+            Some(&HeaderValue::from_static("10"))
+        );
+        let body = response.next_response().await.unwrap();
+        assert_eq!(
+            body.errors[0].extensions.get("code"),
+            Some(&json!("REQUEST_RATE_LIMITED"))
+        );
+
+        let response = shaping
+            .supergraph_service_internal(mocked(2))
+            .oneshot(request("b"))
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), http::StatusCode::OK);
+    }
 }
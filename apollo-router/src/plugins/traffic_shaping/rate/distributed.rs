@@ -0,0 +1,141 @@
+//! Rate limit shared by router instances through Redis
+//!
+//! The limit is enforced with the Generic Cell Rate Algorithm: Redis stores the theoretical
+//! arrival time of the next request, which is pushed back by `interval / capacity` for every
+//! accepted request. A request is rejected if it would push it more than `interval` in the
+//! future. Redis provides the current time, so router instances do not need synchronized clocks.
+
+use std::sync::Arc;
+
+use fred::interfaces::LuaInterface;
+use fred::prelude::RedisError;
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+use tower::BoxError;
+
+use super::Rate;
+use super::RateLimited;
+use crate::cache::redis::RedisCacheStorage;
+use crate::cache::redis::RedisKey;
+
+/// Returns 0 if the request is accepted, or the number of milliseconds until it would be
+const GCRA_SCRIPT: &str = r#"
+local time = redis.call("TIME")
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local emission_interval = tonumber(ARGV[1])
+local period = tonumber(ARGV[2])
+local tat = tonumber(redis.call("GET", KEYS[1])) or now
+if tat < now then
+    tat = now
+end
+local new_tat = tat + emission_interval
+local allow_at = new_tat - period
+if now < allow_at then
+    return math.ceil(allow_at - now)
+end
+redis.call("SET", KEYS[1], new_tat, "PX", math.ceil(new_tat - now))
+return 0
+"#;
+
+#[derive(Clone)]
+pub(crate) struct DistributedRateLimiter {
+    storage: RedisCacheStorage,
+    key: Arc<String>,
+    rate: Rate,
+    fail_open: bool,
+}
+
+impl DistributedRateLimiter {
+    /// `scope` separates the limits sharing the same Redis instance, like the router and each
+    /// subgraph
+    pub(crate) fn new(
+        storage: RedisCacheStorage,
+        scope: &str,
+        rate: Rate,
+        fail_open: bool,
+    ) -> Self {
+        let key = storage.make_key(RedisKey(format!("rate_limit:{scope}")));
+        Self {
+            storage,
+            key: Arc::new(key),
+            rate,
+            fail_open,
+        }
+    }
+
+    pub(crate) async fn acquire(self) -> Result<(), BoxError> {
+        let emission_interval = self.rate.per().as_secs_f64() * 1000.0 / self.rate.num() as f64;
+        let res: Result<i64, RedisError> = self
+            .storage
+            .client()
+            .eval(
+                GCRA_SCRIPT,
+                vec![self.key.to_string()],
+                vec![
+                    emission_interval.to_string(),
+                    self.rate.per().as_millis().to_string(),
+                ],
+            )
+            .await;
+
+        match res {
+            Ok(0) => Ok(()),
+            Ok(_) => {
+                tracing::trace!("distributed rate limit exceeded");
+                Err(RateLimited::new().into())
+            }
+            Err(err) => {
+                tracing::error!("cannot check the distributed rate limit: {err}");
+                if self.fail_open {
+                    Ok(())
+                } else {
+                    Err(RateLimited::new().into())
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for DistributedRateLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DistributedRateLimiter")
+            .field("key", &self.key)
+            .field("rate", &self.rate)
+            .field("fail_open", &self.fail_open)
+            .finish()
+    }
+}
+
+/// Progress of a service through the distributed rate limit. Cloning it resets it, so every
+/// request goes through the limit.
+#[derive(Default)]
+pub(crate) struct DistributedState(
+    // only accessed through `get_mut`, the mutex makes the rate limit service `Sync`
+    Mutex<Progress>,
+);
+
+#[derive(Default)]
+pub(crate) enum Progress {
+    #[default]
+    Idle,
+    Acquiring(BoxFuture<'static, Result<(), BoxError>>),
+    Acquired,
+}
+
+impl DistributedState {
+    pub(crate) fn get_mut(&mut self) -> &mut Progress {
+        self.0.get_mut()
+    }
+}
+
+impl Clone for DistributedState {
+    fn clone(&self) -> Self {
+        Default::default()
+    }
+}
+
+impl std::fmt::Debug for DistributedState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DistributedState")
+    }
+}
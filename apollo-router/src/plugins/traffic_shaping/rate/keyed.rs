@@ -0,0 +1,221 @@
+//! Rate limits applied separately to each client, identified by a key extracted from the request
+
+use std::num::NonZeroU64;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+
+use futures::future::ready;
+use futures::future::Either;
+use futures::future::Ready;
+use http::header::RETRY_AFTER;
+use http::HeaderValue;
+use http::StatusCode;
+use lru::LruCache;
+use parking_lot::Mutex;
+use serde_json_bytes::Value;
+use tokio::time::Instant;
+use tower::util::Oneshot;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+use tower::ServiceExt;
+
+use super::RateLimited;
+use crate::graphql;
+use crate::plugins::authentication::APOLLO_AUTHENTICATION_JWT_CLAIMS;
+use crate::plugins::telemetry::CLIENT_NAME;
+use crate::plugins::traffic_shaping::RateLimitKey;
+use crate::services::supergraph;
+
+/// Context entry holding the cost of a request for keyed rate limits, as a number. Requests
+/// without this entry cost 1
+pub(crate) const RATE_LIMIT_COST: &str = "apollo_traffic_shaping::rate_limit_cost";
+
+/// Maximum number of keys tracked at the same time. When it is reached, the least recently
+/// seen key is forgotten, which resets its limit
+const MAX_KEYS: usize = 100_000;
+
+/// Token bucket of one key
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+#[derive(Clone)]
+pub(crate) struct KeyedRateLimitLayer {
+    key: RateLimitKey,
+    capacity: f64,
+    interval: Duration,
+    buckets: Arc<Mutex<LruCache<String, Bucket>>>,
+}
+
+impl KeyedRateLimitLayer {
+    pub(crate) fn new(key: RateLimitKey, capacity: NonZeroU64, interval: Duration) -> Self {
+        Self {
+            key,
+            capacity: capacity.get() as f64,
+            interval,
+            buckets: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(MAX_KEYS).expect("MAX_KEYS is not zero"),
+            ))),
+        }
+    }
+
+    /// Takes `cost` tokens from the bucket of `key`. If there are not enough tokens, returns
+    /// the time until there are
+    fn acquire(&self, key: String, cost: f64) -> Result<(), Duration> {
+        let now = Instant::now();
+        let refill_per_sec = self.capacity / self.interval.as_secs_f64();
+
+        let mut buckets = self.buckets.lock();
+        let bucket = buckets.get_or_insert_mut(key, || Bucket {
+            tokens: self.capacity,
+            last_refill: now,
+        });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill_per_sec).min(self.capacity);
+        bucket.last_refill = now;
+
+        if cost > self.capacity {
+            // this request will never fit in the limit
+            return Err(self.interval);
+        }
+
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (cost - bucket.tokens) * self.interval.as_secs_f64() / self.capacity,
+            ))
+        }
+    }
+}
+
+impl<S> Layer<S> for KeyedRateLimitLayer {
+    type Service = KeyedRateLimit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        KeyedRateLimit {
+            inner: service,
+            limiter: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct KeyedRateLimit<S> {
+    inner: S,
+    limiter: KeyedRateLimitLayer,
+}
+
+impl RateLimitKey {
+    /// Requests without a value for the key share the same limit
+    fn extract(&self, request: &supergraph::Request) -> String {
+        let value = match self {
+            RateLimitKey::RequestHeader(name) => request
+                .supergraph_request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string()),
+            RateLimitKey::JwtClaim(name) => request
+                .context
+                .get::<_, Value>(APOLLO_AUTHENTICATION_JWT_CLAIMS)
+                .ok()
+                .flatten()
+                .and_then(|claims| claims.as_object()?.get(name.as_str()).cloned())
+                .map(|claim| match claim {
+                    Value::String(s) => s.as_str().to_string(),
+                    other => other.to_string(),
+                }),
+            RateLimitKey::ClientName => {
+                request.context.get::<_, String>(CLIENT_NAME).ok().flatten()
+            }
+            RateLimitKey::OperationName => request.supergraph_request.body().operation_name.clone(),
+        };
+
+        value.unwrap_or_default()
+    }
+}
+
+impl<S> Service<supergraph::Request> for KeyedRateLimit<S>
+where
+    S: Service<supergraph::Request, Response = supergraph::Response, Error = BoxError> + Clone,
+{
+    type Response = supergraph::Response;
+    type Error = BoxError;
+    type Future =
+        Either<Ready<Result<supergraph::Response, BoxError>>, Oneshot<S, supergraph::Request>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // the inner service is only made ready once the request went through the limit, to not
+        // use a slot of the global rate limit for a rejected request
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: supergraph::Request) -> Self::Future {
+        let key = self.limiter.key.extract(&request);
+        let cost = request
+            .context
+            .get::<_, f64>(RATE_LIMIT_COST)
+            .ok()
+            .flatten()
+            .unwrap_or(1.0);
+
+        match self.limiter.acquire(key, cost) {
+            Ok(()) => Either::Right(self.inner.clone().oneshot(request)),
+            Err(retry_after) => {
+                tracing::trace!("keyed rate limit exceeded");
+                let retry_after = retry_after.as_secs_f64().ceil() as u64;
+                Either::Left(ready(Ok(supergraph::Response::infallible_builder()
+                    .error(
+                        graphql::Error::builder()
+                            .message(RateLimited::new().to_string())
+                            .extension_code("REQUEST_RATE_LIMITED")
+                            .build(),
+                    )
+                    .status_code(StatusCode::TOO_MANY_REQUESTS)
+                    .header(RETRY_AFTER, HeaderValue::from(retry_after))
+                    .context(request.context)
+                    .build())))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn buckets_are_separated_by_key() {
+        let limiter = KeyedRateLimitLayer::new(
+            RateLimitKey::ClientName,
+            NonZeroU64::new(2).unwrap(),
+            Duration::from_secs(2),
+        );
+
+        assert!(limiter.acquire("a".to_string(), 1.0).is_ok());
+        assert!(limiter.acquire("a".to_string(), 1.0).is_ok());
+        assert_eq!(
+            limiter.acquire("a".to_string(), 1.0),
+            Err(Duration::from_secs(1))
+        );
+        assert!(limiter.acquire("b".to_string(), 2.0).is_ok());
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(limiter.acquire("a".to_string(), 1.0).is_ok());
+        assert!(limiter.acquire("a".to_string(), 1.0).is_err());
+
+        // more expensive than the whole capacity
+        assert_eq!(
+            limiter.acquire("c".to_string(), 3.0),
+            Err(Duration::from_secs(2))
+        );
+    }
+}
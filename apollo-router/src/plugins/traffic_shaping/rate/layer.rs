@@ -8,8 +8,10 @@ use std::time::UNIX_EPOCH;
 
 use tower::Layer;
 
+use super::distributed::DistributedRateLimiter;
 use super::Rate;
 use super::RateLimit;
+use crate::cache::redis::RedisCacheStorage;
 /// Enforces a rate limit on the number of requests the underlying
 /// service can handle over a period of time.
 #[derive(Debug, Clone)]
@@ -18,6 +20,7 @@ pub(crate) struct RateLimitLayer {
     window_start: Arc<AtomicU64>,
     previous_nb_requests: Arc<AtomicUsize>,
     current_nb_requests: Arc<AtomicUsize>,
+    distributed: Option<DistributedRateLimiter>,
 }
 
 impl RateLimitLayer {
@@ -34,8 +37,24 @@ impl RateLimitLayer {
             )),
             previous_nb_requests: Arc::default(),
             current_nb_requests: Arc::new(AtomicUsize::new(1)),
+            distributed: None,
         }
     }
+
+    /// Create a rate limit layer shared with other router instances through Redis
+    pub(crate) fn distributed(
+        num: NonZeroU64,
+        per: Duration,
+        storage: RedisCacheStorage,
+        scope: &str,
+        fail_open: bool,
+    ) -> Self {
+        let mut layer = Self::new(num, per);
+        layer.distributed = Some(DistributedRateLimiter::new(
+            storage, scope, layer.rate, fail_open,
+        ));
+        layer
+    }
 }
 
 impl<S> Layer<S> for RateLimitLayer {
@@ -48,6 +67,8 @@ impl<S> Layer<S> for RateLimitLayer {
             window_start: self.window_start.clone(),
             previous_nb_requests: self.previous_nb_requests.clone(),
             current_nb_requests: self.current_nb_requests.clone(),
+            distributed: self.distributed.clone(),
+            distributed_state: Default::default(),
         }
     }
 }
@@ -1,7 +1,9 @@
 //! Limit the rate at which requests are processed.
 
+pub(crate) mod distributed;
 mod error;
 pub(crate) mod future;
+pub(crate) mod keyed;
 mod layer;
 #[allow(clippy::module_inception)]
 mod rate;
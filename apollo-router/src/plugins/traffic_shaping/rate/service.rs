@@ -1,3 +1,4 @@
+use std::future::Future;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
@@ -10,6 +11,9 @@ use std::time::UNIX_EPOCH;
 use futures::ready;
 use tower::Service;
 
+use super::distributed::DistributedRateLimiter;
+use super::distributed::DistributedState;
+use super::distributed::Progress;
 use super::future::ResponseFuture;
 use super::Rate;
 use crate::plugins::traffic_shaping::rate::error::RateLimited;
@@ -24,6 +28,9 @@ pub(crate) struct RateLimit<T> {
     pub(crate) window_start: Arc<AtomicU64>,
     pub(crate) previous_nb_requests: Arc<AtomicUsize>,
     pub(crate) current_nb_requests: Arc<AtomicUsize>,
+    /// If set, the limit is shared with other router instances through Redis
+    pub(crate) distributed: Option<DistributedRateLimiter>,
+    pub(crate) distributed_state: DistributedState,
 }
 
 impl<S, Request> Service<Request> for RateLimit<S>
@@ -36,6 +43,26 @@ where
     type Future = ResponseFuture<S::Future>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let Some(distributed) = self.distributed.as_ref() {
+            loop {
+                let progress = self.distributed_state.get_mut();
+                match progress {
+                    Progress::Idle => {
+                        *progress = Progress::Acquiring(Box::pin(distributed.clone().acquire()));
+                    }
+                    Progress::Acquiring(acquire) => {
+                        let res = ready!(acquire.as_mut().poll(cx));
+                        *progress = Progress::Idle;
+                        res?;
+                        *progress = Progress::Acquired;
+                    }
+                    Progress::Acquired => {
+                        return Poll::Ready(ready!(self.inner.poll_ready(cx)).map_err(Into::into));
+                    }
+                }
+            }
+        }
+
         let time_unit = self.rate.per().as_millis() as u64;
 
         let updated =
@@ -78,6 +105,7 @@ where
     }
 
     fn call(&mut self, request: Request) -> Self::Future {
+        *self.distributed_state.get_mut() = Progress::Idle;
         ResponseFuture::new(self.inner.call(request))
     }
 }
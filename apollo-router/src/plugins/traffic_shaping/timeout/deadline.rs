@@ -0,0 +1,132 @@
+//! Deadline of a client request, shared with the subgraph requests made for it.
+//!
+//! The router timeout starts the deadline, and each subgraph request with deadline propagation
+//! is then limited by the time remaining before it, so a sequence of fetches cannot outlive the
+//! client request.
+
+use std::time::Duration;
+
+use http::HeaderMap;
+use http::HeaderName;
+use http::HeaderValue;
+use tokio::time::Instant;
+
+use crate::services::subgraph;
+use crate::services::supergraph;
+use crate::Context;
+
+/// Deadline of the client request, stored in the context extensions
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Deadline(pub(crate) Instant);
+
+impl Deadline {
+    /// Removes the deadline, for the requests outliving the client request, like the fetches of
+    /// subscription events
+    pub(crate) fn clear(context: &Context) {
+        context.extensions().lock().remove::<Deadline>();
+    }
+}
+
+/// Requests going through the timeout layer
+pub(crate) trait TimeoutRequest {
+    /// The client request starts the deadline, subgraph requests may be limited by it
+    const STARTS_DEADLINE: bool;
+
+    fn context(&self) -> &Context;
+
+    fn headers_mut(&mut self) -> &mut HeaderMap;
+
+    /// Deferred fetches can start after the deadline, they are only limited by their own timeout
+    fn is_deferred(&self) -> bool;
+}
+
+impl TimeoutRequest for supergraph::Request {
+    const STARTS_DEADLINE: bool = true;
+
+    fn context(&self) -> &Context {
+        &self.context
+    }
+
+    fn headers_mut(&mut self) -> &mut HeaderMap {
+        self.supergraph_request.headers_mut()
+    }
+
+    fn is_deferred(&self) -> bool {
+        false
+    }
+}
+
+impl TimeoutRequest for subgraph::Request {
+    const STARTS_DEADLINE: bool = false;
+
+    fn context(&self) -> &Context {
+        &self.context
+    }
+
+    fn headers_mut(&mut self) -> &mut HeaderMap {
+        self.subgraph_request.headers_mut()
+    }
+
+    fn is_deferred(&self) -> bool {
+        self.deferred
+    }
+}
+
+/// How the remaining time is written in the propagated header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DeadlineFormat {
+    /// Number of milliseconds
+    Milliseconds,
+    /// gRPC timeout format: at most 8 digits followed by a unit
+    Grpc,
+}
+
+/// Sends the time remaining before the deadline to subgraphs
+#[derive(Clone, Debug)]
+pub(crate) struct DeadlinePropagation {
+    pub(crate) header: HeaderName,
+    pub(crate) format: DeadlineFormat,
+}
+
+impl DeadlinePropagation {
+    pub(crate) fn apply(&self, headers: &mut HeaderMap, remaining: Duration) {
+        headers.insert(self.header.clone(), self.header_value(remaining));
+    }
+
+    fn header_value(&self, remaining: Duration) -> HeaderValue {
+        let millis = remaining.as_millis();
+        match self.format {
+            DeadlineFormat::Milliseconds => HeaderValue::from(millis as u64),
+            DeadlineFormat::Grpc if millis < 100_000_000 => {
+                HeaderValue::from_str(&format!("{millis}m")).expect("digits are valid; qed")
+            }
+            DeadlineFormat::Grpc => {
+                let secs = remaining.as_secs().min(99_999_999);
+                HeaderValue::from_str(&format!("{secs}S")).expect("digits are valid; qed")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_the_remaining_time() {
+        let propagation = |format| DeadlinePropagation {
+            header: HeaderName::from_static("x-deadline"),
+            format,
+        };
+
+        let milliseconds = propagation(DeadlineFormat::Milliseconds);
+        assert_eq!(
+            milliseconds.header_value(Duration::from_millis(1500)),
+            "1500"
+        );
+
+        let grpc = propagation(DeadlineFormat::Grpc);
+        assert_eq!(grpc.header_value(Duration::from_millis(1500)), "1500m");
+        assert_eq!(grpc.header_value(Duration::from_secs(200_000)), "200000S");
+    }
+}
@@ -1,19 +1,40 @@
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 
 use tower::Layer;
 
+use super::deadline::DeadlinePropagation;
 use super::Timeout;
 
 /// Applies a timeout to requests via the supplied inner service.
 #[derive(Debug, Clone)]
 pub(crate) struct TimeoutLayer {
     timeout: Duration,
+    operations: Arc<HashMap<String, Duration>>,
+    propagation: Option<DeadlinePropagation>,
 }
 
 impl TimeoutLayer {
     /// Create a timeout from a duration
     pub(crate) fn new(timeout: Duration) -> Self {
-        TimeoutLayer { timeout }
+        TimeoutLayer {
+            timeout,
+            operations: Default::default(),
+            propagation: None,
+        }
+    }
+
+    /// Override the timeout of some operations, by name
+    pub(crate) fn with_operations(mut self, operations: HashMap<String, Duration>) -> Self {
+        self.operations = Arc::new(operations);
+        self
+    }
+
+    /// Send the time remaining before the deadline in a request header
+    pub(crate) fn with_propagation(mut self, propagation: Option<DeadlinePropagation>) -> Self {
+        self.propagation = propagation;
+        self
     }
 }
 
@@ -21,6 +42,10 @@ impl<S: Clone> Layer<S> for TimeoutLayer {
     type Service = Timeout<S>;
 
     fn layer(&self, service: S) -> Self::Service {
-        Timeout::new(service, self.timeout)
+        Timeout {
+            operations: self.operations.clone(),
+            propagation: self.propagation.clone(),
+            ..Timeout::new(service, self.timeout)
+        }
     }
 }
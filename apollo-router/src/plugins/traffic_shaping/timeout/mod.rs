@@ -4,21 +4,32 @@
 //!
 //! If the response does not complete within the specified timeout, the response
 //! will be aborted.
+//!
+//! The timeout of a client request is its deadline, which also limits the subgraph requests
+//! it is propagated to. See [`deadline`].
 
+pub(crate) mod deadline;
 pub(crate) mod error;
 pub(crate) mod future;
 mod layer;
 
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::task::Context;
 use std::task::Poll;
 use std::time::Duration;
 
+use tokio::time::Instant;
 use tower::util::Oneshot;
 use tower::Service;
 use tower::ServiceExt;
 
+use self::deadline::Deadline;
+use self::deadline::DeadlinePropagation;
+use self::deadline::TimeoutRequest;
 use self::future::ResponseFuture;
 pub(crate) use self::layer::TimeoutLayer;
+use crate::context::OPERATION_NAME;
 pub(crate) use crate::plugins::traffic_shaping::timeout::error::Elapsed;
 
 /// Applies a timeout to requests.
@@ -26,6 +37,9 @@ pub(crate) use crate::plugins::traffic_shaping::timeout::error::Elapsed;
 pub(crate) struct Timeout<T: Clone> {
     inner: T,
     timeout: Duration,
+    /// Timeouts overriding the default one, by operation name
+    operations: Arc<HashMap<String, Duration>>,
+    propagation: Option<DeadlinePropagation>,
 }
 
 // ===== impl Timeout =====
@@ -33,7 +47,39 @@ pub(crate) struct Timeout<T: Clone> {
 impl<T: Clone> Timeout<T> {
     /// Creates a new [`Timeout`]
     pub(crate) fn new(inner: T, timeout: Duration) -> Self {
-        Timeout { inner, timeout }
+        Timeout {
+            inner,
+            timeout,
+            operations: Default::default(),
+            propagation: None,
+        }
+    }
+
+    fn deadline<Request: TimeoutRequest>(&self, request: &Request, now: Instant) -> Instant {
+        let context = request.context();
+        let timeout = if self.operations.is_empty() {
+            self.timeout
+        } else {
+            context
+                .get::<_, String>(OPERATION_NAME)
+                .ok()
+                .flatten()
+                .and_then(|name| self.operations.get(&name).copied())
+                .unwrap_or(self.timeout)
+        };
+        let deadline = now + timeout;
+
+        if Request::STARTS_DEADLINE {
+            context.extensions().lock().insert(Deadline(deadline));
+            deadline
+        } else if self.propagation.is_some() && !request.is_deferred() {
+            match context.extensions().lock().get::<Deadline>() {
+                Some(Deadline(client_deadline)) => deadline.min(*client_deadline),
+                None => deadline,
+            }
+        } else {
+            deadline
+        }
     }
 }
 
@@ -41,6 +87,7 @@ impl<S, Request> Service<Request> for Timeout<S>
 where
     S: Service<Request> + Clone,
     S::Error: Into<tower::BoxError>,
+    Request: TimeoutRequest,
 {
     type Response = S::Response;
     type Error = tower::BoxError;
@@ -50,11 +97,20 @@ where
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, request: Request) -> Self::Future {
+    fn call(&mut self, mut request: Request) -> Self::Future {
         let service = self.inner.clone();
 
+        let now = Instant::now();
+        let deadline = self.deadline(&request, now);
+        if let Some(propagation) = &self.propagation {
+            propagation.apply(
+                request.headers_mut(),
+                deadline.saturating_duration_since(now),
+            );
+        }
+
         let response = service.oneshot(request);
 
-        ResponseFuture::new(response, Box::pin(tokio::time::sleep(self.timeout)))
+        ResponseFuture::new(response, Box::pin(tokio::time::sleep_until(deadline)))
     }
 }
@@ -0,0 +1,200 @@
+//! Server-Sent Events transport, following the "distinct connections" mode of the
+//! [GraphQL over SSE protocol](https://github.com/enisdenjo/graphql-sse/blob/master/PROTOCOL.md).
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::stream::select;
+use futures::stream::StreamExt;
+use futures::Stream;
+use serde_json_bytes::Value;
+use tokio_stream::once;
+use tokio_stream::wrappers::IntervalStream;
+
+use crate::graphql;
+use crate::protocols::multipart::Error;
+use crate::protocols::multipart::ProtocolMode;
+
+#[cfg(test)]
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(10);
+#[cfg(not(test))]
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+// Comment lines are ignored by clients but keep the connection alive through proxies
+const HEARTBEAT: &[u8] = b":\n\n";
+const COMPLETE_EVENT: &[u8] = b"event: complete\ndata:\n\n";
+
+enum MessageKind {
+    Heartbeat,
+    Message(graphql::Response),
+    Eof,
+}
+
+pub(crate) struct EventStream {
+    stream: Pin<Box<dyn Stream<Item = MessageKind> + Send>>,
+    is_terminated: bool,
+}
+
+impl EventStream {
+    pub(crate) fn new<S>(stream: S, mode: ProtocolMode) -> Self
+    where
+        S: Stream<Item = graphql::Response> + Send + 'static,
+    {
+        let stream = match mode {
+            ProtocolMode::Subscription => select(
+                stream
+                    .map(MessageKind::Message)
+                    .chain(once(MessageKind::Eof)),
+                IntervalStream::new(tokio::time::interval(HEARTBEAT_INTERVAL))
+                    .map(|_| MessageKind::Heartbeat),
+            )
+            .boxed(),
+            ProtocolMode::Defer => stream
+                .map(MessageKind::Message)
+                .chain(once(MessageKind::Eof))
+                .boxed(),
+        };
+
+        Self {
+            stream,
+            is_terminated: false,
+        }
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        if self.is_terminated {
+            return Poll::Ready(None);
+        }
+        match self.stream.as_mut().poll_next(cx) {
+            Poll::Ready(message) => match message {
+                Some(MessageKind::Heartbeat) => {
+                    // It's the ticker for heartbeat for subscription
+                    Poll::Ready(Some(Ok(Bytes::from_static(HEARTBEAT))))
+                }
+                Some(MessageKind::Message(response)) => {
+                    let is_still_open =
+                        response.has_next.unwrap_or(false) || response.subscribed.unwrap_or(false);
+
+                    // Gracefully closed at the server side
+                    if !is_still_open
+                        && matches!(response.data, None | Some(Value::Null))
+                        && response.errors.is_empty()
+                        && response.extensions.is_empty()
+                        && response.incremental.is_empty()
+                    {
+                        self.is_terminated = true;
+                        return Poll::Ready(Some(Ok(Bytes::from_static(COMPLETE_EVENT))));
+                    }
+
+                    let mut buf = Vec::from(&b"event: next\ndata: "[..]);
+                    serde_json::to_writer(&mut buf, &response)?;
+                    buf.extend_from_slice(b"\n\n");
+                    if !is_still_open {
+                        self.is_terminated = true;
+                        buf.extend_from_slice(COMPLETE_EVENT);
+                    }
+
+                    Poll::Ready(Some(Ok(buf.into())))
+                }
+                Some(MessageKind::Eof) => {
+                    // If the stream ends or is empty
+                    self.is_terminated = true;
+                    Poll::Ready(Some(Ok(Bytes::from_static(COMPLETE_EVENT))))
+                }
+                None => {
+                    self.is_terminated = true;
+                    Poll::Ready(None)
+                }
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+    use serde_json_bytes::json;
+
+    use super::*;
+    use crate::json_ext::Path;
+
+    #[tokio::test]
+    async fn test_subscription_events() {
+        let responses = vec![
+            graphql::Response::builder()
+                .data(json!({"userWasCreated": {"name": "Ada"}}))
+                .subscribed(true)
+                .build(),
+            graphql::Response::builder()
+                .data(json!({"userWasCreated": {"name": "Grace"}}))
+                .subscribed(true)
+                .build(),
+            graphql::Response::builder().build(),
+        ];
+
+        let events = EventStream::new(stream::iter(responses), ProtocolMode::Subscription)
+            .map(|event| String::from_utf8(event.unwrap().to_vec()).unwrap())
+            .filter(|event| futures::future::ready(event != ":\n\n"))
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(
+            events,
+            vec![
+                "event: next\ndata: {\"data\":{\"userWasCreated\":{\"name\":\"Ada\"}}}\n\n",
+                "event: next\ndata: {\"data\":{\"userWasCreated\":{\"name\":\"Grace\"}}}\n\n",
+                "event: complete\ndata:\n\n",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deferred_events() {
+        let responses = vec![
+            graphql::Response::builder()
+                .data(json!({"me": {"id": "1"}}))
+                .has_next(true)
+                .build(),
+            graphql::Response::builder()
+                .incremental(vec![graphql::IncrementalResponse::builder()
+                    .data(json!({"name": "Ada"}))
+                    .path(Path::from("me"))
+                    .build()])
+                .has_next(false)
+                .build(),
+        ];
+
+        let events = EventStream::new(stream::iter(responses), ProtocolMode::Defer)
+            .map(|event| String::from_utf8(event.unwrap().to_vec()).unwrap())
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(
+            events,
+            vec![
+                "event: next\ndata: {\"data\":{\"me\":{\"id\":\"1\"}},\"hasNext\":true}\n\n",
+                "event: next\ndata: {\"hasNext\":false,\"incremental\":[{\"data\":{\"name\":\"Ada\"},\"path\":[\"me\"]}]}\n\nevent: complete\ndata:\n\n",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_empty_stream() {
+        let events = EventStream::new(stream::iter(vec![]), ProtocolMode::Subscription)
+            .map(|event| String::from_utf8(event.unwrap().to_vec()).unwrap())
+            .filter(|event| futures::future::ready(event != ":\n\n"))
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(events, vec!["event: complete\ndata:\n\n"]);
+    }
+}
@@ -8,6 +8,8 @@ use futures::Sink;
 use futures::SinkExt;
 use futures::Stream;
 use futures::StreamExt;
+use http::header::SEC_WEBSOCKET_PROTOCOL;
+use http::HeaderMap;
 use http::HeaderValue;
 use pin_project_lite::pin_project;
 use schemars::JsonSchema;
@@ -43,14 +45,62 @@ impl Default for WebSocketProtocol {
 
 impl From<WebSocketProtocol> for HeaderValue {
     fn from(value: WebSocketProtocol) -> Self {
-        match value {
-            WebSocketProtocol::GraphqlWs => HeaderValue::from_static("graphql-transport-ws"),
-            WebSocketProtocol::SubscriptionsTransportWs => HeaderValue::from_static("graphql-ws"),
-        }
+        HeaderValue::from_static(value.sub_protocol())
     }
 }
 
 impl WebSocketProtocol {
+    /// Name of the protocol as used in the `Sec-WebSocket-Protocol` header
+    pub(crate) fn sub_protocol(&self) -> &'static str {
+        match self {
+            WebSocketProtocol::GraphqlWs => "graphql-transport-ws",
+            WebSocketProtocol::SubscriptionsTransportWs => "graphql-ws",
+        }
+    }
+
+    /// Select the protocol requested by a client, in the order the client listed them.
+    ///
+    /// Defaults to `graphql-transport-ws` if the client did not ask for any sub-protocol,
+    /// returns `None` if none of the requested sub-protocols is supported.
+    pub(crate) fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let mut requested = headers
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .peekable();
+
+        if requested.peek().is_none() {
+            return Some(WebSocketProtocol::GraphqlWs);
+        }
+
+        requested.find_map(|name| {
+            [
+                WebSocketProtocol::GraphqlWs,
+                WebSocketProtocol::SubscriptionsTransportWs,
+            ]
+            .into_iter()
+            .find(|protocol| protocol.sub_protocol() == name)
+        })
+    }
+
+    /// Serialize a message sent by the router to a client, using the message types of this protocol
+    pub(crate) fn encode(&self, message: &ServerMessage) -> serde_json::Result<String> {
+        let mut message = serde_json::to_value(message)?;
+        if let WebSocketProtocol::SubscriptionsTransportWs = self {
+            if let Some(ty) = message.get_mut("type") {
+                match ty.as_str() {
+                    Some("next") => *ty = "data".into(),
+                    Some("keep_alive") => *ty = "ka".into(),
+                    _ => {}
+                }
+            }
+        }
+        serde_json::to_string(&message)
+    }
+
     fn subscribe(&self, id: String, payload: graphql::Request) -> ClientMessage {
         match self {
             // old
@@ -154,9 +204,11 @@ pub(crate) enum ServerMessage {
     ///
     /// https://github.com/enisdenjo/graphql-ws/blob/master/PROTOCOL.md#pong
     Pong {
+        #[serde(skip_serializing_if = "Option::is_none")]
         payload: Option<serde_json::Value>,
     },
     Ping {
+        #[serde(skip_serializing_if = "Option::is_none")]
         payload: Option<serde_json::Value>,
     },
 }
@@ -200,7 +252,7 @@ impl ServerMessage {
         }
     }
 
-    fn id(&self) -> Option<String> {
+    pub(crate) fn id(&self) -> Option<String> {
         match self {
             ServerMessage::ConnectionAck
             | ServerMessage::KeepAlive
@@ -904,4 +956,54 @@ mod tests {
             "It should be completed"
         );
     }
+
+    #[test]
+    fn test_negotiate_protocol() {
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(
+            WebSocketProtocol::negotiate(&headers),
+            Some(WebSocketProtocol::GraphqlWs)
+        );
+
+        headers.insert(
+            http::header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("graphql-ws, graphql-transport-ws"),
+        );
+        assert_eq!(
+            WebSocketProtocol::negotiate(&headers),
+            Some(WebSocketProtocol::SubscriptionsTransportWs)
+        );
+
+        headers.insert(
+            http::header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("mqtt"),
+        );
+        assert_eq!(WebSocketProtocol::negotiate(&headers), None);
+    }
+
+    #[test]
+    fn test_encode_server_message() {
+        let message = ServerMessage::Next {
+            id: "1".to_string(),
+            payload: graphql::Response::builder()
+                .data(serde_json_bytes::json!({"me": {"name": "Ada"}}))
+                .build(),
+        };
+        assert_eq!(
+            WebSocketProtocol::GraphqlWs.encode(&message).unwrap(),
+            r#"{"type":"next","id":"1","payload":{"data":{"me":{"name":"Ada"}}}}"#
+        );
+        assert_eq!(
+            WebSocketProtocol::SubscriptionsTransportWs
+                .encode(&message)
+                .unwrap(),
+            r#"{"type":"data","id":"1","payload":{"data":{"me":{"name":"Ada"}}}}"#
+        );
+        assert_eq!(
+            WebSocketProtocol::SubscriptionsTransportWs
+                .encode(&ServerMessage::KeepAlive)
+                .unwrap(),
+            r#"{"type":"ka"}"#
+        );
+    }
 }
@@ -70,6 +70,7 @@ impl QueryPlan {
                     root_node: &self.root,
                     subscription_handle: &subscription_handle,
                     subscription_config,
+                    deferred: false,
                 },
                 &root,
                 &initial_value.unwrap_or_default(),
@@ -103,6 +104,8 @@ pub(crate) struct ExecutionParameters<'a> {
     pub(crate) root_node: &'a PlanNode,
     pub(crate) subscription_handle: &'a Option<SubscriptionHandle>,
     pub(crate) subscription_config: &'a Option<SubscriptionConfig>,
+    /// Executing a deferred fragment
+    pub(crate) deferred: bool,
 }
 
 impl PlanNode {
@@ -282,6 +285,7 @@ impl PlanNode {
                                         root_node: parameters.root_node,
                                         subscription_handle: parameters.subscription_handle,
                                         subscription_config: parameters.subscription_config,
+                                        deferred: parameters.deferred,
                                     },
                                     current_dir,
                                     &value,
@@ -470,6 +474,7 @@ impl DeferredNode {
                             root_node: &root_node,
                             subscription_handle: &subscription_handle,
                             subscription_config: &subscription_config,
+                            deferred: true,
                         },
                         &Path::default(),
                         &value,
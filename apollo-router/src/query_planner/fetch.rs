@@ -296,6 +296,7 @@ impl FetchNode {
             .build();
         subgraph_request.query_hash = self.schema_aware_hash.clone();
         subgraph_request.authorization = self.authorization.clone();
+        subgraph_request.deferred = parameters.deferred;
 
         let service = parameters
             .service_factory
@@ -312,7 +313,8 @@ impl FetchNode {
             // know if we should be redacting errors for this subgraph...
             .map_err(|e| match e.downcast::<FetchError>() {
                 Ok(inner) => match *inner {
-                    FetchError::SubrequestHttpError { .. } => *inner,
+                    FetchError::SubrequestHttpError { .. }
+                    | FetchError::SubrequestCircuitOpen { .. } => *inner,
                     _ => FetchError::SubrequestHttpError {
                         status_code: None,
                         service: service_name.to_string(),
@@ -327,7 +327,7 @@ async fn defer() {
         mock_x_service
             .expect_call()
             .times(1)
-            .withf(move |_request| true)
+            .withf(move |request| !request.deferred)
             .returning(|_| {
                 Ok(SubgraphResponse::fake_builder()
                     .data(serde_json::json! {{
@@ -347,7 +347,8 @@ async fn defer() {
         mock_y_service
             .expect_call()
             .times(1)
-            .withf(move |_request| true)
+            // the fetch of the deferred fragment is marked as such
+            .withf(move |request| request.deferred)
             .returning(|_| {
                 Ok(SubgraphResponse::fake_builder()
                     .data(serde_json::json! {{
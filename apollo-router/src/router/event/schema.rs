@@ -11,6 +11,7 @@ use url::Url;
 use crate::router::Event;
 use crate::router::Event::NoMoreSchema;
 use crate::router::Event::UpdateSchema;
+use crate::services::http::proxy::ClientBuilderExt;
 use crate::uplink::schema_stream::SupergraphSdlQuery;
 use crate::uplink::stream_from_uplink;
 use crate::uplink::UplinkConfig;
@@ -198,6 +199,7 @@ impl Fetcher {
     fn new(urls: Vec<Url>, period: Duration) -> Result<Self, FetcherError> {
         Ok(Self {
             client: reqwest::Client::builder()
+                .outbound_proxy()
                 .no_gzip()
                 .timeout(Duration::from_secs(10))
                 .build()
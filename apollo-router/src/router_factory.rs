@@ -381,6 +381,7 @@ pub(crate) async fn create_subgraph_services(
             configuration,
             &tls_root_store,
             shaping.enable_subgraph_http2(name),
+            &shaping.subgraph_connection_pool(name),
         )?;
 
         let http_service_factory =
@@ -608,10 +609,12 @@ pub(crate) async fn create_plugins(
     add_optional_apollo_plugin!("forbid_mutations");
     add_optional_apollo_plugin!("subscription");
     add_optional_apollo_plugin!("override_subgraph_url");
+    add_optional_apollo_plugin!("experimental_subgraph_discovery");
     add_optional_apollo_plugin!("authorization");
     add_optional_apollo_plugin!("authentication");
     add_optional_apollo_plugin!("preview_file_uploads");
     add_optional_apollo_plugin!("preview_entity_cache");
+    add_optional_apollo_plugin!("preview_response_cache");
     add_mandatory_apollo_plugin!("progressive_override");
 
     // This relative ordering is documented in `docs/source/customizations/native.mdx`:
@@ -41,6 +41,7 @@ pub(crate) enum PipelineStep {
     ExecutionResponse,
     SubgraphRequest,
     SubgraphResponse,
+    QueryPlannerResponse,
 }
 
 impl From<PipelineStep> for opentelemetry::Value {
@@ -100,7 +101,17 @@ pub(crate) struct Externalizable<T> {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) has_next: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    query_plan: Option<Arc<QueryPlan>>,
+    pub(crate) query_plan: Option<ExternalizedQueryPlan>,
+}
+
+/// The query plan as sent to a coprocessor
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub(crate) enum ExternalizedQueryPlan {
+    /// The complete query plan, as sent to the execution stage
+    Plan(Arc<QueryPlan>),
+    /// The query plan in the shape used by `experimental.expose_query_plan`
+    Exposed(serde_json::Value),
 }
 
 #[buildstructor::buildstructor]
@@ -222,7 +233,40 @@ where
             method,
             service_name: None,
             has_next,
-            query_plan,
+            query_plan: query_plan.map(ExternalizedQueryPlan::Plan),
+        }
+    }
+
+    #[builder(visibility = "pub(crate)")]
+    /// This is the constructor (or builder) to use when constructing a Query Planner
+    /// `Externalizable`.
+    ///
+    fn query_planner_new(
+        stage: PipelineStep,
+        control: Option<Control>,
+        id: String,
+        body: Option<T>,
+        context: Option<Context>,
+        sdl: Option<String>,
+        query_plan: Option<serde_json::Value>,
+    ) -> Self {
+        assert!(matches!(stage, PipelineStep::QueryPlannerResponse));
+        Externalizable {
+            version: EXTERNALIZABLE_VERSION,
+            stage: stage.to_string(),
+            control,
+            id: Some(id),
+            headers: None,
+            body,
+            context,
+            status_code: None,
+            sdl,
+            uri: None,
+            path: None,
+            method: None,
+            service_name: None,
+            has_next: None,
+            query_plan: query_plan.map(ExternalizedQueryPlan::Exposed),
         }
     }
 
@@ -282,12 +326,7 @@ where
             .header(CONTENT_TYPE, "application/json")
             .body(serde_json::to_vec(&self)?.into())?;
 
-        get_text_map_propagator(|propagator| {
-            propagator.inject_context(
-                &prepare_context(tracing::span::Span::current().context()),
-                &mut opentelemetry_http::HeaderInjector(request.headers_mut()),
-            );
-        });
+        inject_trace_context(request.headers_mut());
 
         let response = client.call(request).await?;
         hyper::body::to_bytes(response.into_body())
@@ -297,6 +336,16 @@ where
     }
 }
 
+/// Propagate the current trace to the external service
+pub(crate) fn inject_trace_context(headers: &mut HeaderMap) {
+    get_text_map_propagator(|propagator| {
+        propagator.inject_context(
+            &prepare_context(tracing::span::Span::current().context()),
+            &mut opentelemetry_http::HeaderInjector(headers),
+        );
+    });
+}
+
 /// Convert a HeaderMap into a HashMap
 pub(crate) fn externalize_header_map(
     input: &HeaderMap<HeaderValue>,
@@ -376,4 +425,21 @@ mod test {
             .id(String::default())
             .build();
     }
+
+    #[test]
+    fn it_will_build_query_planner_externalizable_correctly() {
+        Externalizable::<String>::query_planner_builder()
+            .stage(PipelineStep::QueryPlannerResponse)
+            .id(String::default())
+            .build();
+    }
+
+    #[test]
+    #[should_panic]
+    fn it_will_not_build_query_planner_externalizable_incorrectly() {
+        Externalizable::<String>::query_planner_builder()
+            .stage(PipelineStep::ExecutionRequest)
+            .id(String::default())
+            .build();
+    }
 }
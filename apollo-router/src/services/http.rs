@@ -9,10 +9,14 @@ use tower_service::Service;
 use super::Plugins;
 use crate::Context;
 
+mod connection;
+mod endpoint;
+pub(crate) mod proxy;
 pub(crate) mod service;
 #[cfg(test)]
 mod tests;
 
+pub(crate) use endpoint::Endpoint;
 pub(crate) use service::HttpClientService;
 
 pub(crate) type BoxService = tower::util::BoxService<HttpRequest, HttpResponse, BoxError>;
@@ -55,6 +59,7 @@ impl HttpClientServiceFactory {
             configuration,
             &rustls::RootCertStore::empty(),
             http2,
+            &Default::default(),
         )
         .unwrap();
 
@@ -0,0 +1,227 @@
+//! Connector counting the connections opened to subgraphs and the requests using them, for the
+//! connection pool metrics
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+
+use futures::future::BoxFuture;
+use http::Uri;
+use hyper::client::connect::Connected;
+use hyper::client::connect::Connection;
+use parking_lot::Mutex;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::ReadBuf;
+use tower::Service;
+
+/// Utilisation of the connection pool of a subgraph
+///
+/// hyper does not expose the state of its pool, so the number of connections in use is estimated
+/// from the requests in flight, capped by the number of open connections. With HTTP/2, several
+/// requests can share a connection, so this overestimates the active connections.
+#[derive(Debug)]
+pub(crate) struct PoolUsage {
+    service: Arc<String>,
+    state: Mutex<PoolState>,
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    open: i64,
+    in_flight: i64,
+    active: i64,
+    idle: i64,
+}
+
+impl PoolUsage {
+    pub(crate) fn new(service: Arc<String>) -> Self {
+        Self {
+            service,
+            state: Mutex::new(PoolState::default()),
+        }
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped
+    pub(crate) fn start_request(self: &Arc<Self>) -> InFlightRequest {
+        self.update(0, 1);
+        InFlightRequest {
+            usage: self.clone(),
+        }
+    }
+
+    fn update(&self, open: i64, in_flight: i64) {
+        let (active, idle) = {
+            let mut state = self.state.lock();
+            state.open += open;
+            state.in_flight += in_flight;
+            let active = state.in_flight.clamp(0, state.open);
+            let idle = state.open - active;
+            let deltas = (active - state.active, idle - state.idle);
+            state.active = active;
+            state.idle = idle;
+            deltas
+        };
+
+        if active != 0 {
+            i64_up_down_counter!(
+                "apollo.router.http.client.connections.active",
+                "Estimated number of connections to subgraphs with a request in flight",
+                active,
+                subgraph = self.service.to_string()
+            );
+        }
+        if idle != 0 {
+            i64_up_down_counter!(
+                "apollo.router.http.client.connections.idle",
+                "Estimated number of connections to subgraphs waiting in the pool",
+                idle,
+                subgraph = self.service.to_string()
+            );
+        }
+    }
+}
+
+/// Request to a subgraph, counted as in flight until it is dropped
+pub(crate) struct InFlightRequest {
+    usage: Arc<PoolUsage>,
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.usage.update(0, -1);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct MeteredConnector<C> {
+    inner: C,
+    usage: Arc<PoolUsage>,
+}
+
+impl<C> MeteredConnector<C> {
+    pub(crate) fn new(inner: C, usage: Arc<PoolUsage>) -> Self {
+        Self { inner, usage }
+    }
+}
+
+impl<C> Service<Uri> for MeteredConnector<C>
+where
+    C: Service<Uri>,
+    C::Future: Send + 'static,
+{
+    type Response = MeteredConnection<C::Response>;
+    type Error = C::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let usage = self.usage.clone();
+        let connecting = self.inner.call(uri);
+
+        Box::pin(async move {
+            match connecting.await {
+                Ok(connection) => Ok(MeteredConnection::new(connection, usage)),
+                Err(e) => {
+                    u64_counter!(
+                        "apollo.router.http.client.connections.failed",
+                        "Number of connections to subgraphs that could not be opened",
+                        1,
+                        subgraph = usage.service.to_string()
+                    );
+                    Err(e)
+                }
+            }
+        })
+    }
+}
+
+/// Connection to a subgraph, counted as open until it is dropped
+#[derive(Debug)]
+pub(crate) struct MeteredConnection<T> {
+    inner: T,
+    usage: Arc<PoolUsage>,
+}
+
+impl<T> MeteredConnection<T> {
+    fn new(inner: T, usage: Arc<PoolUsage>) -> Self {
+        let service = &usage.service;
+        u64_counter!(
+            "apollo.router.http.client.connections.opened",
+            "Number of connections opened to subgraphs",
+            1,
+            subgraph = service.to_string()
+        );
+        i64_up_down_counter!(
+            "apollo.router.http.client.connections.open",
+            "Number of connections currently open to subgraphs",
+            1,
+            subgraph = service.to_string()
+        );
+        usage.update(1, 0);
+        Self { inner, usage }
+    }
+}
+
+impl<T> Drop for MeteredConnection<T> {
+    fn drop(&mut self) {
+        i64_up_down_counter!(
+            "apollo.router.http.client.connections.open",
+            "Number of connections currently open to subgraphs",
+            -1,
+            subgraph = self.usage.service.to_string()
+        );
+        self.usage.update(-1, 0);
+    }
+}
+
+impl<T: Connection> Connection for MeteredConnection<T> {
+    fn connected(&self) -> Connected {
+        self.inner.connected()
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for MeteredConnection<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for MeteredConnection<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
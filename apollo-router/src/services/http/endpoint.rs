@@ -0,0 +1,53 @@
+//! Connector sending subgraph requests to the endpoints chosen by subgraph discovery
+
+use std::task::Context;
+use std::task::Poll;
+
+use http::uri::Authority;
+use http::Uri;
+use tower::Service;
+
+/// Endpoint a subgraph request is sent to, set in the extensions of the HTTP request
+///
+/// The request keeps the routing URL of the subgraph, so the `Host` header and the TLS server
+/// name are unchanged, and only the TCP connection goes to the endpoint.
+#[derive(Clone, Debug)]
+pub(crate) struct Endpoint(pub(crate) Authority);
+
+/// Connects to a fixed endpoint instead of the host and port of the requested URI
+#[derive(Clone, Debug)]
+pub(crate) struct EndpointConnector<C> {
+    inner: C,
+    endpoint: Option<Authority>,
+}
+
+impl<C> EndpointConnector<C> {
+    pub(crate) fn new(inner: C, endpoint: Option<Authority>) -> Self {
+        Self { inner, endpoint }
+    }
+}
+
+impl<C> Service<Uri> for EndpointConnector<C>
+where
+    C: Service<Uri>,
+{
+    type Response = C::Response;
+    type Error = C::Error;
+    type Future = C::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let uri = match &self.endpoint {
+            Some(endpoint) => {
+                let mut parts = uri.into_parts();
+                parts.authority = Some(endpoint.clone());
+                Uri::from_parts(parts).expect("the URI only changed authority; qed")
+            }
+            None => uri,
+        };
+        self.inner.call(uri)
+    }
+}
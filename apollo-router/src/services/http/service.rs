@@ -1,4 +1,5 @@
 use std::fmt::Display;
+use std::num::NonZeroUsize;
 use std::sync::Arc;
 use std::task::Poll;
 use std::time::Duration;
@@ -11,6 +12,7 @@ use futures::TryFutureExt;
 use global::get_text_map_propagator;
 use http::header::ACCEPT_ENCODING;
 use http::header::CONTENT_ENCODING;
+use http::uri::Authority;
 use http::HeaderValue;
 use http::Request;
 use hyper::client::HttpConnector;
@@ -18,7 +20,9 @@ use hyper::Body;
 use hyper_rustls::HttpsConnector;
 #[cfg(unix)]
 use hyperlocal::UnixConnector;
+use lru::LruCache;
 use opentelemetry::global;
+use parking_lot::Mutex;
 use pin_project_lite::pin_project;
 use rustls::ClientConfig;
 use rustls::RootCertStore;
@@ -33,6 +37,12 @@ use tower_http::decompression::DecompressionLayer;
 use tracing::Instrument;
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
+use super::connection::InFlightRequest;
+use super::connection::MeteredConnector;
+use super::connection::PoolUsage;
+use super::endpoint::Endpoint;
+use super::endpoint::EndpointConnector;
+use super::proxy::ProxyConnector;
 use super::HttpRequest;
 use super::HttpResponse;
 use crate::axum_factory::compression::Compressor;
@@ -42,14 +52,21 @@ use crate::plugins::authentication::subgraph::SigningParamsConfig;
 use crate::plugins::telemetry::reload::prepare_context;
 use crate::plugins::telemetry::LOGGING_DISPLAY_BODY;
 use crate::plugins::telemetry::LOGGING_DISPLAY_HEADERS;
+use crate::plugins::traffic_shaping::ConnectionPoolConfig;
 use crate::plugins::traffic_shaping::Http2Config;
 use crate::services::trust_dns_connector::new_async_http_connector;
 use crate::services::trust_dns_connector::AsyncHyperResolver;
 use crate::Configuration;
 use crate::Context;
 
-type HTTPClient =
-    Decompression<hyper::Client<HttpsConnector<HttpConnector<AsyncHyperResolver>>, Body>>;
+type HTTPClient = Decompression<
+    hyper::Client<
+        HttpsConnector<
+            MeteredConnector<EndpointConnector<ProxyConnector<HttpConnector<AsyncHyperResolver>>>>,
+        >,
+        Body,
+    >,
+>;
 #[cfg(unix)]
 type UnixHTTPClient = Decompression<hyper::Client<UnixConnector, Body>>;
 #[cfg(unix)]
@@ -60,7 +77,12 @@ type MixedClient = HTTPClient;
 // interior mutability is not a concern here, the value is never modified
 #[allow(clippy::declare_interior_mutable_const)]
 static ACCEPTED_ENCODINGS: HeaderValue = HeaderValue::from_static("gzip, br, deflate");
-const POOL_IDLE_TIMEOUT_DURATION: Option<Duration> = Some(Duration::from_secs(5));
+const POOL_IDLE_TIMEOUT_DURATION: Duration = Duration::from_secs(5);
+const TCP_KEEPALIVE_DURATION: Duration = Duration::from_secs(60);
+const ENDPOINT_CLIENTS_CAPACITY: NonZeroUsize = match NonZeroUsize::new(64) {
+    Some(v) => v,
+    None => unreachable!(),
+};
 
 #[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema, Copy)]
 #[serde(rename_all = "lowercase")]
@@ -92,9 +114,14 @@ pub(crate) struct HttpClientService {
     // in the hot path. We use reqwest elsewhere because it's convenient and some of the
     // opentelemetry crate require reqwest clients to work correctly (at time of writing).
     http_client: HTTPClient,
+    /// Clients connecting to the endpoints chosen by subgraph discovery. Each endpoint needs its
+    /// own connection pool, since hyper pools the connections by URI authority
+    endpoint_clients: Arc<Mutex<LruCache<Authority, HTTPClient>>>,
+    make_client: Arc<dyn Fn(Option<Authority>) -> HTTPClient + Send + Sync>,
     #[cfg(unix)]
     unix_client: UnixHTTPClient,
     service: Arc<String>,
+    pool_usage: Arc<PoolUsage>,
 }
 
 impl HttpClientService {
@@ -103,6 +130,7 @@ impl HttpClientService {
         configuration: &Configuration,
         tls_root_store: &RootCertStore,
         http2: Http2Config,
+        connection_pool: &ConnectionPoolConfig,
     ) -> Result<Self, BoxError> {
         let name: String = service.into();
         let tls_cert_store = configuration
@@ -130,46 +158,96 @@ impl HttpClientService {
 
         let tls_client_config = generate_tls_client_config(tls_cert_store, client_cert_config)?;
 
-        HttpClientService::new(name, http2, tls_client_config)
+        HttpClientService::new(name, http2, tls_client_config, connection_pool)
     }
 
     pub(crate) fn new(
         service: impl Into<String>,
         http2: Http2Config,
         tls_config: ClientConfig,
+        connection_pool: &ConnectionPoolConfig,
     ) -> Result<Self, BoxError> {
+        let service = Arc::new(service.into());
         let mut http_connector = new_async_http_connector()?;
         http_connector.set_nodelay(true);
-        http_connector.set_keepalive(Some(std::time::Duration::from_secs(60)));
+        http_connector.set_keepalive(Some(
+            connection_pool
+                .tcp_keepalive
+                .unwrap_or(TCP_KEEPALIVE_DURATION),
+        ));
+        http_connector.set_connect_timeout(connection_pool.connect_timeout);
         http_connector.enforce_http(false);
+        let pool_usage = Arc::new(PoolUsage::new(service.clone()));
+
+        let mut client_builder = hyper::Client::builder();
+        client_builder
+            .pool_idle_timeout(
+                connection_pool
+                    .idle_timeout
+                    .unwrap_or(POOL_IDLE_TIMEOUT_DURATION),
+            )
+            .http2_only(http2 == Http2Config::Http2Only)
+            .http2_keep_alive_interval(connection_pool.http2_keepalive_interval);
+        if let Some(max_idle_per_host) = connection_pool.max_idle_per_host {
+            client_builder.pool_max_idle_per_host(max_idle_per_host);
+        }
+        if let Some(timeout) = connection_pool.http2_keepalive_timeout {
+            client_builder.http2_keep_alive_timeout(timeout);
+        }
+        if let Some(while_idle) = connection_pool.http2_keepalive_while_idle {
+            client_builder.http2_keep_alive_while_idle(while_idle);
+        }
 
-        let builder = hyper_rustls::HttpsConnectorBuilder::new()
-            .with_tls_config(tls_config)
-            .https_or_http()
-            .enable_http1();
+        let enable_http2 = http2 != Http2Config::Disable;
+        let connector_pool_usage = pool_usage.clone();
+        let make_client = move |endpoint: Option<Authority>| {
+            let http_connector = MeteredConnector::new(
+                EndpointConnector::new(ProxyConnector::new(http_connector.clone()), endpoint),
+                connector_pool_usage.clone(),
+            );
+
+            let builder = hyper_rustls::HttpsConnectorBuilder::new()
+                .with_tls_config(tls_config.clone())
+                .https_or_http()
+                .enable_http1();
+
+            let connector = if enable_http2 {
+                builder.enable_http2().wrap_connector(http_connector)
+            } else {
+                builder.wrap_connector(http_connector)
+            };
 
-        let connector = if http2 != Http2Config::Disable {
-            builder.enable_http2().wrap_connector(http_connector)
-        } else {
-            builder.wrap_connector(http_connector)
+            ServiceBuilder::new()
+                .layer(DecompressionLayer::new())
+                .service(client_builder.build(connector))
         };
 
-        let http_client = hyper::Client::builder()
-            .pool_idle_timeout(POOL_IDLE_TIMEOUT_DURATION)
-            .http2_only(http2 == Http2Config::Http2Only)
-            .build(connector);
         Ok(Self {
-            http_client: ServiceBuilder::new()
-                .layer(DecompressionLayer::new())
-                .service(http_client),
+            http_client: make_client(None),
+            endpoint_clients: Arc::new(Mutex::new(LruCache::new(ENDPOINT_CLIENTS_CAPACITY))),
+            make_client: Arc::new(make_client),
             #[cfg(unix)]
             unix_client: ServiceBuilder::new()
                 .layer(DecompressionLayer::new())
                 .service(hyper::Client::builder().build(UnixConnector)),
-            service: Arc::new(service.into()),
+            service,
+            pool_usage,
         })
     }
 
+    fn endpoint_client(&self, endpoint: Option<&Endpoint>) -> HTTPClient {
+        match endpoint {
+            Some(Endpoint(authority)) => self
+                .endpoint_clients
+                .lock()
+                .get_or_insert(authority.clone(), || {
+                    (self.make_client)(Some(authority.clone()))
+                })
+                .clone(),
+            None => self.http_client.clone(),
+        }
+    }
+
     pub(crate) fn native_roots_store() -> RootCertStore {
         let mut roots = rustls::RootCertStore::empty();
         let mut valid_count = 0;
@@ -245,13 +323,18 @@ impl tower::Service<HttpRequest> for HttpClientService {
             }
         });
 
+        // unix sockets do not go through the metered connector
+        let in_flight =
+            (schema_uri.scheme_str() != Some("unix")).then(|| self.pool_usage.start_request());
+
+        let endpoint = http_request.extensions().get::<Endpoint>();
         #[cfg(unix)]
         let client = match schema_uri.scheme().map(|s| s.as_str()) {
             Some("unix") => Either::B(self.unix_client.clone()),
-            _ => Either::A(self.http_client.clone()),
+            _ => Either::A(self.endpoint_client(endpoint)),
         };
         #[cfg(not(unix))]
-        let client = self.http_client.clone();
+        let client = self.endpoint_client(endpoint);
 
         let service_name = self.service.clone();
 
@@ -316,7 +399,7 @@ impl tower::Service<HttpRequest> for HttpClientService {
                 tracing::info!(http.request.body = ?http_request.body(), apollo.subgraph.name = %service_name, "Request body to subgraph {service_name:?}");
             }
 
-            let http_response = do_fetch(client, &context, &service_name, http_request)
+            let http_response = do_fetch(client, &context, &service_name, http_request, in_flight)
                 .instrument(http_req_span)
                 .await?;
 
@@ -338,6 +421,7 @@ async fn do_fetch(
     context: &Context,
     service_name: &str,
     request: Request<Body>,
+    in_flight: Option<InFlightRequest>,
 ) -> Result<http::Response<Body>, FetchError> {
     let _active_request_guard = context.enter_active_request();
     let (parts, body) = client
@@ -354,21 +438,29 @@ async fn do_fetch(
         .into_parts();
     Ok(http::Response::from_parts(
         parts,
-        Body::wrap_stream(BodyStream { inner: body }),
+        Body::wrap_stream(BodyStream {
+            inner: body,
+            _in_flight: in_flight,
+        }),
     ))
 }
 
 pin_project! {
     pub(crate) struct BodyStream<B: hyper::body::HttpBody> {
         #[pin]
-        inner: DecompressionBody<B>
+        inner: DecompressionBody<B>,
+        // the request stays in flight until its response body is dropped
+        _in_flight: Option<InFlightRequest>,
     }
 }
 
 impl<B: hyper::body::HttpBody> BodyStream<B> {
     /// Create a new `BodyStream`.
     pub(crate) fn new(body: DecompressionBody<B>) -> Self {
-        Self { inner: body }
+        Self {
+            inner: body,
+            _in_flight: None,
+        }
     }
 }
 
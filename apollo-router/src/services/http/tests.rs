@@ -39,9 +39,12 @@ use crate::configuration::load_key;
 use crate::configuration::TlsClient;
 use crate::configuration::TlsClientAuth;
 use crate::graphql::Response;
+use crate::metrics::FutureMetricsExt;
 use crate::plugin::PluginInit;
 use crate::plugin::PluginPrivate;
+use crate::plugins::traffic_shaping::ConnectionPoolConfig;
 use crate::plugins::traffic_shaping::Http2Config;
+use crate::services::http::Endpoint;
 use crate::services::http::HttpClientService;
 use crate::services::http::HttpRequest;
 use crate::services::supergraph;
@@ -116,6 +119,7 @@ async fn tls_self_signed() {
         &config,
         &rustls::RootCertStore::empty(),
         Http2Config::Enable,
+        &Default::default(),
     )
     .unwrap();
 
@@ -173,6 +177,7 @@ async fn tls_custom_root() {
         &config,
         &rustls::RootCertStore::empty(),
         Http2Config::Enable,
+        &Default::default(),
     )
     .unwrap();
 
@@ -283,6 +288,7 @@ async fn tls_client_auth() {
         &config,
         &rustls::RootCertStore::empty(),
         Http2Config::Enable,
+        &Default::default(),
     )
     .unwrap();
 
@@ -347,6 +353,7 @@ async fn test_subgraph_h2c() {
             .with_safe_defaults()
             .with_native_roots()
             .with_no_client_auth(),
+        &Default::default(),
     )
     .expect("can create a HttpService");
 
@@ -426,6 +433,7 @@ async fn test_compressed_request_response_body() {
             .with_safe_defaults()
             .with_native_roots()
             .with_no_client_auth(),
+        &Default::default(),
     )
     .expect("can create a HttpService");
 
@@ -454,6 +462,165 @@ async fn test_compressed_request_response_body() {
     );
 }
 
+#[tokio::test]
+async fn test_connection_pool_metrics() {
+    async {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        let make_svc = make_service_fn(|_conn| async {
+            Ok::<_, Infallible>(service_fn(|_request: http::Request<Body>| async {
+                Ok::<_, Infallible>(http::Response::new(Body::from(r#"{"data":null}"#)))
+            }))
+        });
+        tokio::task::spawn(Server::from_tcp(listener).unwrap().serve(make_svc));
+
+        let call = |subgraph_service: HttpClientService| async move {
+            let response = subgraph_service
+                .oneshot(HttpRequest {
+                    http_request: http::Request::builder()
+                        .uri(Uri::from_str(&format!("http://{socket_addr}")).unwrap())
+                        .body(r#"{"query":"{ me { name } }"}"#.into())
+                        .unwrap(),
+                    context: Context::new(),
+                })
+                .await
+                .unwrap();
+            hyper::body::to_bytes(response.http_response.into_body())
+                .await
+                .unwrap();
+            // let the connection go back to the pool
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        };
+        let client = |name: &str, connection_pool: &ConnectionPoolConfig| {
+            HttpClientService::new(
+                name,
+                Http2Config::Disable,
+                rustls::ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_native_roots()
+                    .with_no_client_auth(),
+                connection_pool,
+            )
+            .expect("can create a HttpService")
+        };
+
+        let pooled = client("pooled", &Default::default());
+        call(pooled.clone()).await;
+        call(pooled.clone()).await;
+        assert_counter!(
+            "apollo.router.http.client.connections.opened",
+            1,
+            "subgraph" = "pooled"
+        );
+        assert_up_down_counter!(
+            "apollo.router.http.client.connections.idle",
+            1,
+            "subgraph" = "pooled"
+        );
+        assert_up_down_counter!(
+            "apollo.router.http.client.connections.active",
+            0,
+            "subgraph" = "pooled"
+        );
+
+        // the connection is active until the response body is consumed
+        let response = pooled
+            .oneshot(HttpRequest {
+                http_request: http::Request::builder()
+                    .uri(Uri::from_str(&format!("http://{socket_addr}")).unwrap())
+                    .body(r#"{"query":"{ me { name } }"}"#.into())
+                    .unwrap(),
+                context: Context::new(),
+            })
+            .await
+            .unwrap();
+        assert_up_down_counter!(
+            "apollo.router.http.client.connections.active",
+            1,
+            "subgraph" = "pooled"
+        );
+        assert_up_down_counter!(
+            "apollo.router.http.client.connections.idle",
+            0,
+            "subgraph" = "pooled"
+        );
+        hyper::body::to_bytes(response.http_response.into_body())
+            .await
+            .unwrap();
+        assert_up_down_counter!(
+            "apollo.router.http.client.connections.active",
+            0,
+            "subgraph" = "pooled"
+        );
+
+        let not_pooled = client(
+            "not_pooled",
+            &ConnectionPoolConfig {
+                max_idle_per_host: Some(0),
+                ..Default::default()
+            },
+        );
+        call(not_pooled.clone()).await;
+        call(not_pooled).await;
+        assert_counter!(
+            "apollo.router.http.client.connections.opened",
+            2,
+            "subgraph" = "not_pooled"
+        );
+    }
+    .with_metrics()
+    .await;
+}
+
+#[tokio::test]
+async fn test_endpoint_keeps_the_host_of_the_url() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let socket_addr = listener.local_addr().unwrap();
+    let make_svc = make_service_fn(|_conn| async {
+        Ok::<_, Infallible>(service_fn(|request: http::Request<Body>| async move {
+            assert_eq!(
+                request.headers().get("host").unwrap(),
+                "products.example.com"
+            );
+            Ok::<_, Infallible>(http::Response::new(Body::from(r#"{"data":null}"#)))
+        }))
+    });
+    tokio::task::spawn(Server::from_tcp(listener).unwrap().serve(make_svc));
+
+    let subgraph_service = HttpClientService::new(
+        "products",
+        Http2Config::Disable,
+        rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_native_roots()
+            .with_no_client_auth(),
+        &Default::default(),
+    )
+    .expect("can create a HttpService");
+
+    let mut http_request = http::Request::builder()
+        .uri("http://products.example.com/graphql")
+        .body(r#"{"query":"{ me { name } }"}"#.into())
+        .unwrap();
+    http_request
+        .extensions_mut()
+        .insert(Endpoint(socket_addr.to_string().parse().unwrap()));
+    let response = subgraph_service
+        .oneshot(HttpRequest {
+            http_request,
+            context: Context::new(),
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(
+        hyper::body::to_bytes(response.http_response.into_body())
+            .await
+            .unwrap(),
+        r#"{"data":null}"#
+    );
+}
+
 const SCHEMA: &str = r#"schema
         @core(feature: "https://specs.apollo.dev/core/v0.1")
         @core(feature: "https://specs.apollo.dev/join/v0.1")
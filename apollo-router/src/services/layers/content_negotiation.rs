@@ -9,6 +9,7 @@ use mediatype::names::APPLICATION;
 use mediatype::names::JSON;
 use mediatype::names::MIXED;
 use mediatype::names::MULTIPART;
+use mediatype::names::TEXT;
 use mediatype::names::_STAR;
 use mediatype::MediaTypeList;
 use mediatype::ReadParams;
@@ -22,11 +23,13 @@ use crate::graphql;
 use crate::layers::sync_checkpoint::CheckpointService;
 use crate::layers::ServiceExt as _;
 use crate::services::router;
+use crate::services::router::service::EVENT_STREAM_CONTENT_TYPE_HEADER_VALUE;
 use crate::services::router::service::MULTIPART_DEFER_CONTENT_TYPE_HEADER_VALUE;
 use crate::services::router::service::MULTIPART_SUBSCRIPTION_CONTENT_TYPE_HEADER_VALUE;
 use crate::services::router::ClientRequestAccepts;
 use crate::services::supergraph;
 use crate::services::APPLICATION_JSON_HEADER_VALUE;
+use crate::services::EVENT_STREAM_CONTENT_TYPE;
 use crate::services::MULTIPART_DEFER_ACCEPT;
 use crate::services::MULTIPART_DEFER_SPEC_PARAMETER;
 use crate::services::MULTIPART_DEFER_SPEC_VALUE;
@@ -86,11 +89,27 @@ where
                     return Ok(ControlFlow::Break(response.into()));
                 }
 
-                let accepts = parse_accept(req.router_request.headers());
+                let mut accepts = parse_accept(req.router_request.headers());
+
+                // operations sent over a client WebSocket connection get their responses
+                // as separate messages, so they can use @defer and subscriptions
+                if req
+                    .context
+                    .extensions()
+                    .lock()
+                    .get::<ClientRequestAccepts>()
+                    .map(|accepts| accepts.websocket)
+                    .unwrap_or_default()
+                {
+                    accepts.websocket = true;
+                    accepts.multipart_defer = true;
+                    accepts.multipart_subscription = true;
+                }
 
                 if accepts.wildcard
                     || accepts.multipart_defer
                     || accepts.multipart_subscription
+                    || accepts.event_stream
                     || accepts.json
                 {
                     req.context.extensions().lock().insert(accepts);
@@ -103,11 +122,12 @@ where
                                 "errors": [
                                     graphql::Error::builder()
                                         .message(format!(
-                                            r#"'accept' header must be one of: \"*/*\", {:?}, {:?}, {:?} or {:?}"#,
+                                            r#"'accept' header must be one of: \"*/*\", {:?}, {:?}, {:?}, {:?} or {:?}"#,
                                             APPLICATION_JSON.essence_str(),
                                             GRAPHQL_JSON_RESPONSE_HEADER_VALUE,
                                             MULTIPART_SUBSCRIPTION_ACCEPT,
-                                            MULTIPART_DEFER_ACCEPT
+                                            MULTIPART_DEFER_ACCEPT,
+                                            EVENT_STREAM_CONTENT_TYPE,
                                         ))
                                         .extension_code("INVALID_ACCEPT_HEADER")
                                         .build()
@@ -143,6 +163,8 @@ where
                     json: accepts_json,
                     multipart_defer: accepts_multipart_defer,
                     multipart_subscription: accepts_multipart_subscription,
+                    event_stream: accepts_event_stream,
+                    ..
                 } = context
                     .extensions()
                     .lock()
@@ -164,6 +186,10 @@ where
                         CONTENT_TYPE,
                         MULTIPART_SUBSCRIPTION_CONTENT_TYPE_HEADER_VALUE.clone(),
                     );
+                } else if accepts_event_stream {
+                    parts
+                        .headers
+                        .insert(CONTENT_TYPE, EVENT_STREAM_CONTENT_TYPE_HEADER_VALUE.clone());
                 }
                 (parts, res)
             })
@@ -236,6 +262,11 @@ fn parse_accept(headers: &HeaderMap) -> ClientRequestAccepts {
                             accepts.multipart_subscription = true
                         }
                     }
+                    if !accepts.event_stream
+                        && (mime.ty == TEXT && mime.subty.as_str() == "event-stream")
+                    {
+                        accepts.event_stream = true
+                    }
                 }
             }
         }
@@ -292,5 +323,11 @@ mod tests {
         default_headers.append(ACCEPT, HeaderValue::from_static(MULTIPART_DEFER_ACCEPT));
         let accepts = parse_accept(&default_headers);
         assert!(accepts.multipart_defer);
+
+        let mut default_headers = HeaderMap::new();
+        default_headers.insert(ACCEPT, HeaderValue::from_static(EVENT_STREAM_CONTENT_TYPE));
+        let accepts = parse_accept(&default_headers);
+        assert!(accepts.event_stream);
+        assert!(!accepts.json);
     }
 }
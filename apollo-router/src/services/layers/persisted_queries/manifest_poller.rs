@@ -13,6 +13,7 @@ use serde::Serialize;
 use tokio::sync::mpsc;
 use tower::BoxError;
 
+use crate::services::http::proxy::ClientBuilderExt;
 use crate::uplink::persisted_queries_manifest_stream::MaybePersistedQueriesManifestChunks;
 use crate::uplink::persisted_queries_manifest_stream::PersistedQueriesManifestChunk;
 use crate::uplink::persisted_queries_manifest_stream::PersistedQueriesManifestQuery;
@@ -217,7 +218,7 @@ impl PersistedQueryManifestPoller {
                 freeform_graphql_behavior: FreeformGraphQLBehavior::DenyAll { log_unknown: false },
             }));
 
-            let http_client = Client::builder().timeout(uplink_config.timeout).gzip(true).build()
+            let http_client = Client::builder().outbound_proxy().timeout(uplink_config.timeout).gzip(true).build()
             .map_err(|e| -> BoxError {
                 format!(
                     "could not initialize HTTP client for fetching persisted queries manifest chunks: {}",
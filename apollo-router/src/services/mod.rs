@@ -28,6 +28,7 @@ pub(crate) mod external;
 pub(crate) mod http;
 pub(crate) mod layers;
 pub(crate) mod new_service;
+pub(crate) mod query_batching;
 pub(crate) mod query_planner;
 pub mod router;
 pub mod subgraph;
@@ -82,3 +83,5 @@ pub(crate) const MULTIPART_SUBSCRIPTION_CONTENT_TYPE: &str =
     "multipart/mixed;boundary=\"graphql\";subscriptionSpec=1.0";
 pub(crate) const MULTIPART_SUBSCRIPTION_SPEC_PARAMETER: &str = "subscriptionSpec";
 pub(crate) const MULTIPART_SUBSCRIPTION_SPEC_VALUE: &str = "1.0";
+
+pub(crate) const EVENT_STREAM_CONTENT_TYPE: &str = "text/event-stream";
@@ -0,0 +1,593 @@
+//! Batching of the requests sent to subgraphs.
+//!
+//! Fetches made to a subgraph by the operations of a client batch (and optionally by concurrent
+//! operations) are coalesced into a single request whose body is an array of GraphQL requests.
+//! The subgraph must answer with an array of GraphQL responses, in the same order, which are then
+//! dispatched back to the waiting fetches.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use http::header::ACCEPT;
+use http::header::CONTENT_TYPE;
+use hyper::Body;
+use parking_lot::Mutex;
+use serde_json_bytes::Value;
+use tokio::sync::oneshot;
+use tokio::sync::watch;
+use tower::BoxError;
+use tracing::Instrument;
+
+use super::http::HttpClientServiceFactory;
+use super::subgraph_service::call_http;
+use super::subgraph_service::do_fetch;
+use super::subgraph_service::ACCEPT_GRAPHQL_JSON;
+use super::subgraph_service::APPLICATION_JSON_HEADER_VALUE;
+use crate::configuration::SubgraphBatching;
+use crate::error::FetchError;
+use crate::graphql;
+use crate::plugins::telemetry::LOGGING_DISPLAY_BODY;
+use crate::query_planner::OperationKind;
+use crate::services::SubgraphRequest;
+use crate::services::SubgraphResponse;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+fn next_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The operations of a client batch that are still executing
+#[derive(Debug)]
+pub(crate) struct Batch {
+    id: u64,
+    active: watch::Sender<HashSet<usize>>,
+}
+
+impl Batch {
+    pub(crate) fn new(size: usize) -> Arc<Self> {
+        let (active, _) = watch::channel((0..size).collect());
+        Arc::new(Self {
+            id: next_id(),
+            active,
+        })
+    }
+
+    /// Returns the handle of the operation at `index` in the client batch
+    pub(crate) fn query(self: &Arc<Self>, index: usize) -> BatchQuery {
+        BatchQuery {
+            batch: self.clone(),
+            index,
+        }
+    }
+
+    fn is_waiting_for_all(&self, operations: &HashSet<usize>) -> bool {
+        self.active
+            .borrow()
+            .iter()
+            .all(|index| operations.contains(index))
+    }
+}
+
+/// An operation of a client batch, stored in the context extensions of its supergraph request
+#[derive(Clone, Debug)]
+pub(crate) struct BatchQuery {
+    batch: Arc<Batch>,
+    index: usize,
+}
+
+impl BatchQuery {
+    /// Marks the operation as finished: the batch requests to subgraphs will not wait for its fetches anymore
+    pub(crate) fn finish(&self) {
+        self.batch.active.send_modify(|active| {
+            active.remove(&self.index);
+        });
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct BatchKey {
+    /// Client batch the fetches come from, if any
+    batch: Option<u64>,
+    uri: String,
+    /// Only fetches with the same headers can share a request
+    headers: Vec<(String, Vec<u8>)>,
+}
+
+impl BatchKey {
+    fn new(request: &SubgraphRequest, batch: Option<u64>) -> Self {
+        let mut headers: Vec<(String, Vec<u8>)> = request
+            .subgraph_request
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().to_vec()))
+            .collect();
+        headers.sort();
+        Self {
+            batch,
+            uri: request.subgraph_request.uri().to_string(),
+            headers,
+        }
+    }
+}
+
+struct PendingFetch {
+    request: SubgraphRequest,
+    body: graphql::Request,
+    sender: oneshot::Sender<Result<SubgraphResponse, BoxError>>,
+}
+
+struct PendingBatch {
+    generation: u64,
+    client_batch: Option<Arc<Batch>>,
+    /// Indexes of the client batch operations waiting in this batch
+    operations: HashSet<usize>,
+    fetches: Vec<PendingFetch>,
+}
+
+/// Coalesces the fetches made to a subgraph
+pub(crate) struct SubgraphBatcher {
+    service_name: Arc<String>,
+    configuration: SubgraphBatching,
+    client_factory: HttpClientServiceFactory,
+    pending: Mutex<HashMap<BatchKey, PendingBatch>>,
+}
+
+impl SubgraphBatcher {
+    pub(crate) fn new(
+        service_name: Arc<String>,
+        configuration: SubgraphBatching,
+        client_factory: HttpClientServiceFactory,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            service_name,
+            configuration,
+            client_factory,
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns true if the request can be added to a batch request
+    pub(crate) fn accepts(&self, request: &SubgraphRequest) -> bool {
+        // mutations keep their own request to preserve their execution order
+        request.operation_kind == OperationKind::Query
+            && (self.configuration.coalesce_concurrent
+                || request
+                    .context
+                    .extensions()
+                    .lock()
+                    .contains_key::<BatchQuery>())
+    }
+
+    pub(crate) async fn call(
+        self: &Arc<Self>,
+        request: SubgraphRequest,
+        body: graphql::Request,
+    ) -> Result<SubgraphResponse, BoxError> {
+        let batch_query = request
+            .context
+            .extensions()
+            .lock()
+            .get::<BatchQuery>()
+            .cloned();
+        let key = BatchKey::new(&request, batch_query.as_ref().map(|query| query.batch.id));
+        let (sender, receiver) = oneshot::channel();
+        let fetch = PendingFetch {
+            request,
+            body,
+            sender,
+        };
+
+        let new_batch = {
+            let mut pending = self.pending.lock();
+            let (new_batch, batch) = match pending.entry(key.clone()) {
+                Entry::Occupied(entry) => (None, entry.into_mut()),
+                Entry::Vacant(entry) => {
+                    let generation = next_id();
+                    let batch = entry.insert(PendingBatch {
+                        generation,
+                        client_batch: batch_query.as_ref().map(|query| query.batch.clone()),
+                        operations: HashSet::new(),
+                        fetches: Vec::new(),
+                    });
+                    (Some(generation), batch)
+                }
+            };
+            if let Some(query) = &batch_query {
+                batch.operations.insert(query.index);
+            }
+            batch.fetches.push(fetch);
+
+            let is_full = self
+                .configuration
+                .max_size
+                .map(|max_size| batch.fetches.len() >= max_size)
+                .unwrap_or_default();
+            let is_complete = batch
+                .client_batch
+                .as_ref()
+                .map(|client_batch| client_batch.is_waiting_for_all(&batch.operations))
+                .unwrap_or_default();
+            if is_full || is_complete {
+                if let Some(batch) = pending.remove(&key) {
+                    self.send(batch.fetches);
+                }
+                None
+            } else {
+                new_batch
+            }
+        };
+
+        if let Some(generation) = new_batch {
+            self.watch(key, generation, batch_query.map(|query| query.batch));
+        }
+
+        receiver.await.map_err(|_| {
+            BoxError::from(FetchError::SubrequestBatchingError {
+                service: self.service_name.to_string(),
+                reason: "the batch request was cancelled".to_string(),
+            })
+        })?
+    }
+
+    /// Sends the pending batch once `max_wait` elapsed, or once all the operations of its client
+    /// batch that are still executing are waiting in it
+    fn watch(self: &Arc<Self>, key: BatchKey, generation: u64, client_batch: Option<Arc<Batch>>) {
+        let batcher = self.clone();
+        tokio::spawn(async move {
+            let deadline = tokio::time::sleep(batcher.configuration.max_wait);
+            tokio::pin!(deadline);
+            let mut active = client_batch.map(|batch| batch.active.subscribe());
+
+            loop {
+                tokio::select! {
+                    _ = &mut deadline => {
+                        batcher.flush(&key, generation, false);
+                        return;
+                    }
+                    Some(Ok(())) = async {
+                        match active.as_mut() {
+                            Some(active) => Some(active.changed().await),
+                            None => None,
+                        }
+                    } => {
+                        if batcher.flush(&key, generation, true) {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Returns true if the pending batch is not waiting anymore
+    fn flush(&self, key: &BatchKey, generation: u64, only_if_complete: bool) -> bool {
+        let mut pending = self.pending.lock();
+        match pending.get(key) {
+            Some(batch) if batch.generation == generation => {
+                let is_complete = batch
+                    .client_batch
+                    .as_ref()
+                    .map(|client_batch| client_batch.is_waiting_for_all(&batch.operations))
+                    .unwrap_or_default();
+                if only_if_complete && !is_complete {
+                    return false;
+                }
+                if let Some(batch) = pending.remove(key) {
+                    self.send(batch.fetches);
+                }
+                true
+            }
+            // already sent
+            _ => true,
+        }
+    }
+
+    fn send(&self, fetches: Vec<PendingFetch>) {
+        let service_name = self.service_name.clone();
+        let client_factory = self.client_factory.clone();
+        tokio::spawn(async move { send_batch(&service_name, client_factory, fetches).await });
+    }
+}
+
+async fn send_batch(
+    service_name: &str,
+    client_factory: HttpClientServiceFactory,
+    mut fetches: Vec<PendingFetch>,
+) {
+    let client = client_factory.create(service_name);
+
+    if fetches.len() == 1 {
+        let PendingFetch {
+            request,
+            body,
+            sender,
+        } = fetches.remove(0);
+        let context = request.context.clone();
+        let _ = sender.send(call_http(request, body, context, client, service_name).await);
+        return;
+    }
+
+    let first = &fetches[0].request;
+    let context = first.context.clone();
+    let bodies: Vec<&graphql::Request> = fetches.iter().map(|fetch| &fetch.body).collect();
+    let body = serde_json::to_string(&bodies).expect("JSON serialization should not fail");
+    let mut request = http::Request::builder()
+        .method(first.subgraph_request.method().clone())
+        .uri(first.subgraph_request.uri().clone())
+        .version(first.subgraph_request.version())
+        .body(Body::from(body))
+        .expect("the request parts come from a valid request");
+    *request.headers_mut() = first.subgraph_request.headers().clone();
+    request
+        .headers_mut()
+        .insert(CONTENT_TYPE, APPLICATION_JSON_HEADER_VALUE.clone());
+    request
+        .headers_mut()
+        .append(ACCEPT, ACCEPT_GRAPHQL_JSON.clone());
+
+    let subgraph_req_span = tracing::info_span!("subgraph_batch_request",
+        "otel.kind" = "CLIENT",
+        "http.url" = %request.uri(),
+        "apollo.subgraph.name" = %service_name,
+        "apollo.subgraph.batch.size" = fetches.len(),
+    );
+
+    let display_body = context.contains_key(LOGGING_DISPLAY_BODY);
+    let (parts, content_type, body) =
+        match do_fetch(client, &context, service_name, request, display_body)
+            .instrument(subgraph_req_span)
+            .await
+        {
+            Ok(response) => response,
+            Err(err) => {
+                for fetch in fetches {
+                    let _ = fetch.sender.send(Err(Box::new(err.clone())));
+                }
+                return;
+            }
+        };
+
+    if display_body {
+        if let Some(Ok(b)) = &body {
+            tracing::info!(
+                response.body = %String::from_utf8_lossy(b), apollo.subgraph.name = %service_name, "Raw batch response body from subgraph {service_name:?} received"
+            );
+        }
+    }
+
+    let responses = match (content_type, body) {
+        (Ok(_), Some(Ok(body))) => parse_batch_response(service_name, body, fetches.len()),
+        (Err(err), _) | (_, Some(Err(err))) => Err(err),
+        (Ok(_), None) => Err(FetchError::SubrequestBatchingError {
+            service: service_name.to_string(),
+            reason: "empty response body".to_string(),
+        }),
+    };
+    let mut responses = match responses {
+        Ok(responses) => responses,
+        Err(err) => (0..fetches.len())
+            .map(|_| {
+                graphql::Response::builder()
+                    .error(err.to_graphql_error(None))
+                    .build()
+            })
+            .collect(),
+    };
+
+    // Add an error for response codes that are not 2xx
+    if !parts.status.is_success() {
+        let error = FetchError::SubrequestHttpError {
+            service: service_name.to_string(),
+            status_code: Some(parts.status.as_u16()),
+            reason: format!(
+                "{}: {}",
+                parts.status.as_str(),
+                parts.status.canonical_reason().unwrap_or("Unknown")
+            ),
+        }
+        .to_graphql_error(None);
+        for response in responses.iter_mut() {
+            response.errors.insert(0, error.clone());
+        }
+    }
+
+    for (fetch, graphql_response) in fetches.into_iter().zip(responses) {
+        let mut response = http::Response::new(graphql_response);
+        *response.status_mut() = parts.status;
+        *response.version_mut() = parts.version;
+        *response.headers_mut() = parts.headers.clone();
+        let _ = fetch.sender.send(Ok(SubgraphResponse::new_from_response(
+            response,
+            fetch.request.context,
+        )));
+    }
+}
+
+fn parse_batch_response(
+    service_name: &str,
+    body: bytes::Bytes,
+    expected: usize,
+) -> Result<Vec<graphql::Response>, FetchError> {
+    let malformed = |reason: String| FetchError::SubrequestBatchingError {
+        service: service_name.to_string(),
+        reason,
+    };
+    let responses = match Value::from_bytes(body).map_err(|err| malformed(err.to_string()))? {
+        Value::Array(responses) => responses,
+        _ => return Err(malformed("expected an array of responses".to_string())),
+    };
+    if responses.len() != expected {
+        return Err(malformed(format!(
+            "expected {expected} responses, received {}",
+            responses.len()
+        )));
+    }
+
+    Ok(responses
+        .into_iter()
+        .map(|value| {
+            graphql::Response::from_value(service_name, value).unwrap_or_else(|error| {
+                graphql::Response::builder()
+                    .error(error.to_graphql_error(None))
+                    .build()
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::time::Duration;
+
+    use hyper::service::make_service_fn;
+    use hyper::service::service_fn;
+    use hyper::Server;
+    use serde_json_bytes::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::plugins::traffic_shaping::Http2Config;
+    use crate::Configuration;
+    use crate::Context;
+
+    async fn emulate_batching_subgraph(listener: TcpListener) {
+        async fn handle(request: http::Request<Body>) -> Result<http::Response<Body>, Infallible> {
+            let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+            let requests: Vec<graphql::Request> = serde_json::from_slice(&body).unwrap();
+            let responses: Vec<graphql::Response> = requests
+                .into_iter()
+                .map(|request| {
+                    graphql::Response::builder()
+                        .data(json!({ "query": request.query.unwrap_or_default() }))
+                        .build()
+                })
+                .collect();
+            Ok(http::Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(serde_json::to_string(&responses).unwrap().into())
+                .unwrap())
+        }
+
+        let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+        let server = Server::from_tcp(listener.into_std().unwrap())
+            .unwrap()
+            .serve(make_svc);
+        server.await.unwrap();
+    }
+
+    fn subgraph_request(uri: &http::Uri, query: &str, context: Context) -> SubgraphRequest {
+        SubgraphRequest::builder()
+            .supergraph_request(Arc::new(
+                http::Request::builder()
+                    .body(graphql::Request::builder().query(query).build())
+                    .unwrap(),
+            ))
+            .subgraph_request(
+                http::Request::builder()
+                    .uri(uri.clone())
+                    .body(graphql::Request::builder().query(query).build())
+                    .unwrap(),
+            )
+            .operation_kind(OperationKind::Query)
+            .context(context)
+            .build()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn it_coalesces_the_fetches_of_a_client_batch() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        tokio::task::spawn(emulate_batching_subgraph(listener));
+        let uri: http::Uri = format!("http://{socket_addr}").parse().unwrap();
+
+        let batcher = SubgraphBatcher::new(
+            Arc::new("test".to_string()),
+            SubgraphBatching {
+                enabled: true,
+                // the batch must be sent as soon as both operations are waiting
+                max_wait: Duration::from_secs(3600),
+                ..Default::default()
+            },
+            HttpClientServiceFactory::from_config(
+                "test",
+                &Configuration::default(),
+                Http2Config::Enable,
+            ),
+        );
+
+        let batch = Batch::new(2);
+        let requests = ["{ a }", "{ b }"]
+            .into_iter()
+            .enumerate()
+            .map(|(index, query)| {
+                let context = Context::new();
+                context.extensions().lock().insert(batch.query(index));
+                let request = subgraph_request(&uri, query, context);
+                assert!(batcher.accepts(&request));
+                let body = request.subgraph_request.body().clone();
+                let batcher = batcher.clone();
+                async move { batcher.call(request, body).await.unwrap() }
+            });
+        let responses = futures::future::join_all(requests).await;
+
+        assert_eq!(
+            responses[0].response.body().data,
+            Some(json!({ "query": "{ a }" }))
+        );
+        assert_eq!(
+            responses[1].response.body().data,
+            Some(json!({ "query": "{ b }" }))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_does_not_wait_for_finished_operations() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        tokio::task::spawn(emulate_batching_subgraph(listener));
+        let uri: http::Uri = format!("http://{socket_addr}").parse().unwrap();
+
+        let batcher = SubgraphBatcher::new(
+            Arc::new("test".to_string()),
+            SubgraphBatching {
+                enabled: true,
+                max_wait: Duration::from_secs(3600),
+                ..Default::default()
+            },
+            HttpClientServiceFactory::from_config(
+                "test",
+                &Configuration::default(),
+                Http2Config::Enable,
+            ),
+        );
+
+        let batch = Batch::new(2);
+        let context = Context::new();
+        context.extensions().lock().insert(batch.query(0));
+        let request = subgraph_request(&uri, "{ a }", context);
+        let body = request.subgraph_request.body().clone();
+        let response = tokio::spawn({
+            let batcher = batcher.clone();
+            async move { batcher.call(request, body).await.unwrap() }
+        });
+
+        // the second operation of the batch never calls this subgraph
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        batch.query(1).finish();
+
+        let response = tokio::time::timeout(Duration::from_secs(5), response)
+            .await
+            .expect("the batch request should not wait for a finished operation")
+            .unwrap();
+        assert_eq!(
+            response.response.body().data,
+            Some(json!({ "query": "{ a }" }))
+        );
+    }
+}
@@ -392,4 +392,7 @@ pub(crate) struct ClientRequestAccepts {
     pub(crate) multipart_subscription: bool,
     pub(crate) json: bool,
     pub(crate) wildcard: bool,
+    pub(crate) event_stream: bool,
+    /// The request was received over a client WebSocket connection
+    pub(crate) websocket: bool,
 }
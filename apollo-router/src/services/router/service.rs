@@ -14,6 +14,7 @@ use futures::future::BoxFuture;
 use futures::stream;
 use futures::stream::once;
 use futures::stream::StreamExt;
+use http::header::CACHE_CONTROL;
 use http::header::CONTENT_TYPE;
 use http::header::VARY;
 use http::request::Parts;
@@ -43,6 +44,7 @@ use crate::http_ext;
 use crate::plugin::test::MockSupergraphService;
 use crate::protocols::multipart::Multipart;
 use crate::protocols::multipart::ProtocolMode;
+use crate::protocols::sse::EventStream;
 use crate::query_planner::WarmUpCachingQueryKey;
 use crate::router_factory::RouterFactory;
 use crate::services::layers::apq::APQLayer;
@@ -52,6 +54,8 @@ use crate::services::layers::persisted_queries::PersistedQueryLayer;
 use crate::services::layers::query_analysis::QueryAnalysisLayer;
 use crate::services::layers::static_page::StaticPageLayer;
 use crate::services::new_service::ServiceFactory;
+use crate::services::query_batching::Batch;
+use crate::services::query_batching::BatchQuery;
 use crate::services::router;
 #[cfg(test)]
 use crate::services::supergraph;
@@ -64,6 +68,7 @@ use crate::services::SupergraphCreator;
 use crate::services::SupergraphRequest;
 use crate::services::SupergraphResponse;
 use crate::services::APPLICATION_JSON_HEADER_VALUE;
+use crate::services::EVENT_STREAM_CONTENT_TYPE;
 use crate::services::MULTIPART_DEFER_ACCEPT;
 use crate::services::MULTIPART_DEFER_CONTENT_TYPE;
 use crate::services::MULTIPART_SUBSCRIPTION_ACCEPT;
@@ -77,9 +82,12 @@ pub(crate) static MULTIPART_DEFER_CONTENT_TYPE_HEADER_VALUE: HeaderValue =
     HeaderValue::from_static(MULTIPART_DEFER_CONTENT_TYPE);
 pub(crate) static MULTIPART_SUBSCRIPTION_CONTENT_TYPE_HEADER_VALUE: HeaderValue =
     HeaderValue::from_static(MULTIPART_SUBSCRIPTION_CONTENT_TYPE);
+pub(crate) static EVENT_STREAM_CONTENT_TYPE_HEADER_VALUE: HeaderValue =
+    HeaderValue::from_static(EVENT_STREAM_CONTENT_TYPE);
 static ACCEL_BUFFERING_HEADER_NAME: HeaderName = HeaderName::from_static("x-accel-buffering");
 static ACCEL_BUFFERING_HEADER_VALUE: HeaderValue = HeaderValue::from_static("no");
 static ORIGIN_HEADER_VALUE: HeaderValue = HeaderValue::from_static("origin");
+static NO_CACHE_HEADER_VALUE: HeaderValue = HeaderValue::from_static("no-cache");
 
 /// Containing [`Service`] in the request lifecyle.
 #[derive(Clone)]
@@ -255,6 +263,8 @@ impl RouterService {
             json: accepts_json,
             multipart_defer: accepts_multipart_defer,
             multipart_subscription: accepts_multipart_subscription,
+            event_stream: accepts_event_stream,
+            websocket: accepts_websocket,
         } = context
             .extensions()
             .lock()
@@ -279,7 +289,35 @@ impl RouterService {
                 })
             }
             Some(response) => {
-                if !response.has_next.unwrap_or(false)
+                if accepts_websocket {
+                    // the WebSocket connection handler sends each response as a separate
+                    // message, so they are written as newline delimited JSON
+                    parts
+                        .headers
+                        .insert(CONTENT_TYPE, APPLICATION_JSON_HEADER_VALUE.clone());
+                    let responses = match response.subscribed {
+                        Some(true) => body,
+                        _ => once(ready(response)).chain(body).boxed(),
+                    };
+                    let lines = responses
+                        // the last response of a subscription only signals its end
+                        .filter(|response| {
+                            ready(
+                                response.subscribed != Some(false)
+                                    || response.data.is_some()
+                                    || !response.errors.is_empty(),
+                            )
+                        })
+                        .map(|response| {
+                            let mut line = serde_json::to_vec(&response)?;
+                            line.push(b'\n');
+                            Ok::<_, serde_json::Error>(line)
+                        });
+                    Ok(router::Response {
+                        response: http::Response::from_parts(parts, Body::wrap_stream(lines)),
+                        context,
+                    })
+                } else if !response.has_next.unwrap_or(false)
                     && !response.subscribed.unwrap_or(false)
                     && (accepts_json || accepts_wildcard)
                 {
@@ -336,17 +374,43 @@ impl RouterService {
                     });
 
                     Ok(RouterResponse { response, context })
+                } else if accepts_event_stream {
+                    parts
+                        .headers
+                        .insert(CONTENT_TYPE, EVENT_STREAM_CONTENT_TYPE_HEADER_VALUE.clone());
+                    parts
+                        .headers
+                        .insert(CACHE_CONTROL, NO_CACHE_HEADER_VALUE.clone());
+                    parts.headers.insert(
+                        ACCEL_BUFFERING_HEADER_NAME.clone(),
+                        ACCEL_BUFFERING_HEADER_VALUE.clone(),
+                    );
+                    let event_stream = match response.subscribed {
+                        Some(true) => EventStream::new(body, ProtocolMode::Subscription),
+                        _ => {
+                            EventStream::new(once(ready(response)).chain(body), ProtocolMode::Defer)
+                        }
+                    };
+
+                    Ok(RouterResponse {
+                        response: http::Response::from_parts(
+                            parts,
+                            Body::wrap_stream(event_stream),
+                        ),
+                        context,
+                    })
                 } else {
                     // this should be unreachable due to a previous check, but just to be sure...
                     Ok(router::Response::error_builder()
                             .error(
                                 graphql::Error::builder()
                                     .message(format!(
-                                        r#"'accept' header must be one of: \"*/*\", {:?}, {:?}, {:?} or {:?}"#,
+                                        r#"'accept' header must be one of: \"*/*\", {:?}, {:?}, {:?}, {:?} or {:?}"#,
                                         APPLICATION_JSON.essence_str(),
                                         GRAPHQL_JSON_RESPONSE_HEADER_VALUE,
                                         MULTIPART_DEFER_ACCEPT,
                                         MULTIPART_SUBSCRIPTION_ACCEPT,
+                                        EVENT_STREAM_CONTENT_TYPE,
                                     ))
                                     .extension_code("INVALID_ACCEPT_HEADER")
                                     .build(),
@@ -389,9 +453,23 @@ impl RouterService {
             }
         };
 
-        let futures = supergraph_requests
-            .into_iter()
-            .map(|supergraph_request| self.process_supergraph_request(supergraph_request));
+        let futures = supergraph_requests.into_iter().map(|supergraph_request| {
+            let batch_query = supergraph_request
+                .context
+                .extensions()
+                .lock()
+                .get::<BatchQuery>()
+                .cloned();
+            let response = self.process_supergraph_request(supergraph_request);
+            async move {
+                let response = response.await;
+                // The fetches of the other operations of the batch should not wait for this one
+                if let Some(batch_query) = batch_query {
+                    batch_query.finish();
+                }
+                response
+            }
+        });
 
         // Use join_all to preserve ordering of concurrent operations
         // (Short circuit processing and propagate any errors in the batch)
@@ -608,11 +686,12 @@ impl RouterService {
         let ok_results = graphql_requests?;
         let mut results = Vec::with_capacity(ok_results.len());
 
-        if ok_results.len() > 1 {
-            context
-                .extensions()
-                .lock()
-                .insert(self.experimental_batching.clone());
+        // Lets the subgraph services coalesce the fetches made by the operations of the batch
+        let batch = (ok_results.len() > 1).then(|| Batch::new(ok_results.len()));
+        if let Some(batch) = &batch {
+            let mut extensions = context.extensions().lock();
+            extensions.insert(self.experimental_batching.clone());
+            extensions.insert(batch.query(0));
         }
 
         let mut ok_results_it = ok_results.into_iter();
@@ -635,7 +714,7 @@ impl RouterService {
         // would mean all the requests in a batch shared the same set of private entries and review
         // comments expressed the sentiment that this may be a bad thing...)
         //
-        for graphql_request in ok_results_it {
+        for (index, graphql_request) in ok_results_it.enumerate() {
             // XXX Lose http extensions, is that ok?
             let mut new = http_ext::clone_http_request(&sg);
             *new.body_mut() = graphql_request;
@@ -653,10 +732,13 @@ impl RouterService {
                     .lock()
                     .insert(client_request_accepts);
             }
-            new_context
-                .extensions()
-                .lock()
-                .insert(self.experimental_batching.clone());
+            {
+                let mut extensions = new_context.extensions().lock();
+                extensions.insert(self.experimental_batching.clone());
+                if let Some(batch) = &batch {
+                    extensions.insert(batch.query(index + 1));
+                }
+            }
             results.push(SupergraphRequest {
                 supergraph_request: new,
                 // Build a new context. Cloning would cause issues.
@@ -21,6 +21,7 @@ use crate::services::subgraph;
 use crate::services::supergraph;
 use crate::services::SupergraphRequest;
 use crate::services::SupergraphResponse;
+use crate::services::EVENT_STREAM_CONTENT_TYPE;
 use crate::services::MULTIPART_DEFER_CONTENT_TYPE;
 use crate::Context;
 
@@ -471,6 +472,53 @@ async fn it_will_process_a_non_batched_defered_query() {
     assert_eq!(expected_response, data);
 }
 
+#[tokio::test]
+async fn it_streams_a_deferred_query_as_server_sent_events() {
+    let expected_response = "event: next\ndata: {\"data\":{\"topProducts\":[{\"upc\":\"1\",\"name\":\"Table\",\"reviews\":[{\"product\":{\"name\":\"Table\"},\"author\":{\"id\":\"1\",\"name\":\"Ada Lovelace\"}},{\"product\":{\"name\":\"Table\"},\"author\":{\"id\":\"2\",\"name\":\"Alan Turing\"}}]},{\"upc\":\"2\",\"name\":\"Couch\",\"reviews\":[{\"product\":{\"name\":\"Couch\"},\"author\":{\"id\":\"1\",\"name\":\"Ada Lovelace\"}}]}]},\"hasNext\":true}\n\nevent: next\ndata: {\"hasNext\":false,\"incremental\":[{\"data\":{\"id\":\"1\"},\"path\":[\"topProducts\",0,\"reviews\",0]},{\"data\":{\"id\":\"4\"},\"path\":[\"topProducts\",0,\"reviews\",1]},{\"data\":{\"id\":\"2\"},\"path\":[\"topProducts\",1,\"reviews\",0]}]}\n\nevent: complete\ndata:\n\n";
+    let query = "
+        query TopProducts($first: Int) {
+            topProducts(first: $first) {
+                upc
+                name
+                reviews {
+                    ... @defer {
+                    id
+                    }
+                    product { name }
+                    author { id name }
+                }
+            }
+        }
+    ";
+    let http_request = supergraph::Request::canned_builder()
+        .header(http::header::ACCEPT, EVENT_STREAM_CONTENT_TYPE)
+        .query(query)
+        .build()
+        .unwrap()
+        .supergraph_request
+        .map(|req: crate::request::Request| {
+            let bytes = serde_json::to_vec(&req).unwrap();
+            hyper::Body::from(bytes)
+        });
+    let response = crate::TestHarness::builder()
+        .build_router()
+        .await
+        .unwrap()
+        .oneshot(router::Request::from(http_request))
+        .await
+        .unwrap()
+        .response;
+
+    assert_eq!(response.status(), http::StatusCode::OK);
+    assert_eq!(
+        response.headers().get(CONTENT_TYPE).unwrap(),
+        EVENT_STREAM_CONTENT_TYPE
+    );
+    let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let data = String::from_utf8_lossy(&bytes);
+    assert_eq!(expected_response, data);
+}
+
 #[tokio::test]
 async fn it_will_not_process_a_batched_deferred_query() {
     let expected_response = "[\r\n--graphql\r\ncontent-type: application/json\r\n\r\n{\"errors\":[{\"message\":\"Deferred responses and subscriptions aren't supported in batches\",\"extensions\":{\"code\":\"BATCHING_DEFER_UNSUPPORTED\"}}]}\r\n--graphql--\r\n, \r\n--graphql\r\ncontent-type: application/json\r\n\r\n{\"errors\":[{\"message\":\"Deferred responses and subscriptions aren't supported in batches\",\"extensions\":{\"code\":\"BATCHING_DEFER_UNSUPPORTED\"}}]}\r\n--graphql--\r\n]";
@@ -58,6 +58,9 @@ pub struct Request {
 
     // authorization metadata for this request
     pub(crate) authorization: Arc<CacheKeyMetadata>,
+
+    /// Fetch of a deferred fragment, which can still run after the primary response was sent
+    pub(crate) deferred: bool,
 }
 
 #[buildstructor::buildstructor]
@@ -85,6 +88,7 @@ impl Request {
             connection_closed_signal,
             query_hash: Default::default(),
             authorization: Default::default(),
+            deferred: false,
         }
     }
 
@@ -148,6 +152,7 @@ impl Clone for Request {
                 .map(|s| s.resubscribe()),
             query_hash: self.query_hash.clone(),
             authorization: self.authorization.clone(),
+            deferred: self.deferred,
         }
     }
 }
@@ -25,9 +25,14 @@ use mediatype::MediaType;
 use mime::APPLICATION_JSON;
 use rustls::RootCertStore;
 use serde::Serialize;
+use tokio::net::TcpStream;
+use tokio_tungstenite::client_async_tls_with_config;
 use tokio_tungstenite::connect_async;
 use tokio_tungstenite::connect_async_tls_with_config;
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Response as WebSocketResponse;
+use tokio_tungstenite::MaybeTlsStream;
+use tokio_tungstenite::WebSocketStream;
 use tower::util::BoxService;
 use tower::BoxError;
 use tower::Service;
@@ -35,6 +40,8 @@ use tower::ServiceExt;
 use tracing::Instrument;
 use uuid::Uuid;
 
+use super::http::proxy::outbound_proxy;
+use super::http::proxy::ProxyConnector;
 use super::http::HttpClientServiceFactory;
 use super::http::HttpRequest;
 use super::layers::content_negotiation::GRAPHQL_JSON_RESPONSE_HEADER_VALUE;
@@ -43,6 +50,7 @@ use crate::configuration::TlsClientAuth;
 use crate::error::FetchError;
 use crate::graphql;
 use crate::json_ext::Object;
+use crate::notification::HandleSink;
 use crate::plugins::authentication::subgraph::SigningParamsConfig;
 use crate::plugins::file_uploads;
 use crate::plugins::subscription::create_verifier;
@@ -58,6 +66,8 @@ use crate::protocols::websocket::convert_websocket_stream;
 use crate::protocols::websocket::GraphqlWebSocket;
 use crate::query_planner::OperationKind;
 use crate::services::layers::apq;
+use crate::services::query_batching::SubgraphBatcher;
+use crate::services::trust_dns_connector::new_async_http_connector;
 use crate::services::SubgraphRequest;
 use crate::services::SubgraphResponse;
 use crate::Configuration;
@@ -80,7 +90,7 @@ static CALLBACK_PROTOCOL_ACCEPT: HeaderValue =
     HeaderValue::from_static("application/json;callbackSpec=1.0");
 pub(crate) static APPLICATION_JSON_HEADER_VALUE: HeaderValue =
     HeaderValue::from_static("application/json");
-static ACCEPT_GRAPHQL_JSON: HeaderValue =
+pub(super) static ACCEPT_GRAPHQL_JSON: HeaderValue =
     HeaderValue::from_static("application/json, application/graphql-response+json");
 
 enum APQError {
@@ -117,6 +127,8 @@ pub(crate) struct SubgraphService {
     /// Subscription config if enabled
     subscription_config: Option<SubscriptionConfig>,
     notify: Notify<String, graphql::Response>,
+    /// Coalesces the fetches to this subgraph if batching is enabled
+    batcher: Option<Arc<SubgraphBatcher>>,
 }
 
 impl SubgraphService {
@@ -136,13 +148,27 @@ impl SubgraphService {
             .map(|apq| apq.enabled)
             .unwrap_or(configuration.apq.subgraph.all.enabled);
 
-        SubgraphService::new(
-            name,
-            enable_apq,
-            subscription_config,
-            configuration.notify.clone(),
-            client_factory,
-        )
+        let batcher = configuration
+            .experimental_batching
+            .subgraph_batching(&name)
+            .map(|batching| {
+                SubgraphBatcher::new(
+                    Arc::new(name.clone()),
+                    batching.clone(),
+                    client_factory.clone(),
+                )
+            });
+
+        Ok(SubgraphService {
+            batcher,
+            ..SubgraphService::new(
+                name,
+                enable_apq,
+                subscription_config,
+                configuration.notify.clone(),
+                client_factory,
+            )?
+        })
     }
 
     pub(crate) fn new(
@@ -158,6 +184,7 @@ impl SubgraphService {
             apq: Arc::new(<AtomicBool>::new(enable_apq)),
             subscription_config,
             notify,
+            batcher: None,
         })
     }
 }
@@ -233,6 +260,10 @@ impl tower::Service<SubgraphRequest> for SubgraphService {
         let client_factory = self.client_factory.clone();
 
         let arc_apq_enabled = self.apq.clone();
+        let batcher = self
+            .batcher
+            .clone()
+            .filter(|batcher| batcher.accepts(&request));
 
         let mut notify = self.notify.clone();
         let make_calls = async move {
@@ -258,6 +289,7 @@ impl tower::Service<SubgraphRequest> for SubgraphService {
                             service_name,
                             ws_conf,
                             hashed_request,
+                            subscription_config.distributed_deduplication.is_some(),
                         )
                         .await;
                     }
@@ -359,6 +391,12 @@ impl tower::Service<SubgraphRequest> for SubgraphService {
                 }
             }
 
+            // Batched fetches are sent with their whole query, as the subgraph answers for all the
+            // operations of the batch at once
+            if let Some(batcher) = batcher {
+                return batcher.call(request, body).await;
+            }
+
             let client = client_factory.create(&service_name);
 
             // If APQ is not enabled, simply make the graphql call
@@ -434,6 +472,7 @@ async fn call_websocket(
     service_name: String,
     subgraph_cfg: &WebSocketConfiguration,
     subscription_hash: String,
+    distributed: bool,
 ) -> Result<SubgraphResponse, BoxError> {
     let operation_name = request
         .subgraph_request
@@ -471,6 +510,42 @@ async fn call_websocket(
             mode = %"passthrough",
         );
 
+        // The subscription might be owned by another router instance, open it again if that instance goes away
+        if distributed {
+            let (parts, body) = subgraph_request.into_parts();
+            let subgraph_cfg = subgraph_cfg.clone();
+            let takeover_context = context.clone();
+            tokio::task::spawn(async move {
+                if !matches!(notify.takeover(subscription_hash.clone()).await, Ok(true)) {
+                    return;
+                }
+                let handle_sink = match notify.subscribe(subscription_hash.clone()).await {
+                    Ok(handle) => handle.into_sink(),
+                    Err(err) => {
+                        tracing::trace!("subscription closed before taking it over: {err:?}");
+                        return;
+                    }
+                };
+                if let Err(err) = open_websocket(
+                    parts,
+                    body,
+                    &takeover_context,
+                    &service_name,
+                    &operation_name,
+                    &subgraph_cfg,
+                    subscription_hash.clone(),
+                    handle_sink,
+                )
+                .await
+                {
+                    tracing::error!(
+                        "cannot take over the subscription to subgraph {service_name:?}: {err}"
+                    );
+                    let _ = notify.force_delete(subscription_hash).await;
+                }
+            });
+        }
+
         // Dedup happens here
         return Ok(SubgraphResponse::builder()
             .context(context)
@@ -479,7 +554,40 @@ async fn call_websocket(
     }
 
     let (parts, body) = subgraph_request.into_parts();
+    let (handle_sink, handle_stream) = handle.split();
+
+    let resp = open_websocket(
+        parts,
+        body,
+        &context,
+        &service_name,
+        &operation_name,
+        subgraph_cfg,
+        subscription_hash,
+        handle_sink,
+    )
+    .await?;
+
+    subscription_stream_tx.send(Box::pin(handle_stream)).await?;
+
+    Ok(SubgraphResponse::new_from_response(
+        resp.map(|_| graphql::Response::default()),
+        context,
+    ))
+}
 
+/// Opens the websocket to the subgraph, sends the subscription request and forwards the events to `handle_sink`
+#[allow(clippy::too_many_arguments)]
+async fn open_websocket(
+    parts: http::request::Parts,
+    body: graphql::Request,
+    context: &Context,
+    service_name: &str,
+    operation_name: &str,
+    subgraph_cfg: &WebSocketConfiguration,
+    subscription_hash: String,
+    handle_sink: HandleSink<String, graphql::Response>,
+) -> Result<WebSocketResponse, BoxError> {
     // Check context key and Authorization header (context key takes precedence) to set connection params if needed
     let connection_params = match (
         context.get_json_value(SUBSCRIPTION_WS_CUSTOM_CONNECTION_PARAMS),
@@ -493,7 +601,7 @@ async fn call_websocket(
         _ => None,
     };
 
-    let request = get_websocket_request(service_name.clone(), parts, subgraph_cfg)?;
+    let request = get_websocket_request(service_name.to_string(), parts, subgraph_cfg)?;
 
     let display_headers = context.contains_key(LOGGING_DISPLAY_HEADERS);
     let display_body = context.contains_key(LOGGING_DISPLAY_BODY);
@@ -505,9 +613,7 @@ async fn call_websocket(
         .cloned();
 
     let request = if let Some(signing_params) = signing_params {
-        signing_params
-            .sign_empty(request, service_name.as_str())
-            .await?
+        signing_params.sign_empty(request, service_name).await?
     } else {
         request
     };
@@ -545,25 +651,20 @@ async fn call_websocket(
         "graphql.operation.name" = %operation_name,
     );
 
-    let (ws_stream, mut resp) = match request.uri().scheme_str() {
-        Some("wss") => {
-            connect_async_tls_with_config(request, None, false, None)
-                .instrument(subgraph_req_span)
-                .await
-        }
-        _ => connect_async(request).instrument(subgraph_req_span).await,
-    }
-    .map_err(|err| {
-        if display_body || display_headers {
-            tracing::info!(
-                http.response.error = format!("{:?}", &err), apollo.subgraph.name = %service_name, "Websocket connection error from subgraph {service_name:?} received"
-            );
-        }
-        FetchError::SubrequestWsError {
-            service: service_name.clone(),
-            reason: format!("cannot connect websocket to subgraph: {err}"),
-        }
-    })?;
+    let (ws_stream, mut resp) = connect_websocket(request)
+        .instrument(subgraph_req_span)
+        .await
+        .map_err(|err| {
+            if display_body || display_headers {
+                tracing::info!(
+                    http.response.error = format!("{:?}", &err), apollo.subgraph.name = %service_name, "Websocket connection error from subgraph {service_name:?} received"
+                );
+            }
+            FetchError::SubrequestWsError {
+                service: service_name.to_string(),
+                reason: format!("cannot connect websocket to subgraph: {err}"),
+            }
+        })?;
 
     if display_headers {
         tracing::info!(response.headers = ?resp.headers(), apollo.subgraph.name = %service_name, "Websocket response headers to subgraph {service_name:?}");
@@ -582,7 +683,7 @@ async fn call_websocket(
     )
     .await
     .map_err(|_| FetchError::SubrequestWsError {
-        service: service_name.clone(),
+        service: service_name.to_string(),
         reason: "cannot get the GraphQL websocket stream".to_string(),
     })?;
 
@@ -590,11 +691,10 @@ async fn call_websocket(
         .send(body)
         .await
         .map_err(|err| FetchError::SubrequestWsError {
-            service: service_name,
+            service: service_name.to_string(),
             reason: format!("cannot send the subgraph request to websocket stream: {err:?}"),
         })?;
     let (mut gql_sink, gql_stream) = gql_stream.split();
-    let (handle_sink, handle_stream) = handle.split();
 
     tokio::task::spawn(async move {
         let _ = gql_stream
@@ -607,16 +707,11 @@ async fn call_websocket(
         }
     });
 
-    subscription_stream_tx.send(Box::pin(handle_stream)).await?;
-
-    Ok(SubgraphResponse::new_from_response(
-        resp.map(|_| graphql::Response::default()),
-        context,
-    ))
+    Ok(resp)
 }
 
 /// call_http makes http calls with modified graphql::Request (body)
-async fn call_http(
+pub(super) async fn call_http(
     request: SubgraphRequest,
     body: graphql::Request,
     context: Context,
@@ -774,7 +869,7 @@ async fn call_http(
     Ok(SubgraphResponse::new_from_response(resp, context))
 }
 
-enum ContentType {
+pub(super) enum ContentType {
     ApplicationJson,
     ApplicationGraphqlResponseJson,
 }
@@ -813,7 +908,7 @@ fn get_graphql_content_type(service_name: &str, parts: &Parts) -> Result<Content
     }
 }
 
-async fn do_fetch(
+pub(super) async fn do_fetch(
     mut client: crate::services::http::BoxService,
     context: &Context,
     service_name: &str,
@@ -891,6 +986,37 @@ async fn do_fetch(
     Ok((parts, content_type, body))
 }
 
+/// Connects to the subgraph, through an HTTP CONNECT tunnel if the outbound proxy intercepts
+/// requests to its host
+async fn connect_websocket(
+    request: http::Request<()>,
+) -> Result<
+    (
+        WebSocketStream<MaybeTlsStream<TcpStream>>,
+        WebSocketResponse,
+    ),
+    BoxError,
+> {
+    let proxy = match outbound_proxy() {
+        Some(proxy) if proxy.intercepts(request.uri()) => proxy,
+        _ => {
+            let connected = match request.uri().scheme_str() {
+                Some("wss") => connect_async_tls_with_config(request, None, false, None).await,
+                _ => connect_async(request).await,
+            };
+            return connected.map_err(Into::into);
+        }
+    };
+
+    let mut http_connector = new_async_http_connector()?;
+    http_connector.set_nodelay(true);
+    let stream = ProxyConnector::with_proxy(http_connector, Some(proxy))
+        .oneshot(request.uri().clone())
+        .await?;
+    // TLS is negotiated with the subgraph through the tunnel for `wss` URLs
+    Ok(client_async_tls_with_config(request, stream, None, None).await?)
+}
+
 fn get_websocket_request(
     service_name: String,
     mut parts: http::request::Parts,
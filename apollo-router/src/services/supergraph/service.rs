@@ -35,6 +35,7 @@ use crate::plugins::subscription::SubscriptionConfig;
 use crate::plugins::telemetry::tracing::apollo_telemetry::APOLLO_PRIVATE_DURATION_NS;
 use crate::plugins::telemetry::Telemetry;
 use crate::plugins::telemetry::LOGGING_DISPLAY_BODY;
+use crate::plugins::traffic_shaping::timeout::deadline::Deadline;
 use crate::plugins::traffic_shaping::TrafficShaping;
 use crate::plugins::traffic_shaping::APOLLO_TRAFFIC_SHAPING;
 use crate::query_planner::subscription::SubscriptionHandle;
@@ -252,6 +253,7 @@ async fn service_call(
             let ClientRequestAccepts {
                 multipart_defer: accepts_multipart_defer,
                 multipart_subscription: accepts_multipart_subscription,
+                event_stream: accepts_event_stream,
                 ..
             } = context
                 .extensions()
@@ -260,13 +262,13 @@ async fn service_call(
                 .cloned()
                 .unwrap_or_default();
             let mut subscription_tx = None;
-            if (is_deferred && !accepts_multipart_defer)
-                || (is_subscription && !accepts_multipart_subscription)
+            if (is_deferred && !(accepts_multipart_defer || accepts_event_stream))
+                || (is_subscription && !(accepts_multipart_subscription || accepts_event_stream))
             {
                 let (error_message, error_code) = if is_deferred {
-                    (String::from("the router received a query with the @defer directive but the client does not accept multipart/mixed HTTP responses. To enable @defer support, add the HTTP header 'Accept: multipart/mixed;deferSpec=20220824'"), "DEFER_BAD_HEADER")
+                    (String::from("the router received a query with the @defer directive but the client does not accept multipart/mixed or text/event-stream HTTP responses. To enable @defer support, add the HTTP header 'Accept: multipart/mixed;deferSpec=20220824' or 'Accept: text/event-stream'"), "DEFER_BAD_HEADER")
                 } else {
-                    (String::from("the router received a query with a subscription but the client does not accept multipart/mixed HTTP responses. To enable subscription support, add the HTTP header 'Accept: multipart/mixed;subscriptionSpec=1.0'"), "SUBSCRIPTION_BAD_HEADER")
+                    (String::from("the router received a query with a subscription but the client does not accept multipart/mixed or text/event-stream HTTP responses. To enable subscription support, add the HTTP header 'Accept: multipart/mixed;subscriptionSpec=1.0' or 'Accept: text/event-stream'"), "SUBSCRIPTION_BAD_HEADER")
                 };
                 let mut response = SupergraphResponse::new_from_graphql_response(
                     graphql::Response::builder()
@@ -417,6 +419,9 @@ async fn subscription_task(
         OPENED_SUBSCRIPTIONS.fetch_add(1, Ordering::Relaxed);
     }
 
+    // events are fetched long after the client request which opened the subscription
+    Deadline::clear(&context);
+
     let mut configuration_updated_rx = notify.subscribe_configuration();
     let mut schema_updated_rx = notify.subscribe_schema();
 
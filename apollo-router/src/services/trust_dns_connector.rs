@@ -9,6 +9,7 @@ use std::task::Poll;
 use hyper::client::connect::dns::Name;
 use hyper::client::HttpConnector;
 use hyper::service::Service;
+use trust_dns_resolver::error::ResolveError;
 use trust_dns_resolver::TokioAsyncResolver;
 
 /// Wrapper around trust-dns-resolver's
@@ -27,6 +28,32 @@ impl AsyncHyperResolver {
         let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
         Ok(Self(resolver))
     }
+
+    /// Looks up the SRV records of a service name, returning their target and port sorted by
+    /// priority
+    pub(crate) async fn lookup_srv(&self, name: &str) -> Result<Vec<SrvTarget>, ResolveError> {
+        let mut targets = self
+            .0
+            .srv_lookup(name)
+            .await?
+            .iter()
+            .map(|srv| SrvTarget {
+                priority: srv.priority(),
+                host: srv.target().to_utf8().trim_end_matches('.').to_string(),
+                port: srv.port(),
+            })
+            .collect::<Vec<_>>();
+        targets.sort_by_key(|target| target.priority);
+        Ok(targets)
+    }
+}
+
+/// Target of a DNS SRV record
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SrvTarget {
+    pub(crate) priority: u16,
+    pub(crate) host: String,
+    pub(crate) port: u16,
 }
 
 impl Service<Name> for AsyncHyperResolver {
@@ -331,6 +331,11 @@ impl LicenseEnforcementReport {
                 .value(true)
                 .name("Subgraph entity caching")
                 .build(),
+            ConfigurationRestriction::builder()
+                .path("$.preview_response_cache.enabled")
+                .value(true)
+                .name("Supergraph response caching")
+                .build(),
             ConfigurationRestriction::builder()
                 .path("$.subscription.enabled")
                 .value(true)
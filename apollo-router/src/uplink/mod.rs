@@ -14,6 +14,8 @@ use tower::BoxError;
 use tracing::instrument::WithSubscriber;
 use url::Url;
 
+use crate::services::http::proxy::ClientBuilderExt;
+
 pub(crate) mod license_enforcement;
 pub(crate) mod license_stream;
 pub(crate) mod persisted_queries_manifest_stream;
@@ -214,6 +216,7 @@ where
     let query = query_name::<Query>();
     let (sender, receiver) = channel(2);
     let client = match reqwest::Client::builder()
+        .outbound_proxy()
         .no_gzip()
         .timeout(uplink_config.timeout)
         .build()
@@ -845,4 +845,58 @@ mod test {
             "couldn't build Router service: IO Error: Os { code: 111, kind: ConnectionRefused, message: \"Connection refused\" }"
         );
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn distributed_rate_limit() -> Result<(), BoxError> {
+        let config = RedisConfig::from_url("redis://127.0.0.1:6379")?;
+        let client = RedisClient::new(config, None, None, None);
+        let connection_task = client.connect();
+        client.wait_for_connect().await?;
+
+        client
+            .del::<String, _>("test_rate_limit:rate_limit:router")
+            .await
+            .unwrap();
+
+        let configuration = json!({
+            "traffic_shaping": {
+                "router": {
+                    "global_rate_limit": {
+                        "capacity": 2,
+                        "interval": "60s",
+                        "redis": {
+                            "urls": ["redis://127.0.0.1:6379"],
+                            "namespace": "test_rate_limit"
+                        }
+                    }
+                }
+            }
+        });
+
+        // two router instances share the same limit
+        let mut instances = Vec::new();
+        for _ in 0..2 {
+            instances.push(
+                apollo_router::TestHarness::builder()
+                    .configuration_json(configuration.clone())?
+                    .schema(include_str!("../fixtures/supergraph.graphql"))
+                    .build_supergraph()
+                    .await?,
+            );
+        }
+
+        for (i, accepted) in [(0, true), (1, true), (0, false), (1, false)] {
+            let request = supergraph::Request::fake_builder()
+                .query(r#"{ topProducts { name } }"#)
+                .method(Method::POST)
+                .build()?;
+            let response = instances[i].clone().oneshot(request).await;
+            assert_eq!(response.is_ok(), accepted);
+        }
+
+        client.quit().await?;
+        // calling quit ends the connection and event listener tasks
+        let _ = connection_task.await;
+        Ok(())
+    }
 }